@@ -0,0 +1,289 @@
+pub mod tools;
+
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+use serde_json::{json, Value};
+
+use crate::gpt::{
+    chat::ChatGpt,
+    client::{ChatResponse, HandleResult, Message, OpenAIModel, Result, Role},
+    tool::{ToolDefinition, Tools},
+};
+use tools::{CommandPolicy, Sandbox};
+
+// asked before an edit or a command
+pub trait Confirm {
+    fn confirm(&mut self, question: &str) -> bool;
+}
+pub struct StdinConfirm;
+impl Confirm for StdinConfirm {
+    fn confirm(&mut self, question: &str) -> bool {
+        eprint!("{} [y/N] ", question);
+        std::io::stderr().flush().unwrap_or_default();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).is_err() {
+            return false;
+        }
+        matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+    }
+}
+// --yes
+pub struct AutoApprove;
+impl Confirm for AutoApprove {
+    fn confirm(&mut self, _question: &str) -> bool {
+        true
+    }
+}
+
+// tool calls and their results are kept in the chat history of the session
+pub struct Agent {
+    gpt: ChatGpt,
+}
+
+impl Agent {
+    const SYSTEM: &'static str = "You are a coding agent working in a repository. Use the tools to read files, list directories and search the code before answering. To change a file, call edit_file with the whole new content of the file. Verify your changes with run_command. When the task is done, answer with a short summary of what you did.";
+    pub fn new(
+        mut gpt: ChatGpt,
+        sandbox: Sandbox,
+        policy: CommandPolicy,
+        confirm: Box<dyn Confirm>,
+    ) -> Self {
+        gpt.set_tools(agent_tools(sandbox, policy, confirm));
+        Self { gpt }
+    }
+    pub fn run<F: FnMut(&ChatResponse) -> HandleResult>(
+        &mut self,
+        model: OpenAIModel,
+        task: &str,
+        f: &mut F,
+    ) -> Result<()> {
+        let mut req = self
+            .gpt
+            .make_request(model, &Message::new(Role::User, task));
+        req.set_system(Self::SYSTEM);
+        self.gpt.send(req, f)
+    }
+    pub fn history(&self) -> &[Message] {
+        self.gpt.chat_history()
+    }
+}
+
+pub fn agent_tools(sandbox: Sandbox, policy: CommandPolicy, confirm: Box<dyn Confirm>) -> Tools {
+    let sandbox = Rc::new(sandbox);
+    let confirm = Rc::new(RefCell::new(confirm));
+    let mut tools = Tools::new();
+    let path_schema = |description: &str| {
+        json!({
+            "type": "object",
+            "properties": { "path": { "type": "string", "description": description } },
+            "required": ["path"]
+        })
+    };
+
+    let read_sandbox = sandbox.clone();
+    tools.add(
+        ToolDefinition::function(
+            "read_file",
+            "read a file of the repository",
+            path_schema("relative path of the file"),
+        ),
+        move |args| {
+            let path = str_arg(args, "path")?;
+            step("read_file", path);
+            read_sandbox.read_file(path)
+        },
+    );
+    let list_sandbox = sandbox.clone();
+    tools.add(
+        ToolDefinition::function(
+            "list_dir",
+            "list files and dirs in a dir. dirs end with /",
+            path_schema("relative path of the dir. . is the root"),
+        ),
+        move |args| {
+            let path = str_arg(args, "path").unwrap_or(".");
+            step("list_dir", path);
+            list_sandbox.list_dir(path)
+        },
+    );
+    let grep_sandbox = sandbox.clone();
+    tools.add(
+        ToolDefinition::function(
+            "grep",
+            "search lines which contain the text. returns path:line: content",
+            json!({
+                "type": "object",
+                "properties": {
+                    "pattern": { "type": "string", "description": "plain text, not regex" },
+                    "path": { "type": "string", "description": "file or dir to search. default is the root" }
+                },
+                "required": ["pattern"]
+            }),
+        ),
+        move |args| {
+            let pattern = str_arg(args, "pattern")?;
+            let path = str_arg(args, "path").unwrap_or(".");
+            step("grep", &format!("{} in {}", pattern, path));
+            grep_sandbox.grep(pattern, path)
+        },
+    );
+    let edit_sandbox = sandbox.clone();
+    let edit_confirm = confirm.clone();
+    tools.add(
+        ToolDefinition::function(
+            "edit_file",
+            "replace the whole content of a file, or create a new file. the user reviews the diff",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "relative path of the file" },
+                    "content": { "type": "string", "description": "the whole new content of the file" }
+                },
+                "required": ["path", "content"]
+            }),
+        ),
+        move |args| {
+            let path = str_arg(args, "path")?;
+            let edit = edit_sandbox.prepare_edit(path, str_arg(args, "content")?)?;
+            step("edit_file", path);
+            if edit.diff.is_empty() {
+                return Ok(format!("{} is not changed", path));
+            }
+            eprintln!("{}", edit.diff);
+            if !edit_confirm
+                .borrow_mut()
+                .confirm(&format!("apply the edit to {}?", path))
+            {
+                return Ok(format!("the user rejected the edit of {}", path));
+            }
+            edit.apply().map_err(|e| e.to_string())?;
+            Ok(format!("{} is edited", path))
+        },
+    );
+    let command_sandbox = sandbox;
+    let command_confirm = confirm;
+    tools.add(
+        ToolDefinition::function(
+            "run_command",
+            "run a command at the root without shell. only allowed commands can be run",
+            json!({
+                "type": "object",
+                "properties": {
+                    "command": { "type": "string", "description": "e.g. cargo test" }
+                },
+                "required": ["command"]
+            }),
+        ),
+        move |args| {
+            let command = str_arg(args, "command")?;
+            let command_args = policy.check(command)?;
+            step("run_command", command);
+            if !command_confirm
+                .borrow_mut()
+                .confirm(&format!("run `{}`?", command))
+            {
+                return Ok(format!("the user rejected to run {}", command));
+            }
+            let output = command_sandbox.run_command(&command_args)?;
+            eprintln!("{}", output.lines().next().unwrap_or_default());
+            Ok(output)
+        },
+    );
+    tools
+}
+
+fn str_arg<'a>(args: &'a Value, name: &str) -> std::result::Result<&'a str, String> {
+    args[name]
+        .as_str()
+        .ok_or_else(|| format!("{} is required", name))
+}
+
+// steps are shown on stderr so stdout has only the answer
+fn step(tool: &str, detail: &str) {
+    eprintln!("[{}] {}", tool, detail);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{functions::common::test_tool::TestFileFactory, gpt::tool::ToolCall};
+
+    use super::*;
+    struct FakeConfirm {
+        answer: bool,
+        questions: Rc<RefCell<Vec<String>>>,
+    }
+    impl Confirm for FakeConfirm {
+        fn confirm(&mut self, question: &str) -> bool {
+            self.questions.borrow_mut().push(question.to_string());
+            self.answer
+        }
+    }
+    fn tools_with(root: &str, answer: bool) -> (Tools, Rc<RefCell<Vec<String>>>) {
+        let questions = Rc::new(RefCell::new(Vec::new()));
+        let tools = agent_tools(
+            Sandbox::new(root).unwrap(),
+            CommandPolicy::default(),
+            Box::new(FakeConfirm {
+                answer,
+                questions: questions.clone(),
+            }),
+        );
+        (tools, questions)
+    }
+    #[test]
+    fn 確認で承認された編集だけをファイルに書き込む() {
+        let test_file = TestFileFactory::create("tmp_agent_edit");
+        test_file.create_file_under_root("main.rs", "fn main() {}\n");
+        let (mut rejected, _) = tools_with("tmp_agent_edit", false);
+        let (mut approved, questions) = tools_with("tmp_agent_edit", true);
+        let args = json!({
+            "path": "main.rs",
+            "content": "```rust\nfn main() {\n    println!(\"hi\");\n}\n```"
+        })
+        .to_string();
+
+        let rejected = rejected.call(&ToolCall::new("1", "edit_file", args.clone()));
+        let after_reject = std::fs::read_to_string("tmp_agent_edit/main.rs").unwrap();
+        let approved = approved.call(&ToolCall::new("2", "edit_file", args));
+        let after_approve = std::fs::read_to_string("tmp_agent_edit/main.rs").unwrap();
+
+        test_file.remove_dir_all();
+        assert_eq!(rejected, "the user rejected the edit of main.rs");
+        assert_eq!(after_reject, "fn main() {}\n");
+        assert_eq!(approved, "main.rs is edited");
+        assert_eq!(after_approve, "fn main() {\n    println!(\"hi\");\n}\n");
+        assert_eq!(*questions.borrow(), vec!["apply the edit to main.rs?"]);
+    }
+    #[test]
+    fn 許可されていないコマンドは確認せずに拒否する() {
+        let test_file = TestFileFactory::create("tmp_agent_command");
+        let (mut tools, questions) = tools_with("tmp_agent_command", true);
+
+        let result = tools.call(&ToolCall::new(
+            "1",
+            "run_command",
+            json!({"command": "rm -rf ."}).to_string(),
+        ));
+
+        test_file.remove_dir_all();
+        assert!(result.starts_with("error: rm -rf . is not allowed"));
+        assert!(questions.borrow().is_empty());
+    }
+    #[test]
+    fn ワークスペースの外のファイルは読めない() {
+        let test_file = TestFileFactory::create("tmp_agent_read");
+        test_file.create_file_under_root("a.txt", "hello");
+        let (mut tools, _) = tools_with("tmp_agent_read", true);
+
+        let inside = tools.call(&ToolCall::new("1", "read_file", r#"{"path":"a.txt"}"#));
+        let outside = tools.call(&ToolCall::new(
+            "2",
+            "read_file",
+            r#"{"path":"../Cargo.toml"}"#,
+        ));
+
+        test_file.remove_dir_all();
+        assert_eq!(inside, "hello");
+        assert!(outside.starts_with("error:"));
+    }
+}
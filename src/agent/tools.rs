@@ -0,0 +1,353 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+use crate::functions::{
+    code_capture::CodeCapture,
+    common::{diff, get_file_content, is_file_path, list_files},
+};
+
+// the model can touch only files under the root
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    const MAX_OUTPUT: usize = 20_000;
+    const MAX_MATCHES: usize = 100;
+    pub fn new(root: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+        })
+    }
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+    // a new file is allowed if its parent dir is in the root
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let path = Path::new(path.trim());
+        if path.components().any(|c| c == Component::ParentDir) {
+            return Err(format!("{} must not contain ..", path.display()));
+        }
+        let joined = self.root.join(path);
+        let resolved = if joined.exists() {
+            joined.canonicalize().map_err(|e| e.to_string())?
+        } else {
+            let parent = joined
+                .parent()
+                .ok_or_else(|| format!("{} has no parent dir", path.display()))?
+                .canonicalize()
+                .map_err(|e| format!("{} : {}", path.display(), e))?;
+            parent.join(joined.file_name().unwrap_or_default())
+        };
+        if !resolved.starts_with(&self.root) {
+            return Err(format!("{} is outside of the workspace", path.display()));
+        }
+        Ok(resolved)
+    }
+    // relative path shown to the model and the user
+    pub fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string()
+    }
+    pub fn read_file(&self, path: &str) -> Result<String, String> {
+        let path = self.resolve(path)?;
+        let path = path.to_string_lossy();
+        if !is_file_path(&path) {
+            return Err(format!("{} is not a file", path));
+        }
+        get_file_content(&path)
+            .map(|content| truncate(content, Self::MAX_OUTPUT))
+            .map_err(|e| e.to_string())
+    }
+    // dirs end with /
+    pub fn list_dir(&self, path: &str) -> Result<String, String> {
+        let path = self.resolve(path)?;
+        let mut entries = std::fs::read_dir(&path)
+            .map_err(|e| e.to_string())?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                // a symlink is not followed, so it is listed as a file
+                if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                    format!("{}/", name)
+                } else {
+                    name
+                }
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        Ok(entries.join("\n"))
+    }
+    // plain text search. hidden dirs, target and symlinks are skipped,
+    // since a symlink may point outside of the root
+    pub fn grep(&self, pattern: &str, path: &str) -> Result<String, String> {
+        if pattern.is_empty() {
+            return Err("pattern is empty".to_string());
+        }
+        let path = self.resolve(path)?;
+        let files = if path.is_file() {
+            vec![path.to_string_lossy().to_string()]
+        } else {
+            list_files(&path.to_string_lossy()).map_err(|e| e.to_string())?
+        };
+        let matches = files
+            .iter()
+            .filter(|file| {
+                !Path::new(&self.relative(Path::new(file)))
+                    .components()
+                    .any(|c| c.as_os_str() == "target")
+            })
+            .filter(|file| {
+                std::fs::symlink_metadata(file).is_ok_and(|metadata| !metadata.is_symlink())
+            })
+            // binary files can not be read as string
+            .filter_map(|file| Some((file, get_file_content(file).ok()?)))
+            .flat_map(|(file, content)| {
+                content
+                    .lines()
+                    .enumerate()
+                    .filter(|(_, line)| line.contains(pattern))
+                    .map(|(i, line)| {
+                        format!(
+                            "{}:{}: {}",
+                            self.relative(Path::new(file)),
+                            i + 1,
+                            line.trim()
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .take(Self::MAX_MATCHES)
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            return Ok("no match".to_string());
+        }
+        Ok(matches.join("\n"))
+    }
+    // the new content and its diff from the current file
+    pub fn prepare_edit(&self, path: &str, content: &str) -> Result<Edit, String> {
+        let path = self.resolve(path)?;
+        let old = if path.exists() {
+            get_file_content(&path.to_string_lossy()).map_err(|e| e.to_string())?
+        } else {
+            String::new()
+        };
+        let new = extract_code(content);
+        Ok(Edit {
+            diff: diff(&old, &new),
+            path,
+            new,
+        })
+    }
+    pub fn run_command(&self, args: &[String]) -> Result<String, String> {
+        let output = Command::new(&args[0])
+            .args(&args[1..])
+            .current_dir(&self.root)
+            .output()
+            .map_err(|e| format!("{} : {}", args[0], e))?;
+        let result = format!(
+            "{}\n{}{}",
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Ok(truncate_head(result, Self::MAX_OUTPUT))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub path: PathBuf,
+    pub new: String,
+    pub diff: String,
+}
+impl Edit {
+    pub fn apply(&self) -> std::io::Result<()> {
+        std::fs::write(&self.path, &self.new)
+    }
+}
+
+// commands are run without shell. extra args are allowed after the allowed prefix,
+// except args which change what cargo runs. e.g. --config or +toolchain
+#[derive(Debug, Clone)]
+pub struct CommandPolicy {
+    allowed: Vec<Vec<String>>,
+}
+impl Default for CommandPolicy {
+    fn default() -> Self {
+        Self::new(&["cargo test", "cargo check"])
+    }
+}
+impl CommandPolicy {
+    const DENIED_ARGS: [&'static str; 3] = ["--config", "--manifest-path", "-Z"];
+    pub fn new(allowed: &[&str]) -> Self {
+        Self {
+            allowed: allowed.iter().map(|command| split(command)).collect(),
+        }
+    }
+    pub fn check(&self, command: &str) -> Result<Vec<String>, String> {
+        let args = split(command);
+        if args
+            .iter()
+            .any(|arg| arg.contains(['|', '&', ';', '>', '<', '`', '$']))
+        {
+            return Err(format!("{} : shell syntax is not allowed", command));
+        }
+        if let Some(arg) = args.iter().skip(1).find(|arg| {
+            arg.starts_with('+')
+                || Self::DENIED_ARGS
+                    .iter()
+                    .any(|denied| arg.starts_with(denied))
+        }) {
+            return Err(format!("{} : {} is not allowed", command, arg));
+        }
+        if self
+            .allowed
+            .iter()
+            .any(|allowed| !allowed.is_empty() && args.starts_with(allowed))
+        {
+            Ok(args)
+        } else {
+            Err(format!(
+                "{} is not allowed. allowed commands are {}",
+                command,
+                self.allowed
+                    .iter()
+                    .map(|allowed| allowed.join(" "))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        }
+    }
+}
+fn split(command: &str) -> Vec<String> {
+    command.split_whitespace().map(str::to_string).collect()
+}
+
+// the model often answers the content in a markdown code block
+pub fn extract_code(content: &str) -> String {
+    let mut capture = CodeCapture::new();
+    capture.add(content);
+    match capture.get_codes().first() {
+        Some(code) => code.as_str().to_string(),
+        None => content.to_string(),
+    }
+}
+
+fn truncate(mut content: String, max: usize) -> String {
+    if content.len() <= max {
+        return content;
+    }
+    let mut end = max;
+    while !content.is_char_boundary(end) {
+        end -= 1;
+    }
+    content.truncate(end);
+    content.push_str("\n... (truncated)");
+    content
+}
+// the end of command output has the errors
+fn truncate_head(content: String, max: usize) -> String {
+    if content.len() <= max {
+        return content;
+    }
+    let mut start = content.len() - max;
+    while !content.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("(truncated) ...\n{}", &content[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::common::test_tool::TestFileFactory;
+
+    use super::*;
+    #[test]
+    fn ワークスペースの外のパスは拒否する() {
+        let test_file = TestFileFactory::create("tmp_agent_sandbox");
+        test_file.create_file_under_root("main.rs", "fn main() {}");
+        let sut = Sandbox::new("tmp_agent_sandbox").unwrap();
+
+        let inside = sut.resolve("main.rs");
+        let new_file = sut.resolve("new.rs");
+        let parent = sut.resolve("../Cargo.toml");
+        let absolute = sut.resolve("/etc/passwd");
+        let read = sut.read_file("main.rs");
+
+        test_file.remove_dir_all();
+        assert!(inside.is_ok());
+        assert!(new_file.is_ok());
+        assert!(parent.is_err());
+        assert!(absolute.is_err());
+        assert_eq!(read.unwrap(), "fn main() {}");
+    }
+    #[test]
+    fn ディレクトリの一覧と文字列の検索() {
+        let test_file = TestFileFactory::create("tmp_agent_grep");
+        test_file.create_file_under_root("a.rs", "fn main() {\n    todo!()\n}");
+        test_file.create_file_under_root("b.rs", "// nothing");
+        std::fs::create_dir("tmp_agent_grep/sub").unwrap();
+        let sut = Sandbox::new("tmp_agent_grep").unwrap();
+
+        let list = sut.list_dir(".");
+        let grep = sut.grep("todo!", ".");
+        let none = sut.grep("unknown", ".");
+
+        test_file.remove_dir_all();
+        assert_eq!(list.unwrap(), "a.rs\nb.rs\nsub/");
+        assert_eq!(grep.unwrap(), "a.rs:2: todo!()");
+        assert_eq!(none.unwrap(), "no match");
+    }
+    #[test]
+    #[cfg(unix)]
+    fn シンボリックリンクは検索せずディレクトリとして一覧しない() {
+        let test_file = TestFileFactory::create("tmp_agent_grep_link");
+        test_file.create_file_under_root("a.rs", "todo!()");
+        let outside = std::env::current_dir().unwrap().join("Cargo.toml");
+        std::os::unix::fs::symlink(&outside, "tmp_agent_grep_link/outside.toml").unwrap();
+        std::os::unix::fs::symlink(std::env::current_dir().unwrap(), "tmp_agent_grep_link/up")
+            .unwrap();
+        let sut = Sandbox::new("tmp_agent_grep_link").unwrap();
+
+        let list = sut.list_dir(".");
+        let grep = sut.grep("[package]", ".");
+
+        test_file.remove_dir_all();
+        assert_eq!(list.unwrap(), "a.rs\noutside.toml\nup");
+        assert_eq!(grep.unwrap(), "no match");
+    }
+    #[test]
+    fn 許可されたコマンドだけを実行できる() {
+        let sut = CommandPolicy::default();
+
+        assert_eq!(
+            sut.check("cargo test --lib").unwrap(),
+            vec!["cargo", "test", "--lib"]
+        );
+        assert!(sut.check("cargo check").is_ok());
+        assert!(sut.check("cargo install evil").is_err());
+        assert!(sut.check("rm -rf /").is_err());
+        assert!(sut.check("cargo test; rm -rf /").is_err());
+        assert!(sut.check("cargo test --config build.rustc=evil").is_err());
+        assert!(sut.check("cargo test --config=build.rustc=evil").is_err());
+        assert!(sut
+            .check("cargo check --manifest-path /tmp/Cargo.toml")
+            .is_err());
+        assert!(sut.check("cargo test -Zunstable-options").is_err());
+        assert!(sut.check("cargo test +nightly").is_err());
+    }
+    #[test]
+    fn コードブロックの中身を編集内容として取り出す() {
+        assert_eq!(
+            extract_code("here it is\n```rust\nfn main() {}\n```\n"),
+            "fn main() {}\n"
+        );
+        assert_eq!(extract_code("plain text"), "plain text");
+    }
+}
@@ -2,5 +2,8 @@ use termai::cli::TermAI;
 
 fn main() {
     let gpt = TermAI::new();
-    gpt.run();
+    if let Err(e) = gpt.run() {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
+    }
 }
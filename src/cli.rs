@@ -1,3 +1,17 @@
+mod batch;
+mod batch_api;
+mod cache;
+mod commit;
+mod editor;
+pub mod error;
+mod fix;
+mod input;
+mod lsp;
+mod output;
+mod review;
+mod rpc;
+mod serve;
+
 #[cfg(target_os = "macos")]
 use crate::functions::speaker::MacSpeaker;
 
@@ -5,22 +19,76 @@ use crate::functions::speaker::MacSpeaker;
 use crate::functions::speaker::{say_command, MacSayCommandSpeaker};
 use crate::functions::GptDefaultFunction;
 use crate::{
+    agent::{
+        tools::{CommandPolicy, Sandbox},
+        Agent, AutoApprove, Confirm, StdinConfirm,
+    },
+    config::{Config, ConfigError, Profile},
     functions::{
         code_capture::GptCodeCapture,
-        code_reviewer::CodeReviewer,
+        code_reviewer::{
+            diff::parse_diff, finding::Severity, options::Rubric, targets::find_targets,
+            CodeReviewer,
+        },
+        common::{is_file_path, list_files},
+        config_function::ConfigFunction,
+        plugin::{self, PluginFunction},
+        registry::{FunctionKind, FunctionRegistry},
         repl::ChatGptRepl,
         translator::{FileTranslator, TranslateMode, Translator},
-        GptFunction, UserInput,
+        GptFunction, GptFunctionContainer, UserInput,
+    },
+    gpt::{
+        cache::{CacheMode, ResponseCache},
+        chat::ChatGpt,
+        client::{
+            ChatRequest, GptClient, GptClientError, GptClientOption, HandleResult, OpenAIModel,
+            Role,
+        },
+        tool::Tools,
+    },
+    mcp::{
+        client::{start_servers, McpClient},
+        server::McpServer,
     },
-    gpt::client::{ChatRequest, GptClient, OpenAIModel},
 };
+use batch::Batch;
 use clap::{Parser, Subcommand};
-use std::{io::Write, str::FromStr, thread::sleep, time::Duration};
+use error::TermAIError;
+use fix::{DiffSide, StdinFixPrompt};
+use input::InputArgs;
+use lsp::{LspEvent, LspServer};
+use output::{BufferOutput, ExecSummary, OutputFormat, ResponseOutput};
+use review::{FileReview, ReviewFormat};
+use rpc::{Connection, RpcServer};
+use serve::Proxy;
+use std::{
+    io::Write,
+    net::TcpListener,
+    str::FromStr,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 #[derive(Parser)]
 pub struct TermAI {
     #[clap(subcommand)]
     subcommand: SubCommands,
+    /// profile of config.toml. default is the default profile
+    #[clap(long = "profile", global = true)]
+    profile: Option<String>,
+    /// text, json or ndjson
+    #[clap(long = "output", global = true, default_value = "text")]
+    output: OutputFormat,
+    /// always send the request. the response is not cached
+    #[clap(long = "no-cache", global = true)]
+    no_cache: bool,
+    /// send the request even if cached, and overwrite the cache by the response
+    #[clap(long = "refresh", global = true)]
+    refresh: bool,
+    /// do not start mcp servers of the profile
+    #[clap(long = "no-mcp", global = true)]
+    no_mcp: bool,
 }
 
 #[derive(Subcommand)]
@@ -28,7 +96,18 @@ enum SubCommands {
     Ask {
         #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
         gpt_version: GptVersion,
-        source: String,
+        #[clap(flatten)]
+        input: InputArgs,
+        source: Option<String>,
+    },
+    /// coding agent which reads, searches and edits files under the current dir
+    Agent {
+        #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
+        gpt_version: GptVersion,
+        /// apply edits and run commands without asking
+        #[clap(short = 'y', long = "yes")]
+        yes: bool,
+        task: String,
     },
     Chat {
         #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
@@ -38,9 +117,18 @@ enum SubCommands {
         #[clap(short = 'r', long = "code-reviewer", default_value = "false")]
         code_reviewer: bool,
         #[clap(short = 't', long = "translator")]
-        translator: Option<TranslateMode>,
+        translator: Option<String>,
         #[clap(short = 's', long = "speaker", default_value = "false")]
         speaker: bool,
+        /// name[:args] of builtin, config or plugin functions. see `termai functions list`
+        #[clap(long = "with")]
+        with: Vec<String>,
+        /// name[:args] of functions defined in config.toml. same as --with
+        #[clap(long = "function")]
+        functions: Vec<String>,
+        /// name of executables in the plugins dir. same as --with
+        #[clap(long = "plugin")]
+        plugins: Vec<String>,
     },
     #[cfg(target_os = "macos")]
     #[clap(name = "tas")]
@@ -52,7 +140,9 @@ enum SubCommands {
     Speaker {
         #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
         gpt_version: GptVersion,
-        source: String,
+        #[clap(flatten)]
+        input: InputArgs,
+        source: Option<String>,
     },
     #[clap(name = "tjp")]
     TranslatorJp {
@@ -60,6 +150,8 @@ enum SubCommands {
         gpt_version: GptVersion,
         #[clap(short = 'f', long = "file-source")]
         file_path: Option<String>,
+        #[clap(flatten)]
+        input: InputArgs,
         source: Option<String>,
     },
     #[clap(name = "ten")]
@@ -68,22 +160,250 @@ enum SubCommands {
         gpt_version: GptVersion,
         #[clap(short = 'f', long = "file-source")]
         file_path: Option<String>,
+        #[clap(flatten)]
+        input: InputArgs,
         source: Option<String>,
     },
     #[clap(name = "cc")]
     CodeCapture {
         #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
         gpt_version: GptVersion,
-        source: String,
+        #[clap(flatten)]
+        input: InputArgs,
+        source: Option<String>,
     },
     #[clap(name = "cr")]
     CodeReviewer {
         #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
         gpt_version: GptVersion,
+        /// a file, a directory or a glob. files of a directory or a glob are reviewed concurrently
         #[clap(short = 'f', long = "file-source")]
         file_path: Option<String>,
         #[clap(short = 's', long = "source")]
         source: Option<String>,
+        /// files reviewed at once
+        #[clap(short = 'j', long = "concurrency", default_value = "4")]
+        concurrency: usize,
+        /// requests per minute. 0 means no limit
+        #[clap(long = "rpm", default_value = "60")]
+        requests_per_minute: u32,
+        /// larger files of a directory or a glob are skipped
+        #[clap(long = "max-bytes", default_value = "100000")]
+        max_bytes: u64,
+        /// prose is the answer as it is. findings and json ask the model for structured findings
+        #[clap(long = "format", default_value = "prose")]
+        format: ReviewFormat,
+        /// only for structured findings
+        #[clap(long = "min-severity", default_value = "info")]
+        min_severity: Severity,
+        /// language of the review. default is [review] of the config, or Japanese for prose
+        #[clap(long = "lang")]
+        lang: Option<String>,
+        /// security, performance, readability or tests. can be repeated
+        #[clap(long = "rubric")]
+        rubrics: Vec<Rubric>,
+        /// guideline file of the project (default .termai/review.md)
+        #[clap(long = "guidelines")]
+        guidelines: Option<String>,
+        /// review `git diff` of the working tree, or of the rev range. findings are always structured
+        #[clap(long = "diff", value_name = "REV_RANGE", num_args = 0..=1)]
+        diff: Option<Option<String>>,
+        /// review `git diff --staged`
+        #[clap(long = "staged")]
+        staged: bool,
+        /// lines around each hunk sent with the diff
+        #[clap(long = "context-lines", default_value = "10")]
+        context_lines: usize,
+        /// write the findings to the file instead of stdout
+        #[clap(short = 'o', long = "out")]
+        out: Option<String>,
+        /// ask to apply each fix of the findings to the files
+        #[clap(long = "fix")]
+        fix: bool,
+        #[clap(flatten)]
+        input: InputArgs,
+    },
+    /// write a message of the staged changes in Conventional Commits, then commit with it
+    Commit {
+        #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
+        gpt_version: GptVersion,
+        /// language of the message. default is [commit] of the config, or English
+        #[clap(long = "lang")]
+        lang: Option<String>,
+        /// tokens of the diff sent at once. a larger diff is summarized part by part
+        #[clap(long = "max-tokens")]
+        max_tokens: Option<usize>,
+        /// commit without opening $EDITOR
+        #[clap(short = 'y', long = "yes")]
+        yes: bool,
+        /// print the message and do not commit
+        #[clap(long = "dry-run")]
+        dry_run: bool,
+        /// run as prepare-commit-msg hook. e.g. termai commit --hook "$1" "$2" "$3"
+        #[clap(long = "hook", value_name = "MSG_FILE")]
+        hook: Option<String>,
+        /// source and sha given to prepare-commit-msg
+        hook_args: Vec<String>,
+    },
+    /// run a jsonl file of prompts. lines which already succeeded are skipped on re-run
+    Batch {
+        #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
+        gpt_version: GptVersion,
+        /// results jsonl. default is <input>.out.jsonl
+        #[clap(short = 'o', long = "out")]
+        out: Option<String>,
+        /// lines run at once
+        #[clap(short = 'j', long = "concurrency", default_value = "4")]
+        concurrency: usize,
+        /// requests per minute. 0 means no limit
+        #[clap(long = "rpm", default_value = "60")]
+        requests_per_minute: u32,
+        input: String,
+    },
+    /// make request file of OpenAI Batch API and ingest its results
+    #[clap(name = "batch-api")]
+    BatchApi {
+        #[clap(subcommand)]
+        command: BatchApiCommand,
+    },
+    /// inspect and purge cached responses
+    Cache {
+        #[clap(subcommand)]
+        command: CacheCommand,
+    },
+    /// builtin, config and plugin functions
+    Functions {
+        #[clap(subcommand)]
+        command: FunctionsCommand,
+    },
+    /// functions written in any language. see README for the protocol
+    Plugin {
+        #[clap(subcommand)]
+        command: PluginCommand,
+    },
+    /// mcp servers of the profile
+    Mcp {
+        #[clap(subcommand)]
+        command: McpCommand,
+    },
+    /// language server with review diagnostics on save and code actions
+    Lsp {
+        #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
+        gpt_version: GptVersion,
+        /// a file is reviewed when it is not saved again in this time
+        #[clap(long = "debounce-ms", default_value = "1500")]
+        debounce_ms: u64,
+    },
+    /// json-rpc 2.0 server on stdio for editors. see README for the methods
+    Rpc {
+        /// default model. each session or function/run can choose another one
+        #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
+        gpt_version: GptVersion,
+    },
+    /// OpenAI compatible /v1/chat/completions on localhost, sent by the profile of termai
+    Serve {
+        /// address to listen on
+        #[clap(long = "host", default_value = "127.0.0.1")]
+        host: String,
+        /// port to listen on
+        #[clap(short = 'p', long = "port", default_value = "8787")]
+        port: u16,
+        /// name[:args] of functions which change each request. responses are not changed
+        #[clap(long = "with")]
+        with: Vec<String>,
+    },
+    /// serve translate, review, code extraction and ask as an mcp server over stdio
+    #[clap(name = "mcp-serve")]
+    McpServe {
+        /// default model. each tool call can choose another one
+        #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
+        gpt_version: GptVersion,
+    },
+    /// functions defined by [[functions]] of config.toml
+    #[clap(external_subcommand)]
+    Custom(Vec<String>),
+}
+
+// args after the name of a custom function are not parsed by TermAI,
+// so the global flags are parsed here too. e.g. termai summary README.md --no-cache
+#[derive(Parser)]
+struct CustomFunctionArgs {
+    #[clap(long = "profile")]
+    profile: Option<String>,
+    #[clap(long = "output")]
+    output: Option<OutputFormat>,
+    #[clap(long = "no-cache")]
+    no_cache: bool,
+    #[clap(long = "refresh")]
+    refresh: bool,
+    #[clap(long = "no-mcp")]
+    no_mcp: bool,
+    #[clap(short = 'v', long = "gpt-version")]
+    gpt_version: Option<GptVersion>,
+    /// value of {{lang}} in the prompt
+    #[clap(long = "lang")]
+    lang: Option<String>,
+    #[clap(flatten)]
+    input: InputArgs,
+    source: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum FunctionsCommand {
+    List,
+}
+
+#[derive(Subcommand)]
+enum PluginCommand {
+    List,
+    Run {
+        #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
+        gpt_version: GptVersion,
+        name: String,
+        #[clap(flatten)]
+        input: InputArgs,
+        source: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum McpCommand {
+    /// tools given to the model
+    List,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    List,
+    Stats,
+    Purge {
+        /// remove only entries older than ttl
+        #[clap(long = "expired")]
+        expired: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum BatchApiCommand {
+    Build {
+        #[clap(short = 'v', long = "gpt-version", default_value = "gpt4o")]
+        gpt_version: GptVersion,
+        /// request file to upload. the manifest is written next to it
+        #[clap(short = 'o', long = "out", default_value = "batch_requests.jsonl")]
+        out: String,
+        /// every file under the dir becomes an input
+        #[clap(short = 'd', long = "dir")]
+        dir: Option<String>,
+        #[clap(long = "lang")]
+        lang: Option<String>,
+        function: String,
+        inputs: Vec<String>,
+    },
+    Ingest {
+        /// default is the manifest made by build with default out
+        #[clap(short = 'm', long = "manifest")]
+        manifest: Option<String>,
+        results: String,
     },
 }
 
@@ -91,87 +411,237 @@ fn exec_with_function(
     client: &mut GptClient,
     model: OpenAIModel,
     input: UserInput,
-    f: &mut impl GptFunction,
-) {
+    f: &mut (impl GptFunction + ?Sized),
+    tools: &mut Tools,
+    output: &mut dyn ResponseOutput,
+) -> Result<ExecSummary, TermAIError> {
     fn display_result_and_handle_stream(
         client: &mut GptClient,
-        f: &mut impl GptFunction,
+        f: &mut (impl GptFunction + ?Sized),
+        tools: &mut Tools,
+        output: &mut dyn ResponseOutput,
         req: ChatRequest,
+        dispatched: &mut bool,
     ) -> crate::gpt::client::Result<()> {
-        client.request_mut_fn(req, |res| {
-            print!("{}", res.delta_content());
-            std::io::stdout().flush().unwrap();
-            f.handle_stream(res)
-        })
+        client.request_with_tools(
+            req,
+            tools,
+            |res| {
+                output.delta(res);
+                f.handle_stream(res)
+            },
+            |message| *dispatched |= message.role() == Role::Tool,
+        )
     }
+    // auth errors are not retried, and a rate limited request waits for Retry-After.
+    // a request is not retried after a tool is called, since the tool may have side effects
     fn retry_request(
         client: &mut GptClient,
         req: ChatRequest,
-        f: &mut impl GptFunction,
+        f: &mut (impl GptFunction + ?Sized),
+        tools: &mut Tools,
+        output: &mut dyn ResponseOutput,
+        error: GptClientError,
+        dispatched: &mut bool,
     ) -> crate::gpt::client::Result<()> {
+        if *dispatched || !error.is_retryable() {
+            return Err(error);
+        }
         client.re_connect()?;
-        sleep(Duration::from_secs(1));
-        display_result_and_handle_stream(client, f, req.clone())
+        sleep(
+            error
+                .retry_after()
+                .unwrap_or(Duration::from_secs(1))
+                .min(Duration::from_secs(60)),
+        );
+        display_result_and_handle_stream(client, f, tools, output, req.clone(), dispatched)
     }
+    let started = Instant::now();
+    let mut summary = ExecSummary::new(model);
     f.setup_for_action(&input);
     let messages = f.input_to_messages(input);
-    messages.into_iter().for_each(|message| {
-        let req = ChatRequest::from_message(model, message);
-        display_result_and_handle_stream(client, f, req.clone())
-            .or_else(|_e| retry_request(client, req.clone(), f))
-            .or_else(|_e| retry_request(client, req.clone(), f))
-            .or_else(|e| {
-                f.action_at_end().unwrap();
-                Err(e)
-            })
-            .unwrap()
-    });
-    f.action_at_end().unwrap();
+    output.start();
+    for message in messages {
+        let mut req = ChatRequest::from_message(model, message);
+        f.change_request(&mut req);
+        let mut dispatched = false;
+        let result = display_result_and_handle_stream(
+            client,
+            f,
+            tools,
+            output,
+            req.clone(),
+            &mut dispatched,
+        )
+        .or_else(|e| retry_request(client, req.clone(), f, tools, output, e, &mut dispatched))
+        .or_else(|e| retry_request(client, req.clone(), f, tools, output, e, &mut dispatched));
+        if let Err(e) = result {
+            let e = TermAIError::from(e);
+            output.error(&e);
+            // keep what is already received. e.g. file translator appends the translated part.
+            // the error of the request is returned even if this fails
+            if let Err(end_error) = f.action_at_end() {
+                eprintln!("{} failed at the end : {}", f.name(), end_error);
+            }
+            return Err(e);
+        }
+        summary.update(client.last_completion());
+    }
+    if let Err(e) = f.action_at_end() {
+        let e = TermAIError::from(e);
+        output.error(&e);
+        return Err(e);
+    }
+    summary.finish(started.elapsed(), f.artifacts());
+    output.finish(&summary);
+    Ok(summary)
 }
 
 impl TermAI {
     pub fn new() -> Self {
         Self::parse()
     }
+    // global flags given after a custom function win
+    fn with_custom_args(&self, args: &CustomFunctionArgs) -> Self {
+        Self {
+            subcommand: SubCommands::Custom(Vec::new()),
+            profile: args.profile.clone().or_else(|| self.profile.clone()),
+            output: args.output.unwrap_or(self.output),
+            no_cache: self.no_cache || args.no_cache,
+            refresh: self.refresh || args.refresh,
+            no_mcp: self.no_mcp || args.no_mcp,
+        }
+    }
+    fn profile(&self) -> Result<Profile, ConfigError> {
+        Config::load().and_then(|config| config.profile(self.profile.as_deref()))
+    }
+    fn cache(&self) -> Result<Option<(ResponseCache, CacheMode)>, ConfigError> {
+        let config = Config::load()?;
+        if self.no_cache || !config.cache_enabled() {
+            return Ok(None);
+        }
+        let mode = if self.refresh {
+            CacheMode::Refresh
+        } else {
+            CacheMode::Use
+        };
+        Ok(Some((config.cache(), mode)))
+    }
+    fn client(&self) -> Result<GptClient, TermAIError> {
+        let client = GptClient::from_profile(&self.profile()?)?;
+        match self.cache()? {
+            Some((cache, mode)) => Ok(client.with_cache(cache, mode)),
+            None => Ok(client),
+        }
+    }
+    fn repl(&self) -> Result<ChatGptRepl, TermAIError> {
+        Ok(ChatGptRepl::new_with_functions(
+            ChatGpt::from_profile(&self.profile()?)?,
+            GptFunctionContainer::new(),
+        ))
+    }
+    // tools of mcp servers of the profile. the servers are stopped when the tools are dropped.
+    // only commands whose model may call tools start them. e.g. not translation
+    fn tools(&self) -> Result<Tools, TermAIError> {
+        if self.no_mcp {
+            return Ok(Tools::new());
+        }
+        Ok(start_servers(self.profile()?.mcp_servers()))
+    }
+    fn output(&self) -> Box<dyn ResponseOutput> {
+        self.output.to_output()
+    }
+    fn config_function(&self, name: &str) -> Result<ConfigFunction, TermAIError> {
+        let definition = Config::load()?.function(name).cloned().ok_or_else(|| {
+            TermAIError::InvalidInput(format!("subcommand or function {} is not found", name))
+        })?;
+        ConfigFunction::new(definition).map_err(|e| ConfigError::new(e).into())
+    }
+    fn registry(&self) -> Result<FunctionRegistry, TermAIError> {
+        let config = Config::load()?;
+        let mut registry = FunctionRegistry::with_options(config.review(), config.commit());
+        registry.add_config_functions(config.functions());
+        registry.add_plugins(&config.plugins_dir(), config.plugin_timeout())?;
+        Ok(registry)
+    }
+    fn plugin(&self, name: &str) -> Result<PluginFunction, TermAIError> {
+        let config = Config::load()?;
+        let path = plugin::find(&config.plugins_dir(), name)?.ok_or_else(|| {
+            TermAIError::InvalidInput(format!(
+                "plugin {} is not found in {}",
+                name,
+                config.plugins_dir()
+            ))
+        })?;
+        PluginFunction::spawn(path, config.plugin_timeout())
+            .map_err(|e| TermAIError::Function(Box::new(e)))
+    }
 
-    pub fn run(&self) {
+    pub fn run(&self) -> Result<(), TermAIError> {
         match &self.subcommand {
             #[cfg(target_os = "macos")]
             SubCommands::TranslateAndSpeak { gpt_version } => {
-                let mut repl = ChatGptRepl::new();
+                let mut repl = self.repl()?;
                 repl.add_functions(Box::new(Translator::new(TranslateMode::ToJapanese)));
                 match gpt_version {
-                    GptVersion::Gpt3 => repl
-                        .repl_with_input_fn(OpenAIModel::Gpt3Dot5Turbo, |input| {
-                            say_command(input, &MacSayCommandSpeaker::Karen).unwrap();
-                        })
-                        .unwrap(),
-                    GptVersion::Gpt4 => repl
-                        .repl_with_input_fn(OpenAIModel::Gpt4, |input| {
+                    GptVersion::Gpt3 => {
+                        repl.repl_with_input_fn(OpenAIModel::Gpt3Dot5Turbo, |input| {
                             say_command(input, &MacSayCommandSpeaker::Karen).unwrap();
-                        })
-                        .unwrap(),
+                        })?
+                    }
+                    GptVersion::Gpt4 => repl.repl_with_input_fn(OpenAIModel::Gpt4, |input| {
+                        say_command(input, &MacSayCommandSpeaker::Karen).unwrap();
+                    })?,
 
-                    GptVersion::Gpt4o => repl
-                        .repl_with_input_fn(OpenAIModel::Gpt4o, |input| {
-                            say_command(input, &MacSayCommandSpeaker::Karen).unwrap();
-                        })
-                        .unwrap(),
+                    GptVersion::Gpt4o => repl.repl_with_input_fn(OpenAIModel::Gpt4o, |input| {
+                        say_command(input, &MacSayCommandSpeaker::Karen).unwrap();
+                    })?,
                 };
             }
             SubCommands::Ask {
                 gpt_version,
+                input,
                 source,
             } => {
-                let mut client = GptClient::from_env().unwrap();
                 let model = match gpt_version {
                     GptVersion::Gpt3 => OpenAIModel::Gpt3Dot5Turbo,
                     GptVersion::Gpt4 => OpenAIModel::Gpt4,
                     GptVersion::Gpt4o => OpenAIModel::Gpt4o,
                 };
-                let input = UserInput::new(source);
+                let input = input.to_user_input(source.as_deref())?;
                 let mut function = GptDefaultFunction::new();
-                exec_with_function(&mut client, model, input, &mut function)
+                let mut client = self.client()?;
+                exec_with_function(
+                    &mut client,
+                    model,
+                    input,
+                    &mut function,
+                    &mut self.tools()?,
+                    self.output().as_mut(),
+                )?;
+            }
+            SubCommands::Agent {
+                gpt_version,
+                yes,
+                task,
+            } => {
+                let confirm: Box<dyn Confirm> = if *yes {
+                    Box::new(AutoApprove)
+                } else {
+                    Box::new(StdinConfirm)
+                };
+                let mut agent = Agent::new(
+                    ChatGpt::from_profile(&self.profile()?)?,
+                    Sandbox::new(".")?,
+                    CommandPolicy::default(),
+                    confirm,
+                );
+                agent.run(gpt_version.to_model(), task, &mut |res| {
+                    print!("{}", res.delta_content());
+                    std::io::stdout().flush().unwrap_or_default();
+                    HandleResult::from(res)
+                })?;
+                println!();
             }
             SubCommands::Chat {
                 gpt_version,
@@ -179,30 +649,60 @@ impl TermAI {
                 code_reviewer,
                 translator,
                 speaker,
+                with,
+                functions,
+                plugins,
             } => {
-                let mut repl = ChatGptRepl::new();
+                let registry = self.registry()?;
+                for (specs, kind) in [
+                    (functions, FunctionKind::Config),
+                    (plugins, FunctionKind::Plugin),
+                ] {
+                    for spec in specs {
+                        let name = spec.split(':').next().unwrap_or(spec);
+                        if registry.kind(name) != Some(kind) {
+                            return Err(TermAIError::InvalidInput(format!(
+                                "{} function {} is not found",
+                                kind.as_str(),
+                                name
+                            )));
+                        }
+                    }
+                }
+                let mut specs = Vec::new();
                 if *code_capture {
-                    repl.add_functions(Box::new(GptCodeCapture::new_with_file_writer(".")));
-                };
+                    specs.push("capture".to_string());
+                }
                 if *code_reviewer {
-                    repl.add_functions(Box::new(CodeReviewer::default()));
-                };
-                if *speaker {
-                    #[cfg(target_os = "macos")]
-                    repl.add_functions(Box::new(MacSpeaker::default()));
+                    specs.push("review".to_string());
                 }
-                if let Some(mode) = translator.as_ref() {
-                    repl.add_functions(Box::new(Translator::new(mode.clone())));
+                if *speaker && cfg!(target_os = "macos") {
+                    specs.push("speaker".to_string());
                 }
+                if let Some(lang) = translator.as_ref() {
+                    specs.push(format!("translate:{}", lang));
+                }
+                specs.extend(with.iter().cloned());
+                specs.extend(functions.iter().cloned());
+                specs.extend(plugins.iter().cloned());
+                let functions = specs
+                    .iter()
+                    .map(|spec| registry.create(spec).map_err(TermAIError::InvalidInput))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut repl = self.repl()?;
+                functions.into_iter().for_each(|f| repl.add_functions(f));
+                repl.set_registry(registry);
+                repl.set_tools(self.tools()?);
                 if *gpt_version == GptVersion::Gpt3 {
-                    repl.repl_gpt3().unwrap();
+                    repl.repl_gpt3()?;
                 } else {
-                    repl.repl_gpt4().unwrap();
+                    repl.repl_gpt4()?;
                 }
             }
             #[cfg(target_os = "macos")]
             SubCommands::Speaker {
                 gpt_version,
+                input,
                 source,
             } => {
                 let model = if *gpt_version == GptVersion::Gpt3 {
@@ -210,13 +710,21 @@ impl TermAI {
                 } else {
                     OpenAIModel::Gpt4
                 };
-                let mut client = GptClient::from_env().unwrap();
                 let mut function = MacSpeaker::default();
-                let input = UserInput::new(source);
-                exec_with_function(&mut client, model, input, &mut function)
+                let input = input.to_user_input(source.as_deref())?;
+                let mut client = self.client()?;
+                exec_with_function(
+                    &mut client,
+                    model,
+                    input,
+                    &mut function,
+                    &mut Tools::new(),
+                    self.output().as_mut(),
+                )?;
             }
             SubCommands::CodeCapture {
                 gpt_version,
+                input,
                 source,
             } => {
                 let model = if *gpt_version == GptVersion::Gpt3 {
@@ -225,13 +733,21 @@ impl TermAI {
                     OpenAIModel::Gpt4
                 };
                 let mut function = GptCodeCapture::new_with_file_writer(".");
-                let mut client = GptClient::from_env().unwrap();
-                let input = UserInput::new(source);
-                exec_with_function(&mut client, model, input, &mut function)
+                let input = input.to_user_input(source.as_deref())?;
+                let mut client = self.client()?;
+                exec_with_function(
+                    &mut client,
+                    model,
+                    input,
+                    &mut function,
+                    &mut Tools::new(),
+                    self.output().as_mut(),
+                )?;
             }
             SubCommands::TranslatorJp {
                 gpt_version,
                 file_path,
+                input,
                 source,
             } => {
                 let model = if *gpt_version == GptVersion::Gpt3 {
@@ -239,20 +755,36 @@ impl TermAI {
                 } else {
                     OpenAIModel::Gpt4
                 };
-                let mut client = GptClient::from_env().unwrap();
                 if let Some(file_path) = file_path.as_ref() {
                     let mut function = FileTranslator::default();
-                    let input = UserInput::new(file_path);
-                    exec_with_function(&mut client, model, input, &mut function)
+                    let input = input.to_file_input(file_path, source.as_deref())?;
+                    let mut client = self.client()?;
+                    exec_with_function(
+                        &mut client,
+                        model,
+                        input,
+                        &mut function,
+                        &mut Tools::new(),
+                        self.output().as_mut(),
+                    )?;
                 } else {
                     let mut function = Translator::new(TranslateMode::ToJapanese);
-                    let input = UserInput::new(source.as_ref().expect("source is required"));
-                    exec_with_function(&mut client, model, input, &mut function)
+                    let input = input.to_user_input(source.as_deref())?;
+                    let mut client = self.client()?;
+                    exec_with_function(
+                        &mut client,
+                        model,
+                        input,
+                        &mut function,
+                        &mut Tools::new(),
+                        self.output().as_mut(),
+                    )?;
                 };
             }
             SubCommands::TranslatorEn {
                 gpt_version,
                 file_path,
+                input,
                 source,
             } => {
                 let model = if *gpt_version == GptVersion::Gpt3 {
@@ -260,37 +792,529 @@ impl TermAI {
                 } else {
                     OpenAIModel::Gpt4
                 };
-                let mut client = GptClient::from_env().unwrap();
                 if let Some(file_path) = file_path.as_ref() {
                     let mut function = FileTranslator::default();
-                    let input = UserInput::new(file_path);
-                    exec_with_function(&mut client, model, input, &mut function)
+                    let input = input.to_file_input(file_path, source.as_deref())?;
+                    let mut client = self.client()?;
+                    exec_with_function(
+                        &mut client,
+                        model,
+                        input,
+                        &mut function,
+                        &mut Tools::new(),
+                        self.output().as_mut(),
+                    )?;
                 } else {
                     let mut function = Translator::new(TranslateMode::ToEnglish);
-                    let input = UserInput::new(source.as_ref().expect("source is required"));
-                    exec_with_function(&mut client, model, input, &mut function)
+                    let input = input.to_user_input(source.as_deref())?;
+                    let mut client = self.client()?;
+                    exec_with_function(
+                        &mut client,
+                        model,
+                        input,
+                        &mut function,
+                        &mut Tools::new(),
+                        self.output().as_mut(),
+                    )?;
                 };
             }
             SubCommands::CodeReviewer {
                 gpt_version,
                 file_path,
                 source,
+                concurrency,
+                requests_per_minute,
+                max_bytes,
+                format,
+                min_severity,
+                lang,
+                rubrics,
+                guidelines,
+                diff,
+                staged,
+                context_lines,
+                out,
+                fix,
+                input,
             } => {
-                let mut client = GptClient::from_env().unwrap();
                 let model = if *gpt_version == GptVersion::Gpt3 {
                     OpenAIModel::Gpt3Dot5Turbo
                 } else {
                     OpenAIModel::Gpt4
                 };
-                let mut function = CodeReviewer::default();
+                let mut options = Config::load()?.review().clone();
+                if let Some(lang) = lang.as_ref() {
+                    options.language = Some(lang.clone());
+                }
+                if !rubrics.is_empty() {
+                    options.rubrics = rubrics.clone();
+                }
+                if let Some(guidelines) = guidelines.as_ref() {
+                    options.guidelines = guidelines.clone();
+                }
+                if diff.is_some() || *staged {
+                    let target = diff.clone().flatten();
+                    let diff = review::git_diff(target.as_deref(), *staged, *context_lines)?;
+                    if diff.trim().is_empty() {
+                        eprintln!("no changes to review");
+                        return Ok(());
+                    }
+                    let files = parse_diff(&diff);
+                    // refused before the review when the fixes can not be written
+                    let snapshots = if *fix {
+                        fix::diff_snapshots(
+                            files.iter().map(|file| file.path.as_str()),
+                            &DiffSide::of(target.as_deref(), *staged),
+                        )?
+                    } else {
+                        Default::default()
+                    };
+                    let findings = review::filter_severity(
+                        review::review_diff(
+                            &mut self.client()?,
+                            model,
+                            &diff,
+                            &options,
+                            &mut self.tools()?,
+                        )?,
+                        *min_severity,
+                    );
+                    review::write_findings(*format, &findings, out.as_deref())?;
+                    if *fix {
+                        fix::fix(&findings, &snapshots, &mut StdinFixPrompt::new())?;
+                    }
+                    return Ok(());
+                }
+                if let Some(target) = file_path.as_ref().filter(|path| !is_file_path(path)) {
+                    // a target which is not found is a mistyped argument
+                    let targets = find_targets(target, *max_bytes).map_err(|e| match e.kind() {
+                        std::io::ErrorKind::NotFound => TermAIError::InvalidInput(e.to_string()),
+                        _ => TermAIError::from(e),
+                    })?;
+                    let snapshots = fix::snapshots(targets.files.iter().map(String::as_str));
+                    let (findings, failed) = if targets.files.is_empty() {
+                        (Vec::new(), Vec::new())
+                    } else {
+                        let review = FileReview {
+                            concurrency: *concurrency,
+                            requests_per_minute: *requests_per_minute,
+                            model,
+                            cache: self.cache()?,
+                            options,
+                        };
+                        review.run(&self.profile()?, targets.files)?
+                    };
+                    let findings = review::filter_severity(findings, *min_severity);
+                    review::write_report(
+                        *format,
+                        &findings,
+                        &failed,
+                        &targets.skipped,
+                        out.as_deref(),
+                    )?;
+                    if *fix {
+                        fix::fix(&findings, &snapshots, &mut StdinFixPrompt::new())?;
+                    }
+                    return Ok(());
+                }
+                let mut function = CodeReviewer::default().with_options(&options);
                 let input = if let Some(file_path) = file_path.as_ref() {
-                    UserInput::new(file_path)
+                    input.to_file_input(file_path, source.as_deref())?
+                } else {
+                    input.to_user_input(source.as_deref())?
+                };
+                let mut client = self.client()?;
+                // fixes are in structured findings
+                if *format != ReviewFormat::Prose || *fix {
+                    let snapshots = fix::snapshots([input.content().trim()]);
+                    let findings = review::filter_severity(
+                        review::review_findings(
+                            &mut client,
+                            model,
+                            input,
+                            &options,
+                            &mut self.tools()?,
+                        )?,
+                        *min_severity,
+                    );
+                    review::write_findings(*format, &findings, out.as_deref())?;
+                    if *fix {
+                        fix::fix(&findings, &snapshots, &mut StdinFixPrompt::new())?;
+                    }
+                    return Ok(());
+                }
+                if out.is_some() {
+                    return Err(TermAIError::InvalidInput(
+                        "-o needs --format other than prose".to_string(),
+                    ));
+                }
+                exec_with_function(
+                    &mut client,
+                    model,
+                    input,
+                    &mut function,
+                    &mut self.tools()?,
+                    self.output().as_mut(),
+                )?;
+            }
+            SubCommands::Commit {
+                gpt_version,
+                lang,
+                max_tokens,
+                yes,
+                dry_run,
+                hook,
+                hook_args,
+            } => {
+                let options = Config::load()?.commit().clone();
+                let language = lang.clone().unwrap_or(options.language);
+                let max_tokens = max_tokens.unwrap_or(options.max_tokens);
+                let model = gpt_version.to_model();
+                if let Some(file) = hook {
+                    // a message given by -m, a template, a merge, a squash or an amend is kept
+                    if hook_args.first().is_some_and(|source| !source.is_empty()) {
+                        return Ok(());
+                    }
+                    let message = review::git_diff(None, true, 3).and_then(|diff| {
+                        commit::commit_message(
+                            &mut self.client()?,
+                            model,
+                            &diff,
+                            &language,
+                            max_tokens,
+                        )
+                    });
+                    // a failure does not stop the commit. git opens the editor as usual
+                    match message {
+                        Ok(message) => commit::prepend_message(file, &message)?,
+                        Err(e) => eprintln!("termai can not write the commit message : {}", e),
+                    }
+                    return Ok(());
+                }
+                let diff = review::git_diff(None, true, 3)?;
+                if diff.trim().is_empty() {
+                    return Err(TermAIError::InvalidInput(
+                        "no staged changes. stage them by git add".to_string(),
+                    ));
+                }
+                let message = commit::commit_message(
+                    &mut self.client()?,
+                    model,
+                    &diff,
+                    &language,
+                    max_tokens,
+                )?;
+                if *dry_run {
+                    println!("{}", message);
+                    return Ok(());
+                }
+                let message = if *yes {
+                    message
                 } else {
-                    UserInput::new(source.as_ref().expect("source is required"))
+                    commit::edit_message(&message)?
                 };
-                exec_with_function(&mut client, model, input, &mut function)
+                if message.is_empty() {
+                    return Err(TermAIError::InvalidInput(
+                        "the commit message is empty. the commit is aborted".to_string(),
+                    ));
+                }
+                commit::git_commit(&message)?;
+            }
+            SubCommands::Batch {
+                gpt_version,
+                out,
+                concurrency,
+                requests_per_minute,
+                input,
+            } => {
+                let batch = Batch {
+                    input_path: input.clone(),
+                    output_path: out.clone().unwrap_or_else(|| {
+                        format!("{}.out.jsonl", input.trim_end_matches(".jsonl"))
+                    }),
+                    concurrency: *concurrency,
+                    requests_per_minute: *requests_per_minute,
+                    default_model: gpt_version.to_model(),
+                    cache: self.cache()?,
+                    registry: self.registry()?,
+                };
+                batch.run(&self.profile()?)?;
+            }
+            SubCommands::BatchApi { command } => match command {
+                BatchApiCommand::Build {
+                    gpt_version,
+                    out,
+                    dir,
+                    lang,
+                    function,
+                    inputs,
+                } => {
+                    let mut inputs = inputs.clone();
+                    if let Some(dir) = dir.as_ref() {
+                        inputs.extend(list_files(dir)?);
+                    }
+                    if inputs.is_empty() {
+                        return Err(TermAIError::InvalidInput(
+                            "inputs or --dir is required".to_string(),
+                        ));
+                    }
+                    let count = batch_api::build(
+                        &self.registry()?,
+                        function,
+                        lang.as_deref(),
+                        gpt_version.to_model(),
+                        inputs,
+                        out,
+                    )?;
+                    eprintln!(
+                        "{} requests are written to {}. manifest is {}",
+                        count,
+                        out,
+                        batch_api::manifest_path(out)
+                    );
+                }
+                BatchApiCommand::Ingest { manifest, results } => {
+                    let manifest = manifest
+                        .clone()
+                        .unwrap_or_else(|| batch_api::manifest_path("batch_requests.jsonl"));
+                    batch_api::ingest(
+                        &self.registry()?,
+                        results,
+                        &manifest,
+                        self.output().as_mut(),
+                    )?;
+                }
+            },
+            SubCommands::Custom(args) => {
+                // args[0] is the name of the function
+                let name = args[0].clone();
+                let args = CustomFunctionArgs::parse_from(args);
+                let this = self.with_custom_args(&args);
+                let mut function = this.config_function(&name)?;
+                if let Some(version) = args.gpt_version {
+                    function.set_model(version.to_model());
+                }
+                if let Some(lang) = args.lang.as_ref() {
+                    function.set_lang(lang);
+                }
+                let model = function.model().unwrap_or_default();
+                let input = args.input.to_user_input(args.source.as_deref())?;
+                let mut client = this.client()?;
+                exec_with_function(
+                    &mut client,
+                    model,
+                    input,
+                    &mut function,
+                    &mut this.tools()?,
+                    this.output().as_mut(),
+                )?;
+            }
+            SubCommands::Functions { command } => match command {
+                FunctionsCommand::List => {
+                    let registry = self.registry()?;
+                    for (name, kind) in registry.entries() {
+                        let description = registry
+                            .describe(name)
+                            .unwrap_or_else(|e| format!("error: {}", e));
+                        println!("{:<16} {:<8} {}", name, kind.as_str(), description);
+                    }
+                }
+            },
+            SubCommands::Plugin { command } => match command {
+                PluginCommand::List => {
+                    let config = Config::load()?;
+                    for path in plugin::discover(&config.plugins_dir())? {
+                        match PluginFunction::spawn(&path, config.plugin_timeout()) {
+                            Ok(plugin) => println!(
+                                "{:<16} {}",
+                                plugin::plugin_name(&path),
+                                plugin.description().description.as_deref().unwrap_or("")
+                            ),
+                            Err(e) => eprintln!("{}", e),
+                        }
+                    }
+                }
+                PluginCommand::Run {
+                    gpt_version,
+                    name,
+                    input,
+                    source,
+                } => {
+                    let input = input.to_user_input(source.as_deref())?;
+                    let mut function = self.plugin(name)?;
+                    let mut client = self.client()?;
+                    exec_with_function(
+                        &mut client,
+                        gpt_version.to_model(),
+                        input,
+                        &mut function,
+                        &mut self.tools()?,
+                        self.output().as_mut(),
+                    )?;
+                }
+            },
+            SubCommands::Mcp { command } => match command {
+                McpCommand::List => {
+                    let profile = self.profile()?;
+                    let mut servers = profile.mcp_servers().iter().collect::<Vec<_>>();
+                    servers.sort_by_key(|(name, _)| name.as_str());
+                    for (name, config) in servers {
+                        if !config.enabled {
+                            println!("{} (disabled)", name);
+                            continue;
+                        }
+                        match McpClient::start(name, config) {
+                            Ok(client) => {
+                                println!("{}", name);
+                                for tool in client.tools() {
+                                    println!(
+                                        "  {:<24} {}",
+                                        tool.name,
+                                        tool.description.as_deref().unwrap_or("")
+                                    );
+                                }
+                            }
+                            Err(e) => eprintln!("{}", e),
+                        }
+                    }
+                }
+            },
+            SubCommands::Lsp {
+                gpt_version,
+                debounce_ms,
+            } => {
+                let model = gpt_version.to_model();
+                // the server and the review worker have their own clients, made at the first call
+                let runner = || {
+                    let mut client = None;
+                    move |f: &mut dyn GptFunction, input| {
+                        if client.is_none() {
+                            client = Some(self.client().map_err(|e| e.to_string())?);
+                        }
+                        let client = client.as_mut().unwrap();
+                        let mut output = BufferOutput::new();
+                        exec_with_function(client, model, input, f, &mut Tools::new(), &mut output)
+                            .map_err(|e| e.to_string())?;
+                        Ok(output.content().to_string())
+                    }
+                };
+                let options = Config::load()?.review().clone();
+                let (sender, receiver) = std::sync::mpsc::channel();
+                let (reviews, jobs) = std::sync::mpsc::channel();
+                // stdin is read on another thread, so the server can wait for the debounce time
+                let messages = sender.clone();
+                std::thread::spawn(move || {
+                    let mut stdin = std::io::stdin().lock();
+                    while let Ok(Some(message)) = lsp::read_message(&mut stdin) {
+                        if messages.send(LspEvent::Message(message)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                std::thread::scope(|scope| {
+                    scope.spawn(|| lsp::review_worker(runner(), jobs, sender));
+                    // the worker stops when the server is dropped
+                    LspServer::new(
+                        runner(),
+                        std::io::stdout(),
+                        Duration::from_millis(*debounce_ms),
+                        reviews,
+                    )
+                    .with_review_options(options)
+                    .serve(receiver)
+                })?;
+            }
+            SubCommands::Rpc { gpt_version } => {
+                let profile = self.profile()?;
+                let option = GptClientOption::from_profile(&profile);
+                let cache = self.cache()?;
+                // the key is resolved at the first request and shared by all sessions
+                let mut key = None;
+                let mut server = RpcServer::new(gpt_version.to_model(), self.registry()?, || {
+                    if key.is_none() {
+                        key = Some(profile.key_source().resolve()?);
+                    }
+                    let client = GptClient::new(key.clone().unwrap(), option.clone())?;
+                    Ok(match cache.clone() {
+                        Some((cache, mode)) => client.with_cache(cache, mode),
+                        None => client,
+                    })
+                });
+                let (sender, receiver) = std::sync::mpsc::channel();
+                // stdin is read on another thread, so a cancel arrives while streaming
+                std::thread::spawn(move || {
+                    for line in std::io::stdin().lines() {
+                        let Ok(line) = line else { break };
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                });
+                server.serve(&mut Connection::new(receiver, std::io::stdout()));
+            }
+            SubCommands::Serve { host, port, with } => {
+                let registry = self.registry()?;
+                let function = || -> Result<Option<Box<dyn GptFunction>>, TermAIError> {
+                    if with.is_empty() {
+                        return Ok(None);
+                    }
+                    // ask would pass the request through and split long messages
+                    let mut container = GptFunctionContainer::new();
+                    container.remove_functions("ask");
+                    for spec in with {
+                        container.add_functions(
+                            registry.create(spec).map_err(TermAIError::InvalidInput)?,
+                        );
+                    }
+                    Ok(Some(Box::new(container)))
+                };
+                // a wrong spec is reported before listening
+                function()?;
+                let profile = self.profile()?;
+                let key = profile.key_source().resolve()?;
+                let option = GptClientOption::from_profile(&profile);
+                let cache = self.cache()?;
+                let listener = TcpListener::bind((host.as_str(), *port))?;
+                eprintln!(
+                    "listening on http://{}/v1/chat/completions",
+                    listener.local_addr()?
+                );
+                // each connection has its own client and functions
+                serve::serve(listener, || {
+                    let client = GptClient::new(key.clone(), option.clone())?;
+                    let client = match cache.clone() {
+                        Some((cache, mode)) => client.with_cache(cache, mode),
+                        None => client,
+                    };
+                    Ok(Proxy::new(client, function()?))
+                })?;
+            }
+            SubCommands::McpServe { gpt_version } => {
+                // the client is made at the first call, so tools/list works without api key
+                let mut client = None;
+                let mut server = McpServer::new(gpt_version.to_model(), |f, model, input| {
+                    if client.is_none() {
+                        client = Some(self.client().map_err(|e| e.to_string())?);
+                    }
+                    let client = client.as_mut().unwrap();
+                    let mut output = BufferOutput::new();
+                    exec_with_function(client, model, input, f, &mut Tools::new(), &mut output)
+                        .map_err(|e| e.to_string())?;
+                    Ok(output.content().to_string())
+                })
+                .with_review_options(Config::load()?.review().clone());
+                server.serve(std::io::stdin().lock(), std::io::stdout())?;
+            }
+            SubCommands::Cache { command } => {
+                let cache = Config::load()?.cache();
+                match command {
+                    CacheCommand::List => cache::list(&cache)?,
+                    CacheCommand::Stats => cache::stats(&cache)?,
+                    CacheCommand::Purge { expired } => cache::purge(&cache, *expired)?,
+                }
             }
         }
+        Ok(())
     }
 }
 
@@ -298,6 +1322,7 @@ impl FromStr for TranslateMode {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
+            "ja" => Ok(Self::ToJapanese),
             "en" => Ok(Self::ToEnglish),
             "ko" => Ok(Self::ToKorean),
             "ch" => Ok(Self::ToChinese),
@@ -312,6 +1337,15 @@ pub enum GptVersion {
     Gpt4,
     Gpt4o,
 }
+impl GptVersion {
+    fn to_model(self) -> OpenAIModel {
+        match self {
+            Self::Gpt3 => OpenAIModel::Gpt3Dot5Turbo,
+            Self::Gpt4 => OpenAIModel::Gpt4,
+            Self::Gpt4o => OpenAIModel::Gpt4o,
+        }
+    }
+}
 impl FromStr for GptVersion {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -0,0 +1,382 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::OpenOptions,
+    io::Write,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::Profile,
+    functions::{registry::FunctionRegistry, GptFunction, UserInput},
+    gpt::{
+        cache::{CacheMode, ResponseCache},
+        client::{GptClient, GptClientOption, OpenAIModel},
+        tool::Tools,
+    },
+};
+
+use super::{
+    error::TermAIError,
+    exec_with_function,
+    output::{BufferOutput, ExecSummary},
+    GptVersion,
+};
+
+// one line of the input jsonl
+// {"id": "1", "function": "translate", "input": "hello", "lang": "ja", "model": "gpt4"}
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub(crate) struct BatchLine {
+    id: Option<String>,
+    function: String,
+    input: String,
+    model: Option<String>,
+    lang: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchStatus {
+    Ok,
+    Error,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct BatchResult {
+    id: String,
+    status: BatchStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    summary: Option<ExecSummary>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BatchResultRecord {
+    id: String,
+    status: BatchStatus,
+}
+
+pub(crate) struct Batch {
+    pub(crate) input_path: String,
+    pub(crate) output_path: String,
+    pub(crate) concurrency: usize,
+    pub(crate) requests_per_minute: u32,
+    pub(crate) default_model: OpenAIModel,
+    pub(crate) cache: Option<(ResponseCache, CacheMode)>,
+    // builtin, config and plugin functions which lines can name
+    pub(crate) registry: FunctionRegistry,
+}
+
+impl Batch {
+    // a line which fails is written as error, and the batch fails after all lines are run
+    pub(crate) fn run(&self, profile: &Profile) -> Result<(), TermAIError> {
+        let lines = parse_lines(&std::fs::read_to_string(&self.input_path)?)?;
+        let done = match std::fs::read_to_string(&self.output_path) {
+            Ok(content) => succeeded_ids(&content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let jobs = lines
+            .into_iter()
+            .filter(|(id, _)| !done.contains(id))
+            .collect::<Vec<_>>();
+        let total = jobs.len();
+        eprintln!(
+            "{} lines to run. {} lines already succeeded are skipped",
+            total,
+            done.len()
+        );
+        if total == 0 {
+            return Ok(());
+        }
+
+        // resolve key once. a key command may ask a passphrase
+        let key = profile.key_source().resolve()?;
+        let option = GptClientOption::from_profile(profile);
+        let output = Mutex::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.output_path)?,
+        );
+        // a line can be sent as several requests, so each request waits
+        let limiter = Arc::new(RateLimiter::per_minute(self.requests_per_minute));
+        let finished = Mutex::new(0);
+        let failed = Mutex::new(0);
+        run_workers(
+            jobs,
+            self.concurrency,
+            || {
+                let limiter = limiter.clone();
+                GptClient::new(key.clone(), option.clone()).map(|client| {
+                    let client = client.with_throttle(Arc::new(move || limiter.wait()));
+                    match self.cache.clone() {
+                        Some((cache, mode)) => client.with_cache(cache, mode),
+                        None => client,
+                    }
+                })
+            },
+            |client, (id, line)| {
+                let result = match client {
+                    Ok(client) => self.exec_line(client, id, line),
+                    Err(e) => BatchResult::error(id, &TermAIError::from(e.clone())),
+                };
+                if result.status == BatchStatus::Error {
+                    *failed.lock().unwrap() += 1;
+                }
+                let mut finished = finished.lock().unwrap();
+                *finished += 1;
+                eprintln!(
+                    "[{}/{}] {} {}",
+                    finished,
+                    total,
+                    result.id,
+                    match result.status {
+                        BatchStatus::Ok => "ok",
+                        BatchStatus::Error => "error",
+                    }
+                );
+                let mut output = output.lock().unwrap();
+                let line = serde_json::to_string(&result).unwrap_or_default();
+                writeln!(output, "{}", line)
+                    .and_then(|_| output.flush())
+                    .unwrap_or_else(|e| eprintln!("can not write result of {} : {}", result.id, e));
+            },
+        );
+        let failed = failed.into_inner().unwrap();
+        if failed > 0 {
+            return Err(TermAIError::Function(
+                format!(
+                    "{} of {} lines failed. run again to retry them",
+                    failed, total
+                )
+                .into(),
+            ));
+        }
+        Ok(())
+    }
+    fn exec_line(&self, client: &mut GptClient, id: String, line: BatchLine) -> BatchResult {
+        let model = match line.model.as_deref().map(GptVersion::from_str) {
+            Some(Ok(version)) => version.to_model(),
+            Some(Err(e)) => return BatchResult::error(id, &TermAIError::InvalidInput(e)),
+            None => self.default_model,
+        };
+        let mut function = match function_from_line(&self.registry, &line) {
+            Ok(function) => function,
+            Err(e) => return BatchResult::error(id, &e),
+        };
+        let mut output = BufferOutput::new();
+        match exec_with_function(
+            client,
+            model,
+            UserInput::new(line.input),
+            function.as_mut(),
+            // tools are not Send. mcp servers are not used in batch
+            &mut Tools::new(),
+            &mut output,
+        ) {
+            Ok(summary) => BatchResult {
+                id,
+                status: BatchStatus::Ok,
+                content: Some(output.content().to_string()),
+                error: None,
+                summary: Some(summary),
+            },
+            Err(e) => BatchResult::error(id, &e),
+        }
+    }
+}
+
+impl BatchResult {
+    fn error(id: String, e: &TermAIError) -> Self {
+        Self {
+            id,
+            status: BatchStatus::Error,
+            content: None,
+            error: Some(e.to_string()),
+            summary: None,
+        }
+    }
+}
+
+fn function_from_line(
+    registry: &FunctionRegistry,
+    line: &BatchLine,
+) -> Result<Box<dyn GptFunction>, TermAIError> {
+    function_from_name(registry, &line.function, line.lang.as_deref())
+}
+
+pub(crate) fn function_from_name(
+    registry: &FunctionRegistry,
+    name: &str,
+    lang: Option<&str>,
+) -> Result<Box<dyn GptFunction>, TermAIError> {
+    registry
+        .create_with_args(name, lang)
+        .map_err(TermAIError::InvalidInput)
+}
+
+// line number is used as id when id is not written
+fn parse_lines(content: &str) -> Result<Vec<(String, BatchLine)>, TermAIError> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let line = serde_json::from_str::<BatchLine>(line).map_err(|e| {
+                TermAIError::InvalidInput(format!("line {} is invalid : {}", i + 1, e))
+            })?;
+            Ok((line.id.clone().unwrap_or_else(|| (i + 1).to_string()), line))
+        })
+        .collect()
+}
+
+fn succeeded_ids(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<BatchResultRecord>(line).ok())
+        .filter(|record| record.status == BatchStatus::Ok)
+        .map(|record| record.id)
+        .collect()
+}
+
+// each worker has its own state like a gpt client
+pub(crate) fn run_workers<J, S, I, F>(jobs: Vec<J>, concurrency: usize, init: I, f: F)
+where
+    J: Send,
+    I: Fn() -> S + Sync,
+    F: Fn(&mut S, J) + Sync,
+{
+    let queue = Mutex::new(jobs.into_iter().collect::<VecDeque<_>>());
+    std::thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            scope.spawn(|| {
+                let mut state = init();
+                loop {
+                    let Some(job) = queue.lock().unwrap().pop_front() else {
+                        return;
+                    };
+                    f(&mut state, job);
+                }
+            });
+        }
+    });
+}
+
+pub(crate) struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+impl RateLimiter {
+    // 0 means no limit
+    pub(crate) fn per_minute(requests: u32) -> Self {
+        let interval = if requests == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(60) / requests
+        };
+        Self {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+    pub(crate) fn wait(&self) {
+        let at = {
+            let mut next = self.next.lock().unwrap();
+            let at = (*next).max(Instant::now());
+            *next = at + self.interval;
+            at
+        };
+        sleep(at.saturating_duration_since(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn idがない行は行番号をidにする() {
+        let lines = parse_lines(
+            r#"{"id": "a", "function": "ask", "input": "hello"}
+
+{"function": "translate", "input": "hello", "lang": "en", "model": "gpt4"}"#,
+        )
+        .unwrap();
+        assert_eq!(lines[0].0, "a");
+        assert_eq!(lines[1].0, "3");
+        assert_eq!(lines[1].1.lang.as_deref(), Some("en"));
+        assert!(parse_lines(r#"{"input": "hello"}"#).is_err());
+    }
+    #[test]
+    fn 成功した行のidのみを再実行の対象から外す() {
+        let ids = succeeded_ids(
+            r#"{"id":"1","status":"ok","content":"hi"}
+{"id":"2","status":"error","error":"rate limit"}
+broken line
+{"id":"3","status":"ok","content":"hi"}"#,
+        );
+        assert_eq!(ids, HashSet::from(["1".to_string(), "3".to_string()]));
+    }
+    #[test]
+    fn 未対応のfunctionはエラーになる() {
+        let line = BatchLine {
+            id: None,
+            function: "unknown".to_string(),
+            input: String::new(),
+            model: None,
+            lang: None,
+        };
+        assert!(function_from_line(&FunctionRegistry::with_builtins(), &line).is_err());
+    }
+    #[test]
+    fn 設定で定義した関数もバッチで使える() {
+        let mut registry = FunctionRegistry::with_builtins();
+        registry.add_config_functions(&[toml::from_str(
+            r#"
+            name = "summary"
+            prompt = "summarize {{input}}"
+            "#,
+        )
+        .unwrap()]);
+        let line = BatchLine {
+            id: None,
+            function: "summary".to_string(),
+            input: String::new(),
+            model: None,
+            lang: Some("Japanese".to_string()),
+        };
+
+        let function = function_from_line(&registry, &line).unwrap();
+
+        assert_eq!(function.name(), "summary");
+    }
+    #[test]
+    fn 全てのジョブを指定した並列数で処理する() {
+        let results = Mutex::new(Vec::new());
+        let inits = Mutex::new(0);
+        run_workers(
+            (0..10).collect(),
+            3,
+            || *inits.lock().unwrap() += 1,
+            |_, job| results.lock().unwrap().push(job),
+        );
+        let mut results = results.into_inner().unwrap();
+        results.sort();
+        assert_eq!(results, (0..10).collect::<Vec<_>>());
+        assert_eq!(inits.into_inner().unwrap(), 3);
+    }
+    #[test]
+    fn rate_limiterは指定した間隔でリクエストを許可する() {
+        let limiter = RateLimiter::per_minute(600);
+        let start = Instant::now();
+        limiter.wait();
+        limiter.wait();
+        limiter.wait();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
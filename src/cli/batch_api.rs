@@ -0,0 +1,368 @@
+use std::{collections::HashMap, io::Write, time::Duration};
+
+use crate::{
+    functions::{registry::FunctionRegistry, UserInput},
+    gpt::client::{ChatCompletion, ChatRequest, ChatResponse, HandleResult, OpenAIModel},
+};
+
+use super::{
+    batch::function_from_name,
+    error::TermAIError,
+    output::{ExecSummary, ResponseOutput},
+};
+
+// one line of the request file of OpenAI Batch API
+#[derive(Debug, Clone, serde::Serialize)]
+struct BatchApiRequest {
+    custom_id: String,
+    method: &'static str,
+    url: &'static str,
+    body: ChatRequest,
+}
+
+// Batch API returns only custom_id, so what each request was for is kept beside the request file
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct ManifestLine {
+    custom_id: String,
+    index: usize,
+    part: usize,
+    function: String,
+    lang: Option<String>,
+    input: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct BatchApiResult {
+    custom_id: String,
+    response: Option<BatchApiResponse>,
+    error: Option<serde_json::Value>,
+}
+#[derive(Debug, Clone, serde::Deserialize)]
+struct BatchApiResponse {
+    status_code: u16,
+    body: serde_json::Value,
+}
+
+pub(crate) fn manifest_path(requests_path: &str) -> String {
+    format!("{}.manifest.jsonl", requests_path)
+}
+
+pub(crate) fn build(
+    registry: &FunctionRegistry,
+    function: &str,
+    lang: Option<&str>,
+    model: OpenAIModel,
+    inputs: Vec<String>,
+    requests_path: &str,
+) -> Result<usize, TermAIError> {
+    let (requests, manifest) = build_requests(registry, function, lang, model, inputs)?;
+    write_jsonl(requests_path, &requests)?;
+    write_jsonl(&manifest_path(requests_path), &manifest)?;
+    Ok(requests.len())
+}
+
+fn build_requests(
+    registry: &FunctionRegistry,
+    function_name: &str,
+    lang: Option<&str>,
+    model: OpenAIModel,
+    inputs: Vec<String>,
+) -> Result<(Vec<BatchApiRequest>, Vec<ManifestLine>), TermAIError> {
+    let mut requests = Vec::new();
+    let mut manifest = Vec::new();
+    for (index, input) in inputs.into_iter().enumerate() {
+        let mut function = function_from_name(registry, function_name, lang)?;
+        let user_input = UserInput::new(&input);
+        function.setup_for_action(&user_input);
+        for (part, message) in function
+            .input_to_messages(user_input)
+            .into_iter()
+            .enumerate()
+        {
+            let custom_id = format!("{}-{}", index, part);
+            let mut body = ChatRequest::from_message(model, message).without_stream();
+            function.change_request(&mut body);
+            requests.push(BatchApiRequest {
+                custom_id: custom_id.clone(),
+                method: "POST",
+                url: "/v1/chat/completions",
+                body,
+            });
+            manifest.push(ManifestLine {
+                custom_id,
+                index,
+                part,
+                function: function_name.to_string(),
+                lang: lang.map(|s| s.to_string()),
+                input: input.clone(),
+            });
+        }
+    }
+    Ok((requests, manifest))
+}
+
+// stored responses go through the same hooks as a live call.
+// so file writing functions like translate-file or capture still make their files
+pub(crate) fn ingest(
+    registry: &FunctionRegistry,
+    results_path: &str,
+    manifest_path: &str,
+    output: &mut dyn ResponseOutput,
+) -> Result<(), TermAIError> {
+    let manifest = read_jsonl::<ManifestLine>(manifest_path)?;
+    let results = read_jsonl::<BatchApiResult>(results_path)?
+        .into_iter()
+        .map(|result| (result.custom_id.clone(), result))
+        .collect::<HashMap<_, _>>();
+    let mut failed = 0;
+    let inputs = group_by_input(manifest);
+    for parts in inputs.iter() {
+        if let Err(e) = ingest_input(registry, parts, &results, output) {
+            output.error(&e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(TermAIError::Function(
+            format!("{} of {} inputs failed", failed, inputs.len()).into(),
+        ));
+    }
+    Ok(())
+}
+
+fn ingest_input(
+    registry: &FunctionRegistry,
+    parts: &[ManifestLine],
+    results: &HashMap<String, BatchApiResult>,
+    output: &mut dyn ResponseOutput,
+) -> Result<(), TermAIError> {
+    let first = &parts[0];
+    let mut function = function_from_name(registry, &first.function, first.lang.as_deref())?;
+    let mut summary = ExecSummary::new(OpenAIModel::default());
+    function.setup_for_action(&UserInput::new(&first.input));
+    output.start();
+    for part in parts {
+        let completion = match completion_of(part, results) {
+            Ok(completion) => completion,
+            Err(e) => {
+                function.action_at_end()?;
+                return Err(e);
+            }
+        };
+        let response = ChatResponse::from(completion.content());
+        output.delta(&response);
+        let handled = [response, ChatResponse::Done]
+            .iter()
+            .try_for_each(|response| match function.handle_stream(response) {
+                HandleResult::Err(e) => Err(e),
+                _ => Ok(()),
+            });
+        if let Err(e) = handled {
+            // keep what is already handled as exec_with_function does.
+            // the error of the function is returned even if this fails
+            if let Err(end_error) = function.action_at_end() {
+                eprintln!("{} failed at the end : {}", function.name(), end_error);
+            }
+            return Err(TermAIError::from(e));
+        }
+        summary.update(&completion.meta());
+    }
+    function.action_at_end()?;
+    summary.finish(Duration::ZERO, function.artifacts());
+    output.finish(&summary);
+    Ok(())
+}
+
+fn completion_of(
+    part: &ManifestLine,
+    results: &HashMap<String, BatchApiResult>,
+) -> Result<ChatCompletion, TermAIError> {
+    let failed = |reason: String| {
+        TermAIError::Function(format!("{} of {} : {}", part.custom_id, part.input, reason).into())
+    };
+    let result = results
+        .get(&part.custom_id)
+        .ok_or_else(|| failed("result is not found".to_string()))?;
+    if let Some(error) = result.error.as_ref().filter(|e| !e.is_null()) {
+        return Err(failed(error.to_string()));
+    }
+    let response = result
+        .response
+        .as_ref()
+        .ok_or_else(|| failed("response is empty".to_string()))?;
+    if response.status_code != 200 {
+        return Err(failed(format!(
+            "status {} {}",
+            response.status_code, response.body
+        )));
+    }
+    serde_json::from_value(response.body.clone()).map_err(|e| failed(e.to_string()))
+}
+
+fn group_by_input(mut manifest: Vec<ManifestLine>) -> Vec<Vec<ManifestLine>> {
+    manifest.sort_by_key(|line| (line.index, line.part));
+    manifest.into_iter().fold(Vec::new(), |mut acc, line| {
+        match acc.last_mut() {
+            Some(last) if last[0].index == line.index => last.push(line),
+            _ => acc.push(vec![line]),
+        }
+        acc
+    })
+}
+
+fn write_jsonl<T: serde::Serialize>(path: &str, lines: &[T]) -> Result<(), TermAIError> {
+    let mut file = std::fs::File::create(path)?;
+    for line in lines {
+        let line = serde_json::to_string(line).map_err(|e| TermAIError::Function(Box::new(e)))?;
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+
+fn read_jsonl<T: serde::de::DeserializeOwned>(path: &str) -> Result<Vec<T>, TermAIError> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                TermAIError::InvalidInput(format!("line {} of {} is invalid : {}", i + 1, path, e))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::output::BufferOutput;
+
+    use super::*;
+    #[test]
+    fn 入力ごとにstreamしないリクエストとcustom_idを作成する() {
+        let (requests, manifest) = build_requests(
+            &FunctionRegistry::with_builtins(),
+            "translate",
+            Some("en"),
+            OpenAIModel::Gpt4o,
+            vec!["こんにちは".to_string(), "さようなら".to_string()],
+        )
+        .unwrap();
+
+        assert_eq!(requests.len(), 2);
+        let request = serde_json::to_value(&requests[1]).unwrap();
+        assert_eq!(request["custom_id"], "1-0");
+        assert_eq!(request["url"], "/v1/chat/completions");
+        assert_eq!(request["body"]["stream"], false);
+        assert!(request["body"].get("stream_options").is_none());
+        assert_eq!(manifest[1].input, "さようなら");
+        assert_eq!(manifest[1].custom_id, "1-0");
+    }
+    #[test]
+    fn 分割されたリクエストを入力ごとにまとめる() {
+        let line = |index, part| ManifestLine {
+            custom_id: format!("{}-{}", index, part),
+            index,
+            part,
+            function: "ask".to_string(),
+            lang: None,
+            input: String::new(),
+        };
+        let groups = group_by_input(vec![line(1, 0), line(0, 1), line(0, 0)]);
+        assert_eq!(groups, vec![vec![line(0, 0), line(0, 1)], vec![line(1, 0)]]);
+    }
+    #[test]
+    fn 保存されたレスポンスをfunctionに流す() {
+        let manifest = vec![ManifestLine {
+            custom_id: "0-0".to_string(),
+            index: 0,
+            part: 0,
+            function: "ask".to_string(),
+            lang: None,
+            input: "hello".to_string(),
+        }];
+        let results = HashMap::from([(
+            "0-0".to_string(),
+            serde_json::from_str::<BatchApiResult>(
+                r#"{"id":"batch_req_1","custom_id":"0-0","response":{"status_code":200,"request_id":"x","body":{"id":"chatcmpl-1","object":"chat.completion","created":1,"model":"gpt-4o","choices":[{"index":0,"message":{"role":"assistant","content":"hi"},"finish_reason":"stop"}],"usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2}}},"error":null}"#,
+            )
+            .unwrap(),
+        )]);
+        let mut output = BufferOutput::new();
+
+        ingest_input(
+            &FunctionRegistry::with_builtins(),
+            &manifest,
+            &results,
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(output.content(), "hi");
+    }
+    #[test]
+    fn functionのエラーは入力の失敗になる() {
+        struct FailingFunction;
+        impl crate::functions::GptFunction for FailingFunction {
+            fn handle_stream(&mut self, _response: &ChatResponse) -> HandleResult {
+                HandleResult::Err(crate::gpt::client::GptClientError::new(
+                    "sink failed".to_string(),
+                    crate::gpt::client::GptClientErrorKind::FunctionError(
+                        "sink failed".to_string(),
+                    ),
+                ))
+            }
+        }
+        let mut registry = FunctionRegistry::new();
+        registry.register(
+            "failing",
+            crate::functions::registry::FunctionKind::Builtin,
+            |_| Ok(Box::new(FailingFunction)),
+        );
+        let manifest = vec![ManifestLine {
+            custom_id: "0-0".to_string(),
+            index: 0,
+            part: 0,
+            function: "failing".to_string(),
+            lang: None,
+            input: "hello".to_string(),
+        }];
+        let results = HashMap::from([(
+            "0-0".to_string(),
+            serde_json::from_str::<BatchApiResult>(
+                r#"{"custom_id":"0-0","response":{"status_code":200,"body":{"id":"chatcmpl-1","object":"chat.completion","created":1,"model":"gpt-4o","choices":[{"index":0,"message":{"role":"assistant","content":"hi"},"finish_reason":"stop"}]}},"error":null}"#,
+            )
+            .unwrap(),
+        )]);
+
+        let result = ingest_input(&registry, &manifest, &results, &mut BufferOutput::new());
+
+        assert!(result.unwrap_err().to_string().contains("sink failed"));
+    }
+    #[test]
+    fn 失敗したレスポンスはエラーになる() {
+        let part = ManifestLine {
+            custom_id: "0-0".to_string(),
+            index: 0,
+            part: 0,
+            function: "ask".to_string(),
+            lang: None,
+            input: "hello".to_string(),
+        };
+        let results = HashMap::from([(
+            "0-0".to_string(),
+            serde_json::from_str::<BatchApiResult>(
+                r#"{"custom_id":"0-0","response":{"status_code":429,"body":{"error":"rate limit"}},"error":null}"#,
+            )
+            .unwrap(),
+        )]);
+        assert!(completion_of(&part, &results).is_err());
+        assert!(completion_of(
+            &ManifestLine {
+                custom_id: "none".to_string(),
+                ..part
+            },
+            &results
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::gpt::cache::{now, ResponseCache};
+
+use super::error::TermAIError;
+
+pub(crate) fn list(cache: &ResponseCache) -> Result<(), TermAIError> {
+    let now = now();
+    for (entry, size) in cache.entries()? {
+        println!(
+            "{}  {:>5}  {:>8}  {:<12}  {}{}",
+            entry.key.get(..12).unwrap_or(&entry.key),
+            format_age(now.saturating_sub(entry.created_at)),
+            format_size(size),
+            entry.model,
+            entry.prompt_preview.replace('\n', " "),
+            if cache.is_expired(&entry) {
+                " (expired)"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn stats(cache: &ResponseCache) -> Result<(), TermAIError> {
+    let entries = cache.entries()?;
+    let expired = entries
+        .iter()
+        .filter(|(entry, _)| cache.is_expired(entry))
+        .count();
+    let size = entries.iter().map(|(_, size)| size).sum::<u64>();
+    println!("dir     : {}", cache.dir().display());
+    println!("entries : {} ({} expired)", entries.len(), expired);
+    println!("size    : {}", format_size(size));
+    Ok(())
+}
+
+pub(crate) fn purge(cache: &ResponseCache, only_expired: bool) -> Result<(), TermAIError> {
+    let removed = cache.purge(only_expired)?;
+    eprintln!("{} entries are removed", removed);
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1}MB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024 => format!("{:.1}KB", b as f64 / 1024.0),
+        b => format!("{}B", b),
+    }
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        s if s >= 60 * 60 * 24 => format!("{}d", s / (60 * 60 * 24)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn サイズと経過時間を短い単位で表示する() {
+        assert_eq!(format_size(512), "512B");
+        assert_eq!(format_size(2048), "2.0KB");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0MB");
+        assert_eq!(format_age(30), "30s");
+        assert_eq!(format_age(60 * 5), "5m");
+        assert_eq!(format_age(60 * 60 * 2), "2h");
+        assert_eq!(format_age(60 * 60 * 24 * 3), "3d");
+    }
+}
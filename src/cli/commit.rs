@@ -0,0 +1,169 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use crate::{
+    functions::{
+        commit_message::{split_diff, CommitMessage, CommitStage},
+        estimate_tokens, UserInput,
+    },
+    gpt::{
+        client::{GptClient, OpenAIModel},
+        tool::Tools,
+    },
+};
+
+use super::{editor::edit_text, error::TermAIError, exec_with_function, output::BufferOutput};
+
+const EDIT_HELP: &str = "# Edit the commit message. Lines starting with # are removed,\n# and an empty message aborts the commit.";
+
+// a diff larger than max_tokens is summarized part by part, then the summaries make the message.
+// summaries are summarized again until they fit
+pub(crate) fn commit_message(
+    client: &mut GptClient,
+    model: OpenAIModel,
+    diff: &str,
+    language: &str,
+    max_tokens: usize,
+) -> Result<String, TermAIError> {
+    let parts = split_diff(diff, max_tokens);
+    if parts.len() <= 1 {
+        return ask(client, model, CommitStage::Compose, language, diff);
+    }
+    let mut summaries = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        eprintln!("[{}/{}] summarizing the diff", i + 1, parts.len());
+        summaries.push(ask(client, model, CommitStage::Summarize, language, part)?);
+    }
+    loop {
+        let groups = group(&summaries, max_tokens);
+        // a group can not be smaller when each summary is as large as max_tokens
+        if groups.len() <= 1 || groups.len() == summaries.len() {
+            return ask(
+                client,
+                model,
+                CommitStage::Reduce,
+                language,
+                &summaries.join("\n\n"),
+            );
+        }
+        eprintln!("summarizing {} summaries again", summaries.len());
+        summaries = groups
+            .iter()
+            .map(|group| ask(client, model, CommitStage::Summarize, language, group))
+            .collect::<Result<Vec<_>, _>>()?;
+    }
+}
+
+fn ask(
+    client: &mut GptClient,
+    model: OpenAIModel,
+    stage: CommitStage,
+    language: &str,
+    input: &str,
+) -> Result<String, TermAIError> {
+    let mut function = CommitMessage::new(stage).with_language(language);
+    exec_with_function(
+        client,
+        model,
+        UserInput::new(input),
+        &mut function,
+        &mut Tools::new(),
+        &mut BufferOutput::new(),
+    )?;
+    Ok(function.message())
+}
+
+// summaries joined in order while they fit in max_tokens
+fn group(summaries: &[String], max_tokens: usize) -> Vec<String> {
+    let mut groups: Vec<String> = Vec::new();
+    for summary in summaries {
+        match groups.last_mut() {
+            Some(last) if estimate_tokens(last) + estimate_tokens(summary) <= max_tokens => {
+                last.push_str("\n\n");
+                last.push_str(summary);
+            }
+            _ => groups.push(summary.clone()),
+        }
+    }
+    groups
+}
+
+// the message opened by $VISUAL or $EDITOR, without the comment lines
+pub(crate) fn edit_message(message: &str) -> Result<String, TermAIError> {
+    let edited = edit_text("COMMIT_EDITMSG", &format!("{}\n\n{}\n", message, EDIT_HELP))?;
+    Ok(strip_comments(&edited))
+}
+
+fn strip_comments(message: &str) -> String {
+    message
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+// `git commit -F -` with the message on stdin
+pub(crate) fn git_commit(message: &str) -> Result<(), TermAIError> {
+    let mut child = Command::new("git")
+        .args(["commit", "-F", "-"])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(message.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(TermAIError::InvalidInput(format!(
+            "git commit failed with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+// prepare-commit-msg hook. the message is put before what git wrote, e.g. the comments of status
+pub(crate) fn prepend_message(file: &str, message: &str) -> Result<(), TermAIError> {
+    let current = std::fs::read_to_string(file).unwrap_or_default();
+    std::fs::write(file, format!("{}\n{}", message, current))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::common::test_tool::TestFileFactory;
+
+    use super::*;
+    #[test]
+    fn フックはgitのコメントの前にメッセージを書き編集後はコメントを除く() {
+        let test_file = TestFileFactory::create("tmp_commit_hook");
+        test_file.create_file_under_root("COMMIT_EDITMSG", "\n# Please enter the commit message\n");
+
+        prepend_message("tmp_commit_hook/COMMIT_EDITMSG", "feat: add a\n\nbody").unwrap();
+        let content = std::fs::read_to_string("tmp_commit_hook/COMMIT_EDITMSG").unwrap();
+        test_file.remove_dir_all();
+
+        assert_eq!(
+            content,
+            "feat: add a\n\nbody\n\n# Please enter the commit message\n"
+        );
+        assert_eq!(strip_comments(&content), "feat: add a\n\nbody");
+        assert_eq!(strip_comments(&format!("\n\n{}\n", EDIT_HELP)), "");
+    }
+    #[test]
+    fn 要約はトークンの上限まで順にまとめる() {
+        let summaries = vec!["a".repeat(8), "b".repeat(8), "c".repeat(8)];
+
+        assert_eq!(
+            group(&summaries, 5),
+            vec![
+                format!("{}\n\n{}", "a".repeat(8), "b".repeat(8)),
+                "c".repeat(8)
+            ]
+        );
+        assert_eq!(group(&summaries, 2).len(), 3);
+    }
+}
@@ -0,0 +1,24 @@
+use std::process::Command;
+
+// $VISUAL or $EDITOR opens the text in a temp file of the name, and the edited text is returned
+pub(crate) fn edit_text(name: &str, text: &str) -> std::io::Result<String> {
+    let path = std::env::temp_dir().join(format!("termai-{}-{}", std::process::id(), name));
+    std::fs::write(&path, text)?;
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut args = editor.split_whitespace();
+    let status = Command::new(args.next().unwrap_or("vi"))
+        .args(args)
+        .arg(&path)
+        .status()?;
+    let edited = std::fs::read_to_string(&path);
+    std::fs::remove_file(&path).unwrap_or_default();
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} exited with {}",
+            editor, status
+        )));
+    }
+    edited
+}
@@ -0,0 +1,180 @@
+use std::fmt::Display;
+
+use crate::{
+    config::ConfigError,
+    gpt::client::{GptClientError, GptClientErrorKind},
+};
+
+#[derive(Debug)]
+pub enum TermAIError {
+    Gpt(GptClientError),
+    Config(ConfigError),
+    Io(std::io::Error),
+    Function(Box<dyn std::error::Error + 'static>),
+    InvalidInput(String),
+}
+
+impl TermAIError {
+    // scripts can tell what happened by the exit code
+    pub const EXIT_GENERAL: i32 = 1;
+    pub const EXIT_INVALID_INPUT: i32 = 2;
+    pub const EXIT_AUTH: i32 = 3;
+    pub const EXIT_RATE_LIMIT: i32 = 4;
+    pub const EXIT_NETWORK: i32 = 5;
+    pub const EXIT_CONFIG: i32 = 6;
+    pub const EXIT_IO: i32 = 7;
+    pub const EXIT_FUNCTION: i32 = 8;
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Gpt(e) => match &e.kind {
+                GptClientErrorKind::NotFoundEnvAPIKey
+                | GptClientErrorKind::KeySourceError(_)
+                | GptClientErrorKind::Unauthorized(_) => Self::EXIT_AUTH,
+                GptClientErrorKind::RateLimited(_) => Self::EXIT_RATE_LIMIT,
+                GptClientErrorKind::NoResponse
+                | GptClientErrorKind::ProxyConnectionError(_)
+                | GptClientErrorKind::ReadStreamError(_)
+                | GptClientErrorKind::RequestError(_)
+                | GptClientErrorKind::ResponseError(_) => Self::EXIT_NETWORK,
+                GptClientErrorKind::NotFoundCAFile(_) | GptClientErrorKind::InvalidUrl(_) => {
+                    Self::EXIT_CONFIG
+                }
+                GptClientErrorKind::FunctionError(_) => Self::EXIT_FUNCTION,
+                _ => Self::EXIT_GENERAL,
+            },
+            Self::Config(_) => Self::EXIT_CONFIG,
+            Self::Io(_) => Self::EXIT_IO,
+            Self::Function(_) => Self::EXIT_FUNCTION,
+            Self::InvalidInput(_) => Self::EXIT_INVALID_INPUT,
+        }
+    }
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Gpt(e) => match &e.kind {
+                GptClientErrorKind::NotFoundEnvAPIKey => {
+                    Some("set OPENAI_API_KEY or configure a profile in ~/.termai/config.toml")
+                }
+                GptClientErrorKind::KeySourceError(_) => {
+                    Some("check api_key of the profile in ~/.termai/config.toml")
+                }
+                GptClientErrorKind::Unauthorized(_) => {
+                    Some("the api key is rejected. check that the key is valid and not revoked")
+                }
+                GptClientErrorKind::RateLimited(_) => {
+                    Some("rate limit or quota is exceeded. wait a moment or check your plan")
+                }
+                GptClientErrorKind::NoResponse
+                | GptClientErrorKind::ReadStreamError(_)
+                | GptClientErrorKind::RequestError(_)
+                | GptClientErrorKind::ResponseError(_) => {
+                    Some("check the network. if you are behind a proxy, set HTTPS_PROXY or proxy of the profile")
+                }
+                GptClientErrorKind::ProxyConnectionError(_) | GptClientErrorKind::InvalidUrl(_) => {
+                    Some("check HTTPS_PROXY or proxy of the profile")
+                }
+                GptClientErrorKind::NotFoundCAFile(_) => {
+                    Some("check CA_BUNDLE or ca_path of the profile")
+                }
+                _ => None,
+            },
+            Self::Config(_) => Some("check ~/.termai/config.toml or TERMAI_CONFIG"),
+            Self::InvalidInput(_) => Some("run with --help to see the usage"),
+            Self::Io(_) | Self::Function(_) => None,
+        }
+    }
+}
+
+impl Display for TermAIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gpt(e) => write!(f, "{}", e.kind)?,
+            Self::Config(e) => write!(f, "config error: {}", e)?,
+            Self::Io(e) => write!(f, "io error: {}", e)?,
+            Self::Function(e) => write!(f, "function error: {}", e)?,
+            Self::InvalidInput(s) => write!(f, "invalid input: {}", s)?,
+        };
+        if let Some(hint) = self.hint() {
+            write!(f, "\nhint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for TermAIError {}
+
+impl From<GptClientError> for TermAIError {
+    fn from(e: GptClientError) -> Self {
+        Self::Gpt(e)
+    }
+}
+impl From<ConfigError> for TermAIError {
+    fn from(e: ConfigError) -> Self {
+        Self::Config(e)
+    }
+}
+// errors of user input are made as InvalidInput where the input is checked
+impl From<std::io::Error> for TermAIError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+// functions and repl return boxed errors. known errors are kept to choose exit code
+impl From<Box<dyn std::error::Error + 'static>> for TermAIError {
+    fn from(e: Box<dyn std::error::Error + 'static>) -> Self {
+        let e = match e.downcast::<GptClientError>() {
+            Ok(e) => return Self::Gpt(*e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<std::io::Error>() {
+            Ok(e) => return Self::from(*e),
+            Err(e) => e,
+        };
+        match e.downcast::<TermAIError>() {
+            Ok(e) => *e,
+            Err(e) => Self::Function(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn 認証エラーとレート制限と入力エラーは異なる終了コードになる() {
+        let auth = TermAIError::from(GptClientError::new(
+            "OPENAI_API_KEY is not found".to_string(),
+            GptClientErrorKind::NotFoundEnvAPIKey,
+        ));
+        let rate_limit = TermAIError::from(GptClientError::new(
+            "429".to_string(),
+            GptClientErrorKind::RateLimited("429 Too Many Requests".to_string()),
+        ));
+        let input = TermAIError::InvalidInput("source is required".to_string());
+        let io = TermAIError::from(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid argument",
+        ));
+
+        assert_eq!(auth.exit_code(), TermAIError::EXIT_AUTH);
+        assert_eq!(rate_limit.exit_code(), TermAIError::EXIT_RATE_LIMIT);
+        assert_eq!(input.exit_code(), TermAIError::EXIT_INVALID_INPUT);
+        assert_eq!(io.exit_code(), TermAIError::EXIT_IO);
+        assert!(auth.to_string().contains("hint: set OPENAI_API_KEY"));
+    }
+    #[test]
+    fn boxされたエラーから元のエラーを取り出す() {
+        let boxed: Box<dyn std::error::Error> = Box::new(GptClientError::new(
+            "no response".to_string(),
+            GptClientErrorKind::NoResponse,
+        ));
+        assert_eq!(
+            TermAIError::from(boxed).exit_code(),
+            TermAIError::EXIT_NETWORK
+        );
+
+        let boxed: Box<dyn std::error::Error> = "say command failed".into();
+        assert_eq!(
+            TermAIError::from(boxed).exit_code(),
+            TermAIError::EXIT_FUNCTION
+        );
+    }
+}
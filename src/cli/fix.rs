@@ -0,0 +1,322 @@
+use std::{
+    collections::HashMap,
+    io::{IsTerminal, Write},
+    process::Command,
+};
+
+use crate::functions::{
+    code_reviewer::{
+        finding::Finding,
+        patch::{self, Hunk},
+    },
+    common::diff,
+};
+
+use super::{editor::edit_text, error::TermAIError};
+
+// answer of the user to a proposed hunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Decision {
+    Accept,
+    Skip,
+    // accepted with the lines edited by the user
+    Edit(Vec<String>),
+    Quit,
+}
+
+pub(crate) trait FixPrompt {
+    fn decide(&mut self, finding: &Finding, hunk: &Hunk) -> Decision;
+}
+
+// asks on stderr, so stdout is only the findings
+pub(crate) struct StdinFixPrompt {
+    color: bool,
+}
+impl StdinFixPrompt {
+    pub(crate) fn new() -> Self {
+        Self {
+            color: std::io::stderr().is_terminal(),
+        }
+    }
+}
+impl FixPrompt for StdinFixPrompt {
+    fn decide(&mut self, finding: &Finding, hunk: &Hunk) -> Decision {
+        eprintln!("{}", render(finding, hunk, self.color));
+        loop {
+            eprint!("apply this fix? [a]ccept, [s]kip, [e]dit, [q]uit ");
+            std::io::stderr().flush().unwrap_or_default();
+            let mut answer = String::new();
+            match std::io::stdin().read_line(&mut answer) {
+                Ok(0) | Err(_) => return Decision::Quit,
+                Ok(_) => {}
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "a" | "accept" | "y" => return Decision::Accept,
+                "s" | "skip" | "n" | "" => return Decision::Skip,
+                "q" | "quit" => return Decision::Quit,
+                "e" | "edit" => match edit_text("fix.txt", &format!("{}\n", hunk.new.join("\n"))) {
+                    Ok(edited) => {
+                        return Decision::Edit(edited.lines().map(str::to_string).collect())
+                    }
+                    Err(e) => eprintln!("can not edit the fix : {}", e),
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+// the file and its diff. removed lines are red and added lines are green on terminal
+pub(crate) fn render(finding: &Finding, hunk: &Hunk, color: bool) -> String {
+    let diff = diff(&hunk.old.join("\n"), &hunk.new.join("\n"))
+        .lines()
+        .map(|line| match line.chars().next() {
+            Some('-') if color => format!("\x1b[31m{}\x1b[0m", line),
+            Some('+') if color => format!("\x1b[32m{}\x1b[0m", line),
+            _ => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "{}:{}: {}\n{}",
+        hunk.file, hunk.start, finding.message, diff
+    )
+}
+
+// content of the files before the review. a file which can not be read is left out
+pub(crate) fn snapshots<'a>(files: impl IntoIterator<Item = &'a str>) -> HashMap<String, String> {
+    files
+        .into_iter()
+        .filter_map(|file| {
+            std::fs::read_to_string(file)
+                .ok()
+                .map(|content| (file.to_string(), content))
+        })
+        .collect()
+}
+
+// the new side of `git diff`, which the findings of the diff are of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DiffSide {
+    WorkingTree,
+    Index,
+    Revision(String),
+}
+impl DiffSide {
+    // `--staged` is the index, and `a..b` or `a...b` is b. `a..` is HEAD.
+    // a single rev is compared with the working tree
+    pub(crate) fn of(target: Option<&str>, staged: bool) -> Self {
+        if staged {
+            return Self::Index;
+        }
+        match target.and_then(|target| target.split_once("..")) {
+            Some((_, new)) => match new.trim_start_matches('.') {
+                "" => Self::Revision("HEAD".to_string()),
+                new => Self::Revision(new.to_string()),
+            },
+            None => Self::WorkingTree,
+        }
+    }
+    fn name(&self) -> String {
+        match self {
+            Self::WorkingTree => "the working tree".to_string(),
+            Self::Index => "the index".to_string(),
+            Self::Revision(rev) => rev.clone(),
+        }
+    }
+    // `git show <rev>:<path>`. paths of a diff are from the root of the repository
+    fn content(&self, file: &str) -> Result<String, TermAIError> {
+        let spec = match self {
+            Self::WorkingTree => return Ok(std::fs::read_to_string(file)?),
+            Self::Index => format!(":{}", file),
+            Self::Revision(rev) => format!("{}:{}", rev, file),
+        };
+        let output = Command::new("git").args(["show", &spec]).output()?;
+        if !output.status.success() {
+            return Err(TermAIError::InvalidInput(format!(
+                "git show {} failed : {}",
+                spec,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+// content of the files at the new side of the diff. fixes are written to the working tree,
+// so a file which differs there is refused before the review
+pub(crate) fn diff_snapshots<'a>(
+    files: impl IntoIterator<Item = &'a str>,
+    side: &DiffSide,
+) -> Result<HashMap<String, String>, TermAIError> {
+    let mut snapshots = HashMap::new();
+    for file in files {
+        let content = side.content(file)?;
+        if std::fs::read_to_string(file).ok().as_ref() != Some(&content) {
+            return Err(TermAIError::InvalidInput(format!(
+                "{} of {} differs from the working tree. --fix writes only the working tree",
+                file,
+                side.name()
+            )));
+        }
+        snapshots.insert(file.to_string(), content);
+    }
+    Ok(snapshots)
+}
+
+// asks each hunk of the fixes, then writes the accepted hunks per file.
+// a file changed since the review is not written. returns the number of applied hunks
+pub(crate) fn fix(
+    findings: &[Finding],
+    snapshots: &HashMap<String, String>,
+    prompt: &mut impl FixPrompt,
+) -> Result<usize, TermAIError> {
+    let mut accepted: Vec<(String, Vec<Hunk>)> = Vec::new();
+    'findings: for finding in findings.iter().filter(|finding| finding.fix.is_some()) {
+        let Some(snapshot) = snapshots.get(&finding.file) else {
+            eprintln!(
+                "{} is not a reviewed file. its fix is skipped",
+                finding.file
+            );
+            continue;
+        };
+        let hunks = match patch::hunks(finding, snapshot) {
+            Ok(hunks) => hunks,
+            Err(e) => {
+                eprintln!(
+                    "can not make the fix of {}:{} : {}",
+                    finding.file, finding.line, e
+                );
+                continue;
+            }
+        };
+        for hunk in hunks {
+            let hunk = match prompt.decide(finding, &hunk) {
+                Decision::Accept => hunk,
+                Decision::Edit(new) => Hunk { new, ..hunk },
+                Decision::Skip => continue,
+                Decision::Quit => break 'findings,
+            };
+            match accepted.iter_mut().find(|(file, _)| *file == hunk.file) {
+                Some((_, hunks)) => hunks.push(hunk),
+                None => accepted.push((hunk.file.clone(), vec![hunk])),
+            }
+        }
+    }
+    let mut applied = 0;
+    for (file, hunks) in accepted {
+        let current = std::fs::read_to_string(&file)?;
+        if snapshots.get(&file) != Some(&current) {
+            eprintln!(
+                "{} changed since the review. its fixes are not applied",
+                file
+            );
+            continue;
+        }
+        match patch::apply(&current, &hunks) {
+            Ok(patched) => {
+                std::fs::write(&file, patched)?;
+                applied += hunks.len();
+            }
+            Err(e) => eprintln!("can not apply the fixes of {} : {}", file, e),
+        }
+    }
+    eprintln!("{} fixes applied", applied);
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::{
+        code_reviewer::finding::{Category, Severity},
+        common::test_tool::TestFileFactory,
+    };
+
+    use super::*;
+    struct FakePrompt(Vec<Decision>);
+    impl FixPrompt for FakePrompt {
+        fn decide(&mut self, _finding: &Finding, _hunk: &Hunk) -> Decision {
+            self.0.remove(0)
+        }
+    }
+    fn finding(file: &str, line: usize, fix: &str) -> Finding {
+        Finding {
+            file: file.to_string(),
+            line,
+            end_line: None,
+            severity: Severity::Warning,
+            category: Category::Bug,
+            message: "wrong".to_string(),
+            suggestion: None,
+            fix: Some(fix.to_string()),
+        }
+    }
+    #[test]
+    fn 受け入れた修正だけを当てレビュー後に変わったファイルには当てない() {
+        let root = "tmp_review_fix";
+        let test_file = TestFileFactory::create(root);
+        test_file.create_file_under_root("a.rs", "let a = 1;\nlet b = 2;\nlet c = 3;\n");
+        test_file.create_file_under_root("b.rs", "let d = 4;\n");
+        let (a, b) = (format!("{}/a.rs", root), format!("{}/b.rs", root));
+        let snapshots = snapshots([a.as_str(), b.as_str()]);
+        test_file.create_file_under_root("b.rs", "let d = 5;\n");
+        let findings = vec![
+            finding(&a, 1, "let a = 10;"),
+            finding(&a, 2, "let b = 20;"),
+            finding(&a, 3, "let c = 30;"),
+            finding(&b, 1, "let d = 40;"),
+        ];
+        let mut prompt = FakePrompt(vec![
+            Decision::Accept,
+            Decision::Skip,
+            Decision::Edit(vec!["let c = 300;".to_string()]),
+            Decision::Accept,
+        ]);
+
+        let applied = fix(&findings, &snapshots, &mut prompt).unwrap();
+        let a_content = std::fs::read_to_string(&a).unwrap();
+        let b_content = std::fs::read_to_string(&b).unwrap();
+        test_file.remove_dir_all();
+
+        assert_eq!(applied, 2);
+        assert_eq!(a_content, "let a = 10;\nlet b = 2;\nlet c = 300;\n");
+        assert_eq!(b_content, "let d = 5;\n");
+    }
+    #[test]
+    fn 修正は削除を赤で追加を緑で表示する() {
+        let finding = finding("a.rs", 1, "let a = 10;");
+        let hunk = Hunk {
+            file: "a.rs".to_string(),
+            start: 1,
+            old: vec!["let a = 1;".to_string()],
+            new: vec!["let a = 10;".to_string()],
+        };
+
+        assert_eq!(
+            render(&finding, &hunk, true),
+            "a.rs:1: wrong\n\x1b[31m- let a = 1;\x1b[0m\n\x1b[32m+ let a = 10;\x1b[0m"
+        );
+        assert_eq!(
+            render(&finding, &hunk, false),
+            "a.rs:1: wrong\n- let a = 1;\n+ let a = 10;"
+        );
+    }
+    #[test]
+    fn diffの新しい側はステージかレンジの後ろか作業ツリー() {
+        assert_eq!(DiffSide::of(Some("main"), true), DiffSide::Index);
+        assert_eq!(
+            DiffSide::of(Some("main..dev"), false),
+            DiffSide::Revision("dev".to_string())
+        );
+        assert_eq!(
+            DiffSide::of(Some("main...dev"), false),
+            DiffSide::Revision("dev".to_string())
+        );
+        assert_eq!(
+            DiffSide::of(Some("main.."), false),
+            DiffSide::Revision("HEAD".to_string())
+        );
+        assert_eq!(DiffSide::of(Some("HEAD~1"), false), DiffSide::WorkingTree);
+        assert_eq!(DiffSide::of(None, false), DiffSide::WorkingTree);
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::{IsTerminal, Read};
+
+use clap::Args;
+
+use crate::functions::{common::get_file_content, UserInput};
+
+use super::error::TermAIError;
+
+#[derive(Args, Debug, Clone, Default)]
+pub(crate) struct InputArgs {
+    /// attach files to the prompt. each file is labeled with its path
+    #[clap(long = "file")]
+    files: Vec<String>,
+}
+
+impl InputArgs {
+    // instruction, piped stdin and attached files are joined in this order.
+    // e.g. `cargo build 2>&1 | termai ask "why does this fail"`
+    pub(crate) fn to_user_input(
+        &self,
+        instruction: Option<&str>,
+    ) -> Result<UserInput, TermAIError> {
+        let stdin = read_piped_stdin()?;
+        let attachments = self
+            .files
+            .iter()
+            .map(|path| get_file_content(path).map(|content| (path.as_str(), content)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        compose(instruction, stdin.as_deref(), &attachments).ok_or_else(|| {
+            TermAIError::InvalidInput(
+                "source is required. pass it as an argument, by stdin or by --file".to_string(),
+            )
+        })
+    }
+    // -f of tjp, ten and cr is the whole input, so other sources are an error
+    pub(crate) fn to_file_input(
+        &self,
+        file_path: &str,
+        source: Option<&str>,
+    ) -> Result<UserInput, TermAIError> {
+        let stdin = read_piped_stdin()?;
+        if let Some(other) = other_source(source, stdin.as_deref(), &self.files) {
+            return Err(TermAIError::InvalidInput(format!(
+                "-f {} can not be used with {}",
+                file_path, other
+            )));
+        }
+        Ok(UserInput::new(file_path))
+    }
+}
+
+fn other_source(source: Option<&str>, stdin: Option<&str>, files: &[String]) -> Option<String> {
+    if source.is_some_and(|s| !s.trim().is_empty()) {
+        return Some("the source argument".to_string());
+    }
+    if stdin.is_some_and(|s| !s.trim().is_empty()) {
+        return Some("stdin".to_string());
+    }
+    if !files.is_empty() {
+        return Some("--file".to_string());
+    }
+    None
+}
+
+fn read_piped_stdin() -> std::io::Result<Option<String>> {
+    let mut stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return Ok(None);
+    }
+    let mut buf = String::new();
+    stdin.read_to_string(&mut buf)?;
+    Ok(Some(buf))
+}
+
+fn compose(
+    instruction: Option<&str>,
+    stdin: Option<&str>,
+    attachments: &[(&str, String)],
+) -> Option<UserInput> {
+    let mut parts = Vec::new();
+    if let Some(instruction) = instruction.filter(|s| !s.trim().is_empty()) {
+        parts.push(instruction.to_string());
+    }
+    if let Some(stdin) = stdin.filter(|s| !s.trim().is_empty()) {
+        parts.push(stdin.trim_end().to_string());
+    }
+    attachments.iter().for_each(|(path, content)| {
+        parts.push(format!("--- {} ---\n{}", path, content.trim_end()));
+    });
+    if parts.is_empty() {
+        return None;
+    }
+    Some(UserInput::new(parts.join("\n\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn 指示と標準入力と添付ファイルを順に結合する() {
+        let input = compose(
+            Some("why does this fail"),
+            Some("error[E0308]: mismatched types\n"),
+            &[("src/main.rs", "fn main() {}\n".to_string())],
+        )
+        .unwrap();
+        assert_eq!(
+            input.content(),
+            "why does this fail\n\nerror[E0308]: mismatched types\n\n--- src/main.rs ---\nfn main() {}"
+        );
+    }
+    #[test]
+    fn 標準入力のみでも入力になる() {
+        let input = compose(None, Some("hello"), &[]).unwrap();
+        assert_eq!(input.content(), "hello");
+    }
+    #[test]
+    fn ファイル指定と他の入力は同時に使えない() {
+        assert_eq!(other_source(None, Some("\n"), &[]), None);
+        assert_eq!(
+            other_source(None, Some("hello"), &[]),
+            Some("stdin".to_string())
+        );
+        assert_eq!(
+            other_source(None, None, &["a.rs".to_string()]),
+            Some("--file".to_string())
+        );
+    }
+    #[test]
+    fn 何も入力がなければnoneを返す() {
+        assert!(compose(None, Some("\n"), &[]).is_none());
+    }
+}
@@ -0,0 +1,820 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, Write},
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    functions::{
+        code_capture::{CodeCapture, Lang},
+        code_reviewer::{
+            finding::{parse_findings, Finding, Severity},
+            options::ReviewOptions,
+            CodeReviewer,
+        },
+        GptDefaultFunction, GptFunction, UserInput,
+    },
+    mcp::{JsonRpcError, JsonRpcMessage},
+};
+
+const MAX_BODY: usize = 10 * 1024 * 1024;
+
+// messages are framed by Content-Length header, not by lines
+pub(crate) fn read_message(reader: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = Some(
+                    value
+                        .trim()
+                        .parse::<usize>()
+                        .map_err(|_| invalid(format!("invalid content-length {}", value)))?,
+                );
+            }
+        }
+    }
+    let length = length.unwrap_or_default();
+    if length > MAX_BODY {
+        return Err(invalid(format!(
+            "message is larger than {} bytes",
+            MAX_BODY
+        )));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+fn write_message(writer: &mut impl Write, message: &JsonRpcMessage) -> std::io::Result<()> {
+    let body = serde_json::to_string(message).unwrap_or_default();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Review,
+    Explain,
+    DocComment,
+    TranslateComments,
+}
+impl Action {
+    const ALL: [Self; 4] = [
+        Self::Review,
+        Self::Explain,
+        Self::DocComment,
+        Self::TranslateComments,
+    ];
+    fn command(&self) -> &'static str {
+        match self {
+            Self::Review => "termai.review",
+            Self::Explain => "termai.explain",
+            Self::DocComment => "termai.docComment",
+            Self::TranslateComments => "termai.translateComments",
+        }
+    }
+    fn title(&self) -> &'static str {
+        match self {
+            Self::Review => "termai: review this function",
+            Self::Explain => "termai: explain",
+            Self::DocComment => "termai: add doc comment",
+            Self::TranslateComments => "termai: translate comments to English",
+        }
+    }
+    fn from_command(command: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|action| action.command() == command)
+    }
+}
+
+// the client sends the whole text on each change
+struct Document {
+    text: String,
+}
+
+// what the server waits for. a message of the client, or a review done by review_worker
+pub(crate) enum LspEvent {
+    Message(String),
+    Reviewed {
+        uri: String,
+        text: String,
+        result: Result<Vec<Finding>, String>,
+    },
+}
+
+// a review of a saved document
+pub(crate) struct ReviewJob {
+    uri: String,
+    text: String,
+    options: ReviewOptions,
+}
+impl ReviewJob {
+    // long files are split by the chunking of CodeReviewer.
+    // the model is asked again once when the answer is not valid findings
+    fn run<R>(self, runner: &mut R) -> LspEvent
+    where
+        R: FnMut(&mut dyn GptFunction, UserInput) -> Result<String, String>,
+    {
+        let mut reviewer = CodeReviewer::structured()
+            .with_options(&self.options)
+            .with_lang(Lang::from_path(&self.uri));
+        let result = runner(&mut reviewer, UserInput::new(&self.text)).and_then(|answer| {
+            parse_findings(&answer, "").or_else(|e| {
+                reviewer.retry(e);
+                let answer = runner(&mut reviewer, UserInput::new(&self.text))?;
+                parse_findings(&answer, "")
+            })
+        });
+        LspEvent::Reviewed {
+            uri: self.uri,
+            text: self.text,
+            result,
+        }
+    }
+}
+
+// reviews run on their own thread, so the server keeps answering while the model writes
+pub(crate) fn review_worker<R>(mut runner: R, jobs: Receiver<ReviewJob>, events: Sender<LspEvent>)
+where
+    R: FnMut(&mut dyn GptFunction, UserInput) -> Result<String, String>,
+{
+    for job in jobs {
+        if events.send(job.run(&mut runner)).is_err() {
+            break;
+        }
+    }
+}
+
+const EXPLAIN_PREFIX: &str = "Explain what the following code does, briefly.";
+const DOC_COMMENT_PREFIX: &str = "Write a doc comment for the following code in the comment style of its language. Answer only the doc comment in a code block.";
+const TRANSLATE_COMMENTS_PREFIX: &str = "Translate the comments of the following code to English. Do not change the code. Answer the whole code in a code block.";
+
+// language server of termai. runner calls gpt with the function and returns the answer
+pub(crate) struct LspServer<R, W> {
+    runner: R,
+    writer: W,
+    debounce: Duration,
+    documents: HashMap<String, Document>,
+    // uri and when to review it. a save in the debounce time postpones the review
+    scheduled: HashMap<String, Instant>,
+    // diagnostics by hash of the text
+    reviewed: HashMap<String, Vec<Value>>,
+    // hashes of the texts sent to review_worker
+    reviewing: HashSet<String>,
+    reviews: Sender<ReviewJob>,
+    // [review] of config.toml
+    review: ReviewOptions,
+    next_id: u64,
+}
+
+impl<R, W> LspServer<R, W>
+where
+    R: FnMut(&mut dyn GptFunction, UserInput) -> Result<String, String>,
+    W: Write,
+{
+    // saved documents are sent to reviews, which review_worker runs
+    pub(crate) fn new(
+        runner: R,
+        writer: W,
+        debounce: Duration,
+        reviews: Sender<ReviewJob>,
+    ) -> Self {
+        Self {
+            runner,
+            writer,
+            debounce,
+            documents: HashMap::new(),
+            scheduled: HashMap::new(),
+            reviewed: HashMap::new(),
+            reviewing: HashSet::new(),
+            reviews,
+            review: ReviewOptions::default(),
+            next_id: 0,
+        }
+    }
+    pub(crate) fn with_review_options(mut self, options: ReviewOptions) -> Self {
+        self.review = options;
+        self
+    }
+    // until exit or the client is gone
+    pub(crate) fn serve(&mut self, input: Receiver<LspEvent>) -> std::io::Result<()> {
+        loop {
+            let event = match self.scheduled.values().min() {
+                Some(deadline) => {
+                    match input.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(event) => Some(event),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match input.recv() {
+                    Ok(event) => Some(event),
+                    Err(_) => break,
+                },
+            };
+            match event {
+                Some(LspEvent::Message(message)) if !self.handle(&message)? => break,
+                Some(LspEvent::Reviewed { uri, text, result }) => {
+                    self.finish_review(&uri, &text, result)?
+                }
+                _ => {}
+            }
+            self.review_scheduled(Instant::now())?;
+        }
+        Ok(())
+    }
+    // false when the client asks to exit
+    fn handle(&mut self, body: &str) -> std::io::Result<bool> {
+        let message = match serde_json::from_str::<JsonRpcMessage>(body) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = JsonRpcMessage::error_response(
+                    Value::Null,
+                    JsonRpcError::PARSE_ERROR,
+                    e.to_string(),
+                );
+                write_message(&mut self.writer, &error)?;
+                return Ok(true);
+            }
+        };
+        // responses of our requests e.g. workspace/applyEdit
+        let Some(method) = message.method else {
+            return Ok(true);
+        };
+        let params = message.params.unwrap_or_default();
+        let Some(id) = message.id else {
+            return self.notification(&method, &params);
+        };
+        let result = match method.as_str() {
+            "initialize" => Ok(Self::capabilities()),
+            // nothing to clean up. the server stops at exit
+            "shutdown" => Ok(Value::Null),
+            "textDocument/codeAction" => Ok(Self::code_actions(&params)),
+            "workspace/executeCommand" => self.execute(&params),
+            _ => Err((
+                JsonRpcError::METHOD_NOT_FOUND,
+                format!("method {} is not found", method),
+            )),
+        };
+        let response = match result {
+            Ok(result) => JsonRpcMessage::response(id, result),
+            Err((code, message)) => JsonRpcMessage::error_response(id, code, message),
+        };
+        write_message(&mut self.writer, &response)?;
+        Ok(true)
+    }
+    fn notification(&mut self, method: &str, params: &Value) -> std::io::Result<bool> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        match method {
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                // reviewed before with the same text
+                if let Some(diagnostics) = self.reviewed.get(&hash(&text)).cloned() {
+                    self.publish(&uri, diagnostics)?;
+                }
+                self.documents.insert(uri, Document { text });
+            }
+            "textDocument/didChange" => {
+                if let (Some(text), Some(document)) = (
+                    params["contentChanges"]
+                        .as_array()
+                        .and_then(|changes| changes.last())
+                        .and_then(|change| change["text"].as_str()),
+                    self.documents.get_mut(&uri),
+                ) {
+                    document.text = text.to_string();
+                }
+            }
+            "textDocument/didSave" => {
+                if let (Some(text), Some(document)) =
+                    (params["text"].as_str(), self.documents.get_mut(&uri))
+                {
+                    document.text = text.to_string();
+                }
+                self.scheduled.insert(uri, Instant::now() + self.debounce);
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.scheduled.remove(&uri);
+                self.publish(&uri, Vec::new())?;
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+    fn capabilities() -> Value {
+        json!({
+            "capabilities": {
+                "textDocumentSync": { "openClose": true, "change": 1, "save": { "includeText": true } },
+                "codeActionProvider": true,
+                "executeCommandProvider": {
+                    "commands": Action::ALL.iter().map(|action| action.command()).collect::<Vec<_>>()
+                }
+            },
+            "serverInfo": { "name": "termai", "version": env!("CARGO_PKG_VERSION") }
+        })
+    }
+    // gpt is called only when the command is executed
+    fn code_actions(params: &Value) -> Value {
+        let arguments = json!([params["textDocument"]["uri"], params["range"]]);
+        Action::ALL
+            .iter()
+            .map(|action| {
+                json!({
+                    "title": action.title(),
+                    "command": action.command(),
+                    "arguments": arguments
+                })
+            })
+            .collect()
+    }
+    fn execute(&mut self, params: &Value) -> Result<Value, (i64, String)> {
+        let command = params["command"].as_str().unwrap_or_default();
+        let action = Action::from_command(command).ok_or_else(|| {
+            (
+                JsonRpcError::INVALID_PARAMS,
+                format!("command {} is not found", command),
+            )
+        })?;
+        let uri = params["arguments"][0].as_str().unwrap_or_default();
+        let document = self.documents.get(uri).ok_or_else(|| {
+            (
+                JsonRpcError::INVALID_PARAMS,
+                format!("{} is not opened", uri),
+            )
+        })?;
+        let lines = document.text.split_inclusive('\n').collect::<Vec<_>>();
+        let (start, end) = selected_lines(&lines, &params["arguments"][1]);
+        let code = lines[start..end].concat();
+        let internal = |e: String| (JsonRpcError::INTERNAL_ERROR, e);
+        match action {
+            Action::Review => {
+                let mut reviewer = CodeReviewer::default().with_options(&self.review);
+                let review =
+                    (self.runner)(&mut reviewer, UserInput::new(code)).map_err(internal)?;
+                self.show_message(&review)
+                    .map_err(|e| internal(e.to_string()))?;
+            }
+            Action::Explain => {
+                let explanation = self.ask(EXPLAIN_PREFIX, &code).map_err(internal)?;
+                self.show_message(&explanation)
+                    .map_err(|e| internal(e.to_string()))?;
+            }
+            Action::DocComment => {
+                let answer = self.ask(DOC_COMMENT_PREFIX, &code).map_err(internal)?;
+                let indent = code
+                    .chars()
+                    .take_while(|c| c.is_whitespace() && *c != '\n')
+                    .collect::<String>();
+                let comment = first_code_block(&answer)
+                    .lines()
+                    .map(|line| format!("{}{}\n", indent, line.trim_start()))
+                    .collect::<String>();
+                self.apply_edit(uri.to_string(), start, start, comment)
+                    .map_err(|e| internal(e.to_string()))?;
+            }
+            Action::TranslateComments => {
+                let answer = self
+                    .ask(TRANSLATE_COMMENTS_PREFIX, &code)
+                    .map_err(internal)?;
+                let mut translated = first_code_block(&answer);
+                if code.ends_with('\n') && !translated.ends_with('\n') {
+                    translated.push('\n');
+                }
+                self.apply_edit(uri.to_string(), start, end, translated)
+                    .map_err(|e| internal(e.to_string()))?;
+            }
+        }
+        Ok(Value::Null)
+    }
+    fn ask(&mut self, prefix: &str, code: &str) -> Result<String, String> {
+        (self.runner)(
+            &mut GptDefaultFunction::new(),
+            UserInput::new(format!("{}\n{}", prefix, code)),
+        )
+    }
+    // sends documents whose debounce time has passed to review_worker.
+    // the same text is reviewed only once
+    fn review_scheduled(&mut self, now: Instant) -> std::io::Result<()> {
+        let due = self
+            .scheduled
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(uri, _)| uri.clone())
+            .collect::<Vec<_>>();
+        for uri in due {
+            self.scheduled.remove(&uri);
+            let Some(text) = self
+                .documents
+                .get(&uri)
+                .map(|document| document.text.clone())
+            else {
+                continue;
+            };
+            let key = hash(&text);
+            if let Some(diagnostics) = self.reviewed.get(&key).cloned() {
+                self.publish(&uri, diagnostics)?;
+                continue;
+            }
+            if !self.reviewing.insert(key) {
+                continue;
+            }
+            let job = ReviewJob {
+                uri: uri.clone(),
+                text,
+                options: self.review.clone(),
+            };
+            if self.reviews.send(job).is_err() {
+                self.log(&format!(
+                    "review of {} failed : the reviewer is stopped",
+                    uri
+                ))?;
+            }
+        }
+        Ok(())
+    }
+    // diagnostics are published to documents which still have the reviewed text
+    fn finish_review(
+        &mut self,
+        uri: &str,
+        text: &str,
+        result: Result<Vec<Finding>, String>,
+    ) -> std::io::Result<()> {
+        let key = hash(text);
+        self.reviewing.remove(&key);
+        let findings = match result {
+            Ok(findings) => findings,
+            Err(e) => return self.log(&format!("review of {} failed : {}", uri, e)),
+        };
+        let diagnostics = diagnostics(&findings, text);
+        self.reviewed.insert(key.clone(), diagnostics.clone());
+        let uris = self
+            .documents
+            .iter()
+            .filter(|(_, document)| hash(&document.text) == key)
+            .map(|(uri, _)| uri.clone())
+            .collect::<Vec<_>>();
+        for uri in uris {
+            self.publish(&uri, diagnostics.clone())?;
+        }
+        Ok(())
+    }
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Value>) -> std::io::Result<()> {
+        self.notify(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        )
+    }
+    fn show_message(&mut self, message: &str) -> std::io::Result<()> {
+        // 3 is info
+        self.notify(
+            "window/showMessage",
+            json!({ "type": 3, "message": message }),
+        )
+    }
+    fn log(&mut self, message: &str) -> std::io::Result<()> {
+        // 1 is error
+        self.notify(
+            "window/logMessage",
+            json!({ "type": 1, "message": message }),
+        )
+    }
+    // replaces lines start..end by text. start == end inserts before the line
+    fn apply_edit(
+        &mut self,
+        uri: String,
+        start: usize,
+        end: usize,
+        text: String,
+    ) -> std::io::Result<()> {
+        self.next_id += 1;
+        let range = json!({
+            "start": { "line": start, "character": 0 },
+            "end": { "line": end, "character": 0 }
+        });
+        let mut changes = serde_json::Map::new();
+        changes.insert(uri, json!([{ "range": range, "newText": text }]));
+        write_message(
+            &mut self.writer,
+            &JsonRpcMessage::request(
+                self.next_id,
+                "workspace/applyEdit",
+                json!({ "edit": { "changes": changes } }),
+            ),
+        )
+    }
+    fn notify(&mut self, method: &str, params: Value) -> std::io::Result<()> {
+        let mut notification = JsonRpcMessage::notification(method);
+        notification.params = Some(params);
+        write_message(&mut self.writer, &notification)
+    }
+}
+
+fn hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn first_code_block(answer: &str) -> String {
+    let mut capture = CodeCapture::new();
+    capture.add(answer);
+    capture
+        .get_codes()
+        .first()
+        .map(|code| code.as_str().to_string())
+        .unwrap_or_else(|| answer.trim().to_string())
+}
+
+// lines start..end of the lsp range. an empty range means the block around the cursor
+fn selected_lines(lines: &[&str], range: &Value) -> (usize, usize) {
+    let line = |position: &str| range[position]["line"].as_u64().unwrap_or_default() as usize;
+    let start = line("start").min(lines.len().saturating_sub(1));
+    let end = if range["end"]["character"].as_u64().unwrap_or_default() > 0 {
+        line("end") + 1
+    } else {
+        line("end")
+    };
+    if range["start"] == range["end"] || end <= start {
+        return enclosing_block(lines, start);
+    }
+    (start, end.min(lines.len()))
+}
+
+// from the last top level line before the cursor to the next top level line.
+// closing brackets belong to the block, so a function with its body is selected
+fn enclosing_block(lines: &[&str], line: usize) -> (usize, usize) {
+    let is_top_level = |line: &str| {
+        !line.trim().is_empty()
+            && !line.starts_with(char::is_whitespace)
+            && !line.starts_with(['}', ')', ']'])
+    };
+    if lines.is_empty() {
+        return (0, 0);
+    }
+    let start = (0..=line)
+        .rev()
+        .find(|i| is_top_level(lines[*i]))
+        .unwrap_or_default();
+    let mut end = (start + 1..lines.len())
+        .find(|i| is_top_level(lines[*i]))
+        .unwrap_or(lines.len());
+    while end > start + 1 && lines[end - 1].trim().is_empty() {
+        end -= 1;
+    }
+    (start, end)
+}
+
+// "12: warning: message". lines in other forms are ignored
+fn diagnostics(findings: &[Finding], text: &str) -> Vec<Value> {
+    let lines = text.lines().collect::<Vec<_>>();
+    findings
+        .iter()
+        .filter_map(|finding| {
+            let start = finding.line - 1;
+            let end = finding
+                .end_line()
+                .min(lines.len())
+                .checked_sub(1)?
+                .max(start);
+            // lsp counts characters in utf-16
+            let width = lines.get(end)?.encode_utf16().count();
+            let severity = match finding.severity {
+                Severity::Error => 1,
+                Severity::Warning => 2,
+                Severity::Info => 3,
+            };
+            let message = match finding.suggestion.as_ref() {
+                Some(suggestion) => format!("{}\n{}", finding.message, suggestion),
+                None => finding.message.clone(),
+            };
+            Some(json!({
+                "range": {
+                    "start": { "line": start, "character": 0 },
+                    "end": { "line": end, "character": width }
+                },
+                "severity": severity,
+                "code": finding.category.as_str(),
+                "source": "termai",
+                "message": message
+            }))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    fn frames(output: &[u8]) -> Vec<JsonRpcMessage> {
+        let mut reader = output;
+        let mut messages = Vec::new();
+        while let Some(body) = read_message(&mut reader).unwrap() {
+            messages.push(serde_json::from_str(&body).unwrap());
+        }
+        messages
+    }
+    fn notification(method: &str, params: Value) -> String {
+        json!({"jsonrpc": "2.0", "method": method, "params": params}).to_string()
+    }
+    #[test]
+    fn content_lengthで区切られたメッセージを読み書きする() {
+        let mut output = Vec::new();
+        write_message(&mut output, &JsonRpcMessage::notification("exit")).unwrap();
+        write_message(&mut output, &JsonRpcMessage::notification("こんにちは")).unwrap();
+
+        let messages = frames(&output);
+
+        assert_eq!(messages[0].method.as_deref(), Some("exit"));
+        assert_eq!(messages[1].method.as_deref(), Some("こんにちは"));
+    }
+    #[test]
+    fn 大きすぎるcontent_lengthは読まずにエラーにする() {
+        let input = format!("Content-Length: {}\r\n\r\n{{}}", MAX_BODY + 1);
+
+        let result = read_message(&mut input.as_bytes());
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+    #[test]
+    fn 保存から待って別のスレッドでレビューし同じ内容はキャッシュから診断を出す() {
+        let called = Rc::new(Cell::new(0));
+        let runner_called = called.clone();
+        let mut runner = |_: &mut dyn GptFunction, input: UserInput| {
+            runner_called.set(runner_called.get() + 1);
+            assert!(input.content().starts_with("fn main() {\n"));
+            Ok(r#"[{"line": 2, "severity": "warning", "category": "readability", "message": "x is not used"}]"#.to_string())
+        };
+        let (reviews, jobs) = std::sync::mpsc::channel();
+        let mut output = Vec::new();
+        let mut sut = LspServer::new(
+            |_: &mut dyn GptFunction, _: UserInput| Err("not called".to_string()),
+            &mut output,
+            Duration::from_secs(60),
+            reviews,
+        );
+        // what review_worker does
+        let mut run_jobs = |sut: &mut LspServer<_, _>| {
+            while let Ok(job) = jobs.try_recv() {
+                if let LspEvent::Reviewed { uri, text, result } = job.run(&mut runner) {
+                    sut.finish_review(&uri, &text, result).unwrap();
+                }
+            }
+        };
+        let text = "fn main() {\n    let x = 1;\n}\n";
+        let open = notification(
+            "textDocument/didOpen",
+            json!({"textDocument": {"uri": "file:///a.rs", "text": text}}),
+        );
+        let save = notification(
+            "textDocument/didSave",
+            json!({"textDocument": {"uri": "file:///a.rs"}}),
+        );
+
+        sut.handle(&open).unwrap();
+        sut.handle(&save).unwrap();
+        sut.handle(&save).unwrap();
+        sut.review_scheduled(Instant::now()).unwrap();
+        let before_debounce = called.get();
+        sut.review_scheduled(Instant::now() + Duration::from_secs(61))
+            .unwrap();
+        run_jobs(&mut sut);
+        sut.handle(&save).unwrap();
+        sut.review_scheduled(Instant::now() + Duration::from_secs(61))
+            .unwrap();
+        run_jobs(&mut sut);
+        drop(sut);
+
+        let messages = frames(&output);
+        assert_eq!(before_debounce, 0);
+        assert_eq!(called.get(), 1);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], messages[1]);
+        assert_eq!(
+            messages[0].params.as_ref().unwrap()["diagnostics"],
+            json!([{
+                "range": {"start": {"line": 1, "character": 0}, "end": {"line": 1, "character": 14}},
+                "severity": 2,
+                "code": "readability",
+                "source": "termai",
+                "message": "x is not used"
+            }])
+        );
+    }
+    #[test]
+    fn ドキュメントコメントをカーソルのある関数の前に挿入する() {
+        let mut output = Vec::new();
+        let mut sut = LspServer::new(
+            |_: &mut dyn GptFunction, _: UserInput| {
+                Ok("here\n```rust\n/// adds one\n```\n".to_string())
+            },
+            &mut output,
+            Duration::from_secs(1),
+            std::sync::mpsc::channel().0,
+        );
+        let text = "use std::io;\n\nfn add(x: i32) -> i32 {\n    x + 1\n}\n\nfn main() {}\n";
+        sut.handle(&notification(
+            "textDocument/didOpen",
+            json!({"textDocument": {"uri": "file:///a.rs", "text": text}}),
+        ))
+        .unwrap();
+        let cursor =
+            json!({"start": {"line": 3, "character": 2}, "end": {"line": 3, "character": 2}});
+
+        sut.handle(
+            &json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/codeAction", "params": {
+                "textDocument": {"uri": "file:///a.rs"}, "range": cursor
+            }})
+            .to_string(),
+        )
+        .unwrap();
+        sut.handle(
+            &json!({"jsonrpc": "2.0", "id": 2, "method": "workspace/executeCommand", "params": {
+                "command": "termai.docComment", "arguments": ["file:///a.rs", cursor]
+            }})
+            .to_string(),
+        )
+        .unwrap();
+        drop(sut);
+
+        let messages = frames(&output);
+        assert_eq!(
+            messages[0]
+                .result
+                .as_ref()
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            4
+        );
+        assert_eq!(messages[1].method.as_deref(), Some("workspace/applyEdit"));
+        assert_eq!(
+            messages[1].params.as_ref().unwrap()["edit"]["changes"]["file:///a.rs"],
+            json!([{
+                "range": {"start": {"line": 2, "character": 0}, "end": {"line": 2, "character": 0}},
+                "newText": "/// adds one\n"
+            }])
+        );
+        // result is null
+        assert_eq!(messages[2].id, Some(json!(2)));
+        assert!(messages[2].error.is_none());
+    }
+    #[test]
+    fn 診断の終わりの位置はutf16で数える() {
+        let finding = parse_findings(
+            r#"[{"line": 1, "severity": "info", "category": "style", "message": "m"}]"#,
+            "",
+        )
+        .unwrap();
+
+        let diagnostics = diagnostics(&finding, "let s = \"日本🎉\";\n");
+
+        assert_eq!(diagnostics[0]["range"]["end"]["character"], 15);
+    }
+    #[test]
+    fn 空の範囲はカーソルを囲むブロックを選ぶ() {
+        let text = "fn a() {\n    1\n}\n\nimpl A {\n    fn b() {}\n}\n";
+        let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+
+        assert_eq!(enclosing_block(&lines, 1), (0, 3));
+        assert_eq!(enclosing_block(&lines, 5), (4, 7));
+        assert_eq!(
+            selected_lines(
+                &lines,
+                &json!({"start": {"line": 0, "character": 0}, "end": {"line": 2, "character": 0}})
+            ),
+            (0, 2)
+        );
+    }
+}
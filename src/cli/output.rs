@@ -0,0 +1,206 @@
+use std::{io::Write, str::FromStr, time::Duration};
+
+use crate::{
+    functions::FunctionArtifact,
+    gpt::client::{ChatResponse, CompletionMeta, OpenAIModel, Usage},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    Text,
+    Json,
+    Ndjson,
+}
+impl FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(format!("{} is not supported", s)),
+        }
+    }
+}
+impl OutputFormat {
+    pub(crate) fn to_output(self) -> Box<dyn ResponseOutput> {
+        match self {
+            Self::Text => Box::new(TextOutput::new()),
+            Self::Json => Box::new(JsonOutput::new()),
+            Self::Ndjson => Box::new(NdjsonOutput),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct ExecSummary {
+    model: String,
+    finish_reason: Option<String>,
+    usage: Option<Usage>,
+    elapsed_ms: u128,
+    artifacts: Vec<FunctionArtifact>,
+}
+impl ExecSummary {
+    pub(crate) fn new(model: OpenAIModel) -> Self {
+        Self {
+            model: model.as_str().to_string(),
+            finish_reason: None,
+            usage: None,
+            elapsed_ms: 0,
+            artifacts: Vec::new(),
+        }
+    }
+    // input may be split into some requests. usage is summed and last finish reason wins
+    pub(crate) fn update(&mut self, meta: &CompletionMeta) {
+        if let Some(model) = meta.model.as_ref() {
+            self.model = model.clone();
+        }
+        if meta.finish_reason.is_some() {
+            self.finish_reason = meta.finish_reason.clone();
+        }
+        if let Some(usage) = meta.usage.as_ref() {
+            self.usage = Some(self.usage.unwrap_or_default().add(usage));
+        }
+    }
+    pub(crate) fn finish(&mut self, elapsed: Duration, artifacts: Vec<FunctionArtifact>) {
+        self.elapsed_ms = elapsed.as_millis();
+        self.artifacts = artifacts;
+    }
+}
+
+pub(crate) trait ResponseOutput {
+    fn start(&mut self) {}
+    fn delta(&mut self, response: &ChatResponse);
+    fn finish(&mut self, _summary: &ExecSummary) {}
+    fn error(&mut self, _error: &dyn std::error::Error) {}
+}
+
+// only the answer is printed
+pub(crate) struct TextOutput {
+    last: String,
+}
+
+impl TextOutput {
+    pub(crate) fn new() -> Self {
+        Self {
+            last: String::new(),
+        }
+    }
+}
+
+impl ResponseOutput for TextOutput {
+    fn delta(&mut self, response: &ChatResponse) {
+        let content = response.delta_content();
+        if content.is_empty() {
+            return;
+        }
+        print!("{}", content);
+        std::io::stdout().flush().unwrap_or_default();
+        self.last = content.to_string();
+    }
+    fn finish(&mut self, _summary: &ExecSummary) {
+        if !self.last.ends_with('\n') {
+            println!();
+        }
+    }
+}
+
+// keeps the answer in memory. used when termai is not a terminal command e.g. batch
+pub(crate) struct BufferOutput {
+    content: String,
+}
+impl BufferOutput {
+    pub(crate) fn new() -> Self {
+        Self {
+            content: String::new(),
+        }
+    }
+    pub(crate) fn content(&self) -> &str {
+        &self.content
+    }
+}
+impl ResponseOutput for BufferOutput {
+    fn delta(&mut self, response: &ChatResponse) {
+        self.content.push_str(response.delta_content());
+    }
+}
+
+pub(crate) struct JsonOutput {
+    content: String,
+}
+impl JsonOutput {
+    pub(crate) fn new() -> Self {
+        Self {
+            content: String::new(),
+        }
+    }
+}
+impl ResponseOutput for JsonOutput {
+    fn delta(&mut self, response: &ChatResponse) {
+        self.content.push_str(response.delta_content());
+    }
+    fn finish(&mut self, summary: &ExecSummary) {
+        let mut value = serde_json::to_value(summary).unwrap_or_default();
+        value["content"] = serde_json::Value::String(self.content.clone());
+        println!("{}", value);
+    }
+    fn error(&mut self, error: &dyn std::error::Error) {
+        println!("{}", serde_json::json!({ "error": error.to_string() }));
+    }
+}
+
+pub(crate) struct NdjsonOutput;
+impl NdjsonOutput {
+    fn emit(value: serde_json::Value) {
+        println!("{}", value);
+        std::io::stdout().flush().unwrap_or_default();
+    }
+}
+impl ResponseOutput for NdjsonOutput {
+    fn start(&mut self) {
+        Self::emit(serde_json::json!({ "type": "start" }));
+    }
+    fn delta(&mut self, response: &ChatResponse) {
+        let content = response.delta_content();
+        if content.is_empty() {
+            return;
+        }
+        Self::emit(serde_json::json!({ "type": "delta", "content": content }));
+    }
+    fn finish(&mut self, summary: &ExecSummary) {
+        let mut value = serde_json::to_value(summary).unwrap_or_default();
+        value["type"] = serde_json::Value::String("done".to_string());
+        Self::emit(value);
+    }
+    fn error(&mut self, error: &dyn std::error::Error) {
+        Self::emit(serde_json::json!({ "type": "error", "message": error.to_string() }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn 分割されたリクエストの使用トークン数は合計する() {
+        let mut summary = ExecSummary::new(OpenAIModel::Gpt4o);
+        let usage = Usage {
+            prompt_tokens: 1,
+            completion_tokens: 2,
+            total_tokens: 3,
+        };
+        summary.update(&CompletionMeta {
+            model: Some("gpt-4o-2024-05-13".to_string()),
+            finish_reason: Some("length".to_string()),
+            usage: Some(usage),
+        });
+        summary.update(&CompletionMeta {
+            model: None,
+            finish_reason: Some("stop".to_string()),
+            usage: Some(usage),
+        });
+
+        assert_eq!(summary.model, "gpt-4o-2024-05-13");
+        assert_eq!(summary.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(summary.usage, Some(usage.add(&usage)));
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    process::Command,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    config::Profile,
+    functions::{
+        code_capture::Lang,
+        code_reviewer::{
+            diff::{self, parse_diff},
+            finding::{Finding, Severity},
+            options::ReviewOptions,
+            report::{self, summary},
+            CodeReviewer,
+        },
+        UserInput,
+    },
+    gpt::{
+        cache::{CacheMode, ResponseCache},
+        client::{GptClient, GptClientOption, OpenAIModel},
+        tool::Tools,
+    },
+};
+
+use super::{
+    batch::{run_workers, RateLimiter},
+    error::TermAIError,
+    exec_with_function,
+    output::BufferOutput,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReviewFormat {
+    // free-form answer of CodeReviewer as it is
+    Prose,
+    // path:line:col: severity: message
+    Findings,
+    Json,
+    // SARIF 2.1.0 for code scanning dashboards
+    Sarif,
+    Checkstyle,
+    // grouped by file and severity. prose of many files is this
+    Markdown,
+}
+impl FromStr for ReviewFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prose" => Ok(Self::Prose),
+            "findings" => Ok(Self::Findings),
+            "json" => Ok(Self::Json),
+            "sarif" => Ok(Self::Sarif),
+            "checkstyle" => Ok(Self::Checkstyle),
+            "markdown" => Ok(Self::Markdown),
+            _ => Err(format!("{} is not supported", s)),
+        }
+    }
+}
+impl ReviewFormat {
+    pub(crate) fn render(&self, findings: &[Finding]) -> String {
+        match self {
+            Self::Prose | Self::Findings => findings
+                .iter()
+                .map(|finding| format!("{}\n", finding))
+                .collect(),
+            Self::Json => format!(
+                "{}\n",
+                serde_json::to_string_pretty(findings).unwrap_or_default()
+            ),
+            // fingerprints are of the code in the files of the current directory
+            Self::Sarif => format!(
+                "{}\n",
+                serde_json::to_string_pretty(&report::to_sarif(findings, |path| {
+                    std::fs::read_to_string(path).ok()
+                }))
+                .unwrap_or_default()
+            ),
+            Self::Checkstyle => report::to_checkstyle(findings),
+            Self::Markdown => report::to_markdown(findings, &[], &[]),
+        }
+    }
+}
+
+// tokens of a chunk. a token is at most 4 bytes, so a chunk fits in one message
+// and the prefix is not repeated in the middle of a hunk
+const MAX_DIFF_CHUNK: usize = 900;
+
+pub(crate) fn review_findings(
+    client: &mut GptClient,
+    model: OpenAIModel,
+    input: UserInput,
+    options: &ReviewOptions,
+    tools: &mut Tools,
+) -> Result<Vec<Finding>, TermAIError> {
+    let reviewer = CodeReviewer::structured().with_options(options);
+    run_review(client, model, input, reviewer, tools)
+}
+
+// findings are of the new files of the diff
+pub(crate) fn review_diff(
+    client: &mut GptClient,
+    model: OpenAIModel,
+    diff: &str,
+    options: &ReviewOptions,
+    tools: &mut Tools,
+) -> Result<Vec<Finding>, TermAIError> {
+    let chunks = diff::chunks(&parse_diff(diff), MAX_DIFF_CHUNK);
+    let mut findings = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        eprintln!("[{}/{}] reviewing {}", i + 1, chunks.len(), chunk.path);
+        let reviewed = run_review(
+            client,
+            model,
+            UserInput::new(chunk.text.as_str()),
+            CodeReviewer::diff()
+                .with_options(options)
+                .with_lang(Lang::from_path(&chunk.path)),
+            tools,
+        )?;
+        findings.extend(reviewed.into_iter().map(|finding| chunk.map(finding)));
+    }
+    Ok(findings)
+}
+
+// files and why each one failed
+type FileErrors = Vec<(String, String)>;
+
+// files reviewed at once by the workers
+pub(crate) struct FileReview {
+    pub(crate) concurrency: usize,
+    pub(crate) requests_per_minute: u32,
+    pub(crate) model: OpenAIModel,
+    pub(crate) cache: Option<(ResponseCache, CacheMode)>,
+    pub(crate) options: ReviewOptions,
+}
+impl FileReview {
+    // findings of all files, and the files failed with the error
+    pub(crate) fn run(
+        &self,
+        profile: &Profile,
+        files: Vec<String>,
+    ) -> Result<(Vec<Finding>, FileErrors), TermAIError> {
+        let total = files.len();
+        // resolve key once. a key command may ask a passphrase
+        let key = profile.key_source().resolve()?;
+        let option = GptClientOption::from_profile(profile);
+        // each request waits, including the re-ask and the parts of a long file
+        let limiter = Arc::new(RateLimiter::per_minute(self.requests_per_minute));
+        let results = Mutex::new(Vec::new());
+        run_workers(
+            files.clone(),
+            self.concurrency,
+            || {
+                let limiter = limiter.clone();
+                GptClient::new(key.clone(), option.clone()).map(|client| {
+                    let client = client.with_throttle(Arc::new(move || limiter.wait()));
+                    match self.cache.clone() {
+                        Some((cache, mode)) => client.with_cache(cache, mode),
+                        None => client,
+                    }
+                })
+            },
+            |client, file| {
+                let result = match client {
+                    // tools are not Send. mcp servers are not used
+                    Ok(client) => review_findings(
+                        client,
+                        self.model,
+                        UserInput::new(file.as_str()),
+                        &self.options,
+                        &mut Tools::new(),
+                    )
+                    .map_err(|e| e.to_string()),
+                    Err(e) => Err(TermAIError::from(e.clone()).to_string()),
+                };
+                let mut results = results.lock().unwrap();
+                eprintln!(
+                    "[{}/{}] {} {}",
+                    results.len() + 1,
+                    total,
+                    file,
+                    match result.as_ref() {
+                        Ok(findings) => summary(findings),
+                        Err(e) => format!("error : {}", e),
+                    }
+                );
+                results.push((file, result));
+            },
+        );
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(file, _)| files.iter().position(|f| f == file));
+        let mut findings = Vec::new();
+        let mut failed = Vec::new();
+        for (file, result) in results {
+            match result {
+                Ok(reviewed) => findings.extend(reviewed),
+                // the hint was shown in the progress
+                Err(e) => failed.push((file, e.lines().next().unwrap_or_default().to_string())),
+            }
+        }
+        Ok((findings, failed))
+    }
+}
+
+// `git diff [--staged] [target]` of the current directory
+pub(crate) fn git_diff(
+    target: Option<&str>,
+    staged: bool,
+    context_lines: usize,
+) -> Result<String, TermAIError> {
+    let mut command = Command::new("git");
+    command
+        .args(["diff", "--no-color", "--no-ext-diff"])
+        .arg(format!("--unified={}", context_lines));
+    if staged {
+        command.arg("--staged");
+    }
+    if let Some(target) = target {
+        command.arg(target);
+    }
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(TermAIError::InvalidInput(format!(
+            "git diff failed : {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// the model is asked again once when the answer is not valid findings
+fn run_review(
+    client: &mut GptClient,
+    model: OpenAIModel,
+    input: UserInput,
+    mut reviewer: CodeReviewer,
+    tools: &mut Tools,
+) -> Result<Vec<Finding>, TermAIError> {
+    exec_with_function(
+        client,
+        model,
+        input.clone(),
+        &mut reviewer,
+        tools,
+        &mut BufferOutput::new(),
+    )?;
+    match reviewer.findings() {
+        Ok(findings) => return Ok(findings),
+        Err(e) => {
+            eprintln!("review is not valid findings. ask again : {}", e);
+            reviewer.retry(e);
+        }
+    }
+    exec_with_function(
+        client,
+        model,
+        input,
+        &mut reviewer,
+        tools,
+        &mut BufferOutput::new(),
+    )?;
+    reviewer
+        .findings()
+        .map_err(|e| TermAIError::Function(format!("invalid review : {}", e).into()))
+}
+
+// the summary goes to stderr, so stdout is only the findings
+pub(crate) fn write_findings(
+    format: ReviewFormat,
+    findings: &[Finding],
+    out: Option<&str>,
+) -> Result<(), TermAIError> {
+    write_out(&format.render(findings), out)?;
+    eprintln!("{}", summary(findings));
+    Ok(())
+}
+
+// report of many files. failed and skipped files are listed in the markdown
+pub(crate) fn write_report(
+    format: ReviewFormat,
+    findings: &[Finding],
+    failed: &[(String, String)],
+    skipped: &[(String, String)],
+    out: Option<&str>,
+) -> Result<(), TermAIError> {
+    let rendered = match format {
+        ReviewFormat::Prose | ReviewFormat::Markdown => {
+            report::to_markdown(findings, failed, skipped)
+        }
+        _ => format.render(findings),
+    };
+    write_out(&rendered, out)?;
+    eprintln!(
+        "{}. {} failed, {} skipped",
+        summary(findings),
+        failed.len(),
+        skipped.len()
+    );
+    Ok(())
+}
+
+fn write_out(rendered: &str, out: Option<&str>) -> Result<(), TermAIError> {
+    match out {
+        Some(path) => std::fs::write(path, rendered)?,
+        None => print!("{}", rendered),
+    }
+    Ok(())
+}
+
+pub(crate) fn filter_severity(findings: Vec<Finding>, min: Severity) -> Vec<Finding> {
+    findings
+        .into_iter()
+        .filter(|finding| finding.severity >= min)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::code_reviewer::finding::Category;
+
+    use super::*;
+    fn finding(line: usize, severity: Severity) -> Finding {
+        Finding {
+            file: "src/main.rs".to_string(),
+            line,
+            end_line: None,
+            severity,
+            category: Category::Bug,
+            message: "wrong".to_string(),
+            suggestion: None,
+            fix: None,
+        }
+    }
+    #[test]
+    fn 差分のチャンクは日本語でも英語でも1つのメッセージに収まる() {
+        for line in ["+let x = 1; // add x", "+// 日本語のコメントを追加する"] {
+            let diff = format!(
+                "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -0,0 +1,1000 @@\n{}\n",
+                vec![line; 1000].join("\n")
+            );
+
+            let chunks = diff::chunks(&parse_diff(&diff), MAX_DIFF_CHUNK);
+
+            assert!(chunks.len() > 1);
+            assert!(chunks.iter().all(|chunk| chunk.text.len() <= 4096));
+        }
+    }
+    #[test]
+    fn 重要度で絞り込んでコンパイラ形式で表示する() {
+        let findings = vec![
+            finding(1, Severity::Info),
+            finding(2, Severity::Error),
+            finding(3, Severity::Warning),
+        ];
+
+        let filtered = filter_severity(findings.clone(), Severity::Warning);
+
+        assert_eq!(
+            ReviewFormat::Findings.render(&filtered),
+            "src/main.rs:2:1: error: wrong [bug]\nsrc/main.rs:3:1: warning: wrong [bug]\n"
+        );
+        assert_eq!(
+            summary(&findings),
+            "3 findings (1 error, 1 warning, 1 info)"
+        );
+        assert_eq!(summary(&[]), "no findings");
+    }
+}
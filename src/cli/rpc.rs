@@ -0,0 +1,531 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::Write,
+    str::FromStr,
+    sync::mpsc::Receiver,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    functions::{registry::FunctionRegistry, FunctionArtifact, GptFunction, UserInput},
+    gpt::{
+        chat::ChatGpt,
+        client::{ChatRequest, ChatResponse, GptClient, HandleResult, Message, OpenAIModel, Role},
+        tool::Tools,
+    },
+    mcp::{JsonRpcError, JsonRpcMessage},
+};
+
+use super::{error::TermAIError, exec_with_function, output::BufferOutput};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct StartParams {
+    model: Option<String>,
+    system: Option<String>,
+}
+#[derive(Debug, Clone, serde::Deserialize)]
+struct SessionParams {
+    session_id: String,
+}
+#[derive(Debug, Clone, serde::Deserialize)]
+struct SendParams {
+    session_id: String,
+    message: String,
+}
+#[derive(Debug, Clone, serde::Deserialize)]
+struct RunParams {
+    // name[:args] e.g. translate:ja
+    name: String,
+    text: String,
+    range: Option<LineRange>,
+    model: Option<String>,
+}
+// lines of the buffer. 0-based, end is exclusive like LSP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+struct LineRange {
+    start: usize,
+    end: usize,
+}
+impl LineRange {
+    fn select(&self, text: &str) -> String {
+        text.split_inclusive('\n')
+            .skip(self.start)
+            .take(self.end.saturating_sub(self.start))
+            .collect()
+    }
+}
+
+type RpcResult = Result<Value, (i64, String)>;
+
+// one chat per session id. history is kept until the session is closed
+struct Session {
+    gpt: ChatGpt,
+    model: OpenAIModel,
+    system: Option<String>,
+}
+
+// stdin and stdout of the server. requests which arrive while streaming are kept in pending
+pub(crate) struct Connection<W> {
+    input: Receiver<String>,
+    writer: W,
+    pending: VecDeque<String>,
+    cancelled: HashSet<String>,
+}
+
+impl<W: Write> Connection<W> {
+    const CANCEL: &'static str = "$/cancelRequest";
+    pub(crate) fn new(input: Receiver<String>, writer: W) -> Self {
+        Self {
+            input,
+            writer,
+            pending: VecDeque::new(),
+            cancelled: HashSet::new(),
+        }
+    }
+    // None when stdin is closed
+    fn next(&mut self) -> Option<String> {
+        self.pending.pop_front().or_else(|| self.input.recv().ok())
+    }
+    fn send(&mut self, message: &JsonRpcMessage) {
+        // the editor is gone if stdout is broken. the server stops when stdin is closed
+        writeln!(
+            self.writer,
+            "{}",
+            serde_json::to_string(message).unwrap_or_default()
+        )
+        .and_then(|_| self.writer.flush())
+        .unwrap_or_default();
+    }
+    fn notify(&mut self, method: &str, params: Value) {
+        let mut notification = JsonRpcMessage::notification(method);
+        notification.params = Some(params);
+        self.send(&notification);
+    }
+    // reads what arrived without blocking. other messages are handled after the current one
+    fn is_cancelled(&mut self, id: &Value) -> bool {
+        while let Ok(line) = self.input.try_recv() {
+            match serde_json::from_str::<JsonRpcMessage>(&line) {
+                Ok(message) if message.method.as_deref() == Some(Self::CANCEL) => {
+                    let params = message.params.unwrap_or_default();
+                    self.cancelled.insert(params["id"].to_string());
+                }
+                _ => self.pending.push_back(line),
+            }
+        }
+        self.cancelled.remove(&id.to_string())
+    }
+}
+
+// wraps the function to stream deltas as notifications and to stop when cancelled
+struct Streaming<'a, W> {
+    inner: Box<dyn GptFunction>,
+    connection: &'a mut Connection<W>,
+    id: Value,
+    cancelled: bool,
+}
+impl<W: Write> GptFunction for Streaming<'_, W> {
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+    fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
+        self.inner.input_to_messages(input)
+    }
+    fn setup_for_action(&mut self, input: &UserInput) {
+        self.inner.setup_for_action(input)
+    }
+    fn change_request(&self, request: &mut ChatRequest) {
+        self.inner.change_request(request)
+    }
+    fn can_action(&self) -> bool {
+        self.inner.can_action()
+    }
+    fn handle_stream(&mut self, response: &ChatResponse) -> HandleResult {
+        if self.cancelled || self.connection.is_cancelled(&self.id) {
+            self.cancelled = true;
+            return HandleResult::Done;
+        }
+        if !response.delta_content().is_empty() {
+            self.connection.notify(
+                "function/delta",
+                json!({ "request_id": self.id, "content": response.delta_content() }),
+            );
+        }
+        self.inner.handle_stream(response)
+    }
+    fn action_at_end(&mut self) -> Result<(), Box<dyn std::error::Error + 'static>> {
+        // the answer is partial. e.g. a commit must not be made from it
+        if self.cancelled {
+            return Ok(());
+        }
+        self.inner.action_at_end()
+    }
+    fn artifacts(&self) -> Vec<FunctionArtifact> {
+        self.inner.artifacts()
+    }
+}
+
+// long-lived json-rpc 2.0 server for editors. one message per line over stdio
+pub(crate) struct RpcServer<C> {
+    new_client: C,
+    // one-shot client of function/run. sessions have their own
+    client: Option<GptClient>,
+    registry: FunctionRegistry,
+    model: OpenAIModel,
+    sessions: HashMap<String, Session>,
+    next_session: usize,
+}
+
+impl<C: FnMut() -> Result<GptClient, TermAIError>> RpcServer<C> {
+    pub(crate) fn new(model: OpenAIModel, registry: FunctionRegistry, new_client: C) -> Self {
+        Self {
+            new_client,
+            client: None,
+            registry,
+            model,
+            sessions: HashMap::new(),
+            next_session: 0,
+        }
+    }
+    pub(crate) fn serve<W: Write>(&mut self, connection: &mut Connection<W>) {
+        while let Some(line) = connection.next() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<JsonRpcMessage>(&line) {
+                Ok(message) => {
+                    if let Some(response) = self.handle(message, connection) {
+                        connection.send(&response);
+                    }
+                }
+                Err(e) => connection.send(&JsonRpcMessage::error_response(
+                    Value::Null,
+                    JsonRpcError::PARSE_ERROR,
+                    e.to_string(),
+                )),
+            }
+        }
+    }
+    // notifications get no response. a cancel of a finished request is ignored
+    fn handle<W: Write>(
+        &mut self,
+        message: JsonRpcMessage,
+        connection: &mut Connection<W>,
+    ) -> Option<JsonRpcMessage> {
+        let method = message.method.unwrap_or_default();
+        let params = message.params.unwrap_or_default();
+        let Some(id) = message.id else {
+            if method == Connection::<W>::CANCEL {
+                connection.cancelled.insert(params["id"].to_string());
+            }
+            return None;
+        };
+        // cancelled while waiting in pending
+        let result = if connection.cancelled.remove(&id.to_string()) {
+            Err(cancelled())
+        } else {
+            match method.as_str() {
+                "initialize" => Ok(json!({
+                    "serverInfo": { "name": "termai", "version": env!("CARGO_PKG_VERSION") },
+                    "functions": self.registry.entries().iter().map(|(name, _)| name).collect::<Vec<_>>()
+                })),
+                "session/start" => parse(params).and_then(|params| self.start(params)),
+                "session/send" => {
+                    parse(params).and_then(|params| self.send(params, &id, connection))
+                }
+                "session/history" => parse(params).and_then(|params| self.history(params)),
+                "session/close" => parse(params).and_then(|params| self.close(params)),
+                "function/run" => {
+                    parse(params).and_then(|params| self.run(params, &id, connection))
+                }
+                _ => Err((
+                    JsonRpcError::METHOD_NOT_FOUND,
+                    format!("method {} is not found", method),
+                )),
+            }
+        };
+        Some(match result {
+            Ok(result) => JsonRpcMessage::response(id, result),
+            Err((code, message)) => JsonRpcMessage::error_response(id, code, message),
+        })
+    }
+    fn start(&mut self, params: StartParams) -> RpcResult {
+        let model = self.model(params.model.as_deref())?;
+        let client = (self.new_client)().map_err(internal)?;
+        self.next_session += 1;
+        let session_id = self.next_session.to_string();
+        self.sessions.insert(
+            session_id.clone(),
+            Session {
+                gpt: ChatGpt::from_client(client),
+                model,
+                system: params.system,
+            },
+        );
+        Ok(json!({ "session_id": session_id }))
+    }
+    fn send<W: Write>(
+        &mut self,
+        params: SendParams,
+        id: &Value,
+        connection: &mut Connection<W>,
+    ) -> RpcResult {
+        let session = self
+            .sessions
+            .get_mut(&params.session_id)
+            .ok_or_else(|| session_not_found(&params.session_id))?;
+        let history_len = session.gpt.chat_history().len();
+        let mut req = session
+            .gpt
+            .make_request(session.model, &Message::new(Role::User, params.message));
+        if let Some(system) = session.system.as_ref() {
+            req.set_system(system.as_str());
+        }
+        let mut is_cancelled = false;
+        let result = session.gpt.send(req, &mut |res| {
+            if connection.is_cancelled(id) {
+                is_cancelled = true;
+                return HandleResult::Done;
+            }
+            if !res.delta_content().is_empty() {
+                connection.notify(
+                    "session/delta",
+                    json!({
+                        "session_id": params.session_id,
+                        "request_id": id,
+                        "content": res.delta_content()
+                    }),
+                );
+            }
+            HandleResult::from(res)
+        });
+        if is_cancelled {
+            session.gpt.rollback(history_len);
+            return Err(cancelled());
+        }
+        result.map_err(|e| internal(TermAIError::from(e)))?;
+        Ok(json!({ "content": session.gpt.last_response() }))
+    }
+    fn history(&self, params: SessionParams) -> RpcResult {
+        let session = self
+            .sessions
+            .get(&params.session_id)
+            .ok_or_else(|| session_not_found(&params.session_id))?;
+        Ok(json!({ "messages": session.gpt.chat_history() }))
+    }
+    fn close(&mut self, params: SessionParams) -> RpcResult {
+        self.sessions
+            .remove(&params.session_id)
+            .ok_or_else(|| session_not_found(&params.session_id))?;
+        Ok(json!({}))
+    }
+    fn run<W: Write>(
+        &mut self,
+        params: RunParams,
+        id: &Value,
+        connection: &mut Connection<W>,
+    ) -> RpcResult {
+        let model = self.model(params.model.as_deref())?;
+        let function = self
+            .registry
+            .create(&params.name)
+            .map_err(|e| (JsonRpcError::INVALID_PARAMS, e))?;
+        let input = match params.range {
+            Some(range) => range.select(&params.text),
+            None => params.text,
+        };
+        if self.client.is_none() {
+            self.client = Some((self.new_client)().map_err(internal)?);
+        }
+        let client = self.client.as_mut().unwrap();
+        let mut function = Streaming {
+            inner: function,
+            connection,
+            id: id.clone(),
+            cancelled: false,
+        };
+        let mut output = BufferOutput::new();
+        let result = exec_with_function(
+            client,
+            model,
+            UserInput::new(input),
+            &mut function,
+            // mcp tools are not given to editor requests
+            &mut Tools::new(),
+            &mut output,
+        );
+        if function.cancelled {
+            return Err(cancelled());
+        }
+        let summary = result.map_err(internal)?;
+        Ok(json!({ "content": output.content(), "summary": summary }))
+    }
+    fn model(&self, model: Option<&str>) -> Result<OpenAIModel, (i64, String)> {
+        model
+            .map_or(Ok(self.model), OpenAIModel::from_str)
+            .map_err(|e| (JsonRpcError::INVALID_PARAMS, e))
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|e| {
+        (
+            JsonRpcError::INVALID_PARAMS,
+            format!("invalid params : {}", e),
+        )
+    })
+}
+
+fn cancelled() -> (i64, String) {
+    (
+        JsonRpcError::REQUEST_CANCELLED,
+        "request is cancelled".to_string(),
+    )
+}
+
+fn session_not_found(session_id: &str) -> (i64, String) {
+    (
+        JsonRpcError::INVALID_PARAMS,
+        format!("session {} is not found", session_id),
+    )
+}
+
+fn internal(e: TermAIError) -> (i64, String) {
+    (JsonRpcError::INTERNAL_ERROR, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Sender};
+
+    use crate::gpt::client::{GptClientOption, OpenAIKey};
+
+    use super::*;
+    fn server() -> RpcServer<impl FnMut() -> Result<GptClient, TermAIError>> {
+        RpcServer::new(
+            OpenAIModel::Gpt4o,
+            FunctionRegistry::with_builtins(),
+            || {
+                Ok(GptClient::new(
+                    OpenAIKey::new("test"),
+                    GptClientOption::default(),
+                )?)
+            },
+        )
+    }
+    fn connection() -> (Sender<String>, Connection<Vec<u8>>) {
+        let (sender, receiver) = channel();
+        (sender, Connection::new(receiver, Vec::new()))
+    }
+    fn request(id: u64, method: &str, params: Value) -> String {
+        serde_json::to_string(&JsonRpcMessage::request(id, method, params)).unwrap()
+    }
+    fn responses(connection: Connection<Vec<u8>>) -> Vec<JsonRpcMessage> {
+        String::from_utf8(connection.writer)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+    #[test]
+    fn セッションを開始して履歴を取得し閉じる() {
+        let (sender, mut connection) = connection();
+        for line in [
+            request(1, "session/start", json!({"system": "be kind"})),
+            request(2, "session/history", json!({"session_id": "1"})),
+            request(3, "session/close", json!({"session_id": "1"})),
+            request(
+                4,
+                "session/send",
+                json!({"session_id": "1", "message": "hi"}),
+            ),
+            request(5, "session/start", json!({"model": "gpt-unknown"})),
+        ] {
+            sender.send(line).unwrap();
+        }
+        drop(sender);
+
+        server().serve(&mut connection);
+
+        let responses = responses(connection);
+        assert_eq!(responses[0].result, Some(json!({"session_id": "1"})));
+        assert_eq!(responses[1].result, Some(json!({"messages": []})));
+        assert_eq!(responses[2].result, Some(json!({})));
+        assert_eq!(
+            responses[3].error.as_ref().unwrap().message,
+            "session 1 is not found"
+        );
+        assert_eq!(
+            responses[4].error.as_ref().unwrap().code,
+            JsonRpcError::INVALID_PARAMS
+        );
+    }
+    #[test]
+    fn ストリーム中に届いたキャンセルを見つけて他のリクエストは後で処理する() {
+        let (sender, mut connection) = connection();
+        sender
+            .send(request(3, "session/close", json!({"session_id": "1"})))
+            .unwrap();
+        sender
+            .send(r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":2}}"#.to_string())
+            .unwrap();
+        sender
+            .send(r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":4}}"#.to_string())
+            .unwrap();
+        sender
+            .send(request(
+                4,
+                "function/run",
+                json!({"name": "ask", "text": "hi"}),
+            ))
+            .unwrap();
+        drop(sender);
+
+        let cancelled = connection.is_cancelled(&json!(2));
+        let not_cancelled = connection.is_cancelled(&json!(3));
+        server().serve(&mut connection);
+
+        let responses = responses(connection);
+        assert!(cancelled);
+        assert!(!not_cancelled);
+        assert_eq!(
+            responses[0].error.as_ref().unwrap().message,
+            "session 1 is not found"
+        );
+        assert_eq!(
+            responses[1].error.as_ref().unwrap().code,
+            JsonRpcError::REQUEST_CANCELLED
+        );
+    }
+    #[test]
+    fn キャンセルされた関数は最後の処理をしない() {
+        struct Failing;
+        impl GptFunction for Failing {
+            fn action_at_end(&mut self) -> Result<(), Box<dyn std::error::Error + 'static>> {
+                Err("must not be called".into())
+            }
+        }
+        let (_sender, mut connection) = connection();
+        let mut sut = Streaming {
+            inner: Box::new(Failing),
+            connection: &mut connection,
+            id: json!(1),
+            cancelled: true,
+        };
+
+        assert!(sut.action_at_end().is_ok());
+        sut.cancelled = false;
+        assert!(sut.action_at_end().is_err());
+    }
+    #[test]
+    fn 範囲の行だけを関数に渡す() {
+        let text = "fn a() {}\nfn b() {}\nfn c() {}\n";
+
+        assert_eq!(LineRange { start: 1, end: 2 }.select(text), "fn b() {}\n");
+        assert_eq!(
+            LineRange { start: 1, end: 10 }.select(text),
+            "fn b() {}\nfn c() {}\n"
+        );
+        assert_eq!(LineRange { start: 2, end: 1 }.select(text), "");
+    }
+}
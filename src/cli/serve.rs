@@ -0,0 +1,743 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    functions::{GptFunction, UserInput},
+    gpt::{
+        client::{
+            ChatRequest, ChatResponse, CompletionMeta, GptClient, GptClientError, HandleResult,
+            Message, OpenAIModel, Role, Usage,
+        },
+        tool::{ToolCallAccumulator, ToolDefinition},
+    },
+};
+
+use super::error::TermAIError;
+
+// body of /v1/chat/completions. model is the name of OpenAI e.g. gpt-4o
+#[derive(Debug, Clone, serde::Deserialize)]
+struct ProxyRequest {
+    model: String,
+    messages: Vec<Message>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    #[serde(default)]
+    tools: Vec<ToolDefinition>,
+    // the others are passed through. e.g. max_tokens, response_format or tool_choice
+    #[serde(flatten)]
+    params: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: String,
+}
+
+// where the proxy sends requests. GptClient, or a fake in tests
+pub(crate) trait Upstream {
+    fn send(
+        &mut self,
+        request: ChatRequest,
+        f: &mut dyn FnMut(&ChatResponse) -> HandleResult,
+    ) -> Result<CompletionMeta, GptClientError>;
+}
+
+impl Upstream for GptClient {
+    // same retry as one-shot commands, but only while nothing is sent to the client
+    fn send(
+        &mut self,
+        request: ChatRequest,
+        f: &mut dyn FnMut(&ChatResponse) -> HandleResult,
+    ) -> Result<CompletionMeta, GptClientError> {
+        let mut responded = false;
+        let mut result = self.request_mut_fn(request.clone(), |res| {
+            responded = true;
+            f(res)
+        });
+        for _ in 0..2 {
+            let error = match result.as_ref() {
+                Err(e) if !responded && e.is_retryable() => e.clone(),
+                _ => break,
+            };
+            self.re_connect()?;
+            sleep(
+                error
+                    .retry_after()
+                    .unwrap_or(Duration::from_secs(1))
+                    .min(Duration::from_secs(60)),
+            );
+            result = self.request_mut_fn(request.clone(), |res| {
+                responded = true;
+                f(res)
+            });
+        }
+        result.map(|_| self.last_completion().clone())
+    }
+}
+
+// a client which stops reading or writing is dropped after this
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// each connection is handled on its own thread by the proxy made for it,
+// so a slow client or request does not block the others
+pub(crate) fn serve<U, F>(listener: TcpListener, new_proxy: F) -> std::io::Result<()>
+where
+    U: Upstream,
+    F: Fn() -> Result<Proxy<U>, TermAIError> + Sync,
+{
+    let stats = Arc::new(Mutex::new(ProxyStats::default()));
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let (stats, new_proxy) = (stats.clone(), &new_proxy);
+            scope.spawn(move || {
+                // a broken connection must not stop the server
+                if let Err(e) = handle_connection(stream, new_proxy, stats) {
+                    eprintln!("[serve] {}", e);
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+fn handle_connection<U: Upstream>(
+    mut stream: TcpStream,
+    new_proxy: &impl Fn() -> Result<Proxy<U>, TermAIError>,
+    stats: Arc<Mutex<ProxyStats>>,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let reader = BufReader::new(stream.try_clone()?);
+    match new_proxy() {
+        Ok(mut proxy) => {
+            proxy.stats = stats;
+            proxy.handle(reader, stream)
+        }
+        Err(e) => write_error(&mut stream, status_of(&e), &e.to_string()),
+    }
+}
+
+// totals of every connection
+#[derive(Debug, Default)]
+pub(crate) struct ProxyStats {
+    requests: usize,
+    usage: Usage,
+}
+
+// OpenAI compatible gateway
+pub(crate) struct Proxy<U> {
+    upstream: U,
+    // changes each request and sees its response, which is sent as it is.
+    // e.g. translate:ja asks to translate the last user message
+    function: Option<Box<dyn GptFunction>>,
+    stats: Arc<Mutex<ProxyStats>>,
+}
+
+impl<U: Upstream> Proxy<U> {
+    const MAX_BODY: usize = 10 * 1024 * 1024;
+    pub(crate) fn new(upstream: U, function: Option<Box<dyn GptFunction>>) -> Self {
+        Self {
+            upstream,
+            function,
+            stats: Arc::default(),
+        }
+    }
+    pub(crate) fn handle(
+        &mut self,
+        reader: impl BufRead,
+        mut writer: impl Write,
+    ) -> std::io::Result<()> {
+        let request = match read_request(reader) {
+            Ok(request) => request,
+            Err(e) => return write_error(&mut writer, 400, &e.to_string()),
+        };
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/v1/chat/completions") => self.chat_completions(&request.body, writer),
+            (method, path) => write_error(
+                &mut writer,
+                404,
+                &format!("{} {} is not found", method, path),
+            ),
+        }
+    }
+    fn chat_completions(&mut self, body: &str, mut writer: impl Write) -> std::io::Result<()> {
+        let request = serde_json::from_str::<ProxyRequest>(body)
+            .map_err(|e| format!("invalid request : {}", e))
+            .map(|request| self.chat_request(request));
+        let (request, stream) = match request {
+            Ok(request) => request,
+            Err(e) => return write_error(&mut writer, 400, &e),
+        };
+        let count = {
+            let mut stats = self.stats.lock().unwrap();
+            stats.requests += 1;
+            stats.requests
+        };
+        let completion = Completion::new(count, request.model_name());
+        let result = if stream {
+            self.stream(request, &completion, &mut writer)
+        } else {
+            self.complete(request, &completion, &mut writer)
+        };
+        // the answer is already sent, so a failed end action is only reported
+        if let Some(Err(e)) = self.function.as_mut().map(|f| f.action_at_end()) {
+            eprintln!("[serve] function error: {}", e);
+        }
+        result
+    }
+    // hooks of the function change the last user message and the request.
+    // a model unknown to termai is sent as it is
+    fn chat_request(&mut self, request: ProxyRequest) -> (ChatRequest, bool) {
+        let mut messages = request.messages;
+        if let Some(f) = self.function.as_mut() {
+            let last_user = messages
+                .iter()
+                .rposition(|message| message.role() == Role::User);
+            let input = last_user
+                .map(|i| messages[i].content.clone())
+                .unwrap_or_default();
+            f.setup_for_action(&UserInput::new(input.clone()));
+            if let (Some(i), true) = (last_user, f.can_action()) {
+                messages.splice(i..=i, f.input_to_messages(UserInput::new(input)));
+            }
+        }
+        let mut chat_request = ChatRequest::new(OpenAIModel::default(), messages);
+        chat_request.set_model_name(&request.model);
+        for (key, value) in request.params {
+            chat_request.set_param(key, value);
+        }
+        if let Some(temperature) = request.temperature {
+            chat_request.set_temperature(temperature);
+        }
+        if let Some(top_p) = request.top_p {
+            chat_request.set_top_p(top_p);
+        }
+        chat_request.set_tools(request.tools);
+        if let Some(f) = self.function.as_ref() {
+            f.change_request(&mut chat_request);
+        }
+        (chat_request, request.stream)
+    }
+    fn complete(
+        &mut self,
+        request: ChatRequest,
+        completion: &Completion,
+        writer: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let mut content = String::new();
+        let mut calls = ToolCallAccumulator::new();
+        let function = &mut self.function;
+        let result = self.upstream.send(request, &mut |res| {
+            calls.push(res);
+            content.push_str(res.delta_content());
+            hook(function, res)
+        });
+        match result {
+            Ok(meta) => {
+                self.account(completion, &meta);
+                let mut message = json!({ "role": "assistant", "content": content });
+                if !calls.is_empty() {
+                    message["tool_calls"] = json!(calls.into_calls());
+                }
+                write_json(
+                    writer,
+                    200,
+                    &json!({
+                        "id": completion.id,
+                        "object": "chat.completion",
+                        "created": completion.created,
+                        "model": completion.model(&meta),
+                        "choices": [{
+                            "index": 0,
+                            "message": message,
+                            "finish_reason": meta.finish_reason
+                        }],
+                        "usage": meta.usage
+                    }),
+                )
+            }
+            Err(e) => {
+                let e = TermAIError::from(e);
+                eprintln!("[serve] {}", e);
+                write_error(writer, status_of(&e), &e.to_string())
+            }
+        }
+    }
+    // deltas are sent as soon as they arrive. the last chunk has finish reason and usage
+    fn stream(
+        &mut self,
+        request: ChatRequest,
+        completion: &Completion,
+        writer: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let mut started = false;
+        let mut disconnected = None;
+        let function = &mut self.function;
+        let result = self.upstream.send(request, &mut |res| {
+            let result = hook(function, res);
+            let mut delta = match res {
+                ChatResponse::DeltaContent(content) if !content.is_empty() => {
+                    json!({ "content": content })
+                }
+                ChatResponse::ToolCallDelta(deltas) => json!({ "tool_calls": deltas }),
+                _ => return result,
+            };
+            let written = if started {
+                Ok(())
+            } else {
+                started = true;
+                delta["role"] = json!("assistant");
+                write_stream_header(writer)
+            }
+            .and_then(|_| write_event(writer, &completion.chunk(delta, &Value::Null)));
+            // the client is gone
+            if let Err(e) = written {
+                disconnected = Some(e);
+                return HandleResult::Done;
+            }
+            result
+        });
+        if let Some(e) = disconnected {
+            return Err(e);
+        }
+        match result {
+            Ok(meta) => {
+                self.account(completion, &meta);
+                if !started {
+                    write_stream_header(writer)?;
+                }
+                let mut last = completion.chunk(json!({}), &json!(meta.finish_reason));
+                last["usage"] = json!(meta.usage);
+                last["model"] = json!(completion.model(&meta));
+                write_event(writer, &last)?;
+                write!(writer, "data: [DONE]\n\n")?;
+                writer.flush()
+            }
+            Err(e) => {
+                let e = TermAIError::from(e);
+                eprintln!("[serve] {}", e);
+                if !started {
+                    return write_error(writer, status_of(&e), &e.to_string());
+                }
+                write_event(writer, &error_body(status_of(&e), &e.to_string()))?;
+                writer.flush()
+            }
+        }
+    }
+    fn account(&mut self, completion: &Completion, meta: &CompletionMeta) {
+        let mut stats = self.stats.lock().unwrap();
+        if let Some(usage) = meta.usage {
+            stats.usage = stats.usage.add(&usage);
+        }
+        eprintln!(
+            "[serve] {} {} tokens {} (total {})",
+            completion.id,
+            completion.model(meta),
+            meta.usage
+                .map(|usage| usage.total_tokens)
+                .unwrap_or_default(),
+            stats.usage.total_tokens
+        );
+    }
+}
+
+fn hook(function: &mut Option<Box<dyn GptFunction>>, res: &ChatResponse) -> HandleResult {
+    match function {
+        Some(f) => f.handle_stream(res),
+        None => HandleResult::from(res),
+    }
+}
+
+struct Completion {
+    id: String,
+    created: u64,
+    model: String,
+}
+impl Completion {
+    fn new(count: usize, model: &str) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            id: format!("chatcmpl-termai-{}-{}", created, count),
+            created,
+            model: model.to_string(),
+        }
+    }
+    // the model which answered, e.g. gpt-4o-2024-08-06
+    fn model(&self, meta: &CompletionMeta) -> String {
+        meta.model.clone().unwrap_or_else(|| self.model.clone())
+    }
+    fn chunk(&self, delta: Value, finish_reason: &Value) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }]
+        })
+    }
+}
+
+fn read_request(mut reader: impl BufRead) -> std::io::Result<HttpRequest> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(invalid(format!("invalid request line {}", line.trim())));
+    };
+    let method = method.to_string();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid content-length {}", value.trim())))?;
+            }
+        }
+    }
+    if content_length > Proxy::<GptClient>::MAX_BODY {
+        return Err(invalid(format!(
+            "body is larger than {} bytes",
+            Proxy::<GptClient>::MAX_BODY
+        )));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(HttpRequest {
+        method,
+        path,
+        body: String::from_utf8(body).map_err(|e| invalid(e.to_string()))?,
+    })
+}
+
+fn status_of(e: &TermAIError) -> u16 {
+    match e.exit_code() {
+        TermAIError::EXIT_AUTH => 401,
+        TermAIError::EXIT_RATE_LIMIT => 429,
+        TermAIError::EXIT_NETWORK => 502,
+        TermAIError::EXIT_INVALID_INPUT => 400,
+        _ => 500,
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        502 => "Bad Gateway",
+        _ => "Internal Server Error",
+    }
+}
+
+// same shape as errors of OpenAI
+fn error_body(status: u16, message: &str) -> Value {
+    let kind = match status {
+        400 | 404 => "invalid_request_error",
+        401 => "authentication_error",
+        429 => "rate_limit_error",
+        _ => "api_error",
+    };
+    json!({ "error": { "message": message, "type": kind } })
+}
+
+fn write_error(writer: &mut impl Write, status: u16, message: &str) -> std::io::Result<()> {
+    write_json(writer, status, &error_body(status, message))
+}
+
+fn write_json(writer: &mut impl Write, status: u16, body: &Value) -> std::io::Result<()> {
+    let body = body.to_string();
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason_phrase(status),
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+// the body ends when the connection is closed
+fn write_stream_header(writer: &mut impl Write) -> std::io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )
+}
+
+fn write_event(writer: &mut impl Write, data: &Value) -> std::io::Result<()> {
+    write!(writer, "data: {}\n\n", data)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::gpt::client::{GptClientErrorKind, Message};
+
+    use super::*;
+    struct FakeUpstream {
+        deltas: Vec<&'static str>,
+        error: Option<GptClientError>,
+        requests: Rc<RefCell<Vec<ChatRequest>>>,
+    }
+    impl FakeUpstream {
+        fn new(deltas: Vec<&'static str>) -> Self {
+            Self {
+                deltas,
+                error: None,
+                requests: Rc::new(RefCell::new(Vec::new())),
+            }
+        }
+    }
+    impl Upstream for FakeUpstream {
+        fn send(
+            &mut self,
+            request: ChatRequest,
+            f: &mut dyn FnMut(&ChatResponse) -> HandleResult,
+        ) -> Result<CompletionMeta, GptClientError> {
+            self.requests.borrow_mut().push(request);
+            if let Some(e) = self.error.clone() {
+                return Err(e);
+            }
+            for delta in &self.deltas {
+                f(&ChatResponse::from(*delta));
+            }
+            f(&ChatResponse::Done);
+            Ok(CompletionMeta {
+                model: Some("gpt-4o-2024-08-06".to_string()),
+                finish_reason: Some("stop".to_string()),
+                usage: Some(Usage {
+                    prompt_tokens: 3,
+                    completion_tokens: 2,
+                    total_tokens: 5,
+                }),
+            })
+        }
+    }
+    fn post(body: Value) -> String {
+        let body = body.to_string();
+        format!(
+            "POST /v1/chat/completions HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+    fn send<U: Upstream>(proxy: &mut Proxy<U>, request: &str) -> (String, String) {
+        let mut output = Vec::new();
+        proxy.handle(request.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+    #[test]
+    fn ストリームしないリクエストにはまとめた回答と使用量を返す() {
+        let mut sut = Proxy::new(FakeUpstream::new(vec!["Hello", " world"]), None);
+
+        let (status, body) = send(
+            &mut sut,
+            &post(
+                json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}], "temperature": 0.5}),
+            ),
+        );
+        send(
+            &mut sut,
+            &post(json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]})),
+        );
+
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["object"], "chat.completion");
+        assert_eq!(body["model"], "gpt-4o-2024-08-06");
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello world");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        assert_eq!(body["usage"]["total_tokens"], 5);
+        assert_eq!(sut.stats.lock().unwrap().usage.total_tokens, 10);
+    }
+    #[test]
+    fn ストリームするリクエストにはsseでチャンクを返す() {
+        let mut sut = Proxy::new(FakeUpstream::new(vec!["Hello", " world"]), None);
+
+        let (status, body) = send(
+            &mut sut,
+            &post(
+                json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}], "stream": true}),
+            ),
+        );
+
+        let events = body
+            .split("\n\n")
+            .filter_map(|event| event.strip_prefix("data: "))
+            .collect::<Vec<_>>();
+        let chunks = events[..3]
+            .iter()
+            .map(|event| serde_json::from_str::<Value>(event).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            chunks[0]["choices"][0]["delta"],
+            json!({"role": "assistant", "content": "Hello"})
+        );
+        assert_eq!(
+            chunks[1]["choices"][0]["delta"],
+            json!({"content": " world"})
+        );
+        assert_eq!(chunks[2]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks[2]["usage"]["total_tokens"], 5);
+        assert_eq!(events[3], "[DONE]");
+    }
+    #[test]
+    fn 関数のフックでリクエストを変えて回答を受け取る() {
+        struct Prefix {
+            received: Rc<RefCell<String>>,
+        }
+        impl GptFunction for Prefix {
+            fn can_action(&self) -> bool {
+                true
+            }
+            fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
+                vec![Message::new(
+                    Role::User,
+                    format!("translate: {}", input.content()),
+                )]
+            }
+            fn handle_stream(&mut self, response: &ChatResponse) -> HandleResult {
+                self.received
+                    .borrow_mut()
+                    .push_str(response.delta_content());
+                HandleResult::from(response)
+            }
+        }
+        let received = Rc::new(RefCell::new(String::new()));
+        let upstream = FakeUpstream::new(vec!["こんにちは"]);
+        let requests = upstream.requests.clone();
+        let mut sut = Proxy::new(
+            upstream,
+            Some(Box::new(Prefix {
+                received: received.clone(),
+            })),
+        );
+
+        send(
+            &mut sut,
+            &post(json!({"model": "gpt-4o", "messages": [
+                {"role": "system", "content": "be kind"},
+                {"role": "user", "content": "hello"}
+            ]})),
+        );
+
+        assert_eq!(
+            requests.borrow()[0].messages(),
+            &[
+                Message::new(Role::System, "be kind"),
+                Message::new(Role::User, "translate: hello")
+            ]
+        );
+        assert_eq!(*received.borrow(), "こんにちは");
+    }
+    #[test]
+    fn 知らないモデルと使わないパラメータはそのまま上流に送る() {
+        let upstream = FakeUpstream::new(vec!["{}"]);
+        let requests = upstream.requests.clone();
+        let mut sut = Proxy::new(upstream, None);
+
+        send(
+            &mut sut,
+            &post(json!({
+                "model": "gpt-4.1-mini",
+                "messages": [{"role": "user", "content": "hi"}],
+                "max_tokens": 10,
+                "response_format": {"type": "json_object"},
+                "tool_choice": "none",
+                "stream_options": {"include_usage": false}
+            })),
+        );
+
+        let request = serde_json::to_value(&requests.borrow()[0]).unwrap();
+        assert_eq!(request["model"], "gpt-4.1-mini");
+        assert_eq!(request["max_tokens"], 10);
+        assert_eq!(request["response_format"], json!({"type": "json_object"}));
+        assert_eq!(request["tool_choice"], "none");
+        assert_eq!(request["stream_options"], json!({"include_usage": true}));
+    }
+    #[test]
+    fn 接続ごとのスレッドで処理し止まった接続を待たない() {
+        use std::io::Read;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            serve(listener, || {
+                Ok(Proxy::new(FakeUpstream::new(vec!["hi"]), None))
+            })
+        });
+
+        // sends nothing
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(
+                post(json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]}))
+                    .as_bytes(),
+            )
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+    #[test]
+    fn 不正なリクエストと上流のエラーはopenaiの形式で返す() {
+        let mut upstream = FakeUpstream::new(vec![]);
+        upstream.error = Some(GptClientError::new(
+            "429".to_string(),
+            GptClientErrorKind::RateLimited("429".to_string()),
+        ));
+        let mut sut = Proxy::new(upstream, None);
+
+        let (invalid, body) = send(&mut sut, &post(json!({"model": "gpt-4o"})));
+        let (not_found, _) = send(&mut sut, "GET /v1/models HTTP/1.1\r\n\r\n");
+        let (rate_limited, _) = send(
+            &mut sut,
+            &post(
+                json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}], "stream": true}),
+            ),
+        );
+
+        assert_eq!(invalid, "HTTP/1.1 400 Bad Request");
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["error"]["type"],
+            "invalid_request_error"
+        );
+        assert_eq!(not_found, "HTTP/1.1 404 Not Found");
+        assert_eq!(rate_limited, "HTTP/1.1 429 Too Many Requests");
+    }
+}
@@ -0,0 +1,271 @@
+use std::{collections::HashMap, fmt::Display, time::Duration};
+
+use crate::{
+    functions::{
+        code_reviewer::options::ReviewOptions, commit_message::CommitOptions,
+        config_function::FunctionDefinition,
+    },
+    gpt::{
+        cache::ResponseCache,
+        key::{expand_home, KeySource},
+    },
+    mcp::client::McpServerConfig,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct Config {
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    functions: Vec<FunctionDefinition>,
+    #[serde(default)]
+    plugins: PluginConfig,
+    #[serde(default)]
+    review: ReviewOptions,
+    #[serde(default)]
+    commit: CommitOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct PluginConfig {
+    dir: String,
+    timeout_secs: u64,
+}
+impl Default for PluginConfig {
+    fn default() -> Self {
+        Self {
+            dir: "~/.termai/plugins".to_string(),
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    enabled: bool,
+    dir: String,
+    ttl_hours: u64,
+    max_size_mb: u64,
+}
+impl Default for CacheConfig {
+    fn default() -> Self {
+        // opt-in, so that an answer is not replayed without asking
+        Self {
+            enabled: false,
+            dir: "~/.termai/cache".to_string(),
+            ttl_hours: 24 * 7,
+            max_size_mb: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct Profile {
+    // resolved lazily when a client is created, so commands which never call gpt never ask to keyring or run key command
+    api_key: Option<KeySource>,
+    proxy: Option<String>,
+    ca_path: Option<String>,
+    // started for every command which calls gpt, and their tools are given to the model
+    #[serde(default)]
+    mcp_servers: HashMap<String, McpServerConfig>,
+}
+
+impl Config {
+    const ENV_PATH: &'static str = "TERMAI_CONFIG";
+    const ENV_PROFILE: &'static str = "TERMAI_PROFILE";
+    pub fn path() -> String {
+        std::env::var(Self::ENV_PATH).unwrap_or_else(|_| expand_home("~/.termai/config.toml"))
+    }
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_from(&Self::path())
+    }
+    pub fn load_from(path: &str) -> Result<Self, ConfigError> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Self::from_toml(&content),
+            // no config file means every value comes from env
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(ConfigError::new(format!("can not read {} : {}", path, e))),
+        }
+    }
+    pub fn from_toml(content: &str) -> Result<Self, ConfigError> {
+        toml::from_str(content).map_err(|e| ConfigError::new(format!("invalid config : {}", e)))
+    }
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, ConfigError> {
+        let name = name
+            .map(|s| s.to_string())
+            .or_else(|| std::env::var(Self::ENV_PROFILE).ok())
+            .or_else(|| self.default_profile.clone());
+        let Some(name) = name else {
+            return Ok(Profile::default());
+        };
+        self.profiles
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| ConfigError::new(format!("profile {} is not found", name)))
+    }
+    pub fn functions(&self) -> &[FunctionDefinition] {
+        &self.functions
+    }
+    pub fn function(&self, name: &str) -> Option<&FunctionDefinition> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+    pub fn plugins_dir(&self) -> String {
+        expand_home(&self.plugins.dir)
+    }
+    // how long termai waits for each response of a plugin
+    pub fn plugin_timeout(&self) -> Duration {
+        Duration::from_secs(self.plugins.timeout_secs)
+    }
+    pub fn review(&self) -> &ReviewOptions {
+        &self.review
+    }
+    pub fn commit(&self) -> &CommitOptions {
+        &self.commit
+    }
+    pub fn cache_enabled(&self) -> bool {
+        self.cache.enabled
+    }
+    // returned even when disabled, so that `termai cache purge` still works
+    pub fn cache(&self) -> ResponseCache {
+        ResponseCache::new(
+            expand_home(&self.cache.dir),
+            Duration::from_secs(self.cache.ttl_hours * 60 * 60),
+            self.cache.max_size_mb * 1024 * 1024,
+        )
+    }
+}
+
+impl Profile {
+    pub fn key_source(&self) -> KeySource {
+        self.api_key.clone().unwrap_or_default()
+    }
+    pub fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+    pub fn ca_path(&self) -> Option<&str> {
+        self.ca_path.as_deref()
+    }
+    pub fn mcp_servers(&self) -> &HashMap<String, McpServerConfig> {
+        &self.mcp_servers
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    message: String,
+}
+impl ConfigError {
+    pub fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::code_reviewer::options::Rubric;
+
+    use super::*;
+    #[test]
+    fn profileごとにkeyの取得元を設定できる() {
+        let config = Config::from_toml(
+            r#"
+            default_profile = "work"
+            [profiles.work]
+            api_key = { command = "pass show openai/work" }
+            proxy = "http://localhost:8080"
+            [profiles.home.api_key]
+            keyring = { service = "termai", account = "home" }
+            "#,
+        )
+        .unwrap();
+
+        let work = config.profile(None).unwrap();
+        assert_eq!(
+            work.key_source(),
+            KeySource::Command("pass show openai/work".to_string())
+        );
+        assert_eq!(work.proxy(), Some("http://localhost:8080"));
+
+        let home = config.profile(Some("home")).unwrap();
+        assert_eq!(
+            home.key_source(),
+            KeySource::Keyring {
+                service: "termai".to_string(),
+                account: "home".to_string()
+            }
+        );
+        assert!(config.profile(Some("none")).is_err());
+    }
+    #[test]
+    fn 設定がなければ環境変数からkeyを取得する() {
+        let config = Config::from_toml("").unwrap();
+        let profile = config.profile(None).unwrap();
+        assert_eq!(
+            profile.key_source(),
+            KeySource::Env("OPENAI_API_KEY".to_string())
+        );
+    }
+    #[test]
+    fn キャッシュの期限と上限サイズを設定できる() {
+        let config = Config::from_toml(
+            r#"
+            [cache]
+            enabled = true
+            dir = "/tmp/termai"
+            ttl_hours = 1
+            max_size_mb = 2
+            "#,
+        )
+        .unwrap();
+        assert!(config.cache_enabled());
+        assert_eq!(
+            config.cache(),
+            ResponseCache::new("/tmp/termai", Duration::from_secs(3600), 2 * 1024 * 1024)
+        );
+        assert!(!Config::from_toml("[cache]\nenabled = false")
+            .unwrap()
+            .cache_enabled());
+        assert!(!Config::from_toml("").unwrap().cache_enabled());
+    }
+    #[test]
+    fn レビューの言語と観点とガイドラインを設定できる() {
+        let config = Config::from_toml(
+            r#"
+            [review]
+            language = "English"
+            rubrics = ["security", "tests"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.review(),
+            &ReviewOptions {
+                language: Some("English".to_string()),
+                rubrics: vec![Rubric::Security, Rubric::Tests],
+                guidelines: ".termai/review.md".to_string(),
+            }
+        );
+    }
+    #[test]
+    fn コミットメッセージの言語を設定できる() {
+        let config = Config::from_toml("[commit]\nlanguage = \"Japanese\"").unwrap();
+        assert_eq!(
+            config.commit(),
+            &CommitOptions {
+                language: "Japanese".to_string(),
+                max_tokens: 3000,
+            }
+        );
+    }
+}
@@ -1,17 +1,45 @@
-use crate::gpt::client::{ChatResponse, HandleResult, Message, Role};
+use std::fmt::Display;
+
+use crate::gpt::client::{ChatRequest, ChatResponse, HandleResult, Message, Role};
 pub mod code_capture;
 pub mod code_reviewer;
-mod common;
+pub mod commit_message;
+pub(crate) mod common;
+pub mod config_function;
+pub mod plugin;
+pub mod registry;
 pub mod repl;
 #[cfg(target_os = "macos")]
 pub mod speaker;
 pub mod translator;
 
 pub trait GptFunction {
+    // used to report which function failed. type name without the path and generics by default
+    fn name(&self) -> String {
+        let name = std::any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name).to_string()
+    }
+    fn description(&self) -> String {
+        String::new()
+    }
     fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
         input.to_messages()
     }
+    // the messages of the previous function in a container.
+    // user messages are transformed, and the others are passed with their roles
+    fn messages_to_messages(&self, messages: Vec<Message>) -> Vec<Message> {
+        messages
+            .into_iter()
+            .flat_map(|message| match message.role() {
+                Role::User => self.input_to_messages(UserInput::new(message.content)),
+                _ => vec![message],
+            })
+            .collect()
+    }
     fn setup_for_action(&mut self, _input: &UserInput) {}
+    // called for each request made from input_to_messages. e.g. system prompt or sampling params
+    fn change_request(&self, _request: &mut ChatRequest) {}
     fn can_action(&self) -> bool {
         false
     }
@@ -21,6 +49,19 @@ pub trait GptFunction {
     fn action_at_end(&mut self) -> Result<(), Box<dyn std::error::Error + 'static>> {
         Ok(())
     }
+    // what the function produced by the last action. e.g. written file paths
+    fn artifacts(&self) -> Vec<FunctionArtifact> {
+        Vec::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FunctionArtifact {
+    CodeFile { path: String },
+    Review { target: String, review: String },
+    TranslatedFile { path: String },
+    OutputFile { path: String },
 }
 
 pub struct GptFunctionContainer {
@@ -36,6 +77,15 @@ impl GptFunctionContainer {
     pub fn add_functions(&mut self, f: Box<dyn GptFunction>) {
         self.functions.push(f);
     }
+    // returns false if no function has the name
+    pub fn remove_functions(&mut self, name: &str) -> bool {
+        let len = self.functions.len();
+        self.functions.retain(|f| f.name() != name);
+        len != self.functions.len()
+    }
+    pub fn names(&self) -> Vec<String> {
+        self.functions.iter().map(|f| f.name()).collect()
+    }
 }
 
 impl Default for GptFunctionContainer {
@@ -50,47 +100,114 @@ impl Default for GptDefaultFunction {
     }
 }
 
+// functions run as a pipeline.
+// every function which can action transforms the messages of the previous one in added order,
+// every function sees the whole stream and every end action runs even if one fails
 impl GptFunction for GptFunctionContainer {
+    fn name(&self) -> String {
+        "container".to_string()
+    }
     fn setup_for_action(&mut self, input: &UserInput) {
         self.functions
             .iter_mut()
             .for_each(|f| f.setup_for_action(input));
     }
-    // only one function can action input to message
+    fn can_action(&self) -> bool {
+        self.functions.iter().any(|f| f.can_action())
+    }
     fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
-        if let Some(action_func) = self
-            .functions
+        let mut transformers = self.functions.iter().filter(|f| f.can_action());
+        let Some(first) = transformers.next() else {
+            return input.to_messages();
+        };
+        transformers.fold(first.input_to_messages(input), |messages, f| {
+            f.messages_to_messages(messages)
+        })
+    }
+    // a nested container passes the messages through its own functions
+    fn messages_to_messages(&self, messages: Vec<Message>) -> Vec<Message> {
+        self.functions
             .iter()
             .filter(|f| f.can_action())
-            .next()
-            .as_ref()
-        {
-            action_func.input_to_messages(input)
-        } else {
-            input.to_messages()
-        }
+            .fold(messages, |messages, f| f.messages_to_messages(messages))
     }
+    fn change_request(&self, request: &mut ChatRequest) {
+        self.functions
+            .iter()
+            .for_each(|f| f.change_request(request));
+    }
+    // Err wins over Done, and Done wins over Progress
     fn handle_stream(&mut self, response: &ChatResponse) -> HandleResult {
         self.functions
             .iter_mut()
-            .fold(HandleResult::Progress, |_acc, f| f.handle_stream(response))
+            .map(|f| f.handle_stream(response))
+            // the stream still ends when every function is removed
+            .fold(HandleResult::from(response), |acc, result| {
+                match (acc, result) {
+                    (HandleResult::Err(e), _) | (_, HandleResult::Err(e)) => HandleResult::Err(e),
+                    (HandleResult::Done, _) | (_, HandleResult::Done) => HandleResult::Done,
+                    _ => HandleResult::Progress,
+                }
+            })
     }
     fn action_at_end(&mut self) -> Result<(), Box<dyn std::error::Error + 'static>> {
-        self.functions
+        let errors = self
+            .functions
             .iter_mut()
-            .fold(Ok(()), |acc, f| acc.and_then(|_| f.action_at_end()))
+            .filter_map(|f| f.action_at_end().err().map(|e| (f.name(), e)))
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(Box::new(FunctionErrors { errors }))
+    }
+    fn artifacts(&self) -> Vec<FunctionArtifact> {
+        self.functions.iter().flat_map(|f| f.artifacts()).collect()
     }
 }
 
+// end actions which failed in a container, with the function names
+#[derive(Debug)]
+pub struct FunctionErrors {
+    errors: Vec<(String, Box<dyn std::error::Error + 'static>)>,
+}
+impl FunctionErrors {
+    pub fn errors(&self) -> &[(String, Box<dyn std::error::Error + 'static>)] {
+        &self.errors
+    }
+}
+impl Display for FunctionErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let errors = self
+            .errors
+            .iter()
+            .map(|(name, e)| format!("{} : {}", name, e))
+            .collect::<Vec<_>>();
+        write!(f, "{}", errors.join("\n"))
+    }
+}
+impl std::error::Error for FunctionErrors {}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        functions::{GptFunction, UserInput},
-        gpt::client::{ChatResponse, HandleResult},
+        functions::{estimate_tokens, GptFunction, UserInput},
+        gpt::client::{
+            ChatResponse, GptClientError, GptClientErrorKind, HandleResult, Message, Role,
+        },
     };
 
     use super::GptFunctionContainer;
 
+    struct TestFunction {
+        result: HandleResult,
+    }
+    impl GptFunction for TestFunction {
+        fn handle_stream(&mut self, _response: &ChatResponse) -> HandleResult {
+            self.result.clone()
+        }
+    }
+
     #[test]
     #[allow(non_snake_case)]
     fn UserInputはGPT_REQUEST_LIMITを超える文字列を分割してMessageに変換する() {
@@ -101,6 +218,12 @@ mod tests {
         assert_eq!(messages[1].content, " hello. world.");
     }
     #[test]
+    fn トークン数はasciiを4文字で1つそれ以外を1文字で1つと見積もる() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(estimate_tokens("日本語"), 3);
+    }
+    #[test]
     #[allow(non_snake_case)]
     fn GptFunctionContainerはGptDefaultFunctionを保持しておりhandle_streamでProgressDoneを正常に判断できる(
     ) {
@@ -113,26 +236,146 @@ mod tests {
     }
     #[test]
     #[allow(non_snake_case)]
-    fn 最後に追加されたhandle_streamの結果が返る() {
-        struct TestFunction {
-            result: HandleResult,
-        }
-        impl GptFunction for TestFunction {
-            fn handle_stream(&mut self, _response: &ChatResponse) -> HandleResult {
-                self.result.clone()
-            }
-        }
+    fn いずれかの関数がDoneを返せばDoneになる() {
         let mut container = GptFunctionContainer::new();
         container.add_functions(Box::new(TestFunction {
-            result: HandleResult::Progress,
+            result: HandleResult::Done,
         }));
         container.add_functions(Box::new(TestFunction {
-            result: HandleResult::Done,
+            result: HandleResult::Progress,
         }));
 
         let progress = container.handle_stream(&ChatResponse::DeltaContent("hello".to_string()));
         assert_eq!(progress, HandleResult::Done);
     }
+    #[test]
+    fn 前の関数のエラーは後の関数の結果で消えない() {
+        let error = GptClientError::new(
+            "broken".to_string(),
+            GptClientErrorKind::FunctionError("broken".to_string()),
+        );
+        let mut container = GptFunctionContainer::new();
+        container.add_functions(Box::new(TestFunction {
+            result: HandleResult::Err(error.clone()),
+        }));
+        container.add_functions(Box::new(TestFunction {
+            result: HandleResult::Done,
+        }));
+
+        let result = container.handle_stream(&ChatResponse::Done);
+        assert_eq!(result, HandleResult::Err(error));
+    }
+    #[test]
+    fn 入力を変換する関数は追加した順に連鎖する() {
+        struct Prefix(&'static str);
+        impl GptFunction for Prefix {
+            fn can_action(&self) -> bool {
+                true
+            }
+            fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
+                input
+                    .to_messages()
+                    .into_iter()
+                    .map(|mut message| {
+                        *message.change_content() = format!("{}{}", self.0, message.content);
+                        message
+                    })
+                    .collect()
+            }
+        }
+        let mut container = GptFunctionContainer::new();
+        container.add_functions(Box::new(Prefix("review:")));
+        container.add_functions(Box::new(Prefix("translate:")));
+
+        let messages = container.input_to_messages(UserInput::new("hi"));
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "translate:review:hi");
+    }
+    #[test]
+    fn 前の関数が作ったユーザ以外のメッセージは役割を保ったまま渡す() {
+        struct System;
+        impl GptFunction for System {
+            fn can_action(&self) -> bool {
+                true
+            }
+            fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
+                vec![
+                    Message::new(Role::System, "system"),
+                    Message::new(Role::User, input.content()),
+                ]
+            }
+        }
+        struct Upper;
+        impl GptFunction for Upper {
+            fn can_action(&self) -> bool {
+                true
+            }
+            fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
+                vec![Message::new(Role::User, input.content().to_uppercase())]
+            }
+        }
+        let mut container = GptFunctionContainer::new();
+        container.add_functions(Box::new(System));
+        container.add_functions(Box::new(Upper));
+
+        let messages = container.input_to_messages(UserInput::new("hi"));
+
+        assert_eq!(
+            messages,
+            vec![
+                Message::new(Role::System, "system"),
+                Message::new(Role::User, "HI"),
+            ]
+        );
+    }
+    #[test]
+    fn 全ての終了処理を実行し失敗を関数名と共に報告する() {
+        struct End {
+            name: &'static str,
+            fail: bool,
+            called: std::rc::Rc<std::cell::Cell<usize>>,
+        }
+        impl GptFunction for End {
+            fn name(&self) -> String {
+                self.name.to_string()
+            }
+            fn action_at_end(&mut self) -> Result<(), Box<dyn std::error::Error + 'static>> {
+                self.called.set(self.called.get() + 1);
+                if self.fail {
+                    return Err("failed".into());
+                }
+                Ok(())
+            }
+        }
+        let called = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut container = GptFunctionContainer::new();
+        for (name, fail) in [("first", true), ("second", false), ("third", true)] {
+            container.add_functions(Box::new(End {
+                name,
+                fail,
+                called: called.clone(),
+            }));
+        }
+
+        let error = container.action_at_end().unwrap_err();
+
+        assert_eq!(called.get(), 3);
+        assert_eq!(error.to_string(), "first : failed\nthird : failed");
+    }
+    #[test]
+    fn 関数名は型名になる() {
+        let function = TestFunction {
+            result: HandleResult::Progress,
+        };
+        assert_eq!(function.name(), "TestFunction");
+        struct Generic<T>(std::marker::PhantomData<T>);
+        impl<T> GptFunction for Generic<T> {}
+        assert_eq!(
+            Generic::<std::string::String>(std::marker::PhantomData).name(),
+            "Generic"
+        );
+    }
 }
 
 pub struct GptDefaultFunction {}
@@ -144,6 +387,12 @@ impl GptDefaultFunction {
 }
 
 impl GptFunction for GptDefaultFunction {
+    fn name(&self) -> String {
+        "ask".to_string()
+    }
+    fn description(&self) -> String {
+        "send the input as it is".to_string()
+    }
     fn can_action(&self) -> bool {
         true
     }
@@ -153,6 +402,15 @@ impl GptFunction for GptDefaultFunction {
 const GPT_REQUEST_LIMIT: usize = 4096;
 #[cfg(test)]
 const GPT_REQUEST_LIMIT: usize = 15;
+
+// rough count of tokens without a tokenizer. ascii is about 4 chars a token,
+// and other chars like japanese are about a token each
+pub fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.chars().filter(|c| c.is_ascii()).count();
+    let others = text.chars().count() - ascii;
+    ascii.div_ceil(4) + others
+}
+
 #[derive(Debug, Clone)]
 pub struct UserInput(String);
 impl UserInput {
@@ -1,11 +1,12 @@
 use std::io::Write;
 
-use super::GptFunction;
+use super::{FunctionArtifact, GptFunction};
 
 use rand::Rng;
 pub struct SampleFileWriter<R: RandGenerator> {
     root_dir: String,
     rand: R,
+    written: Vec<String>,
 }
 
 impl<R: RandGenerator> SampleFileWriter<R> {
@@ -14,6 +15,7 @@ impl<R: RandGenerator> SampleFileWriter<R> {
         Self {
             root_dir: root_dir.to_string(),
             rand,
+            written: Vec::new(),
         }
     }
     fn make_filepath(&mut self, code: &Code) -> String {
@@ -29,22 +31,31 @@ impl<R: RandGenerator> SampleFileWriter<R> {
 
 impl<R: RandGenerator> CodeWriter for SampleFileWriter<R> {
     fn write_all(&mut self, codes: Vec<Code>) -> Result<(), std::io::Error> {
+        self.written.clear();
         codes
             .iter()
             .map(|code| {
                 let filepath = self.make_filepath(code);
-                let mut file = std::fs::File::create(filepath)?;
-                file.write_all(code.as_bytes())
+                let mut file = std::fs::File::create(&filepath)?;
+                file.write_all(code.as_bytes())?;
+                self.written.push(filepath);
+                Ok(())
             })
             .fold(Ok(()), |acc, result| match acc {
                 Ok(_) => result,
                 Err(e) => Err(e),
             })
     }
+    fn written_paths(&self) -> Vec<String> {
+        self.written.clone()
+    }
 }
 
 pub trait CodeWriter {
     fn write_all(&mut self, code: Vec<Code>) -> Result<(), std::io::Error>;
+    fn written_paths(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
 #[derive(Debug, Clone)]
 pub struct GptCodeCapture<W: CodeWriter> {
@@ -69,6 +80,12 @@ impl GptCodeCapture<SampleFileWriter<DefaultRandGenerator>> {
     }
 }
 impl<W: CodeWriter> GptFunction for GptCodeCapture<W> {
+    fn name(&self) -> String {
+        "capture".to_string()
+    }
+    fn description(&self) -> String {
+        "write code blocks of the answer to sample files".to_string()
+    }
     fn can_action(&self) -> bool {
         true
     }
@@ -81,6 +98,9 @@ impl<W: CodeWriter> GptFunction for GptCodeCapture<W> {
                 self.inner.add(content);
                 crate::gpt::client::HandleResult::Progress
             }
+            crate::gpt::client::ChatResponse::ToolCallDelta(_) => {
+                crate::gpt::client::HandleResult::Progress
+            }
             crate::gpt::client::ChatResponse::Done => crate::gpt::client::HandleResult::Done,
         }
     }
@@ -89,6 +109,13 @@ impl<W: CodeWriter> GptFunction for GptCodeCapture<W> {
             .write_all(self.inner.get_codes())
             .map_err(|e| e.into())
     }
+    fn artifacts(&self) -> Vec<FunctionArtifact> {
+        self.writer
+            .written_paths()
+            .into_iter()
+            .map(|path| FunctionArtifact::CodeFile { path })
+            .collect()
+    }
 }
 #[derive(Debug, Clone)]
 pub struct CodeCapture {
@@ -157,6 +184,13 @@ impl Code {
     pub fn as_bytes(&self) -> &[u8] {
         self.code.as_bytes()
     }
+    pub fn as_str(&self) -> &str {
+        &self.code
+    }
+    // name in the code block. empty when unknown
+    pub fn lang(&self) -> &str {
+        self.lang.to_str()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -172,6 +206,8 @@ pub enum Lang {
     Haskell,
     Yaml,
     Json,
+    // patches, e.g. suggested fixes of a review
+    Diff,
     Unknown,
 }
 impl Lang {
@@ -188,11 +224,43 @@ impl Lang {
             Self::Bash => "sh",
             Self::Yaml => "yaml",
             Self::Json => "json",
+            Self::Diff => "diff",
             Self::Unknown => "",
         }
     }
-    #[allow(dead_code)]
-    fn to_str(&self) -> &str {
+    // detected by the extension. e.g. src/main.rs -> Rust
+    pub fn from_path(path: &str) -> Self {
+        let extension = std::path::Path::new(path.trim())
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+        match extension {
+            "" => Self::Unknown,
+            "yml" => Self::Yaml,
+            "bash" => Self::Bash,
+            "tsx" => Self::TypeScript,
+            "jsx" | "mjs" => Self::JavaScript,
+            "patch" => Self::Diff,
+            _ => [
+                Self::Rust,
+                Self::Python,
+                Self::Go,
+                Self::Java,
+                Self::JavaScript,
+                Self::TypeScript,
+                Self::Ruby,
+                Self::Bash,
+                Self::Haskell,
+                Self::Yaml,
+                Self::Json,
+                Self::Diff,
+            ]
+            .into_iter()
+            .find(|lang| lang.to_extend() == extension)
+            .unwrap_or(Self::Unknown),
+        }
+    }
+    pub fn to_str(&self) -> &str {
         match self {
             Self::Rust => "rust",
             Self::Python => "python",
@@ -205,6 +273,7 @@ impl Lang {
             Self::Bash => "bash",
             Self::Yaml => "yaml",
             Self::Json => "json",
+            Self::Diff => "diff",
             Self::Unknown => "",
         }
     }
@@ -221,6 +290,7 @@ impl Lang {
             "sh" => Self::Bash,
             "yaml" => Self::Yaml,
             "json" => Self::Json,
+            "diff" | "patch" => Self::Diff,
             _ => Self::Unknown,
         }
     }
@@ -299,8 +369,21 @@ mod tests {
         ))
         .unwrap();
 
+        let artifacts = function.artifacts();
+
         test_file.remove_dir_all();
         assert_eq!(result, code);
+        assert_eq!(
+            artifacts,
+            vec![FunctionArtifact::CodeFile {
+                path: format!(
+                    "{}/{}{}.rs",
+                    root_dir,
+                    SampleFileWriter::<FakeRand>::PREFIX,
+                    rand
+                )
+            }]
+        );
     }
     #[test]
     fn gptのレスポンス終了時にcodeが存在していればwriterを利用して書き込みを行う() {
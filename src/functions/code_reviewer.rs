@@ -1,15 +1,130 @@
-use crate::gpt::client::Message;
+pub mod diff;
+pub mod finding;
+pub mod options;
+pub mod patch;
+pub mod report;
+pub mod targets;
 
-use super::{common::get_file_content, GptFunction, UserInput};
+use crate::gpt::client::{ChatRequest, ChatResponse, HandleResult, Message};
+
+use super::{
+    code_capture::Lang,
+    common::{get_file_content, is_file_path},
+    FunctionArtifact, GptFunction, UserInput,
+};
+use finding::{number_lines, parse_findings, Finding};
+use options::{ReviewOptions, Rubric};
 #[derive(Debug, Clone)]
 pub struct CodeReviewer {
-    prefix: &'static str,
+    prefix: String,
+    target: String,
+    // language of the code, detected from the file or given by the caller
+    lang: Lang,
+    // language of the review
+    language: Option<String>,
+    rubrics: Vec<Rubric>,
+    guidelines: Option<String>,
+    review: String,
+    // the answer is json findings instead of prose
+    structured: bool,
+    // the input is numbered by the caller, e.g. hunks of a diff
+    numbered: bool,
+    // why the last structured answer was rejected
+    retry_error: Option<String>,
 }
 
 impl CodeReviewer {
     const PREFIX: &'static str = "以下のコードを日本語でレビューしてください";
-    pub fn new(prefix: &'static str) -> Self {
-        Self { prefix }
+    const PROSE_PREFIX: &'static str = "Review the following code.";
+    const STRUCTURED_PREFIX: &'static str =
+        "Review the following code. Each line starts with its line number and `|`.";
+    const DIFF_PREFIX: &'static str = "Review the following changes of git diff. Each line starts with its number and `|`, then `+` for an added line, `-` for a removed line or a space for a context line. Review only the added and removed lines, and use the numbers before `|` as line.";
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            target: String::new(),
+            lang: Lang::Unknown,
+            language: None,
+            rubrics: Vec::new(),
+            guidelines: None,
+            review: String::new(),
+            structured: false,
+            numbered: false,
+            retry_error: None,
+        }
+    }
+    pub fn structured() -> Self {
+        Self {
+            structured: true,
+            ..Self::new(Self::STRUCTURED_PREFIX)
+        }
+    }
+    // input is a chunk of diff::chunks
+    pub fn diff() -> Self {
+        Self {
+            structured: true,
+            numbered: true,
+            ..Self::new(Self::DIFF_PREFIX)
+        }
+    }
+    // the guideline file is read here. a missing file is not an error
+    pub fn with_options(mut self, options: &ReviewOptions) -> Self {
+        if let Some(language) = options.language.as_ref() {
+            self = self.with_language(language);
+        }
+        self.rubrics = options.rubrics.clone();
+        self.guidelines = get_file_content(&options.guidelines)
+            .ok()
+            .filter(|guidelines| !guidelines.trim().is_empty());
+        self
+    }
+    // the default prose prefix asks for Japanese, so it is replaced
+    pub fn with_language(mut self, language: impl Into<String>) -> Self {
+        if self.prefix == Self::PREFIX {
+            self.prefix = Self::PROSE_PREFIX.to_string();
+        }
+        self.language = Some(language.into());
+        self
+    }
+    // for input which is not a file, e.g. a document of an editor or a hunk of a diff
+    pub fn with_lang(mut self, lang: Lang) -> Self {
+        self.lang = lang;
+        self
+    }
+    // what the review should know besides the code. told by the system prompt
+    fn context(&self) -> Vec<String> {
+        let mut context = Vec::new();
+        if self.lang != Lang::Unknown {
+            context.push(format!("The code is written in {}.", self.lang.to_str()));
+        }
+        if let Some(language) = self.language.as_ref() {
+            context.push(format!("Write the review in {}.", language));
+        }
+        if !self.rubrics.is_empty() {
+            context.push(format!(
+                "Focus on these points.\n{}",
+                self.rubrics
+                    .iter()
+                    .map(|rubric| rubric.to_prompt())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
+        if let Some(guidelines) = self.guidelines.as_ref() {
+            context.push(format!(
+                "Follow the guidelines of the project below.\n{}",
+                guidelines.trim_end()
+            ));
+        }
+        context
+    }
+    // findings of the last review. Err tells what is wrong with the answer
+    pub fn findings(&self) -> Result<Vec<Finding>, String> {
+        parse_findings(&self.review, &self.target)
+    }
+    // the next review tells the model why the last answer was rejected
+    pub fn retry(&mut self, error: impl Into<String>) {
+        self.retry_error = Some(error.into());
     }
 }
 
@@ -19,8 +134,62 @@ impl Default for CodeReviewer {
     }
 }
 impl GptFunction for CodeReviewer {
+    fn name(&self) -> String {
+        "review".to_string()
+    }
+    fn description(&self) -> String {
+        "review the code of the input or the file".to_string()
+    }
+    fn setup_for_action(&mut self, input: &UserInput) {
+        self.target = if is_file_path(input.content()) {
+            input.content().trim().to_string()
+        } else {
+            "input".to_string()
+        };
+        if is_file_path(input.content()) {
+            self.lang = Lang::from_path(input.content());
+        }
+        self.review.clear();
+    }
+    fn handle_stream(&mut self, response: &ChatResponse) -> HandleResult {
+        self.review.push_str(response.delta_content());
+        HandleResult::from(response)
+    }
+    fn artifacts(&self) -> Vec<FunctionArtifact> {
+        if self.review.is_empty() {
+            return Vec::new();
+        }
+        vec![FunctionArtifact::Review {
+            target: self.target.clone(),
+            review: self.review.clone(),
+        }]
+    }
+    fn change_request(&self, request: &mut ChatRequest) {
+        let mut system = Vec::new();
+        if self.structured {
+            system.push(format!(
+                "You are a code reviewer. Answer only a JSON array of findings which matches this JSON schema. Answer [] if there is no problem. When the fix is clear, put in fix the code which replaces the lines from line to end_line without line numbers, or a unified diff.\n{}",
+                finding::SCHEMA
+            ));
+            if let Some(error) = self.retry_error.as_ref() {
+                system.push(format!(
+                    "Your last answer was rejected because {}. Answer valid JSON only.",
+                    error
+                ));
+            }
+        }
+        system.extend(self.context());
+        if !system.is_empty() {
+            request.set_system(system.join("\n"));
+        }
+    }
     fn input_to_messages(&self, input: super::UserInput) -> Vec<Message> {
         let content = get_file_content(input.content()).unwrap_or(input.content().to_string());
+        let content = if self.structured && !self.numbered {
+            number_lines(&content)
+        } else {
+            content
+        };
         UserInput::new(content)
             .to_messages()
             .into_iter()
@@ -58,6 +227,85 @@ mod tests {
         );
     }
     #[test]
+    fn レビュー結果を成果物として返す() {
+        let mut code_reviewer = CodeReviewer::default();
+        code_reviewer.setup_for_action(&UserInput::new("fn main() {}"));
+        code_reviewer.handle_stream(&ChatResponse::DeltaContent("good ".to_string()));
+        code_reviewer.handle_stream(&ChatResponse::DeltaContent("code".to_string()));
+        code_reviewer.handle_stream(&ChatResponse::Done);
+
+        assert_eq!(
+            code_reviewer.artifacts(),
+            vec![FunctionArtifact::Review {
+                target: "input".to_string(),
+                review: "good code".to_string()
+            }]
+        );
+    }
+    #[test]
+    fn 構造化レビューは行番号を付けてjsonで答えるよう頼む() {
+        let mut code_reviewer = CodeReviewer::structured();
+        code_reviewer.retry("answer is not json");
+        let messages = code_reviewer.input_to_messages(UserInput::new("fn main() {\n}"));
+        let mut request = ChatRequest::new(Default::default(), messages.clone());
+        code_reviewer.change_request(&mut request);
+
+        assert_eq!(
+            messages,
+            vec![Message::new(
+                Role::User,
+                format!(
+                    "{}\n1|fn main() {{\n2|}}\n",
+                    CodeReviewer::STRUCTURED_PREFIX
+                )
+            )]
+        );
+        let system = &request.messages()[0];
+        assert_eq!(system.role(), Role::System);
+        assert!(system.content.contains(finding::SCHEMA));
+        assert!(system
+            .content
+            .contains("rejected because answer is not json"));
+    }
+    #[test]
+    fn 言語と観点とプロジェクトのガイドラインをシステムプロンプトで伝える() {
+        let test_file = TestFileFactory::create("tmp_review_options");
+        test_file.create_file_under_root("review.md", "Use snake_case.\n");
+        test_file.create_file_under_root("main.py", "print(1)\n");
+        let options = ReviewOptions {
+            language: Some("English".to_string()),
+            rubrics: vec![Rubric::Security, Rubric::Tests],
+            guidelines: "tmp_review_options/review.md".to_string(),
+        };
+        let mut code_reviewer = CodeReviewer::default().with_options(&options);
+        let input = UserInput::new("tmp_review_options/main.py");
+
+        code_reviewer.setup_for_action(&input);
+        let messages = code_reviewer.input_to_messages(input);
+        let mut request = ChatRequest::new(Default::default(), messages.clone());
+        code_reviewer.change_request(&mut request);
+        test_file.remove_dir_all();
+
+        assert_eq!(
+            messages[0].content,
+            format!("{}\nprint(1)\n", CodeReviewer::PROSE_PREFIX)
+        );
+        assert_eq!(
+            request.messages()[0].content,
+            format!(
+                "The code is written in python.\nWrite the review in English.\nFocus on these points.\n{}\n{}\nFollow the guidelines of the project below.\nUse snake_case.",
+                Rubric::Security.to_prompt(),
+                Rubric::Tests.to_prompt()
+            )
+        );
+        assert_eq!(
+            CodeReviewer::default()
+                .with_options(&ReviewOptions::default())
+                .context(),
+            Vec::<String>::new()
+        );
+    }
+    #[test]
     #[ignore]
     fn messageの入力がfile_pathであればcode_reviewerはmessageの内容をコードレビュー依頼に変換する()
     {
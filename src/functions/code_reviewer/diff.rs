@@ -0,0 +1,319 @@
+use crate::functions::estimate_tokens;
+
+use super::finding::Finding;
+
+// a hunk of unified diff. lines keep their marker (' ', '+' or '-')
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub header: String,
+    pub new_start: usize,
+    pub new_count: usize,
+    pub lines: Vec<String>,
+}
+impl Hunk {
+    // new-file line of each line of the hunk. a removed line is at the line which follows it
+    fn new_lines(&self) -> Vec<usize> {
+        let last = (self.new_start + self.new_count).saturating_sub(1).max(1);
+        let mut cursor = self.new_start;
+        self.lines
+            .iter()
+            .map(|line| {
+                let new_line = cursor.clamp(1, last);
+                if !line.starts_with('-') {
+                    cursor += 1;
+                }
+                new_line
+            })
+            .collect()
+    }
+    // tokens in a chunk. a line gets its number and `|`
+    fn size(&self) -> usize {
+        estimate_tokens(&self.header)
+            + 1
+            + self.lines.iter().map(|line| line_size(line)).sum::<usize>()
+    }
+    // a hunk longer than max_tokens is split at lines, and each part has the header.
+    // a part keeps the new-file lines of its lines. a line longer than max_tokens is still a part
+    fn split(&self, max_tokens: usize) -> Vec<Hunk> {
+        if self.size() <= max_tokens {
+            return vec![self.clone()];
+        }
+        let new_lines = self.new_lines();
+        let mut parts = Vec::new();
+        let mut start = 0;
+        let mut size = estimate_tokens(&self.header) + 1;
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > start && size + line_size(line) > max_tokens {
+                parts.push(self.part(&new_lines, start, i));
+                start = i;
+                size = estimate_tokens(&self.header) + 1;
+            }
+            size += line_size(line);
+        }
+        parts.push(self.part(&new_lines, start, self.lines.len()));
+        parts
+    }
+    fn part(&self, new_lines: &[usize], start: usize, end: usize) -> Hunk {
+        Hunk {
+            header: self.header.clone(),
+            new_start: new_lines[start],
+            new_count: new_lines[end - 1] + 1 - new_lines[start],
+            lines: self.lines[start..end].to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDiff {
+    pub path: String,
+    pub hunks: Vec<Hunk>,
+}
+
+// output of `git diff`. deleted and binary files are left out, they have no lines to review
+pub fn parse_diff(diff: &str) -> Vec<FileDiff> {
+    let mut files: Vec<FileDiff> = Vec::new();
+    // lines left in the current hunk, so content like "+++" is not taken as a header
+    let (mut old_left, mut new_left): (usize, usize) = (0, 0);
+    for line in diff.lines() {
+        if old_left > 0 || new_left > 0 {
+            let Some(hunk) = files.last_mut().and_then(|file| file.hunks.last_mut()) else {
+                break;
+            };
+            match line.chars().next() {
+                Some('+') => new_left = new_left.saturating_sub(1),
+                Some('-') => old_left = old_left.saturating_sub(1),
+                Some('\\') => continue,
+                _ => {
+                    old_left = old_left.saturating_sub(1);
+                    new_left = new_left.saturating_sub(1);
+                }
+            }
+            // git may drop the trailing space of an empty context line
+            hunk.lines.push(if line.is_empty() {
+                " ".to_string()
+            } else {
+                line.to_string()
+            });
+            continue;
+        }
+        if line.starts_with("diff --git ") {
+            files.push(FileDiff {
+                path: String::new(),
+                hunks: Vec::new(),
+            });
+        } else if let Some(path) = line.strip_prefix("+++ ") {
+            if files.last().is_none_or(|file| !file.hunks.is_empty()) {
+                files.push(FileDiff {
+                    path: String::new(),
+                    hunks: Vec::new(),
+                });
+            }
+            let path = path.split('\t').next().unwrap_or_default().trim();
+            if let Some(file) = files.last_mut() {
+                file.path = if path == "/dev/null" {
+                    String::new()
+                } else {
+                    path.strip_prefix("b/").unwrap_or(path).to_string()
+                };
+            }
+        } else if line.starts_with("@@ ") {
+            let Some(((_, old_count), (new_start, new_count))) = parse_hunk_header(line) else {
+                continue;
+            };
+            let Some(file) = files.last_mut() else {
+                continue;
+            };
+            old_left = old_count;
+            new_left = new_count;
+            file.hunks.push(Hunk {
+                header: line.to_string(),
+                new_start,
+                new_count,
+                lines: Vec::new(),
+            });
+        }
+    }
+    files
+        .into_iter()
+        .filter(|file| !file.path.is_empty() && !file.hunks.is_empty())
+        .collect()
+}
+
+// @@ -12,7 +12,9 @@ fn main() {
+fn parse_hunk_header(line: &str) -> Option<((usize, usize), (usize, usize))> {
+    let range = |range: &str| -> Option<(usize, usize)> {
+        match range.split_once(',') {
+            Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+    let mut parts = line.trim_start_matches("@@ ").split(' ');
+    let old = range(parts.next()?.strip_prefix('-')?)?;
+    let new = range(parts.next()?.strip_prefix('+')?)?;
+    Some((old, new))
+}
+
+// the text of hunks sent to the model at once, and where its numbered lines are in the new file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffChunk {
+    pub path: String,
+    pub text: String,
+    new_lines: Vec<usize>,
+}
+impl DiffChunk {
+    fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            text: format!("--- {}\n", path),
+            new_lines: Vec::new(),
+        }
+    }
+    fn push(&mut self, hunk: &Hunk) {
+        self.text.push_str(&hunk.header);
+        self.text.push('\n');
+        for (line, new_line) in hunk.lines.iter().zip(hunk.new_lines()) {
+            self.new_lines.push(new_line);
+            self.text
+                .push_str(&format!("{}|{}\n", self.new_lines.len(), line));
+        }
+    }
+    // the model answers the numbers of the chunk. numbers out of the chunk are clamped
+    pub fn new_line(&self, number: usize) -> usize {
+        let index = number.clamp(1, self.new_lines.len().max(1)) - 1;
+        self.new_lines.get(index).copied().unwrap_or(1)
+    }
+    pub fn map(&self, mut finding: Finding) -> Finding {
+        let end_line = finding.end_line.map(|end_line| self.new_line(end_line));
+        finding.line = self.new_line(finding.line);
+        finding.end_line = end_line.filter(|end_line| *end_line > finding.line);
+        finding.file = self.path.clone();
+        finding
+    }
+}
+
+fn line_size(line: &str) -> usize {
+    estimate_tokens(line) + 2
+}
+
+// hunks of a file are reviewed together unless they are longer than max_tokens.
+// a longer hunk is split, so the message is not split in the middle of a hunk
+pub fn chunks(files: &[FileDiff], max_tokens: usize) -> Vec<DiffChunk> {
+    let mut chunks = Vec::new();
+    for file in files {
+        let mut chunk = DiffChunk::new(&file.path);
+        let max_hunk = max_tokens.saturating_sub(estimate_tokens(&chunk.text));
+        for hunk in file.hunks.iter().flat_map(|hunk| hunk.split(max_hunk)) {
+            if !chunk.new_lines.is_empty()
+                && estimate_tokens(&chunk.text) + hunk.size() > max_tokens
+            {
+                chunks.push(chunk);
+                chunk = DiffChunk::new(&file.path);
+            }
+            chunk.push(&hunk);
+        }
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::code_reviewer::finding::{Category, Severity};
+
+    use super::*;
+    const DIFF: &str = "diff --git a/src/main.rs b/src/main.rs
+index 1111111..2222222 100644
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -10,3 +10,4 @@ fn main() {
+     let a = 1;
+-    let b = 2;
++    let b = 3;
++++ c;
+     println!(\"{}\", a);
+@@ -30,2 +31,2 @@ fn sub() {
+-    old();
++    new();
+ }
+diff --git a/old.rs b/old.rs
+deleted file mode 100644
+--- a/old.rs
++++ /dev/null
+@@ -1 +0,0 @@
+-fn old() {}
+diff --git a/logo.png b/logo.png
+Binary files a/logo.png and b/logo.png differ
+";
+    #[test]
+    fn diffをファイルとハンクに分けて削除とバイナリを除く() {
+        let files = parse_diff(DIFF);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "src/main.rs");
+        assert_eq!(files[0].hunks.len(), 2);
+        assert_eq!(files[0].hunks[0].new_start, 10);
+        assert_eq!(files[0].hunks[0].lines[3], "+++ c;");
+        assert_eq!(
+            files[0].hunks[1].lines,
+            vec!["-    old();", "+    new();", " }"]
+        );
+    }
+    #[test]
+    fn チャンクの行番号を新しいファイルの行番号に戻す() {
+        let files = parse_diff(DIFF);
+        let chunks = chunks(&files, 10000);
+        let split = super::chunks(&files, 45);
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(split.len(), 2);
+        assert!(chunks[0]
+            .text
+            .starts_with("--- src/main.rs\n@@ -10,3 +10,4 @@ fn main() {\n1|     let a = 1;\n2|-    let b = 2;\n3|+    let b = 3;\n"));
+        assert_eq!(
+            (1..=9).map(|n| chunks[0].new_line(n)).collect::<Vec<_>>(),
+            vec![10, 11, 11, 12, 13, 31, 31, 32, 32]
+        );
+        let finding = chunks[0].map(Finding {
+            file: "input".to_string(),
+            line: 3,
+            end_line: Some(4),
+            severity: Severity::Warning,
+            category: Category::Bug,
+            message: "b is changed".to_string(),
+            suggestion: None,
+            fix: None,
+        });
+        assert_eq!(
+            finding.to_string(),
+            "src/main.rs:11:1: warning: b is changed [bug]"
+        );
+        assert_eq!(finding.end_line, Some(12));
+    }
+    #[test]
+    fn 長いハンクは行の境目で分けヘッダを繰り返す() {
+        let files = parse_diff(DIFF);
+        let whole = chunks(&files[..1], 10000);
+        let header = "@@ -10,3 +10,4 @@ fn main() {";
+
+        let split = chunks(&files, 20);
+
+        assert!(split.len() > 2);
+        assert!(split
+            .iter()
+            .all(|chunk| estimate_tokens(&chunk.text) <= 20 || chunk.new_lines.len() == 1));
+        assert!(split[0]
+            .text
+            .starts_with(&format!("--- src/main.rs\n{}\n1|", header)));
+        assert!(split[1]
+            .text
+            .starts_with(&format!("--- src/main.rs\n{}\n1|", header)));
+        // the lines of the parts are the same as the lines of the whole hunk
+        assert_eq!(
+            split
+                .iter()
+                .flat_map(|chunk| chunk.new_lines.clone())
+                .collect::<Vec<_>>(),
+            whole[0].new_lines
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde_json::Value;
+
+use crate::functions::code_capture::CodeCapture;
+
+// info < warning < error, so findings can be filtered by --min-severity
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+}
+impl FromStr for Severity {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "info" => Ok(Self::Info),
+            "warning" => Ok(Self::Warning),
+            "error" => Ok(Self::Error),
+            _ => Err(format!("severity {} is not supported", s)),
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Bug,
+    Security,
+    Performance,
+    Readability,
+    Maintainability,
+    Tests,
+    Style,
+    // the model may answer a category not in the schema
+    #[serde(other)]
+    #[default]
+    Other,
+}
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Bug => "bug",
+            Self::Security => "security",
+            Self::Performance => "performance",
+            Self::Readability => "readability",
+            Self::Maintainability => "maintainability",
+            Self::Tests => "tests",
+            Self::Style => "style",
+            Self::Other => "other",
+        }
+    }
+}
+
+// a problem found by the review. lines are 1-based and end_line is inclusive
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Finding {
+    #[serde(default)]
+    pub file: String,
+    pub line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_line: Option<usize>,
+    pub severity: Severity,
+    #[serde(default)]
+    pub category: Category,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<String>,
+    // code which replaces line..=end_line, or a unified diff. applied by cr --fix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+impl Finding {
+    pub fn end_line(&self) -> usize {
+        self.end_line.unwrap_or(self.line)
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.line == 0 {
+            return Err("line must be 1 or more".to_string());
+        }
+        if self.end_line() < self.line {
+            return Err(format!(
+                "end_line {} is before line {}",
+                self.end_line(),
+                self.line
+            ));
+        }
+        if self.message.trim().is_empty() {
+            return Err("message is empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+// compiler style, so editors and CI log parsers can jump to the line
+// src/main.rs:12:1: warning: x is not used [readability]
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:1: {}: {} [{}]",
+            self.file,
+            self.line,
+            self.severity.as_str(),
+            self.message,
+            self.category.as_str()
+        )?;
+        if let Some(suggestion) = self.suggestion.as_ref() {
+            for line in suggestion.lines() {
+                write!(f, "\n    {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// told to the model in the prompt. parse_findings checks the answer by the same rules,
+// but a missing or unknown category is taken as other
+pub const SCHEMA: &str = r#"{"type":"array","items":{"type":"object","properties":{"file":{"type":"string"},"line":{"type":"integer","minimum":1},"end_line":{"type":"integer","minimum":1},"severity":{"enum":["info","warning","error"]},"category":{"enum":["bug","security","performance","readability","maintainability","tests","style","other"]},"message":{"type":"string"},"suggestion":{"type":"string"},"fix":{"type":"string"}},"required":["line","severity","message"]}}"#;
+
+// the answer may be in code blocks, and has one array per request when the input is split.
+// findings without file are of default_file
+pub fn parse_findings(answer: &str, default_file: &str) -> Result<Vec<Finding>, String> {
+    // a fix may have a diff block in it, so the answer is tried as it is first
+    if let Ok(findings) = parse_json(answer, default_file) {
+        return Ok(findings);
+    }
+    let mut capture = CodeCapture::new();
+    capture.add(answer);
+    let codes = capture
+        .get_codes()
+        .into_iter()
+        .filter(|code| code.lang() != "diff")
+        .collect::<Vec<_>>();
+    if codes.is_empty() {
+        return parse_json(answer, default_file);
+    }
+    parse_json(
+        &codes.iter().map(|code| code.as_str()).collect::<String>(),
+        default_file,
+    )
+}
+
+fn parse_json(json: &str, default_file: &str) -> Result<Vec<Finding>, String> {
+    let mut findings = Vec::new();
+    for value in serde_json::Deserializer::from_str(json).into_iter::<Value>() {
+        let value = value.map_err(|e| format!("answer is not json : {}", e))?;
+        let items = match value {
+            Value::Array(items) => items,
+            Value::Object(mut object) => match object.remove("findings") {
+                Some(Value::Array(items)) => items,
+                _ => return Err("answer must be an array of findings".to_string()),
+            },
+            _ => return Err("answer must be an array of findings".to_string()),
+        };
+        for item in items {
+            let index = findings.len();
+            let mut finding = serde_json::from_value::<Finding>(item)
+                .map_err(|e| format!("findings[{}] : {}", index, e))?;
+            finding
+                .validate()
+                .map_err(|e| format!("findings[{}] : {}", index, e))?;
+            if finding.file.is_empty() {
+                finding.file = default_file.to_string();
+            }
+            findings.push(finding);
+        }
+    }
+    Ok(findings)
+}
+
+// the model refers lines by these numbers
+pub fn number_lines(code: &str) -> String {
+    code.lines()
+        .enumerate()
+        .map(|(i, line)| format!("{}|{}\n", i + 1, line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    #[test]
+    fn コードブロックや分割された回答から指摘を取り出す() {
+        let answer = "here\n```json\n[{\"line\": 2, \"severity\": \"warning\", \"category\": \"readability\", \"message\": \"x is not used\", \"suggestion\": \"let _x = 1;\"}]\n```\n```json\n{\"findings\": [{\"file\": \"b.rs\", \"line\": 1, \"end_line\": 3, \"severity\": \"error\", \"category\": \"unknown\", \"message\": \"panic\"}]}\n```";
+
+        let findings = parse_findings(answer, "a.rs").unwrap();
+
+        assert_eq!(
+            findings,
+            vec![
+                Finding {
+                    file: "a.rs".to_string(),
+                    line: 2,
+                    end_line: None,
+                    severity: Severity::Warning,
+                    category: Category::Readability,
+                    message: "x is not used".to_string(),
+                    suggestion: Some("let _x = 1;".to_string()),
+                    fix: None,
+                },
+                Finding {
+                    file: "b.rs".to_string(),
+                    line: 1,
+                    end_line: Some(3),
+                    severity: Severity::Error,
+                    category: Category::Other,
+                    message: "panic".to_string(),
+                    suggestion: None,
+                    fix: None,
+                }
+            ]
+        );
+        assert_eq!(
+            findings[0].to_string(),
+            "a.rs:2:1: warning: x is not used [readability]\n    let _x = 1;"
+        );
+        assert_eq!(parse_findings("[]", "a.rs").unwrap(), vec![]);
+    }
+    #[test]
+    fn スキーマに合わない回答はどこが悪いかを返す() {
+        assert_eq!(
+            parse_findings(
+                r#"[{"line": 0, "severity": "error", "message": "x"}]"#,
+                "a.rs"
+            ),
+            Err("findings[0] : line must be 1 or more".to_string())
+        );
+        assert!(parse_findings(
+            r#"[{"line": 1, "severity": "fatal", "message": "x"}]"#,
+            "a.rs"
+        )
+        .unwrap_err()
+        .starts_with("findings[0] : unknown variant `fatal`"));
+        assert!(parse_findings("looks good", "a.rs")
+            .unwrap_err()
+            .starts_with("answer is not json"));
+    }
+    #[test]
+    fn スキーマの必須項目だけが欠けると失敗する() {
+        let schema = serde_json::from_str::<Value>(SCHEMA).unwrap();
+        let required = schema["items"]["required"].as_array().unwrap();
+        let properties = schema["items"]["properties"].as_object().unwrap();
+        let full = json!({
+            "file": "a.rs", "line": 1, "end_line": 2, "severity": "info", "category": "bug",
+            "message": "x", "suggestion": "y", "fix": "z"
+        });
+
+        for name in properties.keys() {
+            let mut finding = full.clone();
+            finding.as_object_mut().unwrap().remove(name);
+            let result = parse_findings(&json!([finding]).to_string(), "a.rs");
+            assert_eq!(
+                result.is_err(),
+                required.contains(&json!(name)),
+                "{} : {:?}",
+                name,
+                result
+            );
+        }
+        assert_eq!(
+            parse_findings(
+                r#"[{"line": 1, "severity": "info", "message": "x"}]"#,
+                "a.rs"
+            )
+            .unwrap()[0]
+                .category,
+            Category::Other
+        );
+    }
+}
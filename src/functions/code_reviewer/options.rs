@@ -0,0 +1,65 @@
+use std::str::FromStr;
+
+// points the review focuses on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rubric {
+    Security,
+    Performance,
+    Readability,
+    Tests,
+}
+impl Rubric {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Security => "security",
+            Self::Performance => "performance",
+            Self::Readability => "readability",
+            Self::Tests => "tests",
+        }
+    }
+    fn description(&self) -> &'static str {
+        match self {
+            Self::Security => "injection, unchecked input, secrets, permissions and unsafe code",
+            Self::Performance => "needless allocations and copies, complexity and blocking calls",
+            Self::Readability => "naming, structure, duplication and comments",
+            Self::Tests => "missing tests, untested edge cases and weak assertions",
+        }
+    }
+    // e.g. - security: injection, unchecked input, ...
+    pub fn to_prompt(&self) -> String {
+        format!("- {}: {}", self.as_str(), self.description())
+    }
+}
+impl FromStr for Rubric {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "security" => Ok(Self::Security),
+            "performance" => Ok(Self::Performance),
+            "readability" => Ok(Self::Readability),
+            "tests" => Ok(Self::Tests),
+            _ => Err(format!("rubric {} is not supported", s)),
+        }
+    }
+}
+
+// [review] of config.toml. options of cr override these
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct ReviewOptions {
+    // language of the review. e.g. English
+    pub language: Option<String>,
+    pub rubrics: Vec<Rubric>,
+    // guidelines of the project, relative to the current directory. no file means no guidelines
+    pub guidelines: String,
+}
+impl Default for ReviewOptions {
+    fn default() -> Self {
+        Self {
+            language: None,
+            rubrics: Vec::new(),
+            guidelines: ".termai/review.md".to_string(),
+        }
+    }
+}
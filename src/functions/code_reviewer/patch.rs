@@ -0,0 +1,211 @@
+use crate::functions::code_capture::CodeCapture;
+
+use super::finding::Finding;
+
+// lines of a file replaced by a fix. start is 1-based, and old is empty for an insertion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub file: String,
+    pub start: usize,
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+}
+impl Hunk {
+    fn end(&self) -> usize {
+        self.start + self.old.len()
+    }
+}
+
+// hunks of the fix of the finding against content, the file when it was reviewed.
+// a fix in a code block is taken out of it
+pub fn hunks(finding: &Finding, content: &str) -> Result<Vec<Hunk>, String> {
+    let Some(fix) = finding.fix.as_ref() else {
+        return Ok(Vec::new());
+    };
+    let mut capture = CodeCapture::new();
+    capture.add(fix);
+    let fix = match capture.get_codes().first() {
+        Some(code) => code.as_str().to_string(),
+        None => fix.to_string(),
+    };
+    let lines = content.lines().collect::<Vec<_>>();
+    if fix.lines().any(|line| line.starts_with("@@")) {
+        return diff_hunks(&finding.file, &fix, &lines);
+    }
+    if finding.end_line() > lines.len() {
+        return Err(format!(
+            "{}:{} is out of the file of {} lines",
+            finding.file,
+            finding.end_line(),
+            lines.len()
+        ));
+    }
+    Ok(vec![Hunk {
+        file: finding.file.clone(),
+        start: finding.line,
+        old: to_strings(&lines[finding.line - 1..finding.end_line()]),
+        new: fix.lines().map(str::to_string).collect(),
+    }])
+}
+
+// line numbers of a diff by the model are often wrong, so the old lines are searched
+// from the nearest position to the number
+fn diff_hunks(file: &str, diff: &str, lines: &[&str]) -> Result<Vec<Hunk>, String> {
+    let mut parsed: Vec<(usize, Vec<String>, Vec<String>)> = Vec::new();
+    for line in diff.lines() {
+        if let Some(header) = line.strip_prefix("@@ -") {
+            let start = header
+                .split([',', ' '])
+                .next()
+                .and_then(|start| start.parse::<usize>().ok())
+                .unwrap_or(1);
+            parsed.push((start, Vec::new(), Vec::new()));
+            continue;
+        }
+        let Some((_, old, new)) = parsed.last_mut() else {
+            // --- and +++ before the first hunk
+            continue;
+        };
+        match line.chars().next() {
+            Some('-') => old.push(line[1..].to_string()),
+            Some('+') => new.push(line[1..].to_string()),
+            Some('\\') => {}
+            // the model may drop the space of a context line
+            _ => {
+                let line = line.strip_prefix(' ').unwrap_or(line);
+                old.push(line.to_string());
+                new.push(line.to_string());
+            }
+        }
+    }
+    parsed
+        .into_iter()
+        .map(|(start, old, new)| {
+            // @@ -3,0 +4 @@ inserts after line 3
+            if old.is_empty() {
+                return Ok(Hunk {
+                    file: file.to_string(),
+                    start: (start + 1).min(lines.len() + 1),
+                    old,
+                    new,
+                });
+            }
+            let expected = start.saturating_sub(1);
+            let found = (0..=lines.len().saturating_sub(old.len()))
+                .filter(|i| {
+                    lines[*i..*i + old.len()]
+                        .iter()
+                        .zip(&old)
+                        .all(|(line, old)| line.trim_end() == old.trim_end())
+                })
+                .min_by_key(|i| i.abs_diff(expected))
+                .ok_or_else(|| format!("the diff of {} does not match the file", file))?;
+            Ok(Hunk {
+                file: file.to_string(),
+                start: found + 1,
+                old,
+                new,
+            })
+        })
+        .collect()
+}
+
+// hunks are of the same content. overlapped hunks are an error
+pub fn apply(content: &str, hunks: &[Hunk]) -> Result<String, String> {
+    let mut hunks = hunks.iter().collect::<Vec<_>>();
+    hunks.sort_by_key(|hunk| hunk.start);
+    for pair in hunks.windows(2) {
+        if pair[1].start < pair[0].end() {
+            return Err(format!(
+                "fixes at line {} and {} overlap",
+                pair[0].start, pair[1].start
+            ));
+        }
+    }
+    let mut lines = content.lines().map(str::to_string).collect::<Vec<_>>();
+    for hunk in hunks.iter().rev() {
+        let start = hunk.start - 1;
+        if hunk.end() - 1 > lines.len() {
+            return Err(format!("line {} is out of the file", hunk.start));
+        }
+        lines.splice(start..start + hunk.old.len(), hunk.new.iter().cloned());
+    }
+    let mut patched = lines.join("\n");
+    if content.ends_with('\n') || content.is_empty() {
+        patched.push('\n');
+    }
+    Ok(patched)
+}
+
+fn to_strings(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|line| line.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::code_reviewer::finding::{Category, Severity};
+
+    use super::*;
+    const CONTENT: &str =
+        "fn main() {\n    let x = 1;\n    let y = 2;\n    println!(\"{}\", x);\n}\n";
+    fn finding(line: usize, end_line: Option<usize>, fix: &str) -> Finding {
+        Finding {
+            file: "src/main.rs".to_string(),
+            line,
+            end_line,
+            severity: Severity::Warning,
+            category: Category::Readability,
+            message: "y is not used".to_string(),
+            suggestion: None,
+            fix: Some(fix.to_string()),
+        }
+    }
+    #[test]
+    fn 置き換えるコードは指摘の行を置き換える() {
+        let hunks = hunks(&finding(2, Some(3), "    let x = 1;"), CONTENT).unwrap();
+
+        assert_eq!(
+            hunks,
+            vec![Hunk {
+                file: "src/main.rs".to_string(),
+                start: 2,
+                old: vec!["    let x = 1;".to_string(), "    let y = 2;".to_string()],
+                new: vec!["    let x = 1;".to_string()],
+            }]
+        );
+        assert_eq!(
+            apply(CONTENT, &hunks).unwrap(),
+            "fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n"
+        );
+        assert!(super::hunks(&finding(5, Some(9), "}"), CONTENT).is_err());
+    }
+    #[test]
+    fn コードブロックのdiffは行番号がずれていても元の行を探して当てる() {
+        let fix = "```diff\n--- a/src/main.rs\n+++ b/src/main.rs\n@@ -7,2 +7,1 @@\n     let x = 1;\n-    let y = 2;\n@@ -4,0 +4,1 @@\n+    // done\n```";
+
+        let hunks = hunks(&finding(3, None, fix), CONTENT).unwrap();
+        let patched = apply(CONTENT, &hunks).unwrap();
+
+        assert_eq!(hunks[0].start, 2);
+        assert_eq!(
+            patched,
+            "fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n    // done\n}\n"
+        );
+        assert!(apply(
+            CONTENT,
+            &[
+                hunks[0].clone(),
+                Hunk {
+                    start: 3,
+                    ..hunks[0].clone()
+                }
+            ]
+        )
+        .is_err());
+        assert!(super::hunks(
+            &finding(3, None, "@@ -1 +1 @@\n-fn other() {\n+fn main() {"),
+            CONTENT
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::finding::{Category, Finding, Severity};
+
+const CATEGORIES: [Category; 8] = [
+    Category::Bug,
+    Category::Security,
+    Category::Performance,
+    Category::Readability,
+    Category::Maintainability,
+    Category::Tests,
+    Category::Style,
+    Category::Other,
+];
+
+// e.g. termai/security
+pub fn rule_id(category: Category) -> String {
+    format!("termai/{}", category.as_str())
+}
+
+// the same finding on unchanged code has the same fingerprint even if lines above it move.
+// the message is not used because the model words it differently on each run.
+// source returns the content of the file, and the line number is used when it is not found
+pub fn fingerprints(findings: &[Finding], source: impl Fn(&str) -> Option<String>) -> Vec<String> {
+    let mut seen = Vec::new();
+    findings
+        .iter()
+        .map(|finding| {
+            let code = source(&finding.file)
+                .map(|content| {
+                    content
+                        .lines()
+                        .skip(finding.line - 1)
+                        .take(finding.end_line() - finding.line + 1)
+                        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                })
+                .unwrap_or_else(|| finding.line.to_string());
+            let key = format!("{}\0{}\0{}", finding.file, rule_id(finding.category), code);
+            // findings of the same rule on the same code are told apart by the order
+            let occurrence = seen.iter().filter(|seen| **seen == key).count();
+            seen.push(key.clone());
+            Sha256::digest(format!("{}\0{}", key, occurrence).as_bytes())
+                .iter()
+                .take(16)
+                .map(|b| format!("{:02x}", b))
+                .collect()
+        })
+        .collect()
+}
+
+// SARIF 2.1.0. file paths are relative to the root of the source
+pub fn to_sarif(findings: &[Finding], source: impl Fn(&str) -> Option<String>) -> Value {
+    let rules = CATEGORIES
+        .iter()
+        .map(|category| {
+            json!({
+                "id": rule_id(*category),
+                "name": category.as_str(),
+                "shortDescription": { "text": format!("{} issue found by review", category.as_str()) }
+            })
+        })
+        .collect::<Vec<_>>();
+    let results = findings
+        .iter()
+        .zip(fingerprints(findings, source))
+        .map(|(finding, fingerprint)| {
+            let mut result = json!({
+                "ruleId": rule_id(finding.category),
+                "ruleIndex": CATEGORIES.iter().position(|category| *category == finding.category),
+                "level": match finding.severity {
+                    Severity::Error => "error",
+                    Severity::Warning => "warning",
+                    Severity::Info => "note",
+                },
+                "message": { "text": finding.message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": finding.file, "uriBaseId": "%SRCROOT%" },
+                        "region": { "startLine": finding.line, "endLine": finding.end_line() }
+                    }
+                }],
+                "partialFingerprints": { "termai/v1": fingerprint }
+            });
+            if let Some(suggestion) = finding.suggestion.as_ref() {
+                result["properties"] = json!({ "suggestion": suggestion });
+            }
+            result
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "termai",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": rules
+                }
+            },
+            "results": results
+        }]
+    })
+}
+
+// checkstyle xml, grouped by file in the order of findings
+pub fn to_checkstyle(findings: &[Finding]) -> String {
+    let mut files: Vec<(&str, Vec<&Finding>)> = Vec::new();
+    for finding in findings {
+        match files.iter_mut().find(|(file, _)| *file == finding.file) {
+            Some((_, findings)) => findings.push(finding),
+            None => files.push((&finding.file, vec![finding])),
+        }
+    }
+    let mut xml =
+        String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<checkstyle version=\"4.3\">\n");
+    for (file, findings) in files {
+        xml.push_str(&format!("  <file name=\"{}\">\n", escape(file)));
+        for finding in findings {
+            xml.push_str(&format!(
+                "    <error line=\"{}\" column=\"1\" severity=\"{}\" message=\"{}\" source=\"{}\"/>\n",
+                finding.line,
+                finding.severity.as_str(),
+                escape(&finding.message),
+                escape(&rule_id(finding.category))
+            ));
+        }
+        xml.push_str("  </file>\n");
+    }
+    xml.push_str("</checkstyle>\n");
+    xml
+}
+
+// e.g. 3 findings (1 error, 2 warning)
+pub fn summary(findings: &[Finding]) -> String {
+    let counts = [Severity::Error, Severity::Warning, Severity::Info]
+        .iter()
+        .map(|severity| {
+            (
+                severity,
+                findings
+                    .iter()
+                    .filter(|finding| finding.severity == *severity)
+                    .count(),
+            )
+        })
+        .filter(|(_, count)| *count > 0)
+        .map(|(severity, count)| format!("{} {}", count, severity.as_str()))
+        .collect::<Vec<_>>();
+    if counts.is_empty() {
+        return "no findings".to_string();
+    }
+    format!("{} findings ({})", findings.len(), counts.join(", "))
+}
+
+// report of many files. findings are grouped by file, then by severity from error.
+// failed and skipped are pairs of a file and the reason
+pub fn to_markdown(
+    findings: &[Finding],
+    failed: &[(String, String)],
+    skipped: &[(String, String)],
+) -> String {
+    let mut markdown = format!("# Code review\n\n{}", summary(findings));
+    if !failed.is_empty() {
+        markdown.push_str(&format!(", {} failed", failed.len()));
+    }
+    if !skipped.is_empty() {
+        markdown.push_str(&format!(", {} skipped", skipped.len()));
+    }
+    markdown.push('\n');
+    let mut files: Vec<&str> = Vec::new();
+    for finding in findings {
+        if !files.contains(&finding.file.as_str()) {
+            files.push(&finding.file);
+        }
+    }
+    for file in files {
+        markdown.push_str(&format!("\n## {}\n", file));
+        for severity in [Severity::Error, Severity::Warning, Severity::Info] {
+            let mut of_severity = findings
+                .iter()
+                .filter(|finding| finding.file == file && finding.severity == severity)
+                .collect::<Vec<_>>();
+            if of_severity.is_empty() {
+                continue;
+            }
+            of_severity.sort_by_key(|finding| finding.line);
+            markdown.push_str(&format!("\n### {}\n\n", severity.as_str()));
+            for finding in of_severity {
+                let lines = if finding.end_line() > finding.line {
+                    format!("L{}-L{}", finding.line, finding.end_line())
+                } else {
+                    format!("L{}", finding.line)
+                };
+                markdown.push_str(&format!(
+                    "- {}: {} ({})\n",
+                    lines,
+                    finding.message,
+                    finding.category.as_str()
+                ));
+                if let Some(suggestion) = finding.suggestion.as_ref() {
+                    markdown.push_str("\n  ```\n");
+                    for line in suggestion.lines() {
+                        markdown.push_str(&format!("  {}\n", line));
+                    }
+                    markdown.push_str("  ```\n");
+                }
+            }
+        }
+    }
+    for (title, files) in [("Failed", failed), ("Skipped", skipped)] {
+        if files.is_empty() {
+            continue;
+        }
+        markdown.push_str(&format!("\n## {}\n\n", title));
+        for (file, reason) in files {
+            markdown.push_str(&format!("- {}: {}\n", file, reason));
+        }
+    }
+    markdown
+}
+
+fn escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&apos;".to_string(),
+            '\n' => "&#10;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn finding(line: usize, message: &str) -> Finding {
+        Finding {
+            file: "src/main.rs".to_string(),
+            line,
+            end_line: None,
+            severity: Severity::Warning,
+            category: Category::Security,
+            message: message.to_string(),
+            suggestion: None,
+            fix: None,
+        }
+    }
+    #[test]
+    fn 指紋は行が動いても言い回しが変わっても同じコードなら変わらない() {
+        let before = "fn main() {\n    run(&input);\n}\n";
+        let after = "use std::io;\n\nfn main() {\n        run(&input);\n}\n";
+
+        let first = fingerprints(&[finding(2, "unchecked input")], |_| {
+            Some(before.to_string())
+        });
+        let second = fingerprints(&[finding(4, "input is not validated")], |_| {
+            Some(after.to_string())
+        });
+        let twice = fingerprints(&[finding(2, "a"), finding(2, "b")], |_| {
+            Some(before.to_string())
+        });
+
+        assert_eq!(first, second);
+        assert_eq!(first[0].len(), 32);
+        assert_ne!(twice[0], twice[1]);
+    }
+    #[test]
+    fn マークダウンのレポートはファイルと重要度でまとめる() {
+        let mut error = finding(5, "panics");
+        error.severity = Severity::Error;
+        error.suggestion = Some("check it".to_string());
+        let mut other = finding(1, "slow");
+        other.file = "src/lib.rs".to_string();
+        let findings = vec![finding(9, "late"), other, error, finding(3, "early")];
+
+        let markdown = to_markdown(
+            &findings,
+            &[("src/a.rs".to_string(), "timeout".to_string())],
+            &[("logo.png".to_string(), "binary".to_string())],
+        );
+
+        assert_eq!(
+            markdown,
+            "# Code review\n\n4 findings (1 error, 3 warning), 1 failed, 1 skipped\n\n## src/main.rs\n\n### error\n\n- L5: panics (security)\n\n  ```\n  check it\n  ```\n\n### warning\n\n- L3: early (security)\n- L9: late (security)\n\n## src/lib.rs\n\n### warning\n\n- L1: slow (security)\n\n## Failed\n\n- src/a.rs: timeout\n\n## Skipped\n\n- logo.png: binary\n"
+        );
+    }
+    #[test]
+    fn sarifとcheckstyleに位置とルールを書き出す() {
+        let findings = vec![finding(2, "use \"<input>\" & check")];
+
+        let sarif = to_sarif(&findings, |_| None);
+        let checkstyle = to_checkstyle(&findings);
+
+        let result = &sarif["runs"][0]["results"][0];
+        assert_eq!(sarif["version"], "2.1.0");
+        assert_eq!(result["ruleId"], "termai/security");
+        assert_eq!(
+            sarif["runs"][0]["tool"]["driver"]["rules"]
+                [result["ruleIndex"].as_u64().unwrap() as usize]["id"],
+            "termai/security"
+        );
+        assert_eq!(result["level"], "warning");
+        assert_eq!(
+            result["locations"][0]["physicalLocation"]["region"],
+            json!({"startLine": 2, "endLine": 2})
+        );
+        assert_eq!(
+            checkstyle,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<checkstyle version=\"4.3\">\n  <file name=\"src/main.rs\">\n    <error line=\"2\" column=\"1\" severity=\"warning\" message=\"use &quot;&lt;input&gt;&quot; &amp; check\" source=\"termai/security\"/>\n  </file>\n</checkstyle>\n"
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use std::path::{Path, PathBuf};
+
+use crate::functions::common::is_file_path;
+
+// files of these names are read in each directory, and in the parents up to the git root
+pub const IGNORE_FILES: [&str; 2] = [".gitignore", ".termaiignore"];
+
+// files to review, and the files skipped with the reason
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Targets {
+    pub files: Vec<String>,
+    pub skipped: Vec<(String, String)>,
+}
+
+// target is a file, a directory or a glob like src/**/*.rs.
+// a file given directly is reviewed even if it is ignored
+pub fn find_targets(target: &str, max_bytes: u64) -> std::io::Result<Targets> {
+    let target = target.trim();
+    let files = if is_file_path(target) {
+        vec![target.to_string()]
+    } else if Path::new(target).is_dir() {
+        walk_from(target.trim_end_matches('/'), None)?
+    } else {
+        let pattern = target.trim_start_matches("./");
+        let base = glob_base(pattern);
+        // a path without a wildcard is a mistyped file, not a glob of no files
+        if !pattern.contains(['*', '?', '[']) || !Path::new(&base).is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not a file, a directory or a glob", target),
+            ));
+        }
+        walk_from(&base, Some(pattern))?
+            .into_iter()
+            .filter(|file| glob_match(pattern, file))
+            .collect()
+    };
+    let mut targets = Targets::default();
+    for file in files {
+        match skip_reason(&file, max_bytes)? {
+            Some(reason) => targets.skipped.push((file, reason)),
+            None => targets.files.push(file),
+        }
+    }
+    Ok(targets)
+}
+
+fn skip_reason(file: &str, max_bytes: u64) -> std::io::Result<Option<String>> {
+    let size = std::fs::metadata(file)?.len();
+    if size > max_bytes {
+        return Ok(Some(format!("larger than {} bytes", max_bytes)));
+    }
+    let content = std::fs::read(file)?;
+    if content.iter().take(8000).any(|b| *b == 0) || std::str::from_utf8(&content).is_err() {
+        return Ok(Some("binary".to_string()));
+    }
+    Ok(None)
+}
+
+// the directories before the first wildcard. e.g. src/**/*.rs -> src
+fn glob_base(pattern: &str) -> String {
+    let base = pattern
+        .split('/')
+        .take_while(|part| !part.contains(['*', '?', '[']))
+        .collect::<Vec<_>>();
+    // the last part is a file name when the pattern has no directory after it
+    let base = if base.len() == pattern.split('/').count() {
+        &base[..base.len() - 1]
+    } else {
+        &base[..]
+    };
+    if base.is_empty() {
+        ".".to_string()
+    } else {
+        base.join("/")
+    }
+}
+
+// `*` and `?` do not match `/`, and `**` matches any directories
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    fn matches(pattern: &[char], path: &[char]) -> bool {
+        match pattern {
+            [] => path.is_empty(),
+            ['*', '*', rest @ ..] => {
+                if let ['/', after @ ..] = rest {
+                    if matches(after, path) {
+                        return true;
+                    }
+                }
+                (0..=path.len()).any(|i| matches(rest, &path[i..]))
+            }
+            ['*', rest @ ..] => (0..=path.len())
+                .take_while(|i| *i == 0 || path[i - 1] != '/')
+                .any(|i| matches(rest, &path[i..])),
+            ['?', rest @ ..] => {
+                path.first().is_some_and(|c| *c != '/') && matches(rest, &path[1..])
+            }
+            [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+        }
+    }
+    matches(
+        &pattern.chars().collect::<Vec<_>>(),
+        &path.chars().collect::<Vec<_>>(),
+    )
+}
+
+// a line of .gitignore
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IgnoreRule {
+    // the directory of the ignore file
+    base: PathBuf,
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    // a pattern with `/` is relative to base, otherwise it matches the name at any depth
+    anchored: bool,
+}
+impl IgnoreRule {
+    fn parse(base: &Path, line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        Some(Self {
+            base: base.to_path_buf(),
+            pattern: line.trim_start_matches('/').to_string(),
+            negated,
+            dir_only,
+            anchored: line.contains('/'),
+        })
+    }
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Ok(relative) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        let relative = relative.to_string_lossy();
+        if self.anchored {
+            glob_match(&self.pattern, &relative)
+        } else {
+            relative
+                .rsplit('/')
+                .next()
+                .is_some_and(|name| glob_match(&self.pattern, name))
+        }
+    }
+}
+
+fn read_rules(dir: &Path) -> Vec<IgnoreRule> {
+    IGNORE_FILES
+        .iter()
+        .filter_map(|name| std::fs::read_to_string(dir.join(name)).ok())
+        .flat_map(|content| {
+            content
+                .lines()
+                .filter_map(|line| IgnoreRule::parse(dir, line))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// the last matched rule decides, so `!` can take back a file
+fn is_ignored(rules: &[IgnoreRule], path: &Path, is_dir: bool) -> bool {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| rule.matches(path, is_dir))
+        .is_some_and(|rule| !rule.negated)
+}
+
+// a hidden file or dir is walked when a part of the glob names it. e.g. .github/**/*.yml
+fn is_named_by(pattern: Option<&str>, name: &str) -> bool {
+    name != ".git"
+        && pattern.is_some_and(|pattern| {
+            pattern
+                .split('/')
+                .any(|part| part.starts_with('.') && glob_match(part, name))
+        })
+}
+
+fn walk_from(root: &str, pattern: Option<&str>) -> std::io::Result<Vec<String>> {
+    let absolute = std::fs::canonicalize(root)?;
+    // rules of the parents apply only inside a git repository
+    let mut parents = Vec::new();
+    let mut in_repository = absolute.join(".git").exists();
+    for dir in absolute.ancestors().skip(1) {
+        if in_repository {
+            break;
+        }
+        parents.push(dir.to_path_buf());
+        in_repository = dir.join(".git").exists();
+    }
+    let mut rules = Vec::new();
+    if in_repository {
+        for dir in parents.iter().rev() {
+            rules.extend(read_rules(dir));
+        }
+    }
+    let mut files = Vec::new();
+    walk(root, &absolute, pattern, &mut rules, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+// hidden files and dirs like .git are skipped as list_files does.
+// symlinks are skipped, so a link out of root or a loop is not followed
+fn walk(
+    dir: &str,
+    absolute: &Path,
+    pattern: Option<&str>,
+    rules: &mut Vec<IgnoreRule>,
+    files: &mut Vec<String>,
+) -> std::io::Result<()> {
+    let inherited = rules.len();
+    rules.extend(read_rules(absolute));
+    for entry in std::fs::read_dir(absolute)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') && !is_named_by(pattern, &name) {
+            continue;
+        }
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let is_dir = file_type.is_dir();
+        if is_ignored(rules, &path, is_dir) {
+            continue;
+        }
+        let display = if dir == "." {
+            name
+        } else {
+            format!("{}/{}", dir, name)
+        };
+        if is_dir {
+            walk(&display, &path, pattern, rules, files)?;
+        } else if file_type.is_file() {
+            files.push(display);
+        }
+    }
+    rules.truncate(inherited);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::common::test_tool::TestFileFactory;
+
+    use super::*;
+    #[test]
+    fn globはスラッシュを超えずダブルスターはディレクトリを超える() {
+        assert!(glob_match("src/*.rs", "src/main.rs"));
+        assert!(!glob_match("src/*.rs", "src/cli/review.rs"));
+        assert!(glob_match("src/**/*.rs", "src/main.rs"));
+        assert!(glob_match("src/**/*.rs", "src/cli/review.rs"));
+        assert!(glob_match("**/test_?.rs", "a/b/test_1.rs"));
+        assert!(!glob_match("*.rs", "main.rs.bak"));
+        assert_eq!(glob_base("src/**/*.rs"), "src");
+        assert_eq!(glob_base("*.rs"), ".");
+    }
+    #[test]
+    fn ignoreファイルに従いバイナリと大きなファイルを除いて対象を集める() {
+        let root = "tmp_review_targets";
+        let test_file = TestFileFactory::create(root);
+        std::fs::create_dir_all(format!("{}/src/gen", root)).unwrap();
+        std::fs::create_dir_all(format!("{}/target", root)).unwrap();
+        std::fs::create_dir_all(format!("{}/.github/workflows", root)).unwrap();
+        test_file.create_file_under_root(".gitignore", "target/\n*.log\n");
+        test_file.create_file_under_root(".termaiignore", "/src/gen\n");
+        test_file.create_file_under_root("src/.gitignore", "*.tmp\n!keep.tmp\n");
+        test_file.create_file_under_root("src/main.rs", "fn main() {}\n");
+        test_file.create_file_under_root("src/a.tmp", "a\n");
+        test_file.create_file_under_root("src/keep.tmp", "keep\n");
+        test_file.create_file_under_root("src/gen/out.rs", "fn gen() {}\n");
+        test_file.create_file_under_root("src/big.rs", &"a".repeat(200));
+        test_file.create_file_under_root("target/out.rs", "fn out() {}\n");
+        test_file.create_file_under_root("run.log", "log\n");
+        test_file.create_file_under_root(".github/workflows/ci.yml", "on: push\n");
+        std::fs::write(format!("{}/logo.png", root), [0x89, 0x50, 0x00, 0x0d]).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("..", format!("{}/src/parent", root)).unwrap();
+
+        let all = find_targets(root, 100).unwrap();
+        let globbed = find_targets(&format!("{}/**/*.rs", root), 100).unwrap();
+        let file = find_targets(&format!("{}/run.log", root), 100).unwrap();
+        let hidden = find_targets(&format!("{}/.github/**/*.yml", root), 100).unwrap();
+        let not_hidden = find_targets(&format!("{}/**/*.yml", root), 100).unwrap();
+        let mistyped = find_targets(&format!("{}/src/mian.rs", root), 100);
+        test_file.remove_dir_all();
+
+        assert_eq!(
+            all,
+            Targets {
+                files: vec![
+                    format!("{}/src/keep.tmp", root),
+                    format!("{}/src/main.rs", root),
+                ],
+                skipped: vec![
+                    (format!("{}/logo.png", root), "binary".to_string()),
+                    (
+                        format!("{}/src/big.rs", root),
+                        "larger than 100 bytes".to_string()
+                    ),
+                ],
+            }
+        );
+        assert_eq!(globbed.files, vec![format!("{}/src/main.rs", root)]);
+        assert_eq!(file.files, vec![format!("{}/run.log", root)]);
+        assert_eq!(
+            hidden.files,
+            vec![format!("{}/.github/workflows/ci.yml", root)]
+        );
+        assert!(not_hidden.files.is_empty());
+        assert_eq!(mistyped.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::gpt::client::{ChatRequest, ChatResponse, HandleResult, Message, Role};
+
+use super::{code_capture::CodeCapture, estimate_tokens, GptFunction, UserInput};
+
+// [commit] of config.toml. options of commit override these
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct CommitOptions {
+    // language of the message. e.g. Japanese
+    pub language: String,
+    // tokens of the diff sent at once. a larger diff is summarized part by part
+    pub max_tokens: usize,
+}
+impl Default for CommitOptions {
+    fn default() -> Self {
+        Self {
+            language: "English".to_string(),
+            max_tokens: 3000,
+        }
+    }
+}
+
+// what the model is asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitStage {
+    // the message of the diff
+    Compose,
+    // summary of a part of the diff, or of summaries
+    Summarize,
+    // the message of the summaries of the diff
+    Reduce,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommitMessage {
+    stage: CommitStage,
+    language: String,
+    answer: String,
+}
+
+impl CommitMessage {
+    const COMPOSE_PREFIX: &'static str = "Write the commit message of the following git diff.";
+    const SUMMARIZE_PREFIX: &'static str =
+        "Summarize the following changes. They are a part of git diff or summaries of its parts.";
+    const REDUCE_PREFIX: &'static str =
+        "Write the commit message of the changes summarized below. Each summary is of a part of one git diff.";
+    pub fn new(stage: CommitStage) -> Self {
+        Self {
+            stage,
+            language: CommitOptions::default().language,
+            answer: String::new(),
+        }
+    }
+    pub fn with_language(mut self, language: impl Into<String>) -> Self {
+        self.language = language.into();
+        self
+    }
+    // the answer of the last action. a message in a code block is taken out of it
+    pub fn message(&self) -> String {
+        let mut capture = CodeCapture::new();
+        capture.add(&self.answer);
+        let message = match capture.get_codes().first() {
+            Some(code) => code.as_str().to_string(),
+            None => self.answer.clone(),
+        };
+        message.trim().to_string()
+    }
+    fn prefix(&self) -> &'static str {
+        match self.stage {
+            CommitStage::Compose => Self::COMPOSE_PREFIX,
+            CommitStage::Summarize => Self::SUMMARIZE_PREFIX,
+            CommitStage::Reduce => Self::REDUCE_PREFIX,
+        }
+    }
+}
+
+impl Default for CommitMessage {
+    fn default() -> Self {
+        Self::new(CommitStage::Compose)
+    }
+}
+impl GptFunction for CommitMessage {
+    fn name(&self) -> String {
+        "commit".to_string()
+    }
+    fn description(&self) -> String {
+        "write a commit message of the diff in Conventional Commits. args is the language"
+            .to_string()
+    }
+    fn can_action(&self) -> bool {
+        true
+    }
+    fn setup_for_action(&mut self, _input: &UserInput) {
+        self.answer.clear();
+    }
+    fn handle_stream(&mut self, response: &ChatResponse) -> HandleResult {
+        self.answer.push_str(response.delta_content());
+        HandleResult::from(response)
+    }
+    fn change_request(&self, request: &mut ChatRequest) {
+        let system = match self.stage {
+            CommitStage::Summarize => "You summarize code changes for a commit message. List what changed and why in short bullets, in English. Keep the names of files, types and functions.".to_string(),
+            CommitStage::Compose | CommitStage::Reduce => format!(
+                "You write git commit messages in Conventional Commits. The first line is `type(scope): subject` of at most 72 characters, where type is one of feat, fix, docs, style, refactor, perf, test, build, ci or chore, and scope is optional. Add `!` after the type or scope for a breaking change. Then a blank line and a body which explains what and why, wrapped at 72 characters. Answer only the message. Write the subject and the body in {}, and keep type and scope in English.",
+                self.language
+            ),
+        };
+        request.set_system(system);
+    }
+    // the diff is split by split_diff, so a message is not split in the middle of a hunk
+    fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
+        vec![Message::new(
+            Role::User,
+            format!("{}\n{}", self.prefix(), input.content()),
+        )]
+    }
+}
+
+// the token-aware splitter. files of the diff are packed into parts of max_tokens.
+// a larger file is split at hunks and a larger hunk at lines, with its headers repeated
+pub fn split_diff(diff: &str, max_tokens: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    for file in split_before(diff, "diff --git ") {
+        for piece in split_file(&file, max_tokens) {
+            if !part.is_empty() && estimate_tokens(&part) + estimate_tokens(&piece) > max_tokens {
+                parts.push(std::mem::take(&mut part));
+            }
+            part.push_str(&piece);
+        }
+    }
+    if !part.trim().is_empty() {
+        parts.push(part);
+    }
+    parts
+}
+
+fn split_file(file: &str, max_tokens: usize) -> Vec<String> {
+    if estimate_tokens(file) <= max_tokens {
+        return vec![file.to_string()];
+    }
+    let mut sections = split_before(file, "@@ ").into_iter();
+    // lines before the first hunk. e.g. diff --git, index, --- and +++
+    let header = sections.next().unwrap_or_default();
+    let mut pieces = Vec::new();
+    let mut piece = header.clone();
+    for hunk in sections {
+        let hunk_pieces = if estimate_tokens(&header) + estimate_tokens(&hunk) > max_tokens {
+            split_hunk(&hunk, max_tokens.saturating_sub(estimate_tokens(&header)))
+        } else {
+            vec![hunk]
+        };
+        for hunk in hunk_pieces {
+            if piece != header && estimate_tokens(&piece) + estimate_tokens(&hunk) > max_tokens {
+                pieces.push(std::mem::replace(&mut piece, header.clone()));
+            }
+            piece.push_str(&hunk);
+        }
+    }
+    if piece != header || pieces.is_empty() {
+        pieces.push(piece);
+    }
+    pieces
+}
+
+// a line longer than max_tokens is still kept as a part
+fn split_hunk(hunk: &str, max_tokens: usize) -> Vec<String> {
+    let mut lines = hunk.split_inclusive('\n');
+    let header = lines.next().unwrap_or_default().to_string();
+    let mut pieces = Vec::new();
+    let mut piece = header.clone();
+    for line in lines {
+        if piece != header && estimate_tokens(&piece) + estimate_tokens(line) > max_tokens {
+            pieces.push(std::mem::replace(&mut piece, header.clone()));
+        }
+        piece.push_str(line);
+    }
+    pieces.push(piece);
+    pieces
+}
+
+// text is split before each line which starts with the marker. text before the first one is kept
+fn split_before(text: &str, marker: &str) -> Vec<String> {
+    let mut sections = vec![String::new()];
+    for line in text.split_inclusive('\n') {
+        if line.starts_with(marker) && !sections.last().is_some_and(|s| s.is_empty()) {
+            sections.push(String::new());
+        }
+        if let Some(section) = sections.last_mut() {
+            section.push_str(line);
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const DIFF: &str = "diff --git a/src/a.rs b/src/a.rs
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1,2 +1,2 @@
+-let a = 1;
++let a = 2;
+@@ -10,2 +10,2 @@
+-let b = 1;
++let b = 2;
+diff --git a/old.rs b/old.rs
+deleted file mode 100644
+--- a/old.rs
++++ /dev/null
+@@ -1 +0,0 @@
+-fn old() {}
+";
+    #[test]
+    fn diffはファイルとハンクと行の順に分けヘッダを繰り返す() {
+        let whole = split_diff(DIFF, 1000);
+        let by_file = split_diff(DIFF, 40);
+        let by_hunk = split_diff(DIFF, 30);
+        let by_line = split_file(
+            "--- a/a.rs\n+++ b/a.rs\n@@ -1,3 +1,3 @@\n-let a = 1;\n+let a = 2;\n let c = 3;\n",
+            15,
+        );
+
+        assert_eq!(whole, vec![DIFF.to_string()]);
+        assert_eq!(by_file.len(), 2);
+        assert!(by_file[1].starts_with("diff --git a/old.rs"));
+        assert_eq!(by_hunk.len(), 3);
+        assert!(by_hunk[1].starts_with(
+            "diff --git a/src/a.rs b/src/a.rs\n--- a/src/a.rs\n+++ b/src/a.rs\n@@ -10,2 +10,2 @@\n"
+        ));
+        assert_eq!(
+            by_line,
+            vec![
+                "--- a/a.rs\n+++ b/a.rs\n@@ -1,3 +1,3 @@\n-let a = 1;\n",
+                "--- a/a.rs\n+++ b/a.rs\n@@ -1,3 +1,3 @@\n+let a = 2;\n",
+                "--- a/a.rs\n+++ b/a.rs\n@@ -1,3 +1,3 @@\n let c = 3;\n",
+            ]
+        );
+    }
+    #[test]
+    fn コードブロックに包まれたメッセージを取り出す() {
+        let mut commit = CommitMessage::new(CommitStage::Reduce).with_language("Japanese");
+        commit.setup_for_action(&UserInput::new("- add a"));
+        commit.handle_stream(&ChatResponse::DeltaContent(
+            "```\nfeat: a を追加\n".to_string(),
+        ));
+        commit.handle_stream(&ChatResponse::DeltaContent("```\n".to_string()));
+        let mut request = ChatRequest::new(Default::default(), Vec::new());
+        commit.change_request(&mut request);
+        let messages = commit.input_to_messages(UserInput::new("- add a"));
+
+        assert_eq!(commit.message(), "feat: a を追加");
+        assert!(request.messages()[0].content.contains("in Japanese"));
+        assert_eq!(
+            messages[0].content,
+            format!("{}\n- add a", CommitMessage::REDUCE_PREFIX)
+        );
+    }
+}
@@ -12,8 +12,119 @@ pub fn get_file_content(path: &str) -> Result<String, std::io::Error> {
     Ok(content)
 }
 
+// all files under the dir. hidden files and dirs like .git are skipped.
+// a symlink to a dir is not followed, so a link like `a -> .` does not loop
+pub fn list_files(dir: &str) -> Result<Vec<String>, std::io::Error> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_none_or(|name| name.starts_with('.'))
+        {
+            continue;
+        }
+        let path_str = path.to_string_lossy().to_string();
+        if entry.file_type()?.is_dir() {
+            files.extend(list_files(&path_str)?);
+        } else if path.is_file() {
+            files.push(path_str);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// line diff by lcs. unchanged lines far from changes are omitted
+pub fn diff(old: &str, new: &str) -> String {
+    const CONTEXT: usize = 2;
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+    let changed = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, (mark, _))| *mark != ' ')
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let is_near_change = |i: usize| {
+        changed
+            .iter()
+            .any(|c| i + CONTEXT >= *c && i <= *c + CONTEXT)
+    };
+    let mut result = Vec::new();
+    let mut skipped = false;
+    for (i, (mark, line)) in lines.iter().enumerate() {
+        if is_near_change(i) {
+            if skipped && !result.is_empty() {
+                result.push("...".to_string());
+            }
+            skipped = false;
+            result.push(format!("{} {}", mark, line));
+        } else {
+            skipped = true;
+        }
+    }
+    result.join("\n")
+}
+
 #[cfg(test)]
+mod tests {
+    use super::{test_tool::TestFileFactory, *};
+    #[test]
+    #[cfg(unix)]
+    fn ディレクトリへのシンボリックリンクはたどらない() {
+        let test_file = TestFileFactory::create("tmp_list_files_link");
+        test_file.create_file_under_root("a.rs", "fn a() {}\n");
+        std::os::unix::fs::symlink(".", "tmp_list_files_link/loop").unwrap();
+
+        let files = list_files("tmp_list_files_link");
+        test_file.remove_dir_all();
+
+        assert_eq!(files.unwrap(), vec!["tmp_list_files_link/a.rs"]);
+    }
+    #[test]
+    fn 変更された行と前後の行だけを差分として表示する() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh";
+        let new = "a\nb\nc\nd\nE\nf\ng\nh";
 
+        assert_eq!(diff(old, new), "  c\n  d\n- e\n+ E\n  f\n  g");
+        assert_eq!(diff("", "x\ny"), "+ x\n+ y");
+        assert_eq!(diff("same", "same"), "");
+        assert_eq!(
+            diff("1\n2\n3\n4\n5\n6\n7\n8", "0\n2\n3\n4\n5\n6\n7\n9"),
+            "- 1\n+ 0\n  2\n  3\n...\n  6\n  7\n- 8\n+ 9"
+        );
+    }
+}
+
+#[cfg(test)]
 pub mod test_tool {
     use std::io::Write;
 
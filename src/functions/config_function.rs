@@ -0,0 +1,357 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use crate::gpt::client::{ChatRequest, ChatResponse, HandleResult, Message, OpenAIModel};
+
+use super::{
+    common::{get_file_content, is_file_path},
+    FunctionArtifact, GptFunction, UserInput,
+};
+
+// [[functions]] of config.toml
+// name = "summary"
+// prompt = "summarize {{file}} in {{lang}}\n{{input}}"
+// system = "you are a technical writer"
+// model = "gpt-4o"
+// temperature = 0.2
+// lang = "English"
+// output = { type = "append", path = "summary.md" }
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default = "FunctionDefinition::default_prompt")]
+    pub prompt: String,
+    pub system: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub output: OutputSink,
+}
+impl FunctionDefinition {
+    fn default_prompt() -> String {
+        "{{input}}".to_string()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputSink {
+    // the answer is only shown like other functions
+    #[default]
+    Stdout,
+    Append {
+        path: String,
+    },
+    // fails if the file already exists
+    Write {
+        path: String,
+    },
+    Clipboard,
+}
+
+pub struct ConfigFunction {
+    definition: FunctionDefinition,
+    model: Option<OpenAIModel>,
+    lang: String,
+    file: String,
+    response: String,
+    written: Option<String>,
+}
+
+impl ConfigFunction {
+    const INPUT: &'static str = "{{input}}";
+    const FILE: &'static str = "{{file}}";
+    const LANG: &'static str = "{{lang}}";
+    pub fn new(definition: FunctionDefinition) -> Result<Self, String> {
+        let model = definition
+            .model
+            .as_deref()
+            .map(OpenAIModel::from_str)
+            .transpose()
+            .map_err(|e| format!("function {} : {}", definition.name, e))?;
+        Ok(Self {
+            lang: definition.lang.clone().unwrap_or_default(),
+            model,
+            definition,
+            file: String::new(),
+            response: String::new(),
+            written: None,
+        })
+    }
+    pub fn model(&self) -> Option<OpenAIModel> {
+        self.model
+    }
+    // given by command line. overrides the definition
+    pub fn set_model(&mut self, model: OpenAIModel) {
+        self.model = Some(model);
+    }
+    pub fn set_lang(&mut self, lang: impl Into<String>) {
+        self.lang = lang.into();
+    }
+    fn expand(&self, template: &str) -> String {
+        template
+            .replace(Self::FILE, &self.file)
+            .replace(Self::LANG, &self.lang)
+    }
+    fn render(&self, template: &str, input: &str) -> String {
+        let rendered = self.expand(template);
+        if rendered.contains(Self::INPUT) {
+            rendered.replace(Self::INPUT, input)
+        } else {
+            format!("{}\n{}", rendered, input)
+        }
+    }
+    fn write_output(&mut self) -> std::io::Result<()> {
+        match &self.definition.output {
+            OutputSink::Stdout => Ok(()),
+            OutputSink::Append { path } => {
+                let path = self.expand(path);
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)?;
+                writeln!(file, "{}", self.response)?;
+                self.written = Some(path);
+                Ok(())
+            }
+            OutputSink::Write { path } => {
+                let path = self.expand(path);
+                let mut file = std::fs::OpenOptions::new()
+                    .create_new(true)
+                    .write(true)
+                    .open(&path)?;
+                file.write_all(self.response.as_bytes())?;
+                self.written = Some(path);
+                Ok(())
+            }
+            OutputSink::Clipboard => copy_to_clipboard(&self.response),
+        }
+    }
+}
+
+impl GptFunction for ConfigFunction {
+    fn name(&self) -> String {
+        self.definition.name.clone()
+    }
+    fn description(&self) -> String {
+        self.definition.description.clone().unwrap_or_default()
+    }
+    fn setup_for_action(&mut self, input: &UserInput) {
+        self.file = if is_file_path(input.content()) {
+            input.content().trim().to_string()
+        } else {
+            String::new()
+        };
+        self.response.clear();
+        self.written = None;
+    }
+    fn can_action(&self) -> bool {
+        true
+    }
+    fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
+        let content = get_file_content(input.content().trim())
+            .unwrap_or_else(|_| input.content().to_string());
+        UserInput::new(content)
+            .to_messages()
+            .into_iter()
+            .map(|mut message| {
+                let rendered = self.render(&self.definition.prompt, &message.content);
+                *message.change_content() = rendered;
+                message
+            })
+            .collect()
+    }
+    fn change_request(&self, request: &mut ChatRequest) {
+        if let Some(model) = self.model {
+            request.set_model(model);
+        }
+        if let Some(system) = self.definition.system.as_ref() {
+            request.set_system(self.expand(system));
+        }
+        if let Some(temperature) = self.definition.temperature {
+            request.set_temperature(temperature);
+        }
+        if let Some(top_p) = self.definition.top_p {
+            request.set_top_p(top_p);
+        }
+    }
+    fn handle_stream(&mut self, response: &ChatResponse) -> HandleResult {
+        self.response.push_str(response.delta_content());
+        HandleResult::from(response)
+    }
+    fn action_at_end(&mut self) -> Result<(), Box<dyn std::error::Error + 'static>> {
+        if self.response.is_empty() {
+            return Ok(());
+        }
+        self.write_output()?;
+        Ok(())
+    }
+    fn artifacts(&self) -> Vec<FunctionArtifact> {
+        self.written
+            .iter()
+            .map(|path| FunctionArtifact::OutputFile { path: path.clone() })
+            .collect()
+    }
+}
+
+// the first command found is used
+fn copy_to_clipboard(content: &str) -> std::io::Result<()> {
+    let commands: &[&[&str]] = if cfg!(target_os = "macos") {
+        &[&["pbcopy"]]
+    } else {
+        &[
+            &["wl-copy"],
+            &["xclip", "-selection", "clipboard"],
+            &["xsel", "--clipboard", "--input"],
+        ]
+    };
+    for command in commands {
+        let mut child = match Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(content.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "{} failed with {}",
+                command[0], status
+            )));
+        }
+        return Ok(());
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "clipboard command is not found. install pbcopy, wl-copy, xclip or xsel",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        functions::common::test_tool::TestFileFactory,
+        gpt::client::{Message, Role},
+    };
+
+    use super::*;
+    fn definition(toml: &str) -> FunctionDefinition {
+        toml::from_str(toml).unwrap()
+    }
+    #[test]
+    fn promptのテンプレートに入力と言語を埋め込む() {
+        let function = ConfigFunction::new(definition(
+            r#"
+            name = "summary"
+            prompt = "summarize in {{lang}}: {{input}}"
+            lang = "English"
+            "#,
+        ))
+        .unwrap();
+
+        let messages = function.input_to_messages(UserInput::new("hello"));
+
+        assert_eq!(
+            messages,
+            vec![Message::new(Role::User, "summarize in English: hello")]
+        );
+    }
+    #[test]
+    fn inputを含まないテンプレートは末尾に入力を付ける() {
+        let function = ConfigFunction::new(definition(
+            r#"
+            name = "review"
+            prompt = "review this"
+            "#,
+        ))
+        .unwrap();
+
+        let messages = function.input_to_messages(UserInput::new("fn main() {}"));
+
+        assert_eq!(
+            messages,
+            vec![Message::new(Role::User, "review this\nfn main() {}")]
+        );
+    }
+    #[test]
+    fn system_promptとモデルとサンプリングパラメータをリクエストに設定する() {
+        let function = ConfigFunction::new(definition(
+            r#"
+            name = "summary"
+            system = "you are a writer"
+            model = "gpt-4"
+            temperature = 0.5
+            "#,
+        ))
+        .unwrap();
+        let mut request =
+            ChatRequest::from_message(OpenAIModel::Gpt4o, Message::new(Role::User, "hello"));
+
+        function.change_request(&mut request);
+
+        assert_eq!(request.model(), OpenAIModel::Gpt4);
+        assert_eq!(
+            request.messages(),
+            &[
+                Message::new(Role::System, "you are a writer"),
+                Message::new(Role::User, "hello")
+            ]
+        );
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["temperature"], 0.5);
+        assert!(json.get("top_p").is_none());
+    }
+    #[test]
+    fn 未対応のモデルはエラーになる() {
+        assert!(ConfigFunction::new(definition(
+            r#"
+            name = "summary"
+            model = "gpt-unknown"
+            "#,
+        ))
+        .is_err());
+    }
+    #[test]
+    fn 回答をファイルに追記する() {
+        let test_file = TestFileFactory::create("tmp_config_function");
+        test_file.create_file_under_root("note.txt", "note");
+        let mut function = ConfigFunction::new(definition(
+            r#"
+            name = "summary"
+            output = { type = "append", path = "{{file}}.summary" }
+            "#,
+        ))
+        .unwrap();
+
+        function.setup_for_action(&UserInput::new("tmp_config_function/note.txt"));
+        function.handle_stream(&ChatResponse::DeltaContent("short".to_string()));
+        function.handle_stream(&ChatResponse::Done);
+        function.action_at_end().unwrap();
+        function.setup_for_action(&UserInput::new("tmp_config_function/note.txt"));
+        function.handle_stream(&ChatResponse::DeltaContent("again".to_string()));
+        function.action_at_end().unwrap();
+        let written = std::fs::read_to_string("tmp_config_function/note.txt.summary").unwrap();
+        let artifacts = function.artifacts();
+
+        test_file.remove_dir_all();
+        assert_eq!(written, "short\nagain\n");
+        assert_eq!(
+            artifacts,
+            vec![FunctionArtifact::OutputFile {
+                path: "tmp_config_function/note.txt.summary".to_string()
+            }]
+        );
+    }
+}
@@ -0,0 +1,475 @@
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt::Display,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use crate::gpt::client::{ChatResponse, GptClientError, GptClientErrorKind, HandleResult, Message};
+
+use super::{GptFunction, UserInput};
+
+// one json per line on stdin of the plugin. every request gets exactly one line of response
+// > {"hook":"describe"}
+// < {"result":{"name":"upper","description":"...","hooks":["input_to_messages","handle_stream"]}}
+// > {"hook":"input_to_messages","input":"hello"}
+// < {"result":[{"role":"user","content":"HELLO"}]}
+// > {"hook":"handle_stream","delta":"hi","done":false}
+// < {"result":"progress"}
+// failure of any hook is {"error":"message"}
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "hook", rename_all = "snake_case")]
+enum PluginRequest<'a> {
+    Describe,
+    SetupForAction { input: &'a str },
+    CanAction,
+    InputToMessages { input: &'a str },
+    HandleStream { delta: &'a str, done: bool },
+    ActionAtEnd,
+}
+impl PluginRequest<'_> {
+    fn hook(&self) -> &'static str {
+        match self {
+            Self::Describe => "describe",
+            Self::SetupForAction { .. } => "setup_for_action",
+            Self::CanAction => "can_action",
+            Self::InputToMessages { .. } => "input_to_messages",
+            Self::HandleStream { .. } => "handle_stream",
+            Self::ActionAtEnd => "action_at_end",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct PluginResponse {
+    #[serde(default)]
+    result: serde_json::Value,
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct PluginDescription {
+    pub name: String,
+    pub description: Option<String>,
+    // hooks not listed here are not sent and behave like the default of GptFunction
+    #[serde(default)]
+    pub hooks: HashSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginError {
+    plugin: String,
+    message: String,
+}
+impl PluginError {
+    fn new(plugin: &str, message: impl Into<String>) -> Self {
+        Self {
+            plugin: plugin.to_string(),
+            message: message.into(),
+        }
+    }
+}
+impl Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "plugin {} : {}", self.plugin, self.message)
+    }
+}
+impl std::error::Error for PluginError {}
+impl From<PluginError> for GptClientError {
+    fn from(e: PluginError) -> Self {
+        GptClientError::new(
+            e.to_string(),
+            GptClientErrorKind::FunctionError(e.to_string()),
+        )
+    }
+}
+
+struct PluginProcess {
+    name: String,
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+    timeout: Duration,
+    // once crashed or timed out, the plugin is not called again
+    dead: Option<String>,
+}
+
+impl PluginProcess {
+    fn spawn(path: &Path, timeout: Duration) -> Result<Self, PluginError> {
+        let name = plugin_name(path);
+        let mut child = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // logs of the plugin are shown as they are
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| PluginError::new(&name, format!("can not start : {}", e)))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (tx, lines) = mpsc::channel();
+        // reading in other thread makes timeout possible
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    return;
+                };
+                if tx.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            name,
+            child,
+            stdin,
+            lines,
+            timeout,
+            dead: None,
+        })
+    }
+    fn call(&mut self, request: &PluginRequest) -> Result<serde_json::Value, PluginError> {
+        if let Some(reason) = self.dead.as_ref() {
+            return Err(PluginError::new(&self.name, reason.clone()));
+        }
+        let line = serde_json::to_string(request).unwrap_or_default();
+        if writeln!(self.stdin, "{}", line)
+            .and_then(|_| self.stdin.flush())
+            .is_err()
+        {
+            let reason = self.exit_reason();
+            return Err(self.die(reason));
+        }
+        let line = match self.lines.recv_timeout(self.timeout) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => {
+                self.child.kill().unwrap_or_default();
+                return Err(self.die(format!(
+                    "{} timed out after {}ms",
+                    request.hook(),
+                    self.timeout.as_millis()
+                )));
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                let reason = self.exit_reason();
+                return Err(self.die(reason));
+            }
+        };
+        let response = serde_json::from_str::<PluginResponse>(&line).map_err(|e| {
+            PluginError::new(
+                &self.name,
+                format!("invalid response to {} : {} : {}", request.hook(), e, line),
+            )
+        })?;
+        match response.error {
+            Some(error) => Err(PluginError::new(
+                &self.name,
+                format!("{} failed : {}", request.hook(), error),
+            )),
+            None => Ok(response.result),
+        }
+    }
+    // a plugin may close stdout and keep running, so it is killed before wait
+    fn exit_reason(&mut self) -> String {
+        if let Ok(Some(status)) = self.child.try_wait() {
+            return format!("exited with {}", status);
+        }
+        self.child.kill().unwrap_or_default();
+        match self.child.wait() {
+            Ok(status) => format!("exited with {}", status),
+            Err(e) => format!("exited : {}", e),
+        }
+    }
+    fn die(&mut self, reason: String) -> PluginError {
+        self.dead = Some(reason.clone());
+        PluginError::new(&self.name, reason)
+    }
+}
+
+impl Drop for PluginProcess {
+    fn drop(&mut self) {
+        self.child.kill().unwrap_or_default();
+        self.child.wait().map(|_| ()).unwrap_or_default();
+    }
+}
+
+pub struct PluginFunction {
+    description: PluginDescription,
+    process: RefCell<PluginProcess>,
+    // hooks like input_to_messages can not return an error. it is reported by the next hook that can
+    error: RefCell<Option<PluginError>>,
+}
+
+impl PluginFunction {
+    pub fn spawn(path: impl AsRef<Path>, timeout: Duration) -> Result<Self, PluginError> {
+        let mut process = PluginProcess::spawn(path.as_ref(), timeout)?;
+        let description = process.call(&PluginRequest::Describe).and_then(|result| {
+            serde_json::from_value::<PluginDescription>(result).map_err(|e| {
+                PluginError::new(&process.name, format!("invalid description : {}", e))
+            })
+        })?;
+        Ok(Self {
+            description,
+            process: RefCell::new(process),
+            error: RefCell::new(None),
+        })
+    }
+    pub fn description(&self) -> &PluginDescription {
+        &self.description
+    }
+    fn has_hook(&self, hook: &str) -> bool {
+        self.description.hooks.contains(hook)
+    }
+    fn call(&self, request: &PluginRequest) -> Result<serde_json::Value, PluginError> {
+        self.process.borrow_mut().call(request)
+    }
+    fn keep_error(&self, e: PluginError) {
+        self.error.borrow_mut().get_or_insert(e);
+    }
+    fn invalid_result(&self, hook: &str, e: serde_json::Error) -> PluginError {
+        PluginError::new(
+            &self.description.name,
+            format!("invalid result of {} : {}", hook, e),
+        )
+    }
+}
+
+impl GptFunction for PluginFunction {
+    // file name of the plugin. same as the name to enable it
+    fn name(&self) -> String {
+        self.process.borrow().name.clone()
+    }
+    fn description(&self) -> String {
+        self.description.description.clone().unwrap_or_default()
+    }
+    fn setup_for_action(&mut self, input: &UserInput) {
+        self.error.replace(None);
+        if self.has_hook("setup_for_action") {
+            if let Err(e) = self.call(&PluginRequest::SetupForAction {
+                input: input.content(),
+            }) {
+                self.keep_error(e);
+            }
+        }
+    }
+    fn can_action(&self) -> bool {
+        if !self.has_hook("can_action") {
+            return false;
+        }
+        match self.call(&PluginRequest::CanAction).and_then(|result| {
+            serde_json::from_value(result).map_err(|e| self.invalid_result("can_action", e))
+        }) {
+            Ok(can_action) => can_action,
+            Err(e) => {
+                self.keep_error(e);
+                false
+            }
+        }
+    }
+    fn input_to_messages(&self, input: UserInput) -> Vec<Message> {
+        // no message is sent to gpt when the plugin is already broken
+        if self.error.borrow().is_some() {
+            return Vec::new();
+        }
+        if !self.has_hook("input_to_messages") {
+            return input.to_messages();
+        }
+        match self
+            .call(&PluginRequest::InputToMessages {
+                input: input.content(),
+            })
+            .and_then(|result| {
+                serde_json::from_value(result)
+                    .map_err(|e| self.invalid_result("input_to_messages", e))
+            }) {
+            Ok(messages) => messages,
+            Err(e) => {
+                self.keep_error(e);
+                Vec::new()
+            }
+        }
+    }
+    fn handle_stream(&mut self, response: &ChatResponse) -> HandleResult {
+        if let Some(e) = self.error.borrow().clone() {
+            return HandleResult::Err(e.into());
+        }
+        if !self.has_hook("handle_stream") {
+            return HandleResult::from(response);
+        }
+        let result = self.call(&PluginRequest::HandleStream {
+            delta: response.delta_content(),
+            done: response.is_done(),
+        });
+        match result.as_ref().map(|result| result.as_str()) {
+            Ok(Some("done")) => HandleResult::Done,
+            Ok(Some("progress")) | Ok(None) => HandleResult::from(response),
+            Ok(Some(other)) => HandleResult::Err(
+                PluginError::new(
+                    &self.description.name,
+                    format!("unknown result of handle_stream : {}", other),
+                )
+                .into(),
+            ),
+            Err(e) => HandleResult::Err(e.clone().into()),
+        }
+    }
+    fn action_at_end(&mut self) -> Result<(), Box<dyn std::error::Error + 'static>> {
+        if let Some(e) = self.error.replace(None) {
+            return Err(Box::new(e));
+        }
+        if self.has_hook("action_at_end") {
+            self.call(&PluginRequest::ActionAtEnd)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn plugin_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+// executable files directly under the dir
+pub fn discover(dir: &str) -> std::io::Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut plugins = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_executable(path))
+        .collect::<Vec<_>>();
+    plugins.sort();
+    Ok(plugins)
+}
+
+pub fn find(dir: &str, name: &str) -> std::io::Result<Option<PathBuf>> {
+    Ok(discover(dir)?
+        .into_iter()
+        .find(|path| plugin_name(path) == name))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|metadata| metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    true
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::{
+        functions::common::test_tool::TestFileFactory,
+        gpt::client::{Message, Role},
+    };
+
+    use super::*;
+    fn create_plugin(test_file: &TestFileFactory, root: &str, name: &str, script: &str) -> String {
+        test_file.create_file_under_root(name, script);
+        let path = format!("{}/{}", root, name);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+    const ECHO_PLUGIN: &str = r#"#!/bin/sh
+while read -r line; do
+  case "$line" in
+    *'"hook":"describe"'*) echo '{"result":{"name":"echo","hooks":["can_action","input_to_messages","handle_stream"]}}' ;;
+    *'"hook":"can_action"'*) echo '{"result":true}' ;;
+    *'"hook":"input_to_messages"'*) echo '{"result":[{"role":"user","content":"from plugin"}]}' ;;
+    *'"done":true'*) echo '{"result":"done"}' ;;
+    *) echo '{"result":"progress"}' ;;
+  esac
+done
+"#;
+    #[test]
+    fn プラグインのフックをjsonでやりとりする() {
+        let root = "tmp_plugin_echo";
+        let test_file = TestFileFactory::create(root);
+        let path = create_plugin(&test_file, root, "echo.sh", ECHO_PLUGIN);
+
+        let mut plugin = PluginFunction::spawn(&path, Duration::from_secs(5)).unwrap();
+        plugin.setup_for_action(&UserInput::new("hello"));
+        let can_action = plugin.can_action();
+        let messages = plugin.input_to_messages(UserInput::new("hello"));
+        let progress = plugin.handle_stream(&ChatResponse::DeltaContent("hi".to_string()));
+        let done = plugin.handle_stream(&ChatResponse::Done);
+        let end = plugin.action_at_end();
+        let discovered = discover(root).unwrap();
+
+        test_file.remove_dir_all();
+        assert_eq!(discovered, vec![PathBuf::from(path)]);
+        assert_eq!(plugin.description().name, "echo");
+        assert!(can_action);
+        assert_eq!(messages, vec![Message::new(Role::User, "from plugin")]);
+        assert_eq!(progress, HandleResult::Progress);
+        assert_eq!(done, HandleResult::Done);
+        assert!(end.is_ok());
+    }
+    #[test]
+    fn 応答しないプラグインはタイムアウトのエラーになる() {
+        let root = "tmp_plugin_timeout";
+        let test_file = TestFileFactory::create(root);
+        let path = create_plugin(&test_file, root, "slow.sh", "#!/bin/sh\nsleep 5\n");
+
+        let result = PluginFunction::spawn(&path, Duration::from_millis(100));
+
+        test_file.remove_dir_all();
+        let e = result.err().unwrap();
+        assert!(e.to_string().contains("timed out"));
+    }
+    #[test]
+    fn 途中で終了したプラグインのエラーはaction_at_endで報告する() {
+        let root = "tmp_plugin_crash";
+        let test_file = TestFileFactory::create(root);
+        let path = create_plugin(
+            &test_file,
+            root,
+            "crash.sh",
+            r#"#!/bin/sh
+read -r line
+echo '{"result":{"name":"crash","hooks":["input_to_messages"]}}'
+exit 3
+"#,
+        );
+
+        let mut plugin = PluginFunction::spawn(&path, Duration::from_secs(5)).unwrap();
+        plugin.setup_for_action(&UserInput::new("hello"));
+        let messages = plugin.input_to_messages(UserInput::new("hello"));
+        let end = plugin.action_at_end();
+
+        test_file.remove_dir_all();
+        assert!(messages.is_empty());
+        assert!(end.unwrap_err().to_string().contains("exited with"));
+    }
+    #[test]
+    fn 標準出力を閉じたプラグインは終了を待たずに止める() {
+        let root = "tmp_plugin_closed";
+        let test_file = TestFileFactory::create(root);
+        let path = create_plugin(
+            &test_file,
+            root,
+            "closed.sh",
+            "#!/bin/sh\nexec >&-\nsleep 5\n",
+        );
+        let started = std::time::Instant::now();
+
+        let result = PluginFunction::spawn(&path, Duration::from_secs(10));
+
+        test_file.remove_dir_all();
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,259 @@
+use std::{str::FromStr, time::Duration};
+
+#[cfg(target_os = "macos")]
+use super::speaker::MacSpeaker;
+use super::{
+    code_capture::GptCodeCapture,
+    code_reviewer::{options::ReviewOptions, CodeReviewer},
+    commit_message::{CommitMessage, CommitOptions},
+    config_function::{ConfigFunction, FunctionDefinition},
+    plugin::{self, PluginFunction},
+    translator::{FileTranslator, TranslateMode, Translator},
+    GptDefaultFunction, GptFunction,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionKind {
+    Builtin,
+    Config,
+    Plugin,
+}
+impl FunctionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Builtin => "builtin",
+            Self::Config => "config",
+            Self::Plugin => "plugin",
+        }
+    }
+}
+
+// args is the part after ':' of "name:args". e.g. "translate:ja"
+// Send and Sync, so batch creates functions in its workers
+type Constructor = Box<dyn Fn(Option<&str>) -> Result<Box<dyn GptFunction>, String> + Send + Sync>;
+
+struct RegistryEntry {
+    name: String,
+    kind: FunctionKind,
+    // used instead of creating the function. e.g. a plugin is not started to be listed
+    description: Option<String>,
+    constructor: Constructor,
+}
+
+// every function which can be enabled by name, from --with or repl
+pub struct FunctionRegistry {
+    entries: Vec<RegistryEntry>,
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        Self::with_builtins()
+    }
+}
+
+impl FunctionRegistry {
+    const PLUGIN_DESCRIPTION: &'static str = "plugin. see `termai plugin list` for its description";
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+    pub fn with_builtins() -> Self {
+        Self::with_options(&ReviewOptions::default(), &CommitOptions::default())
+    }
+    // builtins with [review] and [commit] of config.toml
+    pub fn with_options(review: &ReviewOptions, commit: &CommitOptions) -> Self {
+        let mut registry = Self::new();
+        registry.register("ask", FunctionKind::Builtin, |_| {
+            Ok(Box::new(GptDefaultFunction::new()))
+        });
+        registry.register("translate", FunctionKind::Builtin, |args| {
+            let mode = args.map(TranslateMode::from_str).transpose()?;
+            Ok(Box::new(mode.map(Translator::new).unwrap_or_default()))
+        });
+        registry.register("translate-file", FunctionKind::Builtin, |_| {
+            Ok(Box::new(FileTranslator::new()))
+        });
+        // args is the language of the review. e.g. "review:English"
+        let review = review.clone();
+        registry.register("review", FunctionKind::Builtin, move |args| {
+            let reviewer = CodeReviewer::default().with_options(&review);
+            Ok(Box::new(match args {
+                Some(language) => reviewer.with_language(language),
+                None => reviewer,
+            }))
+        });
+        // args is the language of the message. e.g. "commit:Japanese"
+        let language = commit.language.clone();
+        registry.register("commit", FunctionKind::Builtin, move |args| {
+            Ok(Box::new(
+                CommitMessage::default().with_language(args.unwrap_or(&language)),
+            ))
+        });
+        // args is the dir to write captured code
+        registry.register("capture", FunctionKind::Builtin, |args| {
+            Ok(Box::new(GptCodeCapture::new_with_file_writer(
+                args.unwrap_or("."),
+            )))
+        });
+        #[cfg(target_os = "macos")]
+        registry.register("speaker", FunctionKind::Builtin, |_| {
+            Ok(Box::new(MacSpeaker::default()))
+        });
+        registry
+    }
+    // a function registered later replaces the one with the same name. e.g. config over builtin
+    pub fn register<F>(&mut self, name: impl Into<String>, kind: FunctionKind, constructor: F)
+    where
+        F: Fn(Option<&str>) -> Result<Box<dyn GptFunction>, String> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        self.entries.retain(|entry| entry.name != name);
+        self.entries.push(RegistryEntry {
+            name,
+            kind,
+            description: None,
+            constructor: Box::new(constructor),
+        });
+    }
+    // args of a config function overrides its lang
+    pub fn add_config_functions(&mut self, definitions: &[FunctionDefinition]) {
+        for definition in definitions {
+            let definition = definition.clone();
+            self.register(definition.name.clone(), FunctionKind::Config, move |args| {
+                let mut function = ConfigFunction::new(definition.clone())?;
+                if let Some(lang) = args {
+                    function.set_lang(lang);
+                }
+                Ok(Box::new(function))
+            });
+        }
+    }
+    // plugins are started only when they are enabled.
+    // the description is known only by the handshake, so it is not listed
+    pub fn add_plugins(&mut self, dir: &str, timeout: Duration) -> std::io::Result<()> {
+        for path in plugin::discover(dir)? {
+            let name = plugin::plugin_name(&path);
+            self.register(name, FunctionKind::Plugin, move |_| {
+                PluginFunction::spawn(&path, timeout)
+                    .map(|f| Box::new(f) as Box<dyn GptFunction>)
+                    .map_err(|e| e.to_string())
+            });
+            if let Some(entry) = self.entries.last_mut() {
+                entry.description = Some(Self::PLUGIN_DESCRIPTION.to_string());
+            }
+        }
+        Ok(())
+    }
+    // spec is "name" or "name:args"
+    pub fn create(&self, spec: &str) -> Result<Box<dyn GptFunction>, String> {
+        let (name, args) = match spec.split_once(':') {
+            Some((name, args)) => (name, Some(args)),
+            None => (spec, None),
+        };
+        self.create_with_args(name, args)
+    }
+    pub fn create_with_args(
+        &self,
+        name: &str,
+        args: Option<&str>,
+    ) -> Result<Box<dyn GptFunction>, String> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| format!("function {} is not found", name))?;
+        (entry.constructor)(args)
+    }
+    pub fn kind(&self, name: &str) -> Option<FunctionKind> {
+        self.entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.kind)
+    }
+    // the function is created only when the entry has no description
+    pub fn describe(&self, name: &str) -> Result<String, String> {
+        match self.entries.iter().find(|entry| entry.name == name) {
+            Some(RegistryEntry {
+                description: Some(description),
+                ..
+            }) => Ok(description.clone()),
+            _ => self.create(name).map(|function| function.description()),
+        }
+    }
+    pub fn entries(&self) -> Vec<(&str, FunctionKind)> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.kind))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn 名前と引数から関数を作成する() {
+        let registry = FunctionRegistry::with_builtins();
+
+        let translator = registry.create("translate:ja").unwrap();
+        let reviewer = registry.create("review").unwrap();
+
+        assert_eq!(translator.name(), "translate");
+        assert_eq!(reviewer.name(), "review");
+        assert!(registry.create("translate:unknown").is_err());
+        assert!(registry.create("unknown").is_err());
+    }
+    #[test]
+    fn 設定で定義した関数は同じ名前の組み込み関数を置き換える() {
+        let mut registry = FunctionRegistry::with_builtins();
+        let definition = toml::from_str::<FunctionDefinition>(
+            r#"
+            name = "review"
+            description = "my review"
+            "#,
+        )
+        .unwrap();
+
+        registry.add_config_functions(&[definition]);
+        let review = registry.create("review:English").unwrap();
+
+        assert_eq!(review.description(), "my review");
+        assert_eq!(
+            registry
+                .entries()
+                .into_iter()
+                .filter(|(name, _)| *name == "review")
+                .collect::<Vec<_>>(),
+            vec![("review", FunctionKind::Config)]
+        );
+    }
+    #[cfg(unix)]
+    #[test]
+    fn プラグインは起動せずに一覧に出す() {
+        use crate::functions::common::test_tool::TestFileFactory;
+        use std::os::unix::fs::PermissionsExt;
+        let test_file = TestFileFactory::create("tmp_registry_plugins");
+        test_file.create_file_under_root(
+            "started.sh",
+            "#!/bin/sh\ntouch tmp_registry_plugins/started\n",
+        );
+        let path = "tmp_registry_plugins/started.sh";
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut registry = FunctionRegistry::with_builtins();
+        registry
+            .add_plugins("tmp_registry_plugins", Duration::from_secs(1))
+            .unwrap();
+        let description = registry.describe("started");
+        let started = std::path::Path::new("tmp_registry_plugins/started").exists();
+        test_file.remove_dir_all();
+
+        assert_eq!(registry.kind("started"), Some(FunctionKind::Plugin));
+        assert_eq!(
+            description,
+            Ok(FunctionRegistry::PLUGIN_DESCRIPTION.to_string())
+        );
+        assert!(!started);
+    }
+}
@@ -3,15 +3,17 @@ use std::{io::Write, thread};
 use crate::gpt::{
     chat::ChatGpt,
     client::{GptClientError, GptClientErrorKind, Message, OpenAIModel},
+    tool::Tools,
 };
 
-use super::{GptFunction, GptFunctionContainer, UserInput};
+use super::{registry::FunctionRegistry, GptFunction, GptFunctionContainer, UserInput};
 
 pub struct ChatGptRepl {
     chat_gpt: ChatGpt,
     display_gpt: String,
     display_user: String,
     container: GptFunctionContainer,
+    registry: FunctionRegistry,
 }
 impl Default for ChatGptRepl {
     fn default() -> Self {
@@ -25,6 +27,7 @@ impl ChatGptRepl {
             display_gpt: Self::display_gpt_from_env(),
             display_user: Self::display_user_from_env(),
             container: GptFunctionContainer::new(),
+            registry: FunctionRegistry::with_builtins(),
         }
     }
     pub fn new_with_functions(gpt: ChatGpt, functions: GptFunctionContainer) -> Self {
@@ -33,8 +36,17 @@ impl ChatGptRepl {
             display_gpt: Self::display_gpt_from_env(),
             display_user: Self::display_user_from_env(),
             container: functions,
+            registry: FunctionRegistry::with_builtins(),
         }
     }
+    // functions which can be enabled by /enable
+    pub fn set_registry(&mut self, registry: FunctionRegistry) {
+        self.registry = registry;
+    }
+    // e.g. tools of mcp servers
+    pub fn set_tools(&mut self, tools: Tools) {
+        self.chat_gpt.set_tools(tools);
+    }
     fn display_user_from_env() -> String {
         std::env::var("USER").unwrap_or_else(|_| "you".to_string())
     }
@@ -70,6 +82,10 @@ impl ChatGptRepl {
                 println!("clear chat history");
                 continue;
             }
+            if let Some(command) = ReplCommand::parse(&message) {
+                self.exec_command(command);
+                continue;
+            }
             let mes = message.clone();
             let handle = thread::spawn(move || {
                 input_fn(&mes);
@@ -102,6 +118,10 @@ impl ChatGptRepl {
                 println!("clear chat history");
                 continue;
             }
+            if let Some(command) = ReplCommand::parse(&message) {
+                self.exec_command(command);
+                continue;
+            }
             let input = UserInput::new(&message);
 
             self.gpt_first();
@@ -116,7 +136,9 @@ impl ChatGptRepl {
     }
 
     fn chat(&mut self, model: OpenAIModel, message: &Message) -> Result<(), GptClientError> {
-        self.chat_gpt.chat(model, &message, &mut |res| {
+        let mut req = self.chat_gpt.make_request(model, message);
+        self.container.change_request(&mut req);
+        self.chat_gpt.send(req, &mut |res| {
             Self::gpt_message(res.delta_content());
             self.container.handle_stream(res)
         })
@@ -169,6 +191,41 @@ impl ChatGptRepl {
         std::io::stdin().read_line(&mut message)?;
         Ok(message)
     }
+    fn exec_command(&mut self, command: ReplCommand) {
+        match command {
+            ReplCommand::Functions => {
+                let enabled = self.container.names();
+                for (name, kind) in self.registry.entries() {
+                    let mark = if enabled.iter().any(|n| n == name) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    println!("{} {:<16} {}", mark, name, kind.as_str());
+                }
+            }
+            ReplCommand::Enable(spec) => match self.registry.create(&spec) {
+                Ok(function) => {
+                    println!("enable {}", function.name());
+                    self.container.add_functions(function);
+                }
+                Err(e) => println!("{}", e),
+            },
+            ReplCommand::Disable(name) => {
+                if self.container.remove_functions(&name) {
+                    println!("disable {}", name);
+                } else {
+                    println!("{} is not enabled", name);
+                }
+            }
+            ReplCommand::Unknown(command) => {
+                println!(
+                    "unknown command {}. /functions, /enable <name[:args]> or /disable <name>",
+                    command
+                );
+            }
+        }
+    }
     fn is_clear(message: &str) -> bool {
         message == "clear\n"
     }
@@ -187,3 +244,53 @@ impl ChatGptRepl {
         println!();
     }
 }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ReplCommand {
+    Functions,
+    Enable(String),
+    Disable(String),
+    Unknown(String),
+}
+impl ReplCommand {
+    // commands start with '/'. other inputs are sent to gpt
+    fn parse(message: &str) -> Option<Self> {
+        let message = message.trim().strip_prefix('/')?;
+        let (command, arg) = message
+            .split_once(' ')
+            .map(|(command, arg)| (command, arg.trim()))
+            .unwrap_or((message, ""));
+        let command = match (command, arg) {
+            ("functions", _) => Self::Functions,
+            ("enable", arg) if !arg.is_empty() => Self::Enable(arg.to_string()),
+            ("disable", arg) if !arg.is_empty() => Self::Disable(arg.to_string()),
+            (command, _) => Self::Unknown(command.to_string()),
+        };
+        Some(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn スラッシュで始まる入力はreplのコマンドになる() {
+        assert_eq!(
+            ReplCommand::parse("/functions\n"),
+            Some(ReplCommand::Functions)
+        );
+        assert_eq!(
+            ReplCommand::parse("/enable translate:ja\n"),
+            Some(ReplCommand::Enable("translate:ja".to_string()))
+        );
+        assert_eq!(
+            ReplCommand::parse("/disable review\n"),
+            Some(ReplCommand::Disable("review".to_string()))
+        );
+        assert_eq!(
+            ReplCommand::parse("/enable\n"),
+            Some(ReplCommand::Unknown("enable".to_string()))
+        );
+        assert_eq!(ReplCommand::parse("hello /enable\n"), None);
+    }
+}
@@ -20,6 +20,12 @@ impl MacSpeaker {
     }
 }
 impl GptFunction for MacSpeaker {
+    fn name(&self) -> String {
+        "speaker".to_string()
+    }
+    fn description(&self) -> String {
+        "speak the answer by say command".to_string()
+    }
     fn can_action(&self) -> bool {
         true
     }
@@ -32,6 +38,9 @@ impl GptFunction for MacSpeaker {
                 self.inner.push_str(content.as_str());
                 crate::gpt::client::HandleResult::Progress
             }
+            crate::gpt::client::ChatResponse::ToolCallDelta(_) => {
+                crate::gpt::client::HandleResult::Progress
+            }
             crate::gpt::client::ChatResponse::Done => crate::gpt::client::HandleResult::Done,
         }
     }
@@ -4,7 +4,7 @@ use crate::gpt::client::{HandleResult, Message};
 
 use super::{
     common::{get_file_content, is_file_path},
-    GptFunction, UserInput,
+    FunctionArtifact, GptFunction, UserInput,
 };
 
 #[derive(Debug, PartialEq, Eq)]
@@ -34,6 +34,12 @@ impl Default for Translator {
     }
 }
 impl GptFunction for Translator {
+    fn name(&self) -> String {
+        "translate".to_string()
+    }
+    fn description(&self) -> String {
+        "translate the input. args is ja, en, ko or ch".to_string()
+    }
     fn input_to_messages(&self, input: super::UserInput) -> Vec<Message> {
         let add_prefix = |mut message: Message| -> Message {
             let content = Lang::from(message.content.as_str());
@@ -68,6 +74,7 @@ pub struct FileTranslator {
     source_path: String,
     do_action: bool,
     inner: String,
+    translated: Option<String>,
 }
 impl Default for FileTranslator {
     fn default() -> Self {
@@ -82,6 +89,7 @@ impl FileTranslator {
             do_action: false,
             source_path: String::new(),
             inner: String::new(),
+            translated: None,
         }
     }
     fn append_result(&mut self) -> Result<(), std::io::Error> {
@@ -101,7 +109,14 @@ impl FileTranslator {
     }
 }
 impl GptFunction for FileTranslator {
+    fn name(&self) -> String {
+        "translate-file".to_string()
+    }
+    fn description(&self) -> String {
+        "translate the file into japanese and append it to the file".to_string()
+    }
     fn setup_for_action(&mut self, input: &super::UserInput) {
+        self.translated = None;
         if is_file_path(input.content()) {
             self.do_action = true;
             self.source_path = input.content().trim().to_string();
@@ -132,10 +147,18 @@ impl GptFunction for FileTranslator {
     fn action_at_end(&mut self) -> Result<(), Box<dyn std::error::Error + 'static>> {
         if self.can_action() {
             self.append_result()?;
+            let translated = self.source_path.clone();
             *self = Self::new();
+            self.translated = Some(translated);
         }
         Ok(())
     }
+    fn artifacts(&self) -> Vec<FunctionArtifact> {
+        self.translated
+            .iter()
+            .map(|path| FunctionArtifact::TranslatedFile { path: path.clone() })
+            .collect()
+    }
     fn handle_stream(
         &mut self,
         response: &crate::gpt::client::ChatResponse,
@@ -146,6 +169,9 @@ impl GptFunction for FileTranslator {
                     self.inner.push_str(content);
                     crate::gpt::client::HandleResult::Progress
                 }
+                crate::gpt::client::ChatResponse::ToolCallDelta(_) => {
+                    crate::gpt::client::HandleResult::Progress
+                }
                 crate::gpt::client::ChatResponse::Done => crate::gpt::client::HandleResult::Done,
             }
         } else {
@@ -228,6 +254,13 @@ mod tests {
         );
     }
     #[test]
+    fn 翻訳先の言語を略称から決める() {
+        use std::str::FromStr;
+        assert_eq!(TranslateMode::from_str("ja"), Ok(TranslateMode::ToJapanese));
+        assert_eq!(TranslateMode::from_str("ch"), Ok(TranslateMode::ToChinese));
+        assert!(TranslateMode::from_str("jp").is_err());
+    }
+    #[test]
     fn 英語か日本語か判断可能() {
         let en = "hello ";
         assert_eq!(Lang::from(en), Lang::English(en.to_string()));
@@ -331,6 +364,12 @@ mod tests {
         test_file.remove_dir_all();
         assert_eq!(content, "hello\nこんにちは");
         assert_eq!(sut.can_action(), false);
+        assert_eq!(
+            sut.artifacts(),
+            vec![FunctionArtifact::TranslatedFile {
+                path: "tmp/hello.txt".to_string()
+            }]
+        );
     }
     #[test]
     fn actionがoffであれば何もしない() {
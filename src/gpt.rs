@@ -1,2 +1,5 @@
+pub mod cache;
 pub mod chat;
 pub mod client;
+pub mod key;
+pub mod tool;
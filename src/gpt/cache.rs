@@ -0,0 +1,281 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+use super::client::{ChatRequest, CompletionMeta, Usage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    // read and write
+    Use,
+    // do not read, but overwrite by the new response
+    Refresh,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CacheEntry {
+    pub key: String,
+    pub created_at: u64,
+    pub model: String,
+    pub prompt_preview: String,
+    pub deltas: Vec<String>,
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+}
+impl CacheEntry {
+    pub fn meta(&self) -> CompletionMeta {
+        CompletionMeta {
+            model: Some(self.model.clone()),
+            finish_reason: self.finish_reason.clone(),
+            usage: self.usage,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    ttl: Duration,
+    max_bytes: u64,
+}
+
+impl ResponseCache {
+    const PREVIEW_LEN: usize = 60;
+    pub fn new(dir: impl Into<PathBuf>, ttl: Duration, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            ttl,
+            max_bytes,
+        }
+    }
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    // model, messages and sampling params are all in the serialized request
+    pub fn key(req: &ChatRequest) -> String {
+        let serialized = serde_json::to_string(req).unwrap_or_default();
+        Sha256::digest(serialized.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+    pub fn get(&self, req: &ChatRequest) -> Option<CacheEntry> {
+        let entry = self.read(&self.path(&Self::key(req)))?;
+        if self.is_expired(&entry) {
+            return None;
+        }
+        Some(entry)
+    }
+    pub fn put(
+        &self,
+        req: &ChatRequest,
+        deltas: Vec<String>,
+        meta: &CompletionMeta,
+    ) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let key = Self::key(req);
+        let entry = CacheEntry {
+            key: key.clone(),
+            created_at: now(),
+            model: meta
+                .model
+                .clone()
+                .unwrap_or_else(|| req.model_name().to_string()),
+            prompt_preview: req
+                .messages()
+                .last()
+                .map(|message| message.content.chars().take(Self::PREVIEW_LEN).collect())
+                .unwrap_or_default(),
+            deltas,
+            finish_reason: meta.finish_reason.clone(),
+            usage: meta.usage,
+        };
+        std::fs::write(
+            self.path(&key),
+            serde_json::to_string(&entry).unwrap_or_default(),
+        )?;
+        self.shrink()
+    }
+    // entries sorted from the newest, with file size
+    pub fn entries(&self) -> std::io::Result<Vec<(CacheEntry, u64)>> {
+        let mut entries = match std::fs::read_dir(&self.dir) {
+            Ok(dir) => dir
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+                .filter_map(|entry| {
+                    let size = entry.metadata().ok()?.len();
+                    Some((self.read(&entry.path())?, size))
+                })
+                .collect::<Vec<_>>(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        entries.sort_by_key(|(entry, _)| std::cmp::Reverse(entry.created_at));
+        Ok(entries)
+    }
+    // returns the number of removed entries
+    pub fn purge(&self, only_expired: bool) -> std::io::Result<usize> {
+        let mut removed = 0;
+        for (entry, _) in self.entries()? {
+            if (!only_expired || self.is_expired(&entry)) && self.remove(&entry.key)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+    pub fn is_expired(&self, entry: &CacheEntry) -> bool {
+        now().saturating_sub(entry.created_at) > self.ttl.as_secs()
+    }
+    // entries are read only when the files are over the limit
+    fn shrink(&self) -> std::io::Result<()> {
+        let size = std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>();
+        if size <= self.max_bytes {
+            return Ok(());
+        }
+        let mut total = 0;
+        for (entry, size) in self.entries()? {
+            total += size;
+            if total > self.max_bytes || self.is_expired(&entry) {
+                self.remove(&entry.key)?;
+            }
+        }
+        Ok(())
+    }
+    // another process may remove the file first. returns false then
+    fn remove(&self, key: &str) -> std::io::Result<bool> {
+        match std::fs::remove_file(self.path(key)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    fn read(&self, path: &Path) -> Option<CacheEntry> {
+        let content = std::fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+// seconds since the unix epoch, as created_at of entries
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        functions::common::test_tool::TestFileFactory,
+        gpt::client::{Message, OpenAIModel, Role},
+    };
+
+    use super::*;
+    fn request(content: &str) -> ChatRequest {
+        ChatRequest::new(OpenAIModel::Gpt4o, vec![Message::new(Role::User, content)])
+    }
+    #[test]
+    fn 同じリクエストは同じキーになりメッセージが違えば別のキーになる() {
+        assert_eq!(
+            ResponseCache::key(&request("hello")),
+            ResponseCache::key(&request("hello"))
+        );
+        assert_ne!(
+            ResponseCache::key(&request("hello")),
+            ResponseCache::key(&request("hello!"))
+        );
+    }
+    #[test]
+    fn 保存したレスポンスを取得できる() {
+        let test_file = TestFileFactory::create("tmp_cache_get");
+        let cache = ResponseCache::new("tmp_cache_get", Duration::from_secs(60), 1024 * 1024);
+        let meta = CompletionMeta {
+            model: Some("gpt-4o".to_string()),
+            finish_reason: Some("stop".to_string()),
+            usage: None,
+        };
+
+        cache
+            .put(
+                &request("hello"),
+                vec!["he".to_string(), "llo".to_string()],
+                &meta,
+            )
+            .unwrap();
+        let hit = cache.get(&request("hello"));
+        let miss = cache.get(&request("bye"));
+
+        test_file.remove_dir_all();
+        let hit = hit.unwrap();
+        assert_eq!(hit.deltas, vec!["he".to_string(), "llo".to_string()]);
+        assert_eq!(hit.meta(), meta);
+        assert_eq!(hit.prompt_preview, "hello");
+        assert!(miss.is_none());
+    }
+    #[test]
+    fn 上限サイズを超えたら古いエントリから削除する() {
+        let test_file = TestFileFactory::create("tmp_cache_shrink");
+        let entry = |content: &str, age: u64| CacheEntry {
+            key: ResponseCache::key(&request(content)),
+            created_at: now() - age,
+            model: "gpt-4o".to_string(),
+            prompt_preview: content.to_string(),
+            deltas: vec![],
+            finish_reason: None,
+            usage: None,
+        };
+        let writer = ResponseCache::new("tmp_cache_shrink", Duration::from_secs(60), u64::MAX);
+        for old in [entry("old1", 20), entry("old2", 10)] {
+            std::fs::write(writer.path(&old.key), serde_json::to_string(&old).unwrap()).unwrap();
+        }
+        let size = std::fs::metadata(writer.path(&entry("old1", 20).key))
+            .unwrap()
+            .len();
+        // only one entry fits
+        let cache = ResponseCache::new("tmp_cache_shrink", Duration::from_secs(60), size * 3 / 2);
+
+        cache
+            .put(&request("new1"), vec![], &CompletionMeta::default())
+            .unwrap();
+        let entries = cache.entries().unwrap();
+        let removed = cache.remove(&entry("old1", 20).key).unwrap();
+
+        test_file.remove_dir_all();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|(entry, _)| entry.prompt_preview.as_str())
+                .collect::<Vec<_>>(),
+            vec!["new1"]
+        );
+        // already removed by another process
+        assert!(!removed);
+    }
+    #[test]
+    fn ttlを過ぎたエントリは期限切れになる() {
+        let cache = ResponseCache::new("none", Duration::from_secs(60), 0);
+        let entry = CacheEntry {
+            key: "key".to_string(),
+            created_at: now() - 120,
+            model: "gpt-4o".to_string(),
+            prompt_preview: String::new(),
+            deltas: vec![],
+            finish_reason: None,
+            usage: None,
+        };
+        assert!(cache.is_expired(&entry));
+        assert!(!cache.is_expired(&CacheEntry {
+            created_at: now(),
+            ..entry
+        }));
+    }
+}
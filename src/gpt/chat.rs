@@ -1,28 +1,48 @@
-use super::client::{
-    ChatRequest, ChatResponse, GptClient, GptClientOption, HandleResult, Message, OpenAIKey,
-    OpenAIModel, Result, Role,
+use std::cell::RefCell;
+
+use crate::config::Profile;
+
+use super::{
+    client::{
+        ChatRequest, ChatResponse, GptClient, GptClientOption, HandleResult, Message, OpenAIKey,
+        OpenAIModel, Result, Role,
+    },
+    tool::{ToolDefinition, Tools},
 };
 pub struct ChatGpt {
     client: GptClient,
     pub(crate) manager: ChatManager,
+    tools: Tools,
 }
 impl ChatGpt {
     pub fn new(key: OpenAIKey, option: GptClientOption) -> Result<Self> {
-        Ok(Self {
-            client: GptClient::new(key, option)?,
-            manager: ChatManager::new(),
-        })
+        Ok(Self::from_client(GptClient::new(key, option)?))
     }
     pub fn from_env() -> Result<Self> {
-        Ok(Self {
-            client: GptClient::from_env()?,
+        Ok(Self::from_client(GptClient::from_env()?))
+    }
+    pub fn from_profile(profile: &Profile) -> Result<Self> {
+        Ok(Self::from_client(GptClient::from_profile(profile)?))
+    }
+    pub fn from_client(client: GptClient) -> Self {
+        Self {
+            client,
             manager: ChatManager::new(),
-        })
+            tools: Tools::new(),
+        }
+    }
+    // the handler gets the arguments parsed from json
+    pub fn add_tool<F>(&mut self, definition: ToolDefinition, handler: F)
+    where
+        F: FnMut(&serde_json::Value) -> std::result::Result<String, String> + 'static,
+    {
+        self.tools.add(definition, handler);
+    }
+    pub fn set_tools(&mut self, tools: Tools) {
+        self.tools = tools;
     }
     pub fn re_connect(&mut self) -> Result<()> {
-        // TODO gen new client logic
-        self.client = GptClient::from_env()?;
-        Ok(())
+        self.client.re_connect()
     }
     pub fn chat<F: FnMut(&ChatResponse) -> HandleResult>(
         &mut self,
@@ -30,12 +50,35 @@ impl ChatGpt {
         message: &Message,
         f: &mut F,
     ) -> Result<()> {
+        let req = self.make_request(model, message);
+        self.send(req, f)
+    }
+    // the message is added to history. the request can be changed before send
+    pub fn make_request(&mut self, model: OpenAIModel, message: &Message) -> ChatRequest {
         self.manager.update_by_request(message);
-        let req = self.manager.make_request(model);
-        self.client.request_mut_fn(req, |res| {
-            self.manager.update_by_response(res);
-            f(res)
-        })
+        self.manager.make_request(model)
+    }
+    // tool calls and their results are kept in history
+    pub fn send<F: FnMut(&ChatResponse) -> HandleResult>(
+        &mut self,
+        req: ChatRequest,
+        f: &mut F,
+    ) -> Result<()> {
+        let manager = RefCell::new(&mut self.manager);
+        self.client.request_with_tools(
+            req,
+            &mut self.tools,
+            |res| {
+                manager.borrow_mut().update_by_response(res);
+                let result = f(res);
+                // stopped by f e.g. cancelled. the partial answer is kept in history
+                if result == HandleResult::Done && !res.is_done() {
+                    manager.borrow_mut().update_by_response(&ChatResponse::Done);
+                }
+                result
+            },
+            |message| manager.borrow_mut().update_by_tool_message(message),
+        )
     }
     pub fn chat_history(&self) -> &[Message] {
         self.manager.history.all()
@@ -43,6 +86,10 @@ impl ChatGpt {
     pub fn clear(&mut self) {
         self.manager.clear();
     }
+    // drops the messages after len e.g. those of a cancelled request
+    pub fn rollback(&mut self, len: usize) {
+        self.manager.rollback(len);
+    }
     pub fn last_request(&self) -> Option<&Message> {
         self.manager.last_request()
     }
@@ -65,12 +112,15 @@ impl ChatHistory {
     fn clear(&mut self) {
         self.inner.clear();
     }
+    fn truncate(&mut self, len: usize) {
+        self.inner.truncate(len);
+    }
+    // tool calls and results are between the request and the response
     fn last_request(&self) -> Option<&Message> {
-        if self.inner.len() < 2 {
-            self.inner.last()
-        } else {
-            self.inner.get(self.inner.len() - 2)
-        }
+        self.inner
+            .iter()
+            .rev()
+            .find(|message| message.role() == Role::User)
     }
     fn last_response(&self) -> Option<&str> {
         if self.inner.len() < 2 {
@@ -111,6 +161,11 @@ impl ChatManager {
     pub fn update_by_request(&mut self, message: &Message) {
         self.history.push_request(message.clone());
     }
+    // the text before tool calls is already in the tool call message
+    pub fn update_by_tool_message(&mut self, message: &Message) {
+        self.history.push_request(message.clone());
+        self.delta_store = DeltaContentStore::new();
+    }
     pub fn update_by_response(&mut self, res: &ChatResponse) {
         if res.is_done() {
             self.history.push_response(self.delta_store.all_content());
@@ -129,6 +184,10 @@ impl ChatManager {
         self.history.clear();
         self.delta_store = DeltaContentStore::new();
     }
+    pub fn rollback(&mut self, len: usize) {
+        self.history.truncate(len);
+        self.delta_store = DeltaContentStore::new();
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -151,6 +210,8 @@ impl DeltaContentStore {
 #[cfg(test)]
 mod tests {
 
+    use crate::gpt::tool::ToolCall;
+
     use super::*;
     #[test]
     #[ignore = "gpt3のapiを叩くので、テストはスキップ"]
@@ -167,6 +228,7 @@ mod tests {
                     HandleResult::Progress
                 }
                 ChatResponse::Done => HandleResult::Done,
+                ChatResponse::ToolCallDelta(_) => HandleResult::Progress,
             },
         )
         .unwrap();
@@ -182,6 +244,7 @@ mod tests {
                     HandleResult::Progress
                 }
                 ChatResponse::Done => HandleResult::Done,
+                ChatResponse::ToolCallDelta(_) => HandleResult::Progress,
             },
         )
         .unwrap();
@@ -203,6 +266,7 @@ mod tests {
                     HandleResult::Progress
                 }
                 ChatResponse::Done => HandleResult::Done,
+                ChatResponse::ToolCallDelta(_) => HandleResult::Progress,
             },
         )
         .unwrap();
@@ -260,6 +324,31 @@ mod tests {
         assert_eq!(sut.history, expect);
     }
     #[test]
+    fn ツール呼び出しと結果はhistoryに残し途中の回答は最後の回答に混ぜない() {
+        let mut sut = ChatManager::new();
+        sut.update_by_request(&Message::new(Role::User, "read a.rs"));
+        sut.update_by_response(&ChatResponse::DeltaContent("let me see".to_string()));
+
+        let message = Message::tool_calls(
+            "let me see",
+            vec![ToolCall::new("call_1", "read_file", "{}")],
+        );
+        sut.update_by_tool_message(&message);
+        sut.update_by_tool_message(&Message::tool_result("call_1", "fn main() {}"));
+        sut.update_by_response(&ChatResponse::DeltaContent("it is empty".to_string()));
+        sut.update_by_response(&ChatResponse::Done);
+
+        assert_eq!(
+            sut.history.all(),
+            &[
+                Message::new(Role::User, "read a.rs"),
+                message.clone(),
+                Message::tool_result("call_1", "fn main() {}"),
+                Message::new(Role::Assistant, "it is empty"),
+            ]
+        );
+    }
+    #[test]
     fn gptのsseレスポンスを保持可能() {
         let mut sut = DeltaContentStore::new();
         sut.push(&ChatResponse::DeltaContent("hello".to_string()));
@@ -287,6 +376,30 @@ mod tests {
         assert_eq!(chat_history.all(), vec![]);
     }
     #[test]
+    fn chat_managerは途中の履歴まで戻せる() {
+        let mut sut = ChatManager::new();
+        sut.update_by_request(&Message::new(Role::User, "こんにちは"));
+        sut.update_by_response(&ChatResponse::DeltaContent("hello".to_string()));
+        sut.update_by_response(&ChatResponse::Done);
+        sut.update_by_request(&Message::new(Role::User, "さようなら"));
+        sut.update_by_response(&ChatResponse::DeltaContent("good".to_string()));
+
+        sut.rollback(2);
+        sut.update_by_request(&Message::new(Role::User, "元気？"));
+        sut.update_by_response(&ChatResponse::DeltaContent("yes".to_string()));
+        sut.update_by_response(&ChatResponse::Done);
+
+        assert_eq!(
+            sut.history.all(),
+            &[
+                Message::new(Role::User, "こんにちは"),
+                Message::new(Role::Assistant, "hello"),
+                Message::new(Role::User, "元気？"),
+                Message::new(Role::Assistant, "yes"),
+            ]
+        );
+    }
+    #[test]
     fn historyの最後のデータを取得可能() {
         let mut chat_history = ChatHistory::new();
         chat_history.push_request(Message::new(Role::User, "hello"));
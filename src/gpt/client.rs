@@ -1,8 +1,15 @@
 use std::{
     fmt::{Debug, Display},
     marker::PhantomData,
+    str::FromStr,
+    sync::Arc,
 };
 
+use super::{
+    cache::{CacheMode, ResponseCache},
+    tool::{ToolCall, ToolCallAccumulator, ToolCallDelta, ToolDefinition, Tools},
+};
+use crate::config::Profile;
 use rsse::{
     client::{SseClient, SseClientBuilder},
     http::url::Url,
@@ -98,6 +105,7 @@ impl From<&ChatResponse> for HandleResult {
     fn from(res: &ChatResponse) -> Self {
         match res {
             ChatResponse::DeltaContent(_content) => HandleResult::Progress,
+            ChatResponse::ToolCallDelta(_deltas) => HandleResult::Progress,
             ChatResponse::Done => HandleResult::Done,
         }
     }
@@ -108,16 +116,36 @@ pub struct GptClientOption {
     proxy: Option<String>,
     ca_path: Option<String>,
 }
+impl GptClientOption {
+    pub fn from_profile(profile: &Profile) -> Self {
+        Self {
+            proxy: profile
+                .proxy()
+                .map(|s| s.to_string())
+                .or_else(proxy_from_env),
+            ca_path: profile
+                .ca_path()
+                .map(|s| s.to_string())
+                .or_else(root_ca_from_env),
+        }
+    }
+}
 
 pub struct GptClient {
     key: OpenAIKey,
+    option: GptClientOption,
     sse_client: SseClient<SseTlsConnector>,
+    last_completion: CompletionMeta,
+    cache: Option<(ResponseCache, CacheMode)>,
+    throttle: Option<Arc<dyn Fn() + Send + Sync>>,
 }
 impl GptClient {
     const URL: &'static str = "https://api.openai.com/v1/chat/completions";
+    // the model may keep calling tools. stop before it loops forever
+    const MAX_TOOL_ROUNDS: usize = 16;
     pub fn new(key: OpenAIKey, option: GptClientOption) -> Result<Self> {
         let mut builder = Self::client_builder();
-        if let Some(proxy) = option.proxy {
+        if let Some(proxy) = option.proxy.clone() {
             builder = builder
                 .proxy(&Url::from_str(&proxy).map_err(|_e| {
                     GptClientError::new(
@@ -132,7 +160,7 @@ impl GptClient {
                     )
                 })?;
         }
-        if let Some(ca) = option.ca_path {
+        if let Some(ca) = option.ca_path.clone() {
             builder = builder.add_ca(&ca).map_err(|_e| {
                 GptClientError::new(
                     "invalid ca".to_string(),
@@ -141,7 +169,14 @@ impl GptClient {
             })?;
         }
         let sse_client = builder.build();
-        Ok(Self { key, sse_client })
+        Ok(Self {
+            key,
+            option,
+            sse_client,
+            last_completion: CompletionMeta::default(),
+            cache: None,
+            throttle: None,
+        })
     }
     pub fn from_env() -> Result<Self> {
         let key = OpenAIKey::from_env()?;
@@ -154,14 +189,115 @@ impl GptClient {
             },
         )
     }
+    pub fn from_profile(profile: &Profile) -> Result<Self> {
+        let key = profile.key_source().resolve()?;
+        Self::new(key, GptClientOption::from_profile(profile))
+    }
     pub fn re_connect(&mut self) -> Result<()> {
-        *self = Self::from_env()?;
+        // key is not resolved again. key command or keyring may ask user for a passphrase.
+        let cache = self.cache.take();
+        let throttle = self.throttle.take();
+        *self = Self::new(self.key.clone(), self.option.clone())?;
+        self.cache = cache;
+        self.throttle = throttle;
         Ok(())
     }
+    // only request_mut_fn uses the cache
+    pub fn with_cache(mut self, cache: ResponseCache, mode: CacheMode) -> Self {
+        self.cache = Some((cache, mode));
+        self
+    }
+    // called before each request sent to the api, including retries and tool rounds.
+    // e.g. a rate limiter shared by workers. a cached response does not call it
+    pub fn with_throttle(mut self, throttle: Arc<dyn Fn() + Send + Sync>) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
     pub fn request_mut_fn<F: FnMut(&ChatResponse) -> HandleResult>(
         &mut self,
         request: ChatRequest,
         mut f: F,
+    ) -> Result<()> {
+        if let Some(entry) = self
+            .cache
+            .as_ref()
+            .filter(|(_, mode)| *mode == CacheMode::Use)
+            .and_then(|(cache, _)| cache.get(&request))
+        {
+            self.last_completion = entry.meta();
+            return replay(entry.deltas, f);
+        }
+        let mut deltas = Vec::new();
+        let mut is_done = false;
+        let mut has_tool_calls = false;
+        let result = self.request_without_cache(request.clone(), |res| {
+            deltas.push(res.delta_content().to_string());
+            is_done |= res.is_done();
+            has_tool_calls |= matches!(res, ChatResponse::ToolCallDelta(_));
+            f(res)
+        });
+        // only text is cached. tool calls can not be replayed
+        if let (Ok(()), true, false, Some((cache, _))) =
+            (&result, is_done, has_tool_calls, self.cache.as_ref())
+        {
+            deltas.retain(|delta| !delta.is_empty());
+            // a broken cache must not break the answer already shown
+            cache
+                .put(&request, deltas, &self.last_completion)
+                .unwrap_or_default();
+        }
+        result
+    }
+    // tool calls of the model are run by tools and the results are sent back until it answers.
+    // f sees Done only once at the end. on_message gets the tool call and the result messages
+    pub fn request_with_tools<F, M>(
+        &mut self,
+        mut request: ChatRequest,
+        tools: &mut Tools,
+        mut f: F,
+        mut on_message: M,
+    ) -> Result<()>
+    where
+        F: FnMut(&ChatResponse) -> HandleResult,
+        M: FnMut(&Message),
+    {
+        if tools.is_empty() {
+            return self.request_mut_fn(request, f);
+        }
+        request.set_tools(tools.definitions());
+        for _ in 0..Self::MAX_TOOL_ROUNDS {
+            let mut calls = ToolCallAccumulator::new();
+            let mut content = String::new();
+            self.request_mut_fn(request.clone(), |res| {
+                calls.push(res);
+                if res.is_done() && !calls.is_empty() {
+                    return HandleResult::Done;
+                }
+                content.push_str(res.delta_content());
+                f(res)
+            })?;
+            if calls.is_empty() {
+                return Ok(());
+            }
+            let calls = calls.into_calls();
+            let message = Message::tool_calls(content, calls.clone());
+            on_message(&message);
+            request.push_message(message);
+            for call in calls {
+                let result = Message::tool_result(call.id.clone(), tools.call(&call));
+                on_message(&result);
+                request.push_message(result);
+            }
+        }
+        Err(GptClientError::new(
+            format!("tools are called more than {} times", Self::MAX_TOOL_ROUNDS),
+            GptClientErrorKind::FunctionError("too many tool calls".to_string()),
+        ))
+    }
+    fn request_without_cache<F: FnMut(&ChatResponse) -> HandleResult>(
+        &mut self,
+        request: ChatRequest,
+        mut f: F,
     ) -> Result<()> {
         self.send_before(request);
         // TODO
@@ -169,9 +305,11 @@ impl GptClient {
         // So, the closure inside the request_mut_fn is not executed, and a flag is used to notify the did not response from GPT.
         // did_response is PATCH for this problem.
         let mut did_response = false;
+        let last_completion = &mut self.last_completion;
+        *last_completion = CompletionMeta::default();
         self.sse_client
             .send_mut_fn(|sse_response| {
-                let res = ChatResponse::from_sse(sse_response);
+                let res = ChatResponse::from_sse_with_meta(sse_response, last_completion);
                 did_response = true;
                 match res {
                     Ok(res) => {
@@ -211,7 +349,14 @@ impl GptClient {
         self.send_before(request);
         self.sse_client.send(handler).map_err(GptClientError::from)
     }
+    // model, finish reason and token usage of the last request_mut_fn
+    pub fn last_completion(&self) -> &CompletionMeta {
+        &self.last_completion
+    }
     fn send_before(&mut self, req: ChatRequest) {
+        if let Some(throttle) = self.throttle.as_ref() {
+            throttle();
+        }
         self.sse_client.post().bearer_auth(self.key.key()).json(req);
     }
     fn client_builder() -> SseClientBuilder {
@@ -219,6 +364,22 @@ impl GptClient {
     }
 }
 
+// cached response goes through the same handler as a live stream
+fn replay<F: FnMut(&ChatResponse) -> HandleResult>(deltas: Vec<String>, mut f: F) -> Result<()> {
+    let responses = deltas
+        .into_iter()
+        .map(ChatResponse::DeltaContent)
+        .chain(std::iter::once(ChatResponse::Done));
+    for res in responses {
+        match f(&res) {
+            HandleResult::Progress => {}
+            HandleResult::Done => return Ok(()),
+            HandleResult::Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
 fn root_ca_from_env() -> Option<String> {
     match std::env::var("CA_BUNDLE") {
         Ok(ca) => Some(ca),
@@ -248,24 +409,36 @@ fn proxy_from_env() -> Option<String> {
 pub enum ChatResponse {
     Done,
     DeltaContent(String),
+    // joined by ToolCallAccumulator
+    ToolCallDelta(Vec<ToolCallDelta>),
 }
 impl ChatResponse {
     const GPT_DONE: &'static str = "[DONE]";
     fn from_sse(sse_res: SseResponse) -> Result<Self> {
+        Self::from_sse_with_meta(sse_res, &mut CompletionMeta::default())
+    }
+    fn from_sse_with_meta(sse_res: SseResponse, meta: &mut CompletionMeta) -> Result<Self> {
         match sse_res {
             SseResponse::Data(data) => {
                 if data.starts_with(Self::GPT_DONE) {
                     return Ok(Self::Done);
                 };
                 match serde_json::from_str::<StreamChat>(&data) {
-                    Ok(chat) => Ok(Self::from(chat)),
+                    Ok(chat) => {
+                        meta.update(&chat);
+                        Ok(Self::from(chat))
+                    }
                     Err(e) => Err(GptClientError {
                         message: format!("Failed to parse chat response: {}", e),
                         kind: GptClientErrorKind::ParseError(data),
                     }),
                 }
             }
-            _ => todo!(),
+            // openai sends only data. event, id and retry are not expected
+            _ => Err(GptClientError {
+                message: "sse response other than data is not supported".to_string(),
+                kind: GptClientErrorKind::UnexpectedSseResponse,
+            }),
         }
     }
     pub fn is_done(&self) -> bool {
@@ -285,10 +458,51 @@ pub(crate) struct StreamChat {
     id: String,
     model: String,
     object: String,
+    // only the last chunk has usage when stream_options.include_usage is true
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+impl Usage {
+    pub fn add(&self, other: &Usage) -> Self {
+        Self {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+            total_tokens: self.total_tokens + other.total_tokens,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompletionMeta {
+    pub model: Option<String>,
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+}
+impl CompletionMeta {
+    fn update(&mut self, chat: &StreamChat) {
+        self.model = Some(chat.model.clone());
+        if let Some(reason) = chat
+            .choices
+            .last()
+            .and_then(|choice| choice.finish_reason.as_str())
+        {
+            self.finish_reason = Some(reason.to_string());
+        }
+        if let Some(usage) = chat.usage {
+            self.usage = Some(usage);
+        }
+    }
 }
 impl StreamChat {
-    fn last_response(mut self) -> Option<String> {
-        self.choices.pop()?.delta.content
+    fn last_delta(mut self) -> Option<StreamChatChoicesDelta> {
+        Some(self.choices.pop()?.delta)
     }
 }
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -301,11 +515,22 @@ struct StreamChatChoices {
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 struct StreamChatChoicesDelta {
     content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCallDelta>>,
 }
 impl From<StreamChat> for ChatResponse {
     fn from(s: StreamChat) -> Self {
-        s.last_response()
-            .map_or_else(|| Self::DeltaContent(String::new()), Self::DeltaContent)
+        match s.last_delta() {
+            Some(StreamChatChoicesDelta {
+                tool_calls: Some(tool_calls),
+                ..
+            }) => Self::ToolCallDelta(tool_calls),
+            Some(StreamChatChoicesDelta {
+                content: Some(content),
+                ..
+            }) => Self::DeltaContent(content),
+            _ => Self::DeltaContent(String::new()),
+        }
     }
 }
 impl<T: Into<String>> From<T> for ChatResponse {
@@ -328,7 +553,7 @@ impl OpenAIKey {
     pub fn new(key: impl Into<String>) -> Self {
         Self(key.into())
     }
-    fn key(&self) -> &str {
+    pub(crate) fn key(&self) -> &str {
         self.0.as_str()
     }
 }
@@ -343,28 +568,156 @@ impl Display for OpenAIKey {
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
 pub struct ChatRequest {
-    model: OpenAIModel,
+    model: RequestModel,
     messages: Vec<Message>,
     stream: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<ToolDefinition>>,
+    // params which termai does not use but passes through. e.g. max_tokens of a request to serve
+    #[serde(flatten)]
+    params: serde_json::Map<String, serde_json::Value>,
+}
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+struct StreamOptions {
+    include_usage: bool,
+}
+// a model unknown to termai is sent by its name. e.g. the model of a request to serve
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+enum RequestModel {
+    Known(OpenAIModel),
+    Other(String),
 }
 impl ChatRequest {
+    const FIELDS: [&'static str; 7] = [
+        "model",
+        "messages",
+        "stream",
+        "stream_options",
+        "temperature",
+        "top_p",
+        "tools",
+    ];
     pub fn from_message(model: OpenAIModel, message: Message) -> Self {
-        Self {
-            model,
-            messages: vec![message],
-            stream: true,
-        }
+        Self::new(model, vec![message])
     }
     pub fn get_message(&self, index: usize) -> Option<&Message> {
         self.messages.get(index)
     }
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+    // the default model when the model is unknown to termai
+    pub fn model(&self) -> OpenAIModel {
+        match self.model {
+            RequestModel::Known(model) => model,
+            RequestModel::Other(_) => OpenAIModel::default(),
+        }
+    }
+    pub fn model_name(&self) -> &str {
+        match &self.model {
+            RequestModel::Known(model) => model.as_str(),
+            RequestModel::Other(name) => name,
+        }
+    }
     pub fn new(model: OpenAIModel, messages: Vec<Message>) -> Self {
         Self {
-            model,
+            model: RequestModel::Known(model),
             messages,
             stream: true,
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+            temperature: None,
+            top_p: None,
+            tools: None,
+            params: serde_json::Map::new(),
+        }
+    }
+    pub fn set_model(&mut self, model: OpenAIModel) {
+        self.model = RequestModel::Known(model);
+    }
+    pub fn set_model_name(&mut self, name: &str) {
+        self.model = match OpenAIModel::from_str(name) {
+            Ok(model) => RequestModel::Known(model),
+            Err(_) => RequestModel::Other(name.to_string()),
+        };
+    }
+    // params of termai have their setters, so they are not replaced. e.g. stream
+    pub fn set_param(&mut self, key: impl Into<String>, value: serde_json::Value) {
+        let key = key.into();
+        if !Self::FIELDS.contains(&key.as_str()) {
+            self.params.insert(key, value);
+        }
+    }
+    // system message is always the first one. it is replaced if already exists
+    pub fn set_system(&mut self, content: impl Into<String>) {
+        let system = Message::new(Role::System, content);
+        match self.messages.first_mut() {
+            Some(first) if first.role == Role::System => *first = system,
+            _ => self.messages.insert(0, system),
+        }
+    }
+    pub fn set_temperature(&mut self, temperature: f32) {
+        self.temperature = Some(temperature);
+    }
+    pub fn set_top_p(&mut self, top_p: f32) {
+        self.top_p = Some(top_p);
+    }
+    pub fn set_tools(&mut self, tools: Vec<ToolDefinition>) {
+        self.tools = (!tools.is_empty()).then_some(tools);
+    }
+    pub fn push_message(&mut self, message: Message) {
+        self.messages.push(message);
+    }
+    // for requests not sent by sse. e.g. OpenAI Batch API
+    pub fn without_stream(mut self) -> Self {
+        self.stream = false;
+        self.stream_options = None;
+        self
+    }
+}
+
+// response of non stream request
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ChatCompletion {
+    model: String,
+    choices: Vec<ChatCompletionChoice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+struct ChatCompletionChoice {
+    message: ChatCompletionMessage,
+    finish_reason: Option<String>,
+}
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+struct ChatCompletionMessage {
+    content: Option<String>,
+}
+impl ChatCompletion {
+    pub fn content(&self) -> &str {
+        self.choices
+            .last()
+            .and_then(|choice| choice.message.content.as_deref())
+            .unwrap_or_default()
+    }
+    pub fn meta(&self) -> CompletionMeta {
+        CompletionMeta {
+            model: Some(self.model.clone()),
+            finish_reason: self
+                .choices
+                .last()
+                .and_then(|choice| choice.finish_reason.clone()),
+            usage: self.usage,
         }
     }
 }
@@ -374,14 +727,37 @@ impl ChatRequest {
 pub struct Message {
     role: Role,
     pub(crate) content: String,
+    // assistant message which asks to call tools
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    // tool message which answers the call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
 }
 impl Message {
     pub fn new(role: Role, content: impl Into<String>) -> Self {
         Self {
             role,
             content: content.into(),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+    pub fn tool_calls(content: impl Into<String>, calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls: Some(calls),
+            ..Self::new(Role::Assistant, content)
         }
     }
+    pub fn tool_result(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+    pub fn role(&self) -> Role {
+        self.role
+    }
     pub fn change_content(&mut self) -> &mut String {
         &mut self.content
     }
@@ -391,10 +767,12 @@ impl Message {
 }
 
 #[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum Role {
     User,
     System,
     Assistant,
+    Tool,
 }
 impl Role {
     fn as_str(&self) -> &'static str {
@@ -402,6 +780,7 @@ impl Role {
             Self::User => "user",
             Self::System => "system",
             Self::Assistant => "assistant",
+            Self::Tool => "tool",
         }
     }
 }
@@ -449,6 +828,22 @@ impl OpenAIModel {
         }
     }
 }
+impl FromStr for OpenAIModel {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        [
+            Self::Gpt3Dot5Turbo,
+            Self::Gpt4,
+            Self::Gpt40314,
+            Self::Gpt4032k,
+            Self::Gpt4032k0314,
+            Self::Gpt4o,
+        ]
+        .into_iter()
+        .find(|model| model.as_str() == s)
+        .ok_or_else(|| format!("model {} is not supported", s))
+    }
+}
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct GptClientError {
     message: String,
@@ -458,6 +853,31 @@ impl GptClientError {
     pub fn new(message: String, kind: GptClientErrorKind) -> Self {
         Self { message, kind }
     }
+    // a wrong key fails again, so it is not retried
+    pub fn is_retryable(&self) -> bool {
+        !matches!(
+            self.kind,
+            GptClientErrorKind::Unauthorized(_)
+                | GptClientErrorKind::NotFoundEnvAPIKey
+                | GptClientErrorKind::KeySourceError(_)
+                | GptClientErrorKind::NotFoundCAFile(_)
+                | GptClientErrorKind::InvalidUrl(_)
+        )
+    }
+    // seconds of Retry-After of a rate limited response
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        if !matches!(self.kind, GptClientErrorKind::RateLimited(_)) {
+            return None;
+        }
+        let lower = self.message.to_lowercase();
+        let (_, after) = lower.split_once("retry-after")?;
+        after
+            .trim_start_matches([':', ' ', '='])
+            .split(|c: char| !c.is_ascii_digit())
+            .next()
+            .and_then(|seconds| seconds.parse::<u64>().ok())
+            .map(std::time::Duration::from_secs)
+    }
 }
 impl From<SseSubscribeError<GptClientError>> for GptClientError {
     fn from(e: SseSubscribeError<GptClientError>) -> Self {
@@ -469,7 +889,7 @@ impl From<SseSubscribeError<GptClientError>> for GptClientError {
             },
             SseSubscribeError::HttpError(e) => GptClientError {
                 message: e.to_string(),
-                kind: GptClientErrorKind::RequestError(e.to_string()),
+                kind: GptClientErrorKind::from_http_error(e.to_string()),
             },
             SseSubscribeError::ConnectionError(e) => GptClientError {
                 message: e.to_string(),
@@ -491,12 +911,52 @@ pub enum GptClientErrorKind {
     InvalidUrl(String),
     ParseError(String),
     NotFoundEnvAPIKey,
+    KeySourceError(String),
     ProxyConnectionError(String),
     ReadStreamError(String),
     RequestError(String),
     ResponseDeserializeError(String),
     NotMakeChatBody(String),
     ResponseError(String),
+    Unauthorized(String),
+    RateLimited(String),
+    UnexpectedSseResponse,
+    // a function failed while handling the stream. e.g. a plugin crashed
+    FunctionError(String),
+}
+impl GptClientErrorKind {
+    // rsse reports status code only in the error message
+    fn from_http_error(message: String) -> Self {
+        match http_status(&message) {
+            Some(401) | Some(403) => Self::Unauthorized(message),
+            Some(429) => Self::RateLimited(message),
+            _ => Self::RequestError(message),
+        }
+    }
+}
+
+// the status of `HTTP/1.1 429 Too Many Requests` or `status 429`, otherwise the first
+// word of 3 digits before the body. a number in the body like 4010 tokens is not a status
+fn http_status(message: &str) -> Option<u16> {
+    let head = message.split('{').next().unwrap_or_default();
+    let words = head
+        .split(|c: char| c.is_whitespace() || c == ':' || c == '=' || c == ',')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let status = |word: &str| -> Option<u16> {
+        (word.len() == 3)
+            .then(|| word.parse::<u16>().ok())
+            .flatten()
+            .filter(|status| (100..600).contains(status))
+    };
+    words
+        .windows(2)
+        .find(|pair| {
+            pair[0].starts_with("HTTP/")
+                || ["status", "code"].contains(&pair[0].to_lowercase().as_str())
+        })
+        .and_then(|pair| status(pair[1]))
+        .or_else(|| words.iter().find_map(|word| status(word)))
 }
 impl Display for GptClientErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -505,6 +965,7 @@ impl Display for GptClientErrorKind {
             Self::ProxyConnectionError(s) => format!("Proxy Connection Error. Error is : {}", s),
             Self::NotFoundCAFile(s) => format!("Not found CA File. File is : {}", s),
             Self::NotFoundEnvAPIKey => "Not found OPENAI_API_KEY in env".to_string(),
+            Self::KeySourceError(s) => format!("Can not get api key. Error is : {}", s),
             Self::RequestError(s) => format!("Request Error to {}", s),
             Self::NotMakeChatBody(s) => format!("Not make chat body from {}", s),
             Self::ReadStreamError(s) => format!("Not Read Stream. Error is : {}", s),
@@ -514,6 +975,10 @@ impl Display for GptClientErrorKind {
             Self::ResponseError(s) => format!("Response Error. Error is : {}", s),
             Self::ParseError(s) => format!("Parse Error. Error is : {}", s),
             Self::InvalidUrl(s) => format!("Invalid Url. Url is : {}", s),
+            Self::Unauthorized(s) => format!("Unauthorized. Error is : {}", s),
+            Self::RateLimited(s) => format!("Rate Limited. Error is : {}", s),
+            Self::UnexpectedSseResponse => "Unexpected sse response".to_string(),
+            Self::FunctionError(s) => format!("Function Error. Error is : {}", s),
         };
         write!(f, "{}", kind)
     }
@@ -527,6 +992,46 @@ mod tests {
     use super::fakes::*;
     use super::*;
 
+    #[test]
+    fn キャッシュにあるリクエストは通信せずに保存された差分を流す() {
+        use crate::functions::common::test_tool::TestFileFactory;
+        let test_file = TestFileFactory::create("tmp_client_cache");
+        let cache = ResponseCache::new(
+            "tmp_client_cache",
+            std::time::Duration::from_secs(60),
+            1024 * 1024,
+        );
+        let request = ChatRequest::new(OpenAIModel::Gpt4o, vec![Message::new(Role::User, "hi")]);
+        let meta = CompletionMeta {
+            model: Some("gpt-4o".to_string()),
+            finish_reason: Some("stop".to_string()),
+            usage: None,
+        };
+        cache
+            .put(&request, vec!["hel".to_string(), "lo".to_string()], &meta)
+            .unwrap();
+        let mut client = GptClient::new(OpenAIKey::new("dummy"), GptClientOption::default())
+            .unwrap()
+            .with_cache(cache, CacheMode::Use);
+        let mut responses = Vec::new();
+
+        let result = client.request_mut_fn(request, |res| {
+            responses.push(res.clone());
+            HandleResult::from(res)
+        });
+
+        test_file.remove_dir_all();
+        assert!(result.is_ok());
+        assert_eq!(
+            responses,
+            vec![
+                ChatResponse::DeltaContent("hel".to_string()),
+                ChatResponse::DeltaContent("lo".to_string()),
+                ChatResponse::Done
+            ]
+        );
+        assert_eq!(client.last_completion(), &meta);
+    }
     #[test]
     #[ignore = "実際にproxy通信するので、CIでのテストは行わない"]
     fn proxyを利用してgpt通信を行うことが可能() {
@@ -626,6 +1131,121 @@ mod tests {
         );
     }
     #[test]
+    fn sseレスポンスからモデル名と終了理由と使用トークン数を取得する() {
+        let mut meta = CompletionMeta::default();
+        ChatResponse::from_sse_with_meta(
+            SseResponse::Data(make_stream_chat_json("Hello")),
+            &mut meta,
+        )
+        .unwrap();
+        ChatResponse::from_sse_with_meta(
+            SseResponse::Data(
+                r#"{"id":"x","object":"chat.completion.chunk","created":1,"model":"gpt-4o-2024-05-13","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#.to_string(),
+            ),
+            &mut meta,
+        )
+        .unwrap();
+        ChatResponse::from_sse_with_meta(
+            SseResponse::Data(
+                r#"{"id":"x","object":"chat.completion.chunk","created":1,"model":"gpt-4o-2024-05-13","choices":[],"usage":{"prompt_tokens":3,"completion_tokens":5,"total_tokens":8}}"#.to_string(),
+            ),
+            &mut meta,
+        )
+        .unwrap();
+
+        assert_eq!(meta.model.as_deref(), Some("gpt-4o-2024-05-13"));
+        assert_eq!(meta.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(
+            meta.usage,
+            Some(Usage {
+                prompt_tokens: 3,
+                completion_tokens: 5,
+                total_tokens: 8
+            })
+        );
+    }
+    #[test]
+    fn httpエラーのステータスから認証エラーとレート制限を判別する() {
+        assert_eq!(
+            GptClientErrorKind::from_http_error("status 429 Too Many Requests".to_string()),
+            GptClientErrorKind::RateLimited("status 429 Too Many Requests".to_string())
+        );
+        assert_eq!(
+            GptClientErrorKind::from_http_error("status 401 Unauthorized".to_string()),
+            GptClientErrorKind::Unauthorized("status 401 Unauthorized".to_string())
+        );
+        assert_eq!(
+            GptClientErrorKind::from_http_error("status 500".to_string()),
+            GptClientErrorKind::RequestError("status 500".to_string())
+        );
+        let body = "HTTP/1.1 400 Bad Request {\"error\": \"4010 tokens is over 401 limit\"}";
+        assert_eq!(
+            GptClientErrorKind::from_http_error(body.to_string()),
+            GptClientErrorKind::RequestError(body.to_string())
+        );
+        assert_eq!(http_status("http error: 429 retry-after: 20"), Some(429));
+    }
+    #[test]
+    fn 認証エラーは再試行せずレート制限はretry_afterを待つ() {
+        let limited = GptClientError::new(
+            "status 429 Retry-After: 20".to_string(),
+            GptClientErrorKind::from_http_error("status 429 Retry-After: 20".to_string()),
+        );
+        let unauthorized = GptClientError::new(
+            "status 401".to_string(),
+            GptClientErrorKind::from_http_error("status 401".to_string()),
+        );
+
+        assert!(limited.is_retryable());
+        assert_eq!(
+            limited.retry_after(),
+            Some(std::time::Duration::from_secs(20))
+        );
+        assert!(!unauthorized.is_retryable());
+        assert_eq!(unauthorized.retry_after(), None);
+        assert!(ChatResponse::from_sse(SseResponse::Retry(1)).is_err());
+    }
+    #[test]
+    fn ツール呼び出しの差分をsseレスポンスから取得する() {
+        let response = SseResponse::Data(
+            r#"{"id":"x","object":"chat.completion.chunk","created":1,"model":"gpt-4o","choices":[{"index":0,"delta":{"content":null,"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"read_file","arguments":""}}]},"finish_reason":null}]}"#.to_string(),
+        );
+
+        assert_eq!(
+            ChatResponse::from_sse(response).unwrap(),
+            ChatResponse::ToolCallDelta(vec![ToolCallDelta {
+                index: 0,
+                id: Some("call_1".to_string()),
+                function: Some(crate::gpt::tool::ToolCallFunctionDelta {
+                    name: Some("read_file".to_string()),
+                    arguments: Some(String::new()),
+                }),
+            }])
+        );
+    }
+    #[test]
+    fn ツールの呼び出しと結果のメッセージをシリアライズする() {
+        let call = Message::tool_calls("", vec![ToolCall::new("call_1", "read_file", "{}")]);
+        let result = Message::tool_result("call_1", "fn main() {}");
+
+        assert_eq!(
+            serde_json::to_value(&call).unwrap(),
+            serde_json::json!({
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{"id": "call_1", "type": "function", "function": {"name": "read_file", "arguments": "{}"}}]
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({"role": "tool", "content": "fn main() {}", "tool_call_id": "call_1"})
+        );
+        assert!(serde_json::to_value(Message::new(Role::User, "hi"))
+            .unwrap()
+            .get("tool_calls")
+            .is_none());
+    }
+    #[test]
     #[allow(non_snake_case)]
     fn gptのレスポンスはChatResponseに変換可能() {
         let response = ChatResponse::from(make_stream_chat("Hello World"));
@@ -0,0 +1,192 @@
+use std::process::Command;
+
+use super::client::{GptClientError, GptClientErrorKind, OpenAIKey, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    Env(String),
+    File(String),
+    Command(String),
+    Keyring { service: String, account: String },
+}
+
+impl Default for KeySource {
+    fn default() -> Self {
+        Self::Env(Self::DEFAULT_ENV.to_string())
+    }
+}
+
+impl KeySource {
+    const DEFAULT_ENV: &'static str = "OPENAI_API_KEY";
+    pub fn resolve(&self) -> Result<OpenAIKey> {
+        let key = match self {
+            Self::Env(name) => std::env::var(name).map_err(|_| {
+                GptClientError::new(
+                    format!("{} is not found", name),
+                    GptClientErrorKind::NotFoundEnvAPIKey,
+                )
+            })?,
+            Self::File(path) => read_key_file(path)?,
+            Self::Command(command) => run_key_command(command)?,
+            Self::Keyring { service, account } => read_keyring(service, account)?,
+        };
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(key_source_error(format!("api key from {} is empty", self)));
+        }
+        Ok(OpenAIKey::new(key))
+    }
+}
+
+impl std::fmt::Display for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Env(name) => write!(f, "env {}", name),
+            Self::File(path) => write!(f, "file {}", path),
+            Self::Command(command) => write!(f, "command `{}`", command),
+            Self::Keyring { service, account } => {
+                write!(f, "keyring service={} account={}", service, account)
+            }
+        }
+    }
+}
+
+fn key_source_error(message: String) -> GptClientError {
+    GptClientError::new(message.clone(), GptClientErrorKind::KeySourceError(message))
+}
+
+pub(crate) fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home, rest),
+        _ => path.to_string(),
+    }
+}
+
+fn read_key_file(path: &str) -> Result<String> {
+    let path = expand_home(path);
+    let metadata = std::fs::metadata(&path)
+        .map_err(|e| key_source_error(format!("can not read key file {} : {}", path, e)))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // same rule as ssh private keys. group or others must not read the key.
+        let mode = metadata.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(key_source_error(format!(
+                "permissions {:o} for key file {} are too open. run `chmod 600 {}`",
+                mode & 0o777,
+                path,
+                path
+            )));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    std::fs::read_to_string(&path)
+        .map_err(|e| key_source_error(format!("can not read key file {} : {}", path, e)))
+}
+
+fn run_key_command(command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .output()
+        .map_err(|e| key_source_error(format!("can not run `{}` : {}", command, e)))?;
+    if !output.status.success() {
+        return Err(key_source_error(format!(
+            "`{}` failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    // only first line is key. `pass` prints metadata after first line.
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn read_keyring(service: &str, account: &str) -> Result<String> {
+    // secret-tool is the cli of Secret Service (gnome-keyring, kwallet)
+    run_keyring_command(
+        Command::new("secret-tool").args(["lookup", "service", service, "account", account]),
+    )
+}
+
+#[cfg(target_os = "macos")]
+fn read_keyring(service: &str, account: &str) -> Result<String> {
+    run_keyring_command(Command::new("security").args([
+        "find-generic-password",
+        "-s",
+        service,
+        "-a",
+        account,
+        "-w",
+    ]))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn read_keyring(_service: &str, _account: &str) -> Result<String> {
+    Err(key_source_error(
+        "keyring is not supported on this platform".to_string(),
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn run_keyring_command(command: &mut Command) -> Result<String> {
+    let output = command
+        .output()
+        .map_err(|e| key_source_error(format!("can not access keyring : {}", e)))?;
+    if !output.status.success() {
+        return Err(key_source_error(format!(
+            "key is not found in keyring: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::common::test_tool::TestFileFactory;
+
+    use super::*;
+    #[test]
+    fn commandの標準出力の1行目をkeyとして扱う() {
+        let source = KeySource::Command("printf 'sk-test\\nlogin: me\\n'".to_string());
+        let key = source.resolve().unwrap();
+        assert_eq!(key.key(), "sk-test");
+    }
+    #[test]
+    fn commandが失敗した場合はエラーを返す() {
+        let source = KeySource::Command("exit 1".to_string());
+        let err = source.resolve().unwrap_err();
+        assert!(matches!(err.kind, GptClientErrorKind::KeySourceError(_)));
+    }
+    #[test]
+    fn 存在しない環境変数はエラーを返す() {
+        let source = KeySource::Env("TERMAI_TEST_NOT_EXIST_KEY".to_string());
+        let err = source.resolve().unwrap_err();
+        assert_eq!(err.kind, GptClientErrorKind::NotFoundEnvAPIKey);
+    }
+    #[test]
+    #[cfg(unix)]
+    fn 他ユーザーが読めるkey_fileは拒否する() {
+        use std::os::unix::fs::PermissionsExt;
+        let test_file = TestFileFactory::create("tmp_key_file");
+        test_file.create_file_under_root("key", "sk-file\n");
+        let path = "tmp_key_file/key";
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let open = KeySource::File(path.to_string()).resolve();
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let closed = KeySource::File(path.to_string()).resolve();
+
+        test_file.remove_dir_all();
+        assert!(open.is_err());
+        assert_eq!(closed.unwrap().key(), "sk-file");
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+
+use super::client::ChatResponse;
+
+// tools of the request. only "function" type is supported by OpenAI
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ToolDefinition {
+    #[serde(rename = "type")]
+    kind: String,
+    function: ToolFunction,
+}
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct ToolFunction {
+    name: String,
+    description: String,
+    // json schema of the arguments
+    parameters: serde_json::Value,
+}
+impl ToolDefinition {
+    pub fn function(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: serde_json::Value,
+    ) -> Self {
+        Self {
+            kind: "function".to_string(),
+            function: ToolFunction {
+                name: name.into(),
+                description: description.into(),
+                parameters,
+            },
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.function.name
+    }
+}
+
+// tool call requested by the model. arguments is a json string
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    pub function: ToolCallFunction,
+}
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ToolCallFunction {
+    pub name: String,
+    pub arguments: String,
+}
+impl ToolCall {
+    pub fn new(
+        id: impl Into<String>,
+        name: impl Into<String>,
+        arguments: impl Into<String>,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            kind: "function".to_string(),
+            function: ToolCallFunction {
+                name: name.into(),
+                arguments: arguments.into(),
+            },
+        }
+    }
+}
+
+// a piece of a tool call in the stream. id and name come only in the first piece
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub function: Option<ToolCallFunctionDelta>,
+}
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ToolCallFunctionDelta {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub arguments: Option<String>,
+}
+
+// joins tool call deltas of a stream by index
+#[derive(Debug, Clone, Default)]
+pub struct ToolCallAccumulator {
+    calls: BTreeMap<usize, ToolCall>,
+}
+impl ToolCallAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn push(&mut self, res: &ChatResponse) {
+        let ChatResponse::ToolCallDelta(deltas) = res else {
+            return;
+        };
+        for delta in deltas {
+            let call = self
+                .calls
+                .entry(delta.index)
+                .or_insert_with(|| ToolCall::new("", "", ""));
+            if let Some(id) = delta.id.as_ref() {
+                call.id.push_str(id);
+            }
+            if let Some(function) = delta.function.as_ref() {
+                if let Some(name) = function.name.as_ref() {
+                    call.function.name.push_str(name);
+                }
+                if let Some(arguments) = function.arguments.as_ref() {
+                    call.function.arguments.push_str(arguments);
+                }
+            }
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+    pub fn into_calls(self) -> Vec<ToolCall> {
+        self.calls.into_values().collect()
+    }
+}
+
+// arguments are parsed from json. Err is sent back to the model as the result
+pub type ToolHandler = Box<dyn FnMut(&serde_json::Value) -> Result<String, String>>;
+
+#[derive(Default)]
+pub struct Tools {
+    tools: Vec<(ToolDefinition, ToolHandler)>,
+}
+impl Tools {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // a tool with the same name is replaced
+    pub fn add<F>(&mut self, definition: ToolDefinition, handler: F)
+    where
+        F: FnMut(&serde_json::Value) -> Result<String, String> + 'static,
+    {
+        self.tools
+            .retain(|(tool, _)| tool.name() != definition.name());
+        self.tools.push((definition, Box::new(handler)));
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.iter().map(|(tool, _)| tool.clone()).collect()
+    }
+    // the model can recover from a failed call, so errors are returned as the content
+    pub fn call(&mut self, call: &ToolCall) -> String {
+        let Some((_, handler)) = self
+            .tools
+            .iter_mut()
+            .find(|(tool, _)| tool.name() == call.function.name)
+        else {
+            return format!("error: tool {} is not found", call.function.name);
+        };
+        let arguments = if call.function.arguments.trim().is_empty() {
+            Ok(serde_json::Value::Object(Default::default()))
+        } else {
+            serde_json::from_str(&call.function.arguments)
+        };
+        match arguments {
+            Ok(arguments) => handler(&arguments).unwrap_or_else(|e| format!("error: {}", e)),
+            Err(e) => format!("error: invalid arguments : {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn delta(index: usize, id: Option<&str>, name: Option<&str>, arguments: &str) -> ToolCallDelta {
+        ToolCallDelta {
+            index,
+            id: id.map(str::to_string),
+            function: Some(ToolCallFunctionDelta {
+                name: name.map(str::to_string),
+                arguments: Some(arguments.to_string()),
+            }),
+        }
+    }
+    #[test]
+    fn ストリームで分割されたツール呼び出しをindexごとに結合する() {
+        let mut sut = ToolCallAccumulator::new();
+
+        sut.push(&ChatResponse::ToolCallDelta(vec![delta(
+            0,
+            Some("call_1"),
+            Some("read_file"),
+            "",
+        )]));
+        sut.push(&ChatResponse::ToolCallDelta(vec![delta(
+            0,
+            None,
+            None,
+            "{\"path\":",
+        )]));
+        sut.push(&ChatResponse::ToolCallDelta(vec![delta(
+            1,
+            Some("call_2"),
+            Some("list_dir"),
+            "{}",
+        )]));
+        sut.push(&ChatResponse::ToolCallDelta(vec![delta(
+            0,
+            None,
+            None,
+            "\"a.rs\"}",
+        )]));
+        sut.push(&ChatResponse::Done);
+
+        assert_eq!(
+            sut.into_calls(),
+            vec![
+                ToolCall::new("call_1", "read_file", "{\"path\":\"a.rs\"}"),
+                ToolCall::new("call_2", "list_dir", "{}"),
+            ]
+        );
+    }
+    #[test]
+    fn 登録したツールを引数付きで実行し失敗も結果として返す() {
+        let mut sut = Tools::new();
+        sut.add(
+            ToolDefinition::function("echo", "echo the text", serde_json::json!({})),
+            |args| {
+                args["text"]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| "text is required".to_string())
+            },
+        );
+
+        assert_eq!(
+            sut.call(&ToolCall::new("1", "echo", r#"{"text":"hi"}"#)),
+            "hi"
+        );
+        assert_eq!(
+            sut.call(&ToolCall::new("2", "echo", "")),
+            "error: text is required"
+        );
+        assert!(sut
+            .call(&ToolCall::new("3", "echo", "{broken"))
+            .starts_with("error: invalid arguments"));
+        assert_eq!(
+            sut.call(&ToolCall::new("4", "unknown", "{}")),
+            "error: tool unknown is not found"
+        );
+    }
+    #[test]
+    fn ツール定義はopenaiの形式でシリアライズされる() {
+        let definition = ToolDefinition::function(
+            "read_file",
+            "read a file",
+            serde_json::json!({"type": "object", "properties": {"path": {"type": "string"}}}),
+        );
+
+        assert_eq!(
+            serde_json::to_value(&definition).unwrap(),
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": "read_file",
+                    "description": "read a file",
+                    "parameters": {"type": "object", "properties": {"path": {"type": "string"}}}
+                }
+            })
+        );
+    }
+}
@@ -1,3 +1,6 @@
+pub mod agent;
 pub mod cli;
+pub mod config;
 pub mod functions;
 pub mod gpt;
+pub mod mcp;
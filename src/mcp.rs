@@ -0,0 +1,126 @@
+pub mod client;
+pub mod server;
+
+use serde_json::Value;
+
+// Model Context Protocol. json-rpc 2.0, one message per line over stdio
+pub const PROTOCOL_VERSION: &str = "2024-11-05";
+
+// request, notification and response share the same shape. request has id and method,
+// notification has only method, response has id and result or error
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct JsonRpcMessage {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl JsonRpcError {
+    pub const PARSE_ERROR: i64 = -32700;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    pub const INTERNAL_ERROR: i64 = -32603;
+    // same code as LSP
+    pub const REQUEST_CANCELLED: i64 = -32800;
+}
+
+impl JsonRpcMessage {
+    const VERSION: &'static str = "2.0";
+    pub fn request(id: u64, method: impl Into<String>, params: Value) -> Self {
+        Self {
+            jsonrpc: Self::VERSION.to_string(),
+            id: Some(Value::from(id)),
+            method: Some(method.into()),
+            params: Some(params),
+            ..Default::default()
+        }
+    }
+    pub fn notification(method: impl Into<String>) -> Self {
+        Self {
+            jsonrpc: Self::VERSION.to_string(),
+            method: Some(method.into()),
+            ..Default::default()
+        }
+    }
+    pub fn response(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: Self::VERSION.to_string(),
+            id: Some(id),
+            result: Some(result),
+            ..Default::default()
+        }
+    }
+    pub fn error_response(id: Value, code: i64, message: impl Into<String>) -> Self {
+        Self {
+            jsonrpc: Self::VERSION.to_string(),
+            id: Some(id),
+            error: Some(JsonRpcError {
+                code,
+                message: message.into(),
+            }),
+            ..Default::default()
+        }
+    }
+    pub fn is_request(&self) -> bool {
+        self.method.is_some() && self.id.is_some()
+    }
+    pub fn is_response(&self) -> bool {
+        self.method.is_none() && self.id.is_some()
+    }
+}
+
+// a tool of tools/list
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct McpTool {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "inputSchema")]
+    pub input_schema: Value,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn リクエストと通知とレスポンスを区別する() {
+        let request = serde_json::to_string(&JsonRpcMessage::request(
+            1,
+            "tools/list",
+            serde_json::json!({}),
+        ))
+        .unwrap();
+        let notification =
+            serde_json::to_string(&JsonRpcMessage::notification("notifications/initialized"))
+                .unwrap();
+        let response = serde_json::from_str::<JsonRpcMessage>(
+            r#"{"jsonrpc":"2.0","id":1,"result":{"tools":[]}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            request,
+            r#"{"jsonrpc":"2.0","id":1,"method":"tools/list","params":{}}"#
+        );
+        assert_eq!(
+            notification,
+            r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#
+        );
+        assert!(response.is_response());
+        assert!(!response.is_request());
+    }
+}
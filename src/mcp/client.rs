@@ -0,0 +1,490 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    rc::Rc,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::gpt::tool::{ToolDefinition, Tools};
+
+use super::{JsonRpcMessage, McpTool, PROTOCOL_VERSION};
+
+// [profiles.<name>.mcp_servers.<server>] of config.toml
+// command = "npx"
+// args = ["-y", "@modelcontextprotocol/server-filesystem", "."]
+// timeout_secs = 30
+// allow = ["read_file", "list_directory"]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct McpServerConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    // how long termai waits for each response
+    #[serde(default = "McpServerConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+    // tools exposed to the model. every tool when empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default = "McpServerConfig::default_true")]
+    pub enabled: bool,
+    // a crashed server is started again once on the next call
+    #[serde(default = "McpServerConfig::default_true")]
+    pub restart: bool,
+}
+impl McpServerConfig {
+    fn default_timeout_secs() -> u64 {
+        30
+    }
+    fn default_true() -> bool {
+        true
+    }
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+    fn is_allowed(&self, tool: &str) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|allowed| allowed == tool)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct McpError {
+    server: String,
+    message: String,
+}
+impl McpError {
+    fn new(server: &str, message: impl Into<String>) -> Self {
+        Self {
+            server: server.to_string(),
+            message: message.into(),
+        }
+    }
+}
+impl Display for McpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mcp server {} : {}", self.server, self.message)
+    }
+}
+impl std::error::Error for McpError {}
+
+struct McpProcess {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+    next_id: u64,
+    dead: bool,
+}
+
+impl McpProcess {
+    fn spawn(name: &str, config: &McpServerConfig) -> Result<Self, McpError> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // logs of the server are shown as they are
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| {
+                McpError::new(name, format!("can not start {} : {}", config.command, e))
+            })?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (tx, lines) = mpsc::channel();
+        // reading in other thread makes timeout possible
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    return;
+                };
+                if tx.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            child,
+            stdin,
+            lines,
+            next_id: 1,
+            dead: false,
+        })
+    }
+    fn send(&mut self, message: &JsonRpcMessage) -> std::io::Result<()> {
+        let line = serde_json::to_string(message).unwrap_or_default();
+        writeln!(self.stdin, "{}", line).and_then(|_| self.stdin.flush())
+    }
+    fn request(&mut self, method: &str, params: Value, timeout: Duration) -> Result<Value, String> {
+        let id = self.next_id;
+        self.next_id += 1;
+        if self
+            .send(&JsonRpcMessage::request(id, method, params))
+            .is_err()
+        {
+            return Err(self.die());
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let line = match self
+                .lines
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.child.kill().unwrap_or_default();
+                    self.dead = true;
+                    return Err(format!(
+                        "{} timed out after {}ms",
+                        method,
+                        timeout.as_millis()
+                    ));
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(self.die()),
+            };
+            let Ok(message) = serde_json::from_str::<JsonRpcMessage>(&line) else {
+                // not json. e.g. a log written to stdout by mistake
+                continue;
+            };
+            if message.is_request() {
+                // no capability is declared, so the server should not ask anything
+                let id = message.id.unwrap_or_default();
+                self.send(&JsonRpcMessage::error_response(
+                    id,
+                    super::JsonRpcError::METHOD_NOT_FOUND,
+                    "termai does not support requests from the server",
+                ))
+                .unwrap_or_default();
+                continue;
+            }
+            if !message.is_response() || message.id != Some(Value::from(id)) {
+                continue;
+            }
+            return match (message.result, message.error) {
+                (_, Some(error)) => Err(format!("{} failed : {}", method, error.message)),
+                (Some(result), None) => Ok(result),
+                (None, None) => Ok(Value::Null),
+            };
+        }
+    }
+    // the server may still run after closing stdout, so it is killed before wait
+    fn die(&mut self) -> String {
+        self.dead = true;
+        if let Ok(Some(status)) = self.child.try_wait() {
+            return format!("exited with {}", status);
+        }
+        self.child.kill().unwrap_or_default();
+        match self.child.wait() {
+            Ok(status) => format!("exited with {}", status),
+            Err(e) => format!("exited : {}", e),
+        }
+    }
+}
+
+impl Drop for McpProcess {
+    fn drop(&mut self) {
+        self.child.kill().unwrap_or_default();
+        self.child.wait().map(|_| ()).unwrap_or_default();
+    }
+}
+
+pub struct McpClient {
+    name: String,
+    config: McpServerConfig,
+    timeout: Duration,
+    process: McpProcess,
+    tools: Vec<McpTool>,
+    restarted: bool,
+}
+
+impl McpClient {
+    pub fn start(name: &str, config: &McpServerConfig) -> Result<Self, McpError> {
+        Self::start_with_timeout(name, config, config.timeout())
+    }
+    fn start_with_timeout(
+        name: &str,
+        config: &McpServerConfig,
+        timeout: Duration,
+    ) -> Result<Self, McpError> {
+        let process = Self::initialize(name, config, timeout)?;
+        let mut client = Self {
+            name: name.to_string(),
+            config: config.clone(),
+            timeout,
+            process,
+            tools: Vec::new(),
+            restarted: false,
+        };
+        client.tools = client
+            .list_tools()?
+            .into_iter()
+            .filter(|tool| config.is_allowed(&tool.name))
+            .collect();
+        Ok(client)
+    }
+    fn initialize(
+        name: &str,
+        config: &McpServerConfig,
+        timeout: Duration,
+    ) -> Result<McpProcess, McpError> {
+        let mut process = McpProcess::spawn(name, config)?;
+        process
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": "termai", "version": env!("CARGO_PKG_VERSION") }
+                }),
+                timeout,
+            )
+            .map_err(|e| McpError::new(name, e))?;
+        process
+            .send(&JsonRpcMessage::notification("notifications/initialized"))
+            .map_err(|e| McpError::new(name, e.to_string()))?;
+        Ok(process)
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    // only allowed tools
+    pub fn tools(&self) -> &[McpTool] {
+        &self.tools
+    }
+    fn list_tools(&mut self) -> Result<Vec<McpTool>, McpError> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match cursor.as_ref() {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let result = self.request("tools/list", params)?;
+            let page = serde_json::from_value::<Vec<McpTool>>(result["tools"].clone())
+                .map_err(|e| McpError::new(&self.name, format!("invalid tools/list : {}", e)))?;
+            tools.extend(page);
+            cursor = result["nextCursor"].as_str().map(str::to_string);
+            if cursor.is_none() {
+                return Ok(tools);
+            }
+        }
+    }
+    // text contents of the result are joined. isError of the result is an error
+    pub fn call_tool(&mut self, tool: &str, arguments: &Value) -> Result<String, McpError> {
+        if !self.config.is_allowed(tool) {
+            return Err(McpError::new(
+                &self.name,
+                format!("tool {} is not allowed", tool),
+            ));
+        }
+        let result = self.request(
+            "tools/call",
+            json!({ "name": tool, "arguments": arguments }),
+        )?;
+        let text = result["content"]
+            .as_array()
+            .map(|contents| {
+                contents
+                    .iter()
+                    .filter_map(|content| content["text"].as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .unwrap_or_default();
+        if result["isError"].as_bool().unwrap_or_default() {
+            return Err(McpError::new(&self.name, text));
+        }
+        Ok(text)
+    }
+    fn request(&mut self, method: &str, params: Value) -> Result<Value, McpError> {
+        if self.process.dead {
+            self.restart()?;
+        }
+        self.process
+            .request(method, params, self.timeout)
+            .map_err(|e| McpError::new(&self.name, e))
+    }
+    fn restart(&mut self) -> Result<(), McpError> {
+        if !self.config.restart || self.restarted {
+            return Err(McpError::new(&self.name, "the server is stopped"));
+        }
+        self.restarted = true;
+        self.process = Self::initialize(&self.name, &self.config, self.timeout)?;
+        Ok(())
+    }
+}
+
+// tools of every enabled server. servers are stopped when the tools are dropped.
+// a server which fails to start is skipped with a warning
+pub fn start_servers(servers: &HashMap<String, McpServerConfig>) -> Tools {
+    let mut names = servers
+        .iter()
+        .filter(|(_, config)| config.enabled)
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    names.sort();
+    let mut tools = Tools::new();
+    for name in names {
+        match McpClient::start(name, &servers[name]) {
+            Ok(client) => add_tools(&mut tools, client),
+            Err(e) => eprintln!("mcp server {} is skipped : {}", name, e),
+        }
+    }
+    tools
+}
+
+// exposed as "<server>__<tool>" so that tools of different servers do not conflict
+pub fn add_tools(tools: &mut Tools, client: McpClient) {
+    let definitions = client
+        .tools()
+        .iter()
+        .map(|tool| {
+            (
+                tool.name.clone(),
+                ToolDefinition::function(
+                    tool_name(client.name(), &tool.name),
+                    tool.description.clone().unwrap_or_default(),
+                    tool.input_schema.clone(),
+                ),
+            )
+        })
+        .collect::<Vec<_>>();
+    let client = Rc::new(RefCell::new(client));
+    for (name, definition) in definitions {
+        let client = client.clone();
+        tools.add(definition, move |args| {
+            client
+                .borrow_mut()
+                .call_tool(&name, args)
+                .map_err(|e| e.to_string())
+        });
+    }
+}
+
+// openai allows only [a-zA-Z0-9_-]{1,64}
+fn tool_name(server: &str, tool: &str) -> String {
+    format!("{}__{}", server, tool)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::{functions::common::test_tool::TestFileFactory, gpt::tool::ToolCall};
+
+    use super::*;
+    const FAKE_SERVER: &str = r#"#!/bin/sh
+while IFS= read -r line; do
+  id=$(printf '%s' "$line" | sed -n 's/.*"id":\([0-9]*\).*/\1/p')
+  case "$line" in
+    *'"method":"initialize"'*) echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"protocolVersion\":\"2024-11-05\",\"capabilities\":{\"tools\":{}},\"serverInfo\":{\"name\":\"fake\",\"version\":\"0\"}}}" ;;
+    *'"method":"tools/list"'*) echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"tools\":[{\"name\":\"echo\",\"description\":\"echo\",\"inputSchema\":{\"type\":\"object\"}},{\"name\":\"drop_table\",\"inputSchema\":{\"type\":\"object\"}}]}}" ;;
+    *'"name":"echo"'*)
+      echo '{"jsonrpc":"2.0","method":"notifications/message","params":{"level":"info"}}'
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"content\":[{\"type\":\"text\",\"text\":\"pong\"}]}}" ;;
+    *'"method":"tools/call"'*) echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"content\":[{\"type\":\"text\",\"text\":\"failed\"}],\"isError\":true}}" ;;
+  esac
+done
+"#;
+    fn create_server(test_file: &TestFileFactory, root: &str, script: &str) -> McpServerConfig {
+        test_file.create_file_under_root("server.sh", script);
+        let path = format!("{}/server.sh", root);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        toml::from_str(&format!(
+            r#"
+            command = "{}"
+            allow = ["echo"]
+            "#,
+            path
+        ))
+        .unwrap()
+    }
+    #[test]
+    fn 許可されたツールだけをサーバー名付きで公開して呼び出す() {
+        let root = "tmp_mcp_client";
+        let test_file = TestFileFactory::create(root);
+        let config = create_server(&test_file, root, FAKE_SERVER);
+
+        let client = McpClient::start("fake", &config).unwrap();
+        let names = client
+            .tools()
+            .iter()
+            .map(|tool| tool.name.clone())
+            .collect::<Vec<_>>();
+        let mut tools = Tools::new();
+        add_tools(&mut tools, client);
+        let definitions = tools.definitions();
+        let pong = tools.call(&ToolCall::new("1", "fake__echo", "{}"));
+        let denied = tools.call(&ToolCall::new("2", "fake__drop_table", "{}"));
+
+        test_file.remove_dir_all();
+        assert_eq!(names, vec!["echo"]);
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].name(), "fake__echo");
+        assert_eq!(pong, "pong");
+        assert_eq!(denied, "error: tool fake__drop_table is not found");
+    }
+    #[test]
+    fn 応答しないサーバーはタイムアウトのエラーになる() {
+        let root = "tmp_mcp_timeout";
+        let test_file = TestFileFactory::create(root);
+        let config = create_server(&test_file, root, "#!/bin/sh\nsleep 5\n");
+
+        let result = McpClient::start_with_timeout("slow", &config, Duration::from_millis(100));
+
+        test_file.remove_dir_all();
+        assert!(result.err().unwrap().to_string().contains("timed out"));
+    }
+    #[test]
+    fn 起動できないサーバーは飛ばして他のサーバーのツールを使う() {
+        let root = "tmp_mcp_skip";
+        let test_file = TestFileFactory::create(root);
+        let config = create_server(&test_file, root, FAKE_SERVER);
+        let broken =
+            toml::from_str::<McpServerConfig>(r#"command = "tmp_mcp_skip/none.sh""#).unwrap();
+
+        let tools = start_servers(&HashMap::from([
+            ("broken".to_string(), broken),
+            ("fake".to_string(), config),
+        ]));
+        let definitions = tools.definitions();
+
+        test_file.remove_dir_all();
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].name(), "fake__echo");
+    }
+    #[test]
+    fn 標準出力を閉じたサーバーは終了を待たずに止める() {
+        let root = "tmp_mcp_closed";
+        let test_file = TestFileFactory::create(root);
+        let config = create_server(&test_file, root, "#!/bin/sh\nexec >&-\nsleep 5\n");
+        let started = std::time::Instant::now();
+
+        let result = McpClient::start("closed", &config);
+
+        test_file.remove_dir_all();
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+    #[test]
+    fn ツール名はopenaiで使える文字だけにする() {
+        assert_eq!(tool_name("git", "log"), "git__log");
+        assert_eq!(tool_name("my server", "read.file"), "my_server__read_file");
+    }
+}
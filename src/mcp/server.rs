@@ -0,0 +1,370 @@
+use std::{
+    io::{BufRead, Write},
+    str::FromStr,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    functions::{
+        code_capture::CodeCapture,
+        code_reviewer::{options::ReviewOptions, CodeReviewer},
+        translator::{TranslateMode, Translator},
+        GptDefaultFunction, GptFunction, UserInput,
+    },
+    gpt::client::OpenAIModel,
+};
+
+use super::{JsonRpcError, JsonRpcMessage, McpTool, PROTOCOL_VERSION};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateArgs {
+    text: String,
+    target_lang: String,
+    model: Option<String>,
+}
+#[derive(Debug, Clone, serde::Deserialize)]
+struct ReviewCodeArgs {
+    code: Option<String>,
+    path: Option<String>,
+    model: Option<String>,
+}
+#[derive(Debug, Clone, serde::Deserialize)]
+struct ExtractCodeBlocksArgs {
+    text: String,
+}
+#[derive(Debug, Clone, serde::Deserialize)]
+struct AskArgs {
+    question: String,
+    model: Option<String>,
+}
+
+// answer of a tool. text is shown as it is, structured is for programs
+struct ToolOutput {
+    text: String,
+    structured: Value,
+}
+
+// termai functions as mcp tools. runner calls gpt with the function and returns the answer
+pub struct McpServer<R> {
+    model: OpenAIModel,
+    runner: R,
+    // [review] of config.toml
+    review: ReviewOptions,
+}
+
+impl<R> McpServer<R>
+where
+    R: FnMut(&mut dyn GptFunction, OpenAIModel, UserInput) -> Result<String, String>,
+{
+    pub fn new(model: OpenAIModel, runner: R) -> Self {
+        Self {
+            model,
+            runner,
+            review: ReviewOptions::default(),
+        }
+    }
+    pub fn with_review_options(mut self, options: ReviewOptions) -> Self {
+        self.review = options;
+        self
+    }
+    // until stdin is closed
+    pub fn serve(&mut self, reader: impl BufRead, mut writer: impl Write) -> std::io::Result<()> {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<JsonRpcMessage>(&line) {
+                Ok(message) => self.handle(message),
+                Err(e) => Some(JsonRpcMessage::error_response(
+                    Value::Null,
+                    JsonRpcError::PARSE_ERROR,
+                    e.to_string(),
+                )),
+            };
+            if let Some(response) = response {
+                writeln!(
+                    writer,
+                    "{}",
+                    serde_json::to_string(&response).unwrap_or_default()
+                )?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+    // notifications get no response
+    pub fn handle(&mut self, message: JsonRpcMessage) -> Option<JsonRpcMessage> {
+        let id = message.id?;
+        let method = message.method.unwrap_or_default();
+        let params = message.params.unwrap_or_default();
+        let result = match method.as_str() {
+            "initialize" => Ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {} },
+                "serverInfo": { "name": "termai", "version": env!("CARGO_PKG_VERSION") }
+            })),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": Self::tools() })),
+            "tools/call" => self.call_tool(&params),
+            _ => Err((
+                JsonRpcError::METHOD_NOT_FOUND,
+                format!("method {} is not found", method),
+            )),
+        };
+        Some(match result {
+            Ok(result) => JsonRpcMessage::response(id, result),
+            Err((code, message)) => JsonRpcMessage::error_response(id, code, message),
+        })
+    }
+    fn tools() -> Vec<McpTool> {
+        let model = json!({ "type": "string", "description": "e.g. gpt-4o. default is the model of mcp-serve" });
+        vec![
+            McpTool {
+                name: "translate".to_string(),
+                description: Some("translate the text".to_string()),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "text": { "type": "string" },
+                        "target_lang": { "type": "string", "enum": ["ja", "en", "ko", "ch"] },
+                        "model": model
+                    },
+                    "required": ["text", "target_lang"]
+                }),
+            },
+            McpTool {
+                name: "review-code".to_string(),
+                description: Some("review the code, or the file of the path".to_string()),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "code": { "type": "string" },
+                        "path": { "type": "string" },
+                        "model": model
+                    }
+                }),
+            },
+            McpTool {
+                name: "extract-code-blocks".to_string(),
+                description: Some(
+                    "extract code blocks of markdown with their languages. gpt is not called"
+                        .to_string(),
+                ),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": { "text": { "type": "string" } },
+                    "required": ["text"]
+                }),
+            },
+            McpTool {
+                name: "ask".to_string(),
+                description: Some("ask gpt a question".to_string()),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "question": { "type": "string" },
+                        "model": model
+                    },
+                    "required": ["question"]
+                }),
+            },
+        ]
+    }
+    // a failure of the tool is a result with isError, not a json-rpc error
+    fn call_tool(&mut self, params: &Value) -> Result<Value, (i64, String)> {
+        let name = params["name"].as_str().unwrap_or_default();
+        let args = params.get("arguments").cloned().unwrap_or(json!({}));
+        let output = match name {
+            "translate" => self.translate(args),
+            "review-code" => self.review_code(args),
+            "extract-code-blocks" => Self::extract_code_blocks(args),
+            "ask" => self.ask(args),
+            _ => {
+                return Err((
+                    JsonRpcError::INVALID_PARAMS,
+                    format!("tool {} is not found", name),
+                ))
+            }
+        };
+        Ok(match output {
+            Ok(output) => json!({
+                "content": [{ "type": "text", "text": output.text }],
+                "structuredContent": output.structured,
+                "isError": false
+            }),
+            Err(e) => json!({
+                "content": [{ "type": "text", "text": e }],
+                "isError": true
+            }),
+        })
+    }
+    fn translate(&mut self, args: Value) -> Result<ToolOutput, String> {
+        let args = parse::<TranslateArgs>(args)?;
+        let mode = TranslateMode::from_str(&args.target_lang)?;
+        let model = self.model(args.model.as_deref())?;
+        let translation =
+            (self.runner)(&mut Translator::new(mode), model, UserInput::new(args.text))?;
+        Ok(ToolOutput {
+            structured: json!({ "translation": translation, "target_lang": args.target_lang }),
+            text: translation,
+        })
+    }
+    fn review_code(&mut self, args: Value) -> Result<ToolOutput, String> {
+        let args = parse::<ReviewCodeArgs>(args)?;
+        let (target, input) = match (args.path, args.code) {
+            (Some(path), _) => (path.clone(), path),
+            (None, Some(code)) => ("input".to_string(), code),
+            (None, None) => return Err("code or path is required".to_string()),
+        };
+        let model = self.model(args.model.as_deref())?;
+        let mut reviewer = CodeReviewer::default().with_options(&self.review);
+        let review = (self.runner)(&mut reviewer, model, UserInput::new(input))?;
+        Ok(ToolOutput {
+            structured: json!({ "target": target, "review": review }),
+            text: review,
+        })
+    }
+    fn extract_code_blocks(args: Value) -> Result<ToolOutput, String> {
+        let args = parse::<ExtractCodeBlocksArgs>(args)?;
+        let mut capture = CodeCapture::new();
+        capture.add(&args.text);
+        let blocks = capture
+            .get_codes()
+            .iter()
+            .map(|code| json!({ "lang": code.lang(), "code": code.as_str() }))
+            .collect::<Vec<_>>();
+        let structured = json!({ "blocks": blocks });
+        Ok(ToolOutput {
+            text: structured.to_string(),
+            structured,
+        })
+    }
+    fn ask(&mut self, args: Value) -> Result<ToolOutput, String> {
+        let args = parse::<AskArgs>(args)?;
+        let model = self.model(args.model.as_deref())?;
+        let answer = (self.runner)(
+            &mut GptDefaultFunction::new(),
+            model,
+            UserInput::new(args.question),
+        )?;
+        Ok(ToolOutput {
+            structured: json!({ "answer": answer, "model": model.as_str() }),
+            text: answer,
+        })
+    }
+    fn model(&self, model: Option<&str>) -> Result<OpenAIModel, String> {
+        model.map_or(Ok(self.model), OpenAIModel::from_str)
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(args: Value) -> Result<T, String> {
+    serde_json::from_value(args).map_err(|e| format!("invalid arguments : {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn request(id: u64, method: &str, params: Value) -> JsonRpcMessage {
+        JsonRpcMessage::request(id, method, params)
+    }
+    #[test]
+    fn 初期化とツール一覧に応答し通知には応答しない() {
+        let mut sut = McpServer::new(OpenAIModel::Gpt4o, |_: &mut dyn GptFunction, _, _| {
+            Ok(String::new())
+        });
+
+        let initialize = sut.handle(request(1, "initialize", json!({}))).unwrap();
+        let initialized = sut.handle(JsonRpcMessage::notification("notifications/initialized"));
+        let tools = sut.handle(request(2, "tools/list", json!({}))).unwrap();
+        let unknown = sut.handle(request(3, "resources/list", json!({}))).unwrap();
+
+        assert_eq!(
+            initialize.result.unwrap()["protocolVersion"],
+            PROTOCOL_VERSION
+        );
+        assert!(initialized.is_none());
+        assert_eq!(
+            tools.result.unwrap()["tools"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|tool| tool["name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>(),
+            vec!["translate", "review-code", "extract-code-blocks", "ask"]
+        );
+        assert_eq!(unknown.error.unwrap().code, JsonRpcError::METHOD_NOT_FOUND);
+    }
+    #[test]
+    fn 関数とモデルを選んでgptを呼び構造化した結果を返す() {
+        let mut called = Vec::new();
+        let mut sut = McpServer::new(
+            OpenAIModel::Gpt4o,
+            |f: &mut dyn GptFunction, model: OpenAIModel, input: UserInput| {
+                called.push((f.name(), model, input.content().to_string()));
+                Ok("こんにちは".to_string())
+            },
+        );
+
+        let translated = sut
+            .handle(request(
+                1,
+                "tools/call",
+                json!({"name": "translate", "arguments": {"text": "hello", "target_lang": "ja", "model": "gpt-4"}}),
+            ))
+            .unwrap();
+        let invalid = sut
+            .handle(request(
+                2,
+                "tools/call",
+                json!({"name": "translate", "arguments": {"text": "hello", "target_lang": "xx"}}),
+            ))
+            .unwrap();
+        drop(sut);
+
+        let translated = translated.result.unwrap();
+        assert_eq!(translated["content"][0]["text"], "こんにちは");
+        assert_eq!(
+            translated["structuredContent"],
+            json!({"translation": "こんにちは", "target_lang": "ja"})
+        );
+        assert_eq!(invalid.result.unwrap()["isError"], true);
+        assert_eq!(
+            called,
+            vec![(
+                "translate".to_string(),
+                OpenAIModel::Gpt4,
+                "hello".to_string()
+            )]
+        );
+    }
+    #[test]
+    fn コードブロックの抽出はgptを呼ばない() {
+        let mut sut = McpServer::new(OpenAIModel::Gpt4o, |_: &mut dyn GptFunction, _, _| {
+            Err("must not be called".to_string())
+        });
+        let input = format!(
+            "{}\n{}\n",
+            json!({"jsonrpc": "2.0", "id": 1, "method": "tools/call", "params": {"name": "extract-code-blocks", "arguments": {"text": "see\n```rust\nfn main() {}\n```\n"}}}),
+            "not json"
+        );
+        let mut output = Vec::new();
+
+        sut.serve(input.as_bytes(), &mut output).unwrap();
+
+        let lines = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<JsonRpcMessage>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines[0].result.as_ref().unwrap()["structuredContent"],
+            json!({"blocks": [{"lang": "rust", "code": "fn main() {}\n"}]})
+        );
+        assert_eq!(
+            lines[1].error.as_ref().unwrap().code,
+            JsonRpcError::PARSE_ERROR
+        );
+    }
+}